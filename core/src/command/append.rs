@@ -0,0 +1,157 @@
+use time::Timespec;
+
+use command::response::ResponseWriter;
+use command::search::parse_imap_date;
+use error::AppendError;
+use mailstore::MailStore;
+use message::Flag;
+use parser;
+
+/// Representation of an APPEND command
+#[derive(Debug, PartialEq)]
+pub struct AppendCommand {
+    pub mailbox: String,
+    pub flags: Vec<Flag>,
+    /// The INTERNALDATE the client asked for, rather than the time the
+    /// message is appended
+    pub date: Option<Timespec>,
+    pub message: Vec<u8>
+}
+
+/// Parse and perform the APPEND whose arguments, including the message
+/// literal, are given in `append_args`, adding the message to `store`.
+/// Returns the response to the client or None if a BAD response should be
+/// sent back to the client.
+pub fn append<S: MailStore>(store: &S, max_size: Option<usize>, append_args: &[u8],
+                            tag: &str) -> Option<String> {
+    let mut cmd = b"APPEND ".to_vec();
+    cmd.extend_from_slice(append_args);
+    let parsed = match parser::append(&cmd[..]) {
+        Ok(parsed) => parsed,
+        Err(_) => return None
+    };
+
+    // The message is stored exactly as the client sent it, so that FETCH
+    // gives back the same octets and RFC822.SIZE is the literal's length.
+    let data = &parsed.message[..];
+    if max_size.map_or(false, |max| data.len() > max) {
+        return Some(ResponseWriter::new().tagged_no(tag, "[TOOBIG] Message is too big").into_string());
+    }
+
+    let res = ResponseWriter::new();
+    let res = match store.append(&parsed.mailbox[..], data, &parsed.flags[..], parsed.date) {
+        Ok(()) => res.tagged_ok(tag, "APPEND completed"),
+        // The client is told to create the mailbox if it doesn't exist.
+        Err(AppendError::NotFound) => res.tagged_no(tag, "[TRYCREATE] No such mailbox"),
+        Err(AppendError::OverQuota) => res.tagged_no(tag, "[OVERQUOTA] Mailbox is full"),
+        Err(e) => {
+            error!("Failed to append to {}: {}", parsed.mailbox, e);
+            res.tagged_no(tag, "Could not append message")
+        }
+    };
+    Some(res.into_string())
+}
+
+/// Parse a date and time in the IMAP `date-time` form, such as
+/// `17-Jul-1996 02:44:25 -0700`, without the quotes around it.
+pub fn parse_date_time(date_time: &str) -> Option<Timespec> {
+    // The day may be padded with a space rather than a zero.
+    let mut parts = date_time.trim_start().split(' ');
+    let (date, time_of_day, zone) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(date), Some(time_of_day), Some(zone), None) => (date, time_of_day, zone),
+        _ => return None
+    };
+
+    let mut tm = parse_imap_date(date)?;
+    let mut fields = time_of_day.split(':');
+    tm.tm_hour = two_digits(fields.next()?, 23)?;
+    tm.tm_min = two_digits(fields.next()?, 59)?;
+    // Allow for a leap second.
+    tm.tm_sec = two_digits(fields.next()?, 60)?;
+    if fields.next().is_some() {
+        return None;
+    }
+
+    // The zone is the offset from UTC as +hhmm or -hhmm.
+    if zone.len() != 5 || !zone.is_char_boundary(1) {
+        return None;
+    }
+    let sign = match &zone[..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return None
+    };
+    let offset = two_digits(&zone[1..3], 99)? * 3600 + two_digits(&zone[3..], 59)? * 60;
+    Some(Timespec::new(tm.to_timespec().sec - (sign * offset) as i64, 0))
+}
+
+fn two_digits(field: &str, max: i32) -> Option<i32> {
+    if field.len() != 2 || !field.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let value = field.parse().ok()?;
+    if value > max { None } else { Some(value) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use command::Attribute::{Flags, InternalDate, RFC822};
+    use command::RFC822Attribute::SizeRFC822;
+    use command::fetch::FetchOptions;
+    use fixture;
+    use folder::Folder;
+    use maildir::Layout;
+    use mailstore::{MailFolder, MaildirStore};
+    use super::{append, parse_date_time};
+
+    #[test]
+    fn test_parse_date_time() {
+        assert_eq!(parse_date_time("17-Jul-1996 02:44:25 -0700").unwrap().sec, 837596665);
+        assert_eq!(parse_date_time(" 1-Jan-2000 00:00:00 +0000").unwrap().sec, 946684800);
+        assert_eq!(parse_date_time("01-Jan-2000 01:30:00 +0130").unwrap().sec, 946684800);
+
+        assert_eq!(parse_date_time("17-Jul-1996 02:44:25"), None);
+        assert_eq!(parse_date_time("17-Jul-1996 24:00:00 -0700"), None);
+        assert_eq!(parse_date_time("17-Jul-1996 2:44:25 -0700"), None);
+        assert_eq!(parse_date_time("17-Jul-1996 02:44:25 0700"), None);
+        assert_eq!(parse_date_time("30-Feb-1996 02:44:25 -0700"), None);
+    }
+
+    #[test]
+    fn test_append_internal_date() {
+        let dir = fixture::maildir("append", &[]);
+        let store = MaildirStore::new(&dir, Layout::Fs, false, 100);
+
+        let message = "Subject: Lunch\r\n\r\nNoon?\r\n";
+        assert_eq!(message.len(), 25);
+        let args = format!("INBOX (\\Seen) \"17-Jul-1996 02:44:25 -0700\" {{{}}}\r\n{}",
+                           message.len(), message);
+        assert_eq!(append(&store, None, args.as_bytes(), "a1"),
+                   Some("a1 OK APPEND completed\r\n".to_string()));
+        let args = format!("Lost {{{}}}\r\n{}", message.len(), message);
+        assert_eq!(append(&store, None, args.as_bytes(), "a2"),
+                   Some("a2 NO [TRYCREATE] No such mailbox\r\n".to_string()));
+        // The limit applies to the literal as it was sent.
+        let args = format!("INBOX {{{}}}\r\n{}", message.len(), message);
+        assert_eq!(append(&store, Some(24), args.as_bytes(), "a3"),
+                   Some("a3 NO [TOOBIG] Message is too big\r\n".to_string()));
+        let args = format!("INBOX \"17-Jul-1996\" {{{}}}\r\n{}", message.len(), message);
+        assert_eq!(append(&store, None, args.as_bytes(), "a4"), None);
+
+        // The date given is the one FETCH reports, and the message is stored
+        // as it was sent.
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert_eq!(folder.message_count(), 1);
+        assert_eq!(folder.fetch(0, &[InternalDate, Flags, RFC822(SizeRFC822)],
+                                FetchOptions::default()).unwrap(),
+                   "* 1 FETCH (INTERNALDATE \"17-Jul-1996 09:44:25 -0000\" FLAGS (\\Seen) \
+                    RFC822.SIZE 25)\r\n");
+        let entry = fs::read_dir(dir.join("cur")).unwrap().next().unwrap().unwrap();
+        assert_eq!(fs::read(entry.path()).unwrap(), message.as_bytes().to_vec());
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
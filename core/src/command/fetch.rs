@@ -1,12 +1,24 @@
-use std::collections::HashSet;
+use std::io::Write;
 
 use command::FetchCommand;
-use command::Attribute::BodySection;
-use folder::Folder;
+use command::Attribute::{BodySection, Flags, RFC822};
+use command::RFC822Attribute::{AllRFC822, TextRFC822};
+use command::response::ResponseWriter;
+use mailstore::MailFolder;
 use parser::{self, ParserResult};
 
-use message::Flag::Seen;
-use super::store::StoreName::Add;
+/// How the messages are given in FETCH responses, beyond the attributes the
+/// client asked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FetchOptions {
+    /// Decode MIME encoded-words in the ENVELOPE
+    pub decode_headers: bool,
+    /// Send the contents with CRLF line endings
+    pub crlf: bool,
+    /// Include the message's FLAGS, as the client didn't ask for them but
+    /// the fetch changed them
+    pub report_flags: bool
+}
 
 /// Take the rest of the arguments provided by the client and parse them into a
 /// `FetchCommand` object with `parser::fetch`.
@@ -20,36 +32,208 @@ pub fn fetch(args: Vec<&str>) -> ParserResult<FetchCommand> {
     parser::fetch(cmd.as_bytes())
 }
 
-/// Perform the fetch operation on each sequence number indicated and return
-/// the response to be sent back to the client.
-pub fn fetch_loop(parsed_cmd: &FetchCommand, folder: &mut Folder,
-                  sequence_iter: &[usize], tag: &str, uid: bool) -> String {
-    for attr in &parsed_cmd.attributes {
-        if let BodySection(_, _) = *attr {
-            let mut seen_flag_set = HashSet::new();
-            seen_flag_set.insert(Seen);
-            folder.store(sequence_iter.to_vec(), &Add, true, seen_flag_set,
-                         false, tag);
-            break;
-        }
-    }
+/// Perform the fetch operation on each sequence number indicated, writing
+/// each message's FETCH response to `out` as soon as it is generated so that
+/// only one message is held in memory at a time. Returns the tagged
+/// completion response to be sent back to the client.
+pub fn fetch_loop<W: Write>(parsed_cmd: &FetchCommand, folder: &mut dyn MailFolder,
+                            sequence_iter: &[usize], tag: &str, uid: bool,
+                            options: FetchOptions, out: &mut W) -> String {
+    // BODY[...], RFC822 and RFC822.TEXT set the Seen flag while
+    // BODY.PEEK[...], RFC822.HEADER and RFC822.SIZE don't.
+    let sets_seen = parsed_cmd.attributes.iter().any(|attr| match *attr {
+        BodySection(_, _) | RFC822(AllRFC822) | RFC822(TextRFC822) => true,
+        _ => false
+    });
+    let has_flags = parsed_cmd.attributes.contains(&Flags);
 
-    let mut res = String::new();
     for i in sequence_iter {
         let index = if !uid {
             *i-1
         } else if let Some(index) = folder.get_index_from_uid(i) {
-            *index
+            index
         } else {
             continue;
         };
-        res.push_str(&folder.fetch(index, &parsed_cmd.attributes)[..]);
+        // Only the messages which have changed are wanted.
+        if let Some(modseq) = parsed_cmd.changed_since {
+            if folder.modseq(index).map_or(false, |changed| changed <= modseq) {
+                continue;
+            }
+        }
+        // The new flags are reported if the client didn't ask for them.
+        let flags_changed = sets_seen && folder.mark_seen(index);
+        let options = FetchOptions { report_flags: flags_changed && !has_flags, ..options };
+        let res = match folder.fetch(index, &parsed_cmd.attributes, options) {
+            Ok(res) => res,
+            // The message may have been removed by another session since the
+            // folder was selected, or may not be readable at all.
+            Err(e) => {
+                warn!("Failed to fetch message {}: {}", i, e);
+                continue;
+            }
+        };
+        // If the client has gone away there's no point in carrying on.
+        if out.write_all(res.as_bytes()).and_then(|_| out.flush()).is_err() {
+            break;
+        }
     }
-    res.push_str(tag);
-    res.push_str(" OK ");
-    if uid {
-        res.push_str("UID ");
+
+    let text = if uid { "UID FETCH completed" } else { "FETCH completed" };
+    ResponseWriter::new().tagged_ok(tag, text).into_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use command::Attribute::UID;
+    use command::store::StoreName;
+    use fixture;
+    use folder::Folder;
+    use mailstore::{MailFolder, MailStore};
+    use memstore::MemoryStore;
+    use message::Flag;
+    use super::{fetch, fetch_loop, FetchOptions};
+
+    #[test]
+    fn test_fetch_loop_writes_each_message() {
+        let dir = fixture::maildir("fetch", &[(10, &[Flag::Seen]), (20, &[Flag::Seen])]);
+        let mut folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let parsed_cmd = fetch(vec!["1:2", "UID"]).unwrap();
+
+        let mut out = Vec::new();
+        let res = fetch_loop(&parsed_cmd, &mut folder, &[1, 2], "a1", false,
+                             FetchOptions::default(), &mut out);
+        assert_eq!(res, "a1 OK FETCH completed\r\n");
+        let written = String::from_utf8(out).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.contains("UID 10"));
+        assert!(written.contains("UID 20"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_loop_in_memory() {
+        let store = MemoryStore::new();
+        store.append("INBOX", b"Subject: one\n\nOne\n", &[], None).unwrap();
+        store.append("INBOX", b"Subject: two\n\nTwo\n", &[], None).unwrap();
+        let mut folder = store.open("INBOX", false).unwrap();
+
+        // Reading a message sets \Seen, which the client is told about.
+        let parsed_cmd = fetch(vec!["2", "RFC822.TEXT"]).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(fetch_loop(&parsed_cmd, &mut *folder, &[2], "a1", true,
+                               FetchOptions::default(), &mut out),
+                   "a1 OK UID FETCH completed\r\n");
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "* 2 FETCH (RFC822.TEXT {4}\r\nTwo\n FLAGS (\\Seen))\r\n");
+
+        // UIDs which aren't in the folder are skipped.
+        let parsed_cmd = fetch(vec!["1:3", "FLAGS"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut *folder, &[1, 3], "a2", true,
+                   FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "* 1 FETCH (FLAGS ())\r\n");
+    }
+
+    #[test]
+    fn test_uid_fetch_after_expunge() {
+        let dir = fixture::maildir("fetch-expunged", &[]);
+        for uid in &[10, 20, 30] {
+            fixture::write_message(&dir, "cur", *uid, &[Flag::Seen],
+                                   &format!("Subject: hi\n\n{}\n", uid));
+        }
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Deleted);
+        folder.store(vec![1, 2], &StoreName::Add, true, flags, false, false);
+        assert_eq!(folder.expunge(), vec![1, 1]);
+
+        // The message left behind is found by its UID at its new position.
+        let parsed_cmd = fetch(vec!["10:30", "(UID", "RFC822.TEXT)"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[10, 20, 30], "a1", true,
+                   FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "* 1 FETCH (UID 30 RFC822.TEXT {3}\r\n30\n)\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_loop_sets_seen() {
+        let dir = fixture::maildir("fetch-seen", &[(10, &[]), (20, &[])]);
+
+        // Fetching from an EXAMINEd folder never changes the flags.
+        let mut folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let parsed_cmd = fetch(vec!["1", "RFC822.TEXT"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[1], "a1", false, FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "* 1 FETCH (RFC822.TEXT {3}\r\nHi\n)\r\n");
+
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        // Neither the header nor the size sets Seen.
+        let parsed_cmd = fetch(vec!["1:2", "(RFC822.HEADER", "RFC822.SIZE)"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[1, 2], "a2", false,
+                   FetchOptions::default(), &mut out);
+        assert!(!String::from_utf8(out).unwrap().contains("FLAGS"));
+
+        // The text does, and the new flags are reported once.
+        let parsed_cmd = fetch(vec!["1", "RFC822.TEXT"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[1], "a3", false, FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "* 1 FETCH (RFC822.TEXT {3}\r\nHi\n FLAGS (\\Seen))\r\n");
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[1], "a4", false, FetchOptions::default(), &mut out);
+        assert!(!String::from_utf8(out).unwrap().contains("FLAGS"));
+
+        // The flag is written to disk on CHECK, leaving the unread message
+        // alone.
+        folder.check();
+        assert!(dir.join("cur").join("10:2,S").exists());
+        assert!(dir.join("cur").join("20:2,").exists());
+
+        // A UID FETCH of a body section reports the UID first and the
+        // flags it changed last.
+        let mut parsed_cmd = fetch(vec!["20", "(BODY[TEXT])"]).unwrap();
+        parsed_cmd.attributes.insert(0, UID);
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[20], "a5", true, FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "* 2 FETCH (UID 20 BODY[TEXT] {3}\r\nHi\n FLAGS (\\Seen))\r\n");
+        folder.expunge();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_reads_contents_lazily() {
+        let dir = fixture::maildir("fetch-lazy", &[(10, &[Flag::Seen]), (20, &[Flag::Seen])]);
+        let mut folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+
+        // Attributes which only need the filename and metadata work without
+        // the contents, so a message which has since vanished still answers.
+        fs::remove_file(dir.join("cur").join("20:2,S")).unwrap();
+        let parsed_cmd = fetch(vec!["1:2", "(UID", "FLAGS)"]).unwrap();
+        let mut out = Vec::new();
+        fetch_loop(&parsed_cmd, &mut folder, &[1, 2], "a1", false,
+                   FetchOptions::default(), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "* 1 FETCH (UID 10 FLAGS (\\Seen))\r\n* 2 FETCH (UID 20 FLAGS (\\Seen))\r\n");
+
+        // Its contents can't be fetched, but the other message's can.
+        let parsed_cmd = fetch(vec!["1:2", "RFC822.TEXT"]).unwrap();
+        let mut out = Vec::new();
+        let res = fetch_loop(&parsed_cmd, &mut folder, &[1, 2], "a2", false,
+                             FetchOptions::default(), &mut out);
+        assert_eq!(res, "a2 OK FETCH completed\r\n");
+        assert_eq!(String::from_utf8(out).unwrap(), "* 1 FETCH (RFC822.TEXT {3}\r\nHi\n)\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    res.push_str("FETCH completed\r\n");
-    res
 }
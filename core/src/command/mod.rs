@@ -1,6 +1,10 @@
+pub mod append;
 pub mod sequence_set;
 pub mod store;
 pub mod fetch;
+pub mod response;
+pub mod search;
+pub mod select;
 
 use command::sequence_set::SequenceItem;
 
@@ -16,6 +20,7 @@ pub enum Attribute {
     Envelope,
     Flags,
     InternalDate,
+    ModSeq,
     RFC822(RFC822Attribute),
     UID
 }
@@ -33,10 +38,14 @@ pub enum RFC822Attribute {
 /// It has a list of message ids (either UIDs or indexes into the folder's list
 /// of messages)
 /// It has a list of message attributes which are being requested.
+/// It may be limited to the messages changed since a mod-sequence, in which
+/// case the expunged messages can be asked for too (RFC 7162).
 #[derive(PartialEq, Debug)]
 pub struct FetchCommand {
     pub sequence_set: Vec<SequenceItem>,
-    pub attributes: Vec<Attribute>
+    pub attributes: Vec<Attribute>,
+    pub changed_since: Option<u64>,
+    pub vanished: bool
 }
 
 impl FetchCommand {
@@ -44,7 +53,9 @@ impl FetchCommand {
                -> FetchCommand {
         FetchCommand {
             sequence_set: sequence_set,
-            attributes: attributes
+            attributes: attributes,
+            changed_since: None,
+            vanished: false
         }
     }
 }
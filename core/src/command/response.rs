@@ -0,0 +1,99 @@
+//! Building the responses sent back to the client. Every line written here
+//! ends with CRLF, so that a response can't run on into the next one and
+//! leave the client unable to tell where it stops.
+
+/// The responses to a command, in the order they are to be sent.
+#[derive(Debug, Default)]
+pub struct ResponseWriter {
+    res: String
+}
+
+impl ResponseWriter {
+    pub fn new() -> ResponseWriter {
+        ResponseWriter {
+            res: String::new()
+        }
+    }
+
+    /// An untagged response, `* text`.
+    pub fn untagged(self, text: &str) -> ResponseWriter {
+        self.line("*", text)
+    }
+
+    /// The tagged response telling the client its command succeeded.
+    pub fn tagged_ok(self, tag: &str, text: &str) -> ResponseWriter {
+        self.line(tag, &format!("OK {}", text))
+    }
+
+    /// The tagged response telling the client its command failed.
+    pub fn tagged_no(self, tag: &str, text: &str) -> ResponseWriter {
+        self.line(tag, &format!("NO {}", text))
+    }
+
+    /// The tagged response telling the client its command was invalid.
+    pub fn tagged_bad(self, tag: &str, text: &str) -> ResponseWriter {
+        self.line(tag, &format!("BAD {}", text))
+    }
+
+    /// Responses which have already been put together elsewhere, such as
+    /// the untagged FETCH responses from a folder. A missing CRLF at the
+    /// end is added.
+    pub fn responses(mut self, responses: &str) -> ResponseWriter {
+        if !responses.is_empty() {
+            self.res.push_str(trim_line_ending(responses));
+            self.res.push_str("\r\n");
+        }
+        self
+    }
+
+    pub fn into_string(self) -> String {
+        self.res
+    }
+
+    // Any line ending already on the text is replaced so that it isn't
+    // doubled or left as a bare LF.
+    fn line(mut self, prefix: &str, text: &str) -> ResponseWriter {
+        self.res.push_str(prefix);
+        self.res.push(' ');
+        self.res.push_str(trim_line_ending(text));
+        self.res.push_str("\r\n");
+        self
+    }
+}
+
+fn trim_line_ending(text: &str) -> &str {
+    text.trim_end_matches(|c| c == '\r' || c == '\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseWriter;
+
+    #[test]
+    fn test_response_writer() {
+        let res = ResponseWriter::new()
+            .untagged("2 EXISTS")
+            .untagged("BYE Server logging out\r\n")
+            .tagged_ok("a1", "LOGOUT completed\n");
+        assert_eq!(res.into_string(),
+                   "* 2 EXISTS\r\n* BYE Server logging out\r\na1 OK LOGOUT completed\r\n");
+
+        assert_eq!(ResponseWriter::new().tagged_no("a2", "Invalid folder.").into_string(),
+                   "a2 NO Invalid folder.\r\n");
+        assert_eq!(ResponseWriter::new().tagged_bad("a3", "Invalid command").into_string(),
+                   "a3 BAD Invalid command\r\n");
+    }
+
+    #[test]
+    fn test_responses() {
+        assert_eq!(ResponseWriter::new().responses("").into_string(), "");
+        let res = ResponseWriter::new()
+            .responses("* 1 EXPUNGE\r\n")
+            .responses("* 1 FETCH (FLAGS (\\Seen))")
+            .responses("* 2 FETCH (RFC822.TEXT {3}\r\nHi\n)\n")
+            .tagged_ok("a1", "NOOP");
+        assert_eq!(res.into_string(),
+                   "* 1 EXPUNGE\r\n* 1 FETCH (FLAGS (\\Seen))\r\n\
+                    * 2 FETCH (RFC822.TEXT {3}\r\nHi\n)\r\na1 OK NOOP\r\n");
+    }
+}
@@ -0,0 +1,536 @@
+use command::response::ResponseWriter;
+use command::sequence_set::{self, SequenceItem};
+use mailstore::{MailFolder, MailMessage};
+use message::Flag;
+use mime::decode_encoded_words;
+use mime::Message as MIME_Message;
+use parser;
+use time::{self, Timespec, Tm};
+
+/// A criterion which messages are matched against by SEARCH. Keys combine
+/// into a tree through `And`, `Or` and `Not`; keys such as UNSEEN and NEW
+/// are parsed into their equivalent combinations.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchKey {
+    All,
+    // The message matches every key
+    And(Vec<SearchKey>),
+    // The message matches either key
+    Or(Box<SearchKey>, Box<SearchKey>),
+    // The message doesn't match the key
+    Not(Box<SearchKey>),
+    // The message has the flag set
+    Flag(Flag),
+    Recent,
+    // Compares the message's internal date with a date
+    Date(DateComparison, Tm),
+    // The named header field contains the string
+    Header(String, String),
+    // The message's body contains the string
+    Body(String),
+    // The message's header or body contains the string
+    Text(String),
+    // The message is larger than the number of octets
+    Larger(usize),
+    // The message is smaller than the number of octets
+    Smaller(usize),
+    // The message's sequence number is in the set
+    Sequence(Vec<SequenceItem>),
+    // The message's UID is in the set
+    Uid(Vec<SequenceItem>),
+    // The message's mod-sequence is at least the given one (RFC 7162)
+    ModSeq(u64)
+}
+
+/// A parsed SEARCH command: the charset its strings are in, if given, and
+/// the keys every matching message must match.
+#[derive(Debug, PartialEq)]
+pub struct SearchCommand {
+    /// The results asked for with RETURN, if the client wants an ESEARCH
+    /// response (RFC 4731) rather than a SEARCH response
+    pub return_options: Option<Vec<SearchReturn>>,
+    pub charset: Option<String>,
+    pub keys: Vec<SearchKey>
+}
+
+impl SearchCommand {
+    pub fn new(charset: Option<String>, keys: Vec<SearchKey>) -> SearchCommand {
+        SearchCommand {
+            return_options: None,
+            charset: charset,
+            keys: keys
+        }
+    }
+}
+
+/// A result which an ESEARCH response gives about the matching messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchReturn {
+    // The lowest matching message
+    Min,
+    // The highest matching message
+    Max,
+    // How many messages match
+    Count,
+    // Every matching message
+    All
+}
+
+/// Where a message is in the folder being searched.
+pub struct Position {
+    pub seqnum: usize,
+    // Whether the message is recent in this session
+    pub recent: bool,
+    // The values of * for sequence numbers and UIDs
+    pub max_seqnum: usize,
+    pub max_uid: usize,
+    // The message's mod-sequence, if the folder keeps them
+    pub modseq: Option<u64>
+}
+
+/// How a SEARCH date criterion compares the message's date with the date
+/// given by the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateComparison {
+    Before, // the message's date is earlier than the given date
+    On, // the message's date is the given date
+    Since // the message's date is the given date or later
+}
+
+/// Parse the arguments of a SEARCH command and find the matching messages.
+/// Returns the response to the client or None if a BAD response should be
+/// sent back to the client. Searching by mod-sequence sets `condstore`, as
+/// it enables CONDSTORE (RFC 7162).
+pub fn search(folder: &dyn MailFolder, args: Vec<&str>, uid: bool, condstore: &mut bool,
+              tag: &str) -> Option<String> {
+    let mut cmd = "SEARCH".to_string();
+    for arg in args {
+        cmd.push(' ');
+        cmd.push_str(arg);
+    }
+    let parsed_cmd = match parser::search(cmd.as_bytes()) {
+        Ok(parsed_cmd) => parsed_cmd,
+        Err(_) => return None
+    };
+
+    // Strings are compared as UTF-8, which US-ASCII is a subset of.
+    if let Some(ref charset) = parsed_cmd.charset {
+        let charset = charset.to_ascii_uppercase();
+        if charset != "UTF-8" && charset != "US-ASCII" {
+            return Some(ResponseWriter::new()
+                        .tagged_no(tag, "[BADCHARSET (UTF-8 US-ASCII)] Unsupported charset")
+                        .into_string());
+        }
+    }
+
+    // A folder which doesn't keep mod-sequences can't be searched by them.
+    let by_modseq = parsed_cmd.keys.iter().any(uses_modseq);
+    if by_modseq {
+        if folder.highest_modseq().is_none() {
+            return None;
+        }
+        *condstore = true;
+    }
+
+    let results = folder.search(&parsed_cmd.keys, uid);
+    // The highest mod-sequence of the matching messages is given with them
+    // when they were searched for by mod-sequence.
+    let highest = if !by_modseq {
+        None
+    } else {
+        results.iter().filter_map(|&num| {
+            let index = if uid { folder.get_index_from_uid(&num) } else { num.checked_sub(1) };
+            index.and_then(|index| folder.modseq(index))
+        }).max()
+    };
+    let res = match parsed_cmd.return_options {
+        Some(ref options) => esearch_response(&results, options, uid, highest, tag),
+        None => {
+            let mut res = "SEARCH".to_string();
+            for num in results {
+                res.push(' ');
+                res.push_str(&num.to_string()[..]);
+            }
+            if let Some(modseq) = highest {
+                res.push_str(&format!(" (MODSEQ {})", modseq)[..]);
+            }
+            res
+        }
+    };
+    let text = if uid { "UID SEARCH completed" } else { "SEARCH completed" };
+    Some(ResponseWriter::new().untagged(&res).tagged_ok(tag, text).into_string())
+}
+
+/// The text of the untagged ESEARCH response giving the results asked for
+/// about the matching messages, which are in ascending order. No options
+/// means ALL. MIN, MAX and ALL are left out if nothing matched. `modseq` is
+/// the highest mod-sequence of the messages, if it is to be given.
+fn esearch_response(results: &[usize], options: &[SearchReturn], uid: bool,
+                    modseq: Option<u64>, tag: &str) -> String {
+    let mut res = format!("ESEARCH (TAG \"{}\")", tag);
+    if uid {
+        res.push_str(" UID");
+    }
+    let all = options.is_empty() || options.contains(&SearchReturn::All);
+    if let (true, Some(min)) = (options.contains(&SearchReturn::Min), results.first()) {
+        res.push_str(&format!(" MIN {}", min)[..]);
+    }
+    if let (true, Some(max)) = (options.contains(&SearchReturn::Max), results.last()) {
+        res.push_str(&format!(" MAX {}", max)[..]);
+    }
+    if options.contains(&SearchReturn::Count) {
+        res.push_str(&format!(" COUNT {}", results.len())[..]);
+    }
+    if all && !results.is_empty() {
+        res.push_str(" ALL ");
+        res.push_str(&sequence_set::compress_to_sequence_set(results)[..]);
+    }
+    if let Some(modseq) = modseq {
+        res.push_str(&format!(" MODSEQ {}", modseq)[..]);
+    }
+    res
+}
+
+// Whether the key, or one it is made up of, is MODSEQ.
+fn uses_modseq(key: &SearchKey) -> bool {
+    match *key {
+        SearchKey::ModSeq(_) => true,
+        SearchKey::And(ref keys) => keys.iter().any(uses_modseq),
+        SearchKey::Or(ref a, ref b) => uses_modseq(a) || uses_modseq(b),
+        SearchKey::Not(ref key) => uses_modseq(key),
+        _ => false
+    }
+}
+
+/// Find the messages of a folder which match every one of the search keys,
+/// given each message along with where it is in the folder. Returns their
+/// UIDs if `uid` is set and their sequence numbers otherwise.
+pub fn search_messages<M, I>(messages: I, keys: &[SearchKey], uid: bool) -> Vec<usize>
+    where M: MailMessage, I: Iterator<Item = (M, Position)> {
+    let mut results = Vec::new();
+    for (message, position) in messages {
+        // The contents are read at most once per message, and only if a
+        // key needs them.
+        let mut mime_message = None;
+        if keys.iter().all(|key| matches(key, &message, &position, &mut mime_message)) {
+            results.push(if uid { message.get_uid() } else { position.seqnum });
+        }
+    }
+    results
+}
+
+/// Whether the message matches the search key. `mime_message` holds the
+/// contents of the message once they have been read for an earlier key.
+pub fn matches<M: MailMessage>(key: &SearchKey, message: &M, position: &Position,
+                               mime_message: &mut Option<MIME_Message>) -> bool {
+    match *key {
+        SearchKey::All => true,
+        SearchKey::And(ref keys) =>
+            keys.iter().all(|key| matches(key, message, position, mime_message)),
+        SearchKey::Or(ref a, ref b) =>
+            matches(a, message, position, mime_message) ||
+            matches(b, message, position, mime_message),
+        SearchKey::Not(ref key) => !matches(key, message, position, mime_message),
+        SearchKey::Flag(ref flag) => message.has_flag(flag),
+        SearchKey::Recent => position.recent,
+        SearchKey::Date(comparison, ref date) =>
+            date_matches(comparison, date, message.internal_date()),
+        SearchKey::Larger(size) => message.size() > size as u64,
+        SearchKey::Smaller(size) => message.size() < size as u64,
+        SearchKey::Sequence(ref set) =>
+            sequence_set::contains(set, position.seqnum, position.max_seqnum),
+        SearchKey::Uid(ref set) =>
+            sequence_set::contains(set, message.get_uid(), position.max_uid),
+        SearchKey::ModSeq(modseq) => position.modseq.map_or(false, |changed| changed >= modseq),
+        SearchKey::Header(ref field, ref value) => {
+            let mime_message = match message.load(mime_message, false) {
+                Ok(mime_message) => mime_message,
+                Err(_) => return false
+            };
+            // Encoded-words are decoded so that non-ASCII text can be found.
+            mime_message.get_headers(&field[..]).iter()
+                .any(|header| contains(&decode_encoded_words(&header[..])[..], value))
+        }
+        // Text sent as base64 or quoted-printable is looked for once decoded.
+        SearchKey::Body(ref value) => match message.load(mime_message, false) {
+            Ok(mime_message) => contains(&mime_message.get_decoded_text()[..], value),
+            Err(_) => false
+        },
+        SearchKey::Text(ref value) => match message.load(mime_message, false) {
+            Ok(mime_message) => contains(mime_message.get_header_block(), value) ||
+                contains(&mime_message.get_decoded_text()[..], value),
+            Err(_) => false
+        }
+    }
+}
+
+// Searching is by case-insensitive substring.
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase()[..])
+}
+
+/// Parse a date in the IMAP `date-text` form, `d-Mon-yyyy` or `dd-Mon-yyyy`,
+/// with a case-insensitive month name. Only the day, month and year of the
+/// result are meaningful.
+pub fn parse_imap_date(date: &str) -> Option<Tm> {
+    let mut parts = date.split('-');
+    let day = parts.next()?;
+    let month = parts.next()?;
+    let year = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    if day.is_empty() || day.len() > 2 || !day.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if year.len() != 4 || !year.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let day: i32 = day.parse().ok()?;
+    let year: i32 = year.parse().ok()?;
+    let month = match &month.to_ascii_lowercase()[..] {
+        "jan" => 0,
+        "feb" => 1,
+        "mar" => 2,
+        "apr" => 3,
+        "may" => 4,
+        "jun" => 5,
+        "jul" => 6,
+        "aug" => 7,
+        "sep" => 8,
+        "oct" => 9,
+        "nov" => 10,
+        "dec" => 11,
+        _ => return None
+    };
+    if day < 1 || day > days_in_month(month, year) {
+        return None;
+    }
+
+    let mut tm = time::empty_tm();
+    tm.tm_mday = day;
+    tm.tm_mon = month;
+    tm.tm_year = year - 1900;
+    Some(tm)
+}
+
+fn days_in_month(month: i32, year: i32) -> i32 {
+    match month {
+        1 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        1 => 28,
+        3 | 5 | 8 | 10 => 30,
+        _ => 31
+    }
+}
+
+/// Compare a message's internal date with a date given in a SEARCH
+/// criterion. Per RFC 3501 the time of day is disregarded, so the internal
+/// date is first turned into a calendar day in the server's timezone.
+pub fn date_matches(comparison: DateComparison, date: &Tm, internal_date: Timespec) -> bool {
+    let internal_day = day(&time::at(internal_date));
+    let day = day(date);
+    match comparison {
+        DateComparison::Before => internal_day < day,
+        DateComparison::On => internal_day == day,
+        DateComparison::Since => internal_day >= day
+    }
+}
+
+// The calendar day of a time, in an order which can be compared.
+fn day(tm: &Tm) -> (i32, i32, i32) {
+    (tm.tm_year, tm.tm_mon, tm.tm_mday)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use time::{self, Duration, Timespec, Tm};
+
+    use command::store::StoreName;
+    use fixture;
+    use folder::Folder;
+    use mailstore::{MailFolder, MailStore};
+    use memstore::MemoryStore;
+    use message::Flag::{self, Flagged, Seen};
+    use super::{date_matches, parse_imap_date, search};
+    use super::DateComparison::{Before, On, Since};
+
+    #[test]
+    fn test_search_headers_and_text() {
+        let dir = fixture::maildir("search", &[]);
+        let messages: [(&str, usize, &[Flag], &str); 4] = [
+            ("cur", 10, &[Seen], "Message-ID: <1@example.com>\nX-Tag: one\nX-Tag: two\n\nLunch?\n"),
+            ("cur", 20, &[], "Subject: =?UTF-8?Q?Caf=C3=A9?=\nX-Tag: three\n\nSee you there\n"),
+            ("new", 30, &[], "Subject: Lunch plans\n\nNoon\n"),
+            ("new", 40, &[], "Content-Type: text/plain; charset=utf-8\n\
+                              Content-Transfer-Encoding: base64\n\nQ3LDqnBlcyBmb3IgZGlubmVy\n")
+        ];
+        for &(subdir, uid, flags, contents) in &messages {
+            fixture::write_message(&dir, subdir, uid, flags, contents);
+        }
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let run = |args: &str, uid: bool| {
+            search(&folder, args.split(' ').collect(), uid, &mut false, "a1").unwrap()
+        };
+
+        // Every copy of a repeated header is checked.
+        assert_eq!(run("HEADER x-tag TWO", false), "* SEARCH 1\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("HEADER X-Tag \"\"", true),
+                   "* SEARCH 10 20\r\na1 OK UID SEARCH completed\r\n");
+        assert_eq!(run("HEADER Message-ID <1@example.com>", false),
+                   "* SEARCH 1\r\na1 OK SEARCH completed\r\n");
+        // Encoded-words are decoded before matching.
+        assert_eq!(run("SUBJECT caf\u{e9}", false), "* SEARCH 2\r\na1 OK SEARCH completed\r\n");
+        // BODY only looks at the body, while TEXT looks at the header too.
+        assert_eq!(run("BODY lunch", false), "* SEARCH 1\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("TEXT lunch", false), "* SEARCH 1 3\r\na1 OK SEARCH completed\r\n");
+        // Keys are combined with AND.
+        assert_eq!(run("TEXT lunch UNSEEN RECENT", false),
+                   "* SEARCH 3\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("BODY nothing", false), "* SEARCH\r\na1 OK SEARCH completed\r\n");
+        // Bodies sent as base64 or quoted-printable are decoded first.
+        assert_eq!(run("BODY dinner", false), "* SEARCH 4\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("TEXT cr\u{ea}pes", false), "* SEARCH 4\r\na1 OK SEARCH completed\r\n");
+        assert!(search(&folder, vec!["BOGUS"], false, &mut false, "a1").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Format a time as an IMAP date.
+    fn imap_date(tm: &Tm) -> String {
+        time::strftime("%d-%b-%Y", tm).unwrap()
+    }
+
+    // A memory store whose INBOX holds a message with each set of flags.
+    fn memory_inbox(flags: &[&[Flag]]) -> MemoryStore {
+        let store = MemoryStore::new();
+        for message_flags in flags {
+            store.append("INBOX", b"Subject: hi\n\nHi\n", message_flags, None).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_search_uids_and_flags() {
+        let store = memory_inbox(&[&[Seen], &[Flagged, Seen], &[], &[Flagged]]);
+        let folder = store.open("INBOX", true).unwrap();
+        let run = |args: &str, uid: bool| {
+            search(&*folder, args.split(' ').collect(), uid, &mut false, "a1").unwrap()
+        };
+
+        assert_eq!(run("UID 2:* FLAGGED", true),
+                   "* SEARCH 2 4\r\na1 OK UID SEARCH completed\r\n");
+        assert_eq!(run("CHARSET US-ASCII UID 1,3 SEEN", false),
+                   "* SEARCH 1\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("2:3 UNSEEN", false), "* SEARCH 3\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("* FLAGGED", true), "* SEARCH 4\r\na1 OK UID SEARCH completed\r\n");
+        assert_eq!(run("OR UNSEEN (FLAGGED UID 2) NOT 4", false),
+                   "* SEARCH 2 3\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("NOT OR SEEN FLAGGED", true),
+                   "* SEARCH 3\r\na1 OK UID SEARCH completed\r\n");
+        assert_eq!(run("CHARSET ISO-8859-1 ALL", false),
+                   "a1 NO [BADCHARSET (UTF-8 US-ASCII)] Unsupported charset\r\n");
+        // Mod-sequences aren't kept, so they can't be searched by.
+        assert!(search(&*folder, vec!["MODSEQ", "1"], false, &mut false, "a1").is_none());
+    }
+
+    #[test]
+    fn test_esearch() {
+        let store = memory_inbox(&[&[Seen], &[], &[Seen], &[]]);
+        let folder = store.open("INBOX", true).unwrap();
+        let run = |args: &str, uid: bool| {
+            search(&*folder, args.split(' ').collect(), uid, &mut false, "a1").unwrap()
+        };
+
+        assert_eq!(run("RETURN (MIN MAX) SEEN", false),
+                   "* ESEARCH (TAG \"a1\") MIN 1 MAX 3\r\na1 OK SEARCH completed\r\n");
+        assert_eq!(run("RETURN (COUNT) UNSEEN", true),
+                   "* ESEARCH (TAG \"a1\") UID COUNT 2\r\na1 OK UID SEARCH completed\r\n");
+        // No options means ALL.
+        assert_eq!(run("RETURN () UNSEEN", true),
+                   "* ESEARCH (TAG \"a1\") UID ALL 2,4\r\na1 OK UID SEARCH completed\r\n");
+        assert_eq!(run("RETURN (ALL COUNT MIN) ALL", false),
+                   "* ESEARCH (TAG \"a1\") MIN 1 COUNT 4 ALL 1:4\r\na1 OK SEARCH completed\r\n");
+        // Only the count is given when nothing matches.
+        assert_eq!(run("RETURN (MIN MAX COUNT ALL) DRAFT", false),
+                   "* ESEARCH (TAG \"a1\") COUNT 0\r\na1 OK SEARCH completed\r\n");
+    }
+
+    #[test]
+    fn test_search_modseq() {
+        let dir = fixture::maildir("search-modseq", &[(10, &[]), (20, &[]), (30, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        let modseq = folder.modseq(2).unwrap();
+        let mut condstore = false;
+
+        // The highest mod-sequence of the matching messages is given.
+        let res = search(&folder, vec!["MODSEQ", &modseq.to_string()[..]], true, &mut condstore,
+                         "a1").unwrap();
+        assert_eq!(res, format!("* SEARCH 10 30 (MODSEQ {})\r\na1 OK UID SEARCH completed\r\n",
+                                modseq + 1));
+        assert!(condstore);
+        let args = format!("RETURN (COUNT) NOT MODSEQ {}", modseq);
+        let res = search(&folder, args.split(' ').collect(), false, &mut condstore, "a2").unwrap();
+        assert_eq!(res, format!("* ESEARCH (TAG \"a2\") COUNT 1 MODSEQ {}\r\n\
+                                 a2 OK SEARCH completed\r\n", modseq - 1));
+        let res = search(&folder, vec!["MODSEQ", &(modseq + 2).to_string()[..]], false,
+                         &mut condstore, "a3").unwrap();
+        assert_eq!(res, "* SEARCH\r\na3 OK SEARCH completed\r\n");
+
+        folder.check();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_imap_date() {
+        let date = parse_imap_date("1-Feb-1994").unwrap();
+        assert_eq!((date.tm_mday, date.tm_mon, date.tm_year), (1, 1, 94));
+        let date = parse_imap_date("29-feb-2000").unwrap();
+        assert_eq!((date.tm_mday, date.tm_mon, date.tm_year), (29, 1, 100));
+        assert!(parse_imap_date("31-DEC-2019").is_some());
+
+        assert!(parse_imap_date("29-Feb-1900").is_none());
+        assert!(parse_imap_date("31-Apr-2019").is_none());
+        assert!(parse_imap_date("0-Jan-2019").is_none());
+        assert!(parse_imap_date("001-Jan-2019").is_none());
+        assert!(parse_imap_date("1-January-2019").is_none());
+        assert!(parse_imap_date("1-Jan-19").is_none());
+        assert!(parse_imap_date("1-Jan-2019-1").is_none());
+        assert!(parse_imap_date("\"1-Jan-2019\"").is_none());
+    }
+
+    #[test]
+    fn test_date_matches() {
+        // Find the start of a day in the server's timezone.
+        let noon = time::at(Timespec::new(1500000000, 0));
+        let midnight = noon.to_timespec() - Duration::hours(noon.tm_hour as i64)
+            - Duration::minutes(noon.tm_min as i64) - Duration::seconds(noon.tm_sec as i64);
+        let date = parse_imap_date(&imap_date(&noon)[..]).unwrap();
+
+        // The whole day is ON the date and SINCE it, and none of it is
+        // BEFORE it.
+        for &internal in &[midnight, noon.to_timespec()] {
+            assert!(date_matches(On, &date, internal));
+            assert!(date_matches(Since, &date, internal));
+            assert!(!date_matches(Before, &date, internal));
+        }
+
+        // The last second of the previous day is only BEFORE it.
+        let previous = midnight - Duration::seconds(1);
+        assert!(!date_matches(On, &date, previous));
+        assert!(!date_matches(Since, &date, previous));
+        assert!(date_matches(Before, &date, previous));
+
+        // The next day is only SINCE it.
+        let next = noon.to_timespec() + Duration::days(1);
+        assert!(!date_matches(On, &date, next));
+        assert!(date_matches(Since, &date, next));
+        assert!(!date_matches(Before, &date, next));
+    }
+}
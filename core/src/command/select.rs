@@ -0,0 +1,63 @@
+use command::sequence_set::SequenceItem;
+use parser;
+
+/// The parameters which may follow the mailbox name in SELECT or EXAMINE
+/// (RFC 7162)
+#[derive(Debug, Default, PartialEq)]
+pub struct SelectParams {
+    /// Whether CONDSTORE was given, which enables it for the session
+    pub condstore: bool,
+    /// What the client last knew of the mailbox, if it wants to be brought
+    /// up to date
+    pub qresync: Option<Qresync>
+}
+
+/// The state of a mailbox as the client last saw it, given with QRESYNC
+#[derive(Debug, PartialEq)]
+pub struct Qresync {
+    pub uid_validity: usize,
+    pub modseq: u64,
+    /// The UIDs the client knows of, or None if it knows of every message
+    pub known_uids: Option<Vec<SequenceItem>>
+}
+
+/// Parse the arguments which follow the mailbox name, if there are any.
+/// Returns None if they are invalid.
+pub fn params(args: &[&str]) -> Option<SelectParams> {
+    if args.is_empty() {
+        return Some(SelectParams::default());
+    }
+    parser::select_params(args.join(" ").as_bytes()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+    use super::{params, Qresync, SelectParams};
+
+    #[test]
+    fn test_params() {
+        assert_eq!(params(&[]), Some(SelectParams::default()));
+        assert_eq!(params(&["(CONDSTORE)"]).map(|params| params.condstore), Some(true));
+        assert_eq!(params(&["(QRESYNC", "(67890007", "20050715194045000", "41,43:211,214:*))"]),
+                   Some(SelectParams {
+                       condstore: false,
+                       qresync: Some(Qresync {
+                           uid_validity: 67890007,
+                           modseq: 20050715194045000,
+                           known_uids: Some(vec![Number(41),
+                                                 Range(Box::new(Number(43)), Box::new(Number(211))),
+                                                 Range(Box::new(Number(214)), Box::new(Wildcard))])
+                       })
+                   }));
+        // The sequence numbers matched to UIDs are accepted but not needed.
+        let with_match_data = params(&["(QRESYNC", "(1", "5", "1:10", "(1:3", "2:6)))"]).unwrap();
+        assert_eq!(with_match_data.qresync.unwrap().modseq, 5);
+        assert_eq!(params(&["(QRESYNC", "(1", "5))"]).unwrap().qresync.unwrap().known_uids, None);
+
+        assert_eq!(params(&["CONDSTORE"]), None);
+        assert_eq!(params(&["(QRESYNC", "(1))"]), None);
+        assert_eq!(params(&["(QRESYNC", "(1", "0))"]), None);
+        assert_eq!(params(&["(CONDSTORE)", "extra"]), None);
+    }
+}
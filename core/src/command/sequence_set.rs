@@ -15,62 +15,22 @@ pub enum SequenceItem {
     Wildcard
 }
 
-fn parse_item(item: &str) -> Option<SequenceItem> {
-    if let Ok(intseq) = item.parse() {
-        Some(Number(intseq))
-    } else if item == "*" {
-        // item is not a valid number
-        // If it is the wildcard value return that
-        // Otherwise, return no sequence item
-        Some(Wildcard)
-    } else {
-        None
-    }
-}
-
-/// Given a string as passed in from the client, create a list of sequence items
-/// If the string does not represent a valid list, return None
-pub fn parse(sequence_string: &str) -> Option<Vec<SequenceItem>> {
-    let sequences = sequence_string.split(',');
-    let mut sequence_set = Vec::new();
-    for sequence in sequences {
-        let mut range = sequence.split(':');
-        let start = match range.next() {
-            None => return None,
-            Some(seq) => {
-                match parse_item(seq) {
-                    None => return None,
-                    Some(item) => item
-                }
-            }
-        };
-        let stop = match range.next() {
-            None => {
-                // Nothing after ':'
-                // Add the number or wildcard and bail out
-                sequence_set.push(start);
-                continue;
-            }
-            Some(seq) => {
-                match parse_item(seq) {
-                    None => return None,
-                    Some(item) => item
-                }
-            }
-        };
-
-        // A valid range only has one colon
-        if range.next().is_some() {
-            return None;
-        }
-
-        sequence_set.push(Range(Box::new(start), Box::new(stop)));
-    }
-    Some(sequence_set)
+/// Whether the sequence set can be used with a mailbox holding `max_id`
+/// messages. Per RFC 3501 naming a specific message number beyond the end of
+/// the mailbox is an error, so such a set should get a BAD response. Ranges
+/// and the wildcard are not errors even in an empty mailbox; they simply
+/// match fewer messages, or none at all.
+pub fn in_range(sequence_set: &[SequenceItem], max_id: usize) -> bool {
+    sequence_set.iter().all(|item| match *item {
+        Number(num) => num <= max_id,
+        _ => true
+    })
 }
 
 /// Create the list of unsigned integers representing valid ids from a list of
-/// sequence items. Ideally this would handle wildcards in O(1) rather than O(n)
+/// sequence items. Ideally this would handle wildcards in O(1) rather than O(n).
+/// Ids beyond `max_id` are left out, so the list is empty if nothing in the
+/// mailbox matches, such as for `1:*` or `*` in an empty mailbox.
 pub fn iterator(sequence_set: &[SequenceItem], max_id: usize) -> Vec<usize> {
     // If the number of possible messages is 0, we return an empty vec.
     if max_id == 0 { return Vec::new() }
@@ -179,6 +139,88 @@ pub fn uid_iterator(sequence_set: &[SequenceItem]) -> Vec<usize> {
     items
 }
 
+/// Whether the id is in the sequence set, where the wildcard stands for
+/// `max_id`. Unlike `iterator` this doesn't list every id in the set, so
+/// it is cheap even for sets of UIDs spanning large ranges.
+pub fn contains(sequence_set: &[SequenceItem], id: usize, max_id: usize) -> bool {
+    let value = |item: &SequenceItem| match *item {
+        Number(num) => Some(num),
+        Wildcard => Some(max_id),
+        Range(_, _) => {
+            error!("A range of ranges is invalid.");
+            None
+        }
+    };
+    sequence_set.iter().any(|item| match *item {
+        Range(ref a, ref b) => match (value(a), value(b)) {
+            (Some(a), Some(b)) => {
+                let (min, max) = if a <= b { (a, b) } else { (b, a) };
+                min <= id && id <= max
+            }
+            _ => false
+        },
+        _ => value(item) == Some(id)
+    })
+}
+
+/// Write the ids out as a sequence set, with each run of consecutive ids
+/// collapsed into a range, such as `1:4,7,9:10`. The ids may be in any
+/// order.
+pub fn compress_to_sequence_set(ids: &[usize]) -> String {
+    let mut ids = ids.to_vec();
+    ids.sort();
+    ids.dedup();
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < ids.len() {
+        let start = ids[i];
+        while i + 1 < ids.len() && ids[i + 1] == ids[i] + 1 {
+            i += 1;
+        }
+        if ids[i] == start {
+            ranges.push(start.to_string());
+        } else {
+            ranges.push(format!("{}:{}", start, ids[i]));
+        }
+        i += 1;
+    }
+    ranges.join(",")
+}
+
+#[test]
+fn test_compress_to_sequence_set() {
+    assert_eq!(compress_to_sequence_set(&[]), "");
+    assert_eq!(compress_to_sequence_set(&[5]), "5");
+    assert_eq!(compress_to_sequence_set(&[1, 2, 3, 4]), "1:4");
+    assert_eq!(compress_to_sequence_set(&[1, 2, 3, 4, 7, 9, 10]), "1:4,7,9:10");
+    assert_eq!(compress_to_sequence_set(&[2, 4, 6]), "2,4,6");
+    assert_eq!(compress_to_sequence_set(&[10, 3, 9, 3, 1]), "1,3,9:10");
+}
+
+#[test]
+fn test_sequence_contains() {
+    let set = [Number(3), Range(Box::new(Number(10)), Box::new(Number(8))),
+               Range(Box::new(Number(1500000000)), Box::new(Wildcard))];
+    assert!(contains(&set, 3, 1600000000));
+    assert!(!contains(&set, 4, 1600000000));
+    assert!(contains(&set, 9, 1600000000));
+    assert!(contains(&set, 1550000000, 1600000000));
+    assert!(!contains(&set, 1650000000, 1600000000));
+    assert!(contains(&[Wildcard], 7, 7));
+    assert!(!contains(&[Wildcard], 6, 7));
+}
+
+#[test]
+fn test_sequence_in_range() {
+    assert!(in_range(&[Number(3), Range(Box::new(Number(2)), Box::new(Number(9)))], 3));
+    assert!(!in_range(&[Number(1), Number(4)], 3));
+    // Ranges and wildcards never refer to missing messages.
+    assert!(in_range(&[Range(Box::new(Number(1)), Box::new(Wildcard))], 0));
+    assert!(in_range(&[Wildcard], 0));
+    assert!(!in_range(&[Number(1)], 0));
+}
+
 #[test]
 fn test_sequence_num() {
     assert_eq!(iterator(&[Number(4324)], 5000), vec![4324]);
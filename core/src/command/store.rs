@@ -1,99 +1,322 @@
-use std::ascii::AsciiExt;
 use std::collections::HashSet;
 
-use folder::Folder;
+use mailstore::MailFolder;
 use message::Flag;
-use message::parse_flag;
+use parser;
 
-use self::StoreName::{Add, Replace, Sub};
-use super::sequence_set;
+use self::StoreName::Sub;
+use super::response::ResponseWriter;
+use super::sequence_set::{self, SequenceItem};
 
 /// Representation of a STORE operation
+#[derive(Debug, PartialEq)]
 pub enum StoreName {
     Replace, // replace current flags with new flags
     Add, // add new flags to current flags
     Sub // remove new flags from current flags
 }
 
+/// Representation of a STORE command
+#[derive(Debug, PartialEq)]
+pub struct StoreCommand {
+    pub sequence_set: Vec<SequenceItem>,
+    pub action: StoreName,
+    /// Whether the client asked not to be sent the new flags
+    pub silent: bool,
+    pub flags: Vec<Flag>,
+    /// Only messages whose mod-sequence is no higher than this are changed
+    /// (RFC 7162)
+    pub unchanged_since: Option<u64>
+}
+
+/// How a STORE is carried out, from the server's settings and what the
+/// client has enabled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoreOptions {
+    /// Whether the messages a STORE marks \Deleted are expunged straight away
+    pub auto_expunge: bool,
+    /// Whether the client is told of expunged messages with VANISHED, as it
+    /// has enabled QRESYNC
+    pub vanished: bool
+}
+
 /// Parse and perform the store operation specified by `store_args`. Returns the
 /// response to the client or None if a BAD response should be sent back to
 /// the client
-pub fn store(folder: &mut Folder, store_args: &[&str], seq_uid: bool,
-                 tag: &str) -> Option<String> {
-    if store_args.len() < 3 { return None; }
-
-    // Parse the sequence set argument
-    let sequence_set_opt = sequence_set::parse(store_args[0].trim_matches('"'));
-    // Grab how to handle the flags. It should be case insensitive.
-    let data_name = store_args[1].trim_matches('"').to_ascii_lowercase();
-
-    // Split into "flag" part and "silent" part.
-    let mut data_name_parts = (&data_name[..]).split('.');
-    let flag_part = data_name_parts.next();
-    let silent_part = data_name_parts.next();
-
-    // There shouldn't be any more parts to the data name argument
-    if data_name_parts.next().is_some() {
-        return None;
+/// If `options.auto_expunge` is set, the messages this STORE marks \Deleted
+/// are expunged, and the client is told with VANISHED rather than EXPUNGE
+/// if `options.vanished` is set. EXPUNGE responses may not be sent during a
+/// STORE by sequence number (RFC 3501 section 7.4.1), so for one of those
+/// the UIDs are added to `expunge_later` instead, for them to be expunged
+/// once a command which may report it has run.
+/// `condstore` is whether the client has enabled CONDSTORE, which a
+/// conditional STORE (RFC 7162) does. The messages it leaves alone because
+/// they have changed are listed in the tagged response.
+pub fn store(folder: &mut dyn MailFolder, store_args: &[&str], seq_uid: bool,
+             options: StoreOptions, condstore: &mut bool, expunge_later: &mut Vec<usize>,
+             tag: &str) -> Option<String> {
+    let mut cmd = "STORE".to_string();
+    for arg in store_args {
+        cmd.push(' ');
+        cmd.push_str(arg);
     }
-
-    // Grab the flags themselves.
-    let data_value = store_args[2].trim_matches('"');
-
-    // Set the silent flag if it is present. If there is something else
-    // instead of the word "silent", a BAD response should be sent to the
-    // client.
-    let silent = match silent_part {
-        None => false,
-        Some(part) => {
-            if part == "silent" {
-                true
-            } else {
-                return None
-            }
+    let parsed_cmd = match parser::store(cmd.as_bytes()) {
+        Ok(parsed_cmd) => parsed_cmd,
+        Err(_) => return None
+    };
+    // A conditional STORE can't be done without mod-sequences.
+    if parsed_cmd.unchanged_since.is_some() {
+        if folder.highest_modseq().is_none() {
+            return None;
         }
+        *condstore = true;
+    }
+    let flags: HashSet<Flag> = parsed_cmd.flags.into_iter().collect();
+    let deletes = parsed_cmd.action != Sub && flags.contains(&Flag::Deleted);
+
+    // Find the messages the sequence set refers to. Sequence numbers past
+    // the end of the folder and UIDs which aren't in it are left out.
+    let sequence_set = parsed_cmd.sequence_set;
+    let mut sequence_iter = if seq_uid {
+        let uids = folder.uids_from_index(0);
+        let max_uid = uids.last().cloned().unwrap_or(0);
+        uids.into_iter().filter(|uid| sequence_set::contains(&sequence_set, *uid, max_uid)).collect()
+    } else {
+        sequence_set::iterator(&sequence_set, folder.message_count())
     };
+    if sequence_iter.is_empty() {
+        return Some(ResponseWriter::new().tagged_no(tag, "STORE failed: no such message").into_string());
+    }
 
-    // Parse the flag_part into an enum describing what to do with the
-    // data_value.
-    let flag_name = match parse_storename(flag_part) {
-        Some(storename) => storename,
-        None => return None
+    // Messages which have changed since the client's mod-sequence are left
+    // alone.
+    let modified = match parsed_cmd.unchanged_since {
+        Some(modseq) => folder.modified_since(&sequence_iter, seq_uid, modseq),
+        None => Vec::new()
     };
+    sequence_iter.retain(|num| !modified.contains(num));
 
-    // Create the Set of flags to be STORE'd from the data_value argument.
-    let mut flags: HashSet<Flag> = HashSet::new();
-    for flag in data_value.trim_matches('(').trim_matches(')').split(' ') {
-        match parse_flag(flag) {
-            None => { continue; }
-            Some(insert_flag) => { flags.insert(insert_flag); }
-        }
-    }
+    // Only the messages stored to are expunged, not others which were
+    // already marked \Deleted.
+    let deleted_uids = if !(options.auto_expunge && deletes) {
+        Vec::new()
+    } else if seq_uid {
+        sequence_iter.clone()
+    } else {
+        let uids = folder.uids_from_index(0);
+        sequence_iter.iter().filter_map(|&seqnum| uids.get(seqnum - 1).cloned()).collect()
+    };
 
     // Perform the STORE operation on each message specified by the
-    // sequence set.
-    match sequence_set_opt {
-        None => None,
-        Some(sequence_set) => {
-            let sequence_iter = if seq_uid {
-                sequence_set::uid_iterator(&sequence_set)
-            } else {
-                sequence_set::iterator(&sequence_set, folder.message_count())
-            };
-            let res = folder.store(sequence_iter, &flag_name, silent, flags,
-                                   seq_uid, tag);
-            Some(res)
-        }
+    // sequence set. The new mod-sequences are sent after a conditional
+    // STORE even if it is silent.
+    let silent = parsed_cmd.silent && parsed_cmd.unchanged_since.is_none();
+    let mut res = ResponseWriter::new()
+        .responses(&folder.store(sequence_iter, &parsed_cmd.action, silent, flags, seq_uid,
+                                 *condstore));
+    if seq_uid {
+        res = res.responses(&folder.uid_expunge_response(&deleted_uids, options.vanished));
+    } else {
+        expunge_later.extend(deleted_uids);
+    }
+    if modified.is_empty() {
+        Some(res.tagged_ok(tag, "STORE complete").into_string())
+    } else {
+        let text = format!("[MODIFIED {}] Conditional STORE failed",
+                           sequence_set::compress_to_sequence_set(&modified));
+        Some(res.tagged_ok(tag, &text).into_string())
     }
 }
 
-/// Takes the argument specifying what to do with the provided flags in a store
-/// operation and returns the corresponding enum.
-fn parse_storename(storename: Option<&str>) -> Option<StoreName> {
-    match storename {
-        Some("flags") => Some(Replace),
-        Some("+flags") => Some(Add),
-        Some("-flags") => Some(Sub),
-        _ => None
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use command::Attribute::Flags;
+    use command::fetch::FetchOptions;
+    use fixture;
+    use folder::Folder;
+    use mailstore::{MailFolder, MailStore};
+    use memstore::MemoryStore;
+    use message::Flag;
+    use super::{store, StoreOptions};
+
+    const PLAIN: StoreOptions = StoreOptions { auto_expunge: false, vanished: false };
+    // Messages marked \Deleted are expunged, and the client may be told with
+    // VANISHED.
+    const EXPUNGE: StoreOptions = StoreOptions { auto_expunge: true, vanished: false };
+    const VANISHED: StoreOptions = StoreOptions { auto_expunge: true, vanished: true };
+
+    fn setup(name: &str, uids: &[usize], flags: &[Flag]) -> (::std::path::PathBuf, Folder) {
+        let messages: Vec<(usize, &[Flag])> = uids.iter().map(|&uid| (uid, flags)).collect();
+        let dir = fixture::maildir(name, &messages);
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        (dir, folder)
+    }
+
+    #[test]
+    fn test_store_missing_messages() {
+        let (dir, mut folder) = setup("store", &[10, 20], &[Flag::Seen]);
+        let mut later = Vec::new();
+        let mut condstore = false;
+
+        assert_eq!(store(&mut folder, &["3", "+FLAGS", "(\\Flagged)"], false, PLAIN,
+                         &mut condstore, &mut later, "a1"),
+                   Some("a1 NO STORE failed: no such message\r\n".to_string()));
+        assert_eq!(store(&mut folder, &["15", "+FLAGS", "(\\Flagged)"], true, PLAIN,
+                         &mut condstore, &mut later, "a2"),
+                   Some("a2 NO STORE failed: no such message\r\n".to_string()));
+
+        // Messages which do exist are still changed.
+        let res = store(&mut folder, &["2:5", "+FLAGS", "(\\Flagged)"], false, PLAIN,
+                        &mut condstore, &mut later, "a3").unwrap();
+        assert!(res.starts_with("* 2 FETCH (FLAGS ("), "{}", res);
+        assert!(res.contains("\\Flagged"), "{}", res);
+        assert_eq!(res.lines().count(), 2);
+        assert!(res.ends_with("\r\na3 OK STORE complete\r\n"), "{}", res);
+        assert_eq!(store(&mut folder, &["15:*", "-FLAGS.SILENT", "(\\Flagged)"], true, PLAIN,
+                         &mut condstore, &mut later, "a4"),
+                   Some("a4 OK STORE complete\r\n".to_string()));
+
+        // Malformed sequence sets are BAD.
+        assert_eq!(store(&mut folder, &["0", "+FLAGS", "(\\Flagged)"], false, PLAIN,
+                         &mut condstore, &mut later, "a5"), None);
+        assert_eq!(store(&mut folder, &["1:x", "+FLAGS", "(\\Flagged)"], false, PLAIN,
+                         &mut condstore, &mut later, "a6"), None);
+        // So are flags which can't be set.
+        assert_eq!(store(&mut folder, &["1", "+FLAGS", "(\\Recent)"], false, PLAIN,
+                         &mut condstore, &mut later, "a7"), None);
+        assert_eq!(store(&mut folder, &["1", "FLAGS", "\\Seen", "\\Bogus"], false, PLAIN,
+                         &mut condstore, &mut later, "a8"), None);
+        assert_eq!(store(&mut folder, &["1", "FLAGS.SILENT", "\\Seen", "$Junk"], false, PLAIN,
+                         &mut condstore, &mut later, "a9"),
+                   Some("a9 OK STORE complete\r\n".to_string()));
+        assert!(later.is_empty());
+
+        folder.expunge();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_auto_expunge() {
+        let (dir, mut folder) = setup("store-expunge", &[10, 20, 30], &[Flag::Seen]);
+        let uids = folder.uids_from_index(0);
+        let mut later = Vec::new();
+        let mut condstore = false;
+
+        // Clearing \Deleted or setting other flags leaves the messages be.
+        assert_eq!(store(&mut folder, &["1", "-FLAGS.SILENT", "(\\Deleted)"], false, EXPUNGE,
+                         &mut condstore, &mut later, "a1"),
+                   Some("a1 OK STORE complete\r\n".to_string()));
+        assert!(later.is_empty());
+
+        // A STORE by sequence number may not send EXPUNGE, so the message is
+        // left for the session to expunge later.
+        let res = store(&mut folder, &["2", "+FLAGS", "(\\Deleted)"], false, EXPUNGE,
+                        &mut condstore, &mut later, "a2").unwrap();
+        assert!(res.starts_with("* 2 FETCH (FLAGS ("), "{}", res);
+        assert!(res.ends_with(")\r\na2 OK STORE complete\r\n"), "{}", res);
+        assert_eq!(later, vec![uids[1]]);
+        assert_eq!(folder.message_count(), 3);
+        assert_eq!(folder.uid_expunge_response(&later, false), "* 2 EXPUNGE\r\n");
+        assert!(!dir.join("cur/20:2,S").exists());
+
+        // A UID STORE expunges straight away, and clients which have enabled
+        // QRESYNC are given the UID instead.
+        later.clear();
+        assert_eq!(store(&mut folder, &[&uids[2].to_string()[..], "FLAGS.SILENT", "\\Deleted"],
+                         true, VANISHED, &mut condstore, &mut later, "a3"),
+                   Some(format!("* VANISHED {}\r\na3 OK STORE complete\r\n", uids[2])));
+        assert_eq!(folder.message_count(), 1);
+        assert!(later.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_auto_expunge_leaves_others() {
+        // Both messages are already marked \Deleted.
+        let (dir, mut folder) = setup("store-expunge-others", &[10, 20],
+                                      &[Flag::Seen, Flag::Deleted]);
+        let uids = folder.uids_from_index(0);
+        let mut later = Vec::new();
+        let mut condstore = false;
+
+        // Only the message stored to goes.
+        let res = store(&mut folder, &[&uids[1].to_string()[..], "+FLAGS", "(\\Deleted)"],
+                        true, EXPUNGE, &mut condstore, &mut later, "a1").unwrap();
+        assert!(res.ends_with(")\r\n* 2 EXPUNGE\r\na1 OK STORE complete\r\n"), "{}", res);
+        assert_eq!(folder.uids_from_index(0), vec![uids[0]]);
+        assert!(dir.join("cur/10:2,ST").exists());
+
+        folder.expunge();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_unchanged_since() {
+        let dir = fixture::maildir("store-unchanged", &[(10, &[]), (20, &[]), (30, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let mut later = Vec::new();
+        let mut condstore = false;
+
+        // A message changed since the client's mod-sequence is left alone
+        // and listed in the response, while the rest are changed and given
+        // their new mod-sequences.
+        let modseq = folder.modseq(1).unwrap();
+        let res = store(&mut folder, &["1:3", &format!("(UNCHANGEDSINCE {})", modseq)[..],
+                                       "+FLAGS", "(\\Flagged)"],
+                        false, PLAIN, &mut condstore, &mut later, "a1").unwrap();
+        assert_eq!(res, format!("* 1 FETCH (FLAGS (\\Flagged) MODSEQ ({}) )\r\n\
+                                 * 2 FETCH (FLAGS (\\Flagged) MODSEQ ({}) )\r\n\
+                                 a1 OK [MODIFIED 3] Conditional STORE failed\r\n",
+                                modseq + 2, modseq + 3));
+        assert!(condstore);
+        assert_eq!(folder.fetch(2, &[Flags], FetchOptions::default()).unwrap(),
+                   "* 3 FETCH (FLAGS ())\r\n");
+
+        // The new mod-sequences are sent even for a silent STORE, and a
+        // UID STORE lists the UIDs which were left alone.
+        let res = store(&mut folder, &["10:30", "(UNCHANGEDSINCE 1)", "FLAGS.SILENT", "()"],
+                        true, PLAIN, &mut condstore, &mut later, "a2").unwrap();
+        assert_eq!(res, "a2 OK [MODIFIED 10,20,30] Conditional STORE failed\r\n");
+        let res = store(&mut folder, &["30", &format!("(UNCHANGEDSINCE {})", modseq + 3)[..],
+                                       "FLAGS.SILENT", "(\\Seen)"],
+                        true, PLAIN, &mut condstore, &mut later, "a3").unwrap();
+        assert_eq!(res, format!("* 3 FETCH (FLAGS (\\Seen) UID 30 MODSEQ ({}) )\r\n\
+                                 a3 OK STORE complete\r\n", modseq + 4));
+
+        folder.expunge();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_in_memory() {
+        let mail_store = MemoryStore::new();
+        for _ in 0..3 {
+            mail_store.append("INBOX", b"Subject: hi\n\nHi\n", &[Flag::Seen], None).unwrap();
+        }
+        mail_store.append("INBOX", b"Subject: hi\n\nHi\n", &[Flag::Deleted], None).unwrap();
+        let mut folder = mail_store.open("INBOX", false).unwrap();
+        let mut later = Vec::new();
+        let mut condstore = false;
+
+        assert_eq!(store(&mut *folder, &["2", "FLAGS", "(\\Flagged)"], false, PLAIN,
+                          &mut condstore, &mut later, "a1"),
+                   Some("* 2 FETCH (FLAGS (\\Flagged) )\r\na1 OK STORE complete\r\n".to_string()));
+        assert_eq!(store(&mut *folder, &["3", "+FLAGS.SILENT", "(\\Deleted)"], true, VANISHED,
+                          &mut condstore, &mut later, "a2"),
+                   Some("* VANISHED 3\r\na2 OK STORE complete\r\n".to_string()));
+        assert_eq!(store(&mut *folder, &["4", "+FLAGS", "(\\Seen)"], false, PLAIN,
+                          &mut condstore, &mut later, "a3"),
+                   Some("a3 NO STORE failed: no such message\r\n".to_string()));
+        // Mod-sequences aren't kept, so a conditional STORE can't be done.
+        assert_eq!(store(&mut *folder, &["1", "(UNCHANGEDSINCE 1)", "+FLAGS", "(\\Seen)"], false,
+                         PLAIN, &mut condstore, &mut later, "a4"), None);
+
+        // The changes are kept by the store, and the message which was
+        // already marked \Deleted is still there.
+        let folder = mail_store.open("INBOX", true).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![1, 2, 4]);
     }
 }
@@ -0,0 +1,266 @@
+//! Dot-locking, as Dovecot does for the files it keeps in a maildir: a lock
+//! is a file which only one process can create, and whoever creates it may
+//! change what it protects until they remove it again. The lock holds the
+//! PID of its owner, so that one left behind by a crash can be recognised
+//! and taken over. The files themselves are replaced with `replace_file`.
+//!
+//! A lock which may be held for as long as a session lasts is a `FileLock`
+//! instead, which the kernel gives up for its owner however it exits, so
+//! there is nothing to go stale when a PID is reused.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use libc;
+
+// How long to wait for a lock before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait between attempts at taking a lock
+const RETRY: Duration = Duration::from_millis(10);
+
+// A lock which doesn't name its owner is taken to have been left behind once
+// it is this old.
+const STALE_AFTER: Duration = Duration::from_secs(120);
+
+// Counts the temporary files made by this process, so that no two threads
+// use the same one.
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A held lock, which is given up when it is dropped.
+#[derive(Debug)]
+pub struct DotLock {
+    path: PathBuf
+}
+
+impl DotLock {
+    /// Take the lock at `path`, waiting for whoever holds it to give it up.
+    pub fn acquire(path: PathBuf) -> io::Result<DotLock> {
+        let start = Instant::now();
+        loop {
+            if let Some(lock) = DotLock::try_acquire(path.clone())? {
+                return Ok(lock);
+            }
+            if start.elapsed() > TIMEOUT {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          format!("timed out waiting for {}", path.display())));
+            }
+            thread::sleep(RETRY);
+        }
+    }
+
+    /// Take the lock at `path` if no one else holds it, returning None if
+    /// someone does.
+    pub fn try_acquire(path: PathBuf) -> io::Result<Option<DotLock>> {
+        // A stale lock is removed and the lock tried for once more.
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let lock = DotLock { path: path };
+                    file.write_all(process::id().to_string().as_bytes())?;
+                    return Ok(Some(lock));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if !is_stale(&path) {
+                        return Ok(None);
+                    }
+                    warn!("Removing stale lock {}", path.display());
+                    if let Err(e) = fs::remove_file(&path) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            return Err(e);
+                        }
+                    }
+                }
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for DotLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A lock taken with flock(2) on a file which is left in place. It is given
+/// up when it is dropped, or when its owner exits.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File
+}
+
+impl FileLock {
+    /// Take the lock at `path` if no one else holds it, returning None if
+    /// someone does. The file is made if it isn't there, and given our PID
+    /// to show who holds it.
+    pub fn try_acquire(path: &Path) -> io::Result<Option<FileLock>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::WouldBlock { Ok(None) } else { Err(e) };
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(process::id().to_string().as_bytes())?;
+        Ok(Some(FileLock { file: file }))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Replace the file at `path` with `contents` in one step, so that readers
+/// never see it half written. The contents are written to a temporary file
+/// beside it first, which is named after this process and is never shared
+/// with another writer.
+pub fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_file_with(path, contents, |_| Ok(()))
+}
+
+/// Replace the file at `path` as `replace_file` does, handing the temporary
+/// file to `prepare` before anything is written to it, so that its owner or
+/// mode can be set first.
+pub fn replace_file_with<F>(path: &Path, contents: &[u8], prepare: F) -> io::Result<()>
+    where F: FnOnce(&File) -> io::Result<()> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let tmp_path = path.with_file_name(format!("{}.{}.{}.tmp", name, process::id(),
+                                               TMP_FILES.fetch_add(1, Ordering::SeqCst)));
+    let res = create_tmp(&tmp_path)
+        .and_then(|mut file| {
+            prepare(&file)?;
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+// Create the temporary file at `tmp_path`. One already there was left by an
+// earlier process with the same PID, as this one never reuses a name, so it
+// is replaced.
+fn create_tmp(tmp_path: &Path) -> io::Result<File> {
+    let open = || OpenOptions::new().write(true).create_new(true).open(tmp_path);
+    match open() {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::remove_file(tmp_path)?;
+            open()
+        }
+        res => res
+    }
+}
+
+/// Whether the lock at `path` was left behind by a process which has gone.
+/// A lock naming a process which is still running is never stale, however
+/// old it is.
+fn is_stale(path: &Path) -> bool {
+    let mut contents = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        return false;
+    }
+    match contents.trim().parse::<libc::pid_t>() {
+        Ok(pid) if pid > 0 => {
+            let alive = unsafe { libc::kill(pid, 0) } == 0;
+            !alive && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        }
+        // The owner may not have written its PID yet.
+        _ => fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map_or(false, |age| age > STALE_AFTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    use fixture;
+    use super::{replace_file, replace_file_with, DotLock, FileLock};
+
+    #[test]
+    fn test_dotlock() {
+        let dir = fixture::dir("dotlock");
+        let path = dir.join("lock");
+
+        let lock = DotLock::acquire(path.clone()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+        // We are still running, so the lock isn't stale.
+        assert!(DotLock::try_acquire(path.clone()).unwrap().is_none());
+        drop(lock);
+        assert!(!path.exists());
+
+        // A lock left by a process which has gone is taken over.
+        fs::write(&path, "999999999").unwrap();
+        let lock = DotLock::try_acquire(path.clone()).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+        drop(lock);
+
+        // One which doesn't say who holds it is left alone while it is new.
+        fs::write(&path, "").unwrap();
+        assert!(DotLock::try_acquire(path.clone()).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_lock() {
+        let dir = fixture::dir("filelock");
+        let path = dir.join("lock");
+
+        let lock = FileLock::try_acquire(&path).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+        assert!(FileLock::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(path.exists());
+
+        // What the file says doesn't matter, even if it names a process
+        // which is still running, as a lock left by a crash was released
+        // along with its owner.
+        fs::write(&path, "1").unwrap();
+        let lock = FileLock::try_acquire(&path).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let dir = fixture::dir("replace");
+        let path = dir.join("list");
+
+        replace_file(&path, b"one\n").unwrap();
+        replace_file(&path, b"two\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // The replacement can be given its mode before it is written, and
+        // nothing is replaced if that fails.
+        replace_file_with(&path, b"three\n", |file| {
+            file.set_permissions(fs::Permissions::from_mode(0o600))
+        }).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(replace_file_with(&path, b"four\n", |_| Err(io::ErrorKind::Other.into())).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ use serde_json::Error as JsonError;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use toml::ser::Error as TomlError;
 
@@ -13,15 +14,24 @@ pub type ImapResult<T> = StdResult<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     InvalidImapState,
+    /// No config file was given and none of the paths searched for one
+    /// exist.
+    ConfigNotFound(Vec<PathBuf>),
+    /// The config file couldn't be parsed or its settings don't make sense.
+    InvalidConfig(String),
+    /// The command user backend was selected without an auth command.
+    MissingAuthCommand,
     /// An internal `std::io` error.
     Io(io::Error),
     /// An internal `serde_json` error which occurs when serializing or
     /// deserializing JSON data.
     Json(JsonError),
-    /// An error which occurs when attempting to read the UID for a message.
-    MessageUidDecode,
-    /// An error which occurs when a Maildir message has a bad filename
-    MessageBadFilename,
+    /// A sequence number or UID refers to a message which isn't in the
+    /// folder, usually because it has been expunged.
+    NoSuchMessage,
+    /// The message's filename isn't UTF-8, so the UID list can't be
+    /// searched for it and its flags can't be read.
+    MessageUidDecode(PathBuf),
     /// An internal `mime` error.
     Mime(mime::Error),
     /// An internal `toml` error which occurs when serializing or deserializing
@@ -34,7 +44,13 @@ impl fmt::Display for Error {
         use self::Error::*;
 
         match *self {
-            InvalidImapState | MessageUidDecode | MessageBadFilename => write!(f, "{}", StdError::description(self)),
+            InvalidImapState | MissingAuthCommand | NoSuchMessage => write!(f, "{}", StdError::description(self)),
+            ConfigNotFound(ref paths) => {
+                let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "No config file found; searched {}", paths.join(", "))
+            }
+            InvalidConfig(ref problem) => write!(f, "Invalid config {}", problem),
+            MessageUidDecode(ref path) => write!(f, "Message filename {} is not UTF-8", path.display()),
             Io(ref e) => e.fmt(f),
             Json(ref e) => e.fmt(f),
             Mime(ref e) => e.fmt(f),
@@ -49,8 +65,11 @@ impl StdError for Error {
 
         match *self {
             InvalidImapState => "Not in selected state.",
-            MessageUidDecode => "An error occured while decoding the UID for a message.",
-            MessageBadFilename => "An error occured while parsing message information from its filename",
+            ConfigNotFound(_) => "No config file found.",
+            InvalidConfig(_) => "The config file is invalid.",
+            MissingAuthCommand => "The command user backend requires auth_command to be set.",
+            NoSuchMessage => "The message is not in the folder.",
+            MessageUidDecode(_) => "The message filename is not UTF-8.",
             Io(ref e) => e.description(),
             Json(ref e) => e.description(),
             Mime(ref e) => e.description(),
@@ -62,7 +81,8 @@ impl StdError for Error {
         use self::Error::*;
 
         match *self {
-            InvalidImapState | MessageUidDecode | MessageBadFilename => None,
+            InvalidImapState | ConfigNotFound(_) | InvalidConfig(_) | MissingAuthCommand |
+                NoSuchMessage | MessageUidDecode(_) => None,
             Io(ref e) => e.cause(),
             Json(ref e) => e.cause(),
             Mime(ref e) => e.cause(),
@@ -78,6 +98,11 @@ impl PartialEq<Error> for Error {
 
         match (self, other) {
             (&InvalidImapState, &InvalidImapState) |
+                (&ConfigNotFound(_), &ConfigNotFound(_)) |
+                (&InvalidConfig(_), &InvalidConfig(_)) |
+                (&MissingAuthCommand, &MissingAuthCommand) |
+                (&NoSuchMessage, &NoSuchMessage) |
+                (&MessageUidDecode(_), &MessageUidDecode(_)) |
                 (&Io(_), &Io(_)) |
                 (&Json(_), &Json(_)) |
                 (&Mime(_), &Mime(_)) |
@@ -110,3 +135,108 @@ impl From<TomlError> for Error {
         Error::Toml(error)
     }
 }
+
+/// Represents the reasons a folder could not be opened.
+#[derive(Debug)]
+pub enum FolderError {
+    /// The folder does not exist or is not a mail folder.
+    NotFound,
+    /// The mailbox only holds other mailboxes, so it can't be selected.
+    NoSelect,
+    /// An internal `std::io` error while reading the folder.
+    Io(io::Error),
+    /// The folder's lock could not be checked.
+    Locked,
+}
+
+impl fmt::Display for FolderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FolderError::*;
+
+        match *self {
+            NotFound | NoSelect | Locked => write!(f, "{}", StdError::description(self)),
+            Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl StdError for FolderError {
+    fn description(&self) -> &str {
+        use self::FolderError::*;
+
+        match *self {
+            NotFound => "The folder does not exist.",
+            NoSelect => "The folder only holds other folders.",
+            Io(ref e) => e.description(),
+            Locked => "The folder's lock could not be checked.",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        use self::FolderError::*;
+
+        match *self {
+            NotFound | NoSelect | Locked => None,
+            Io(ref e) => e.cause(),
+        }
+    }
+}
+
+impl From<io::Error> for FolderError {
+    fn from(error: io::Error) -> FolderError {
+        if error.kind() == io::ErrorKind::NotFound {
+            FolderError::NotFound
+        } else {
+            FolderError::Io(error)
+        }
+    }
+}
+
+/// Represents the reasons a message could not be appended to a mailbox.
+#[derive(Debug)]
+pub enum AppendError {
+    /// The mailbox does not exist, so the client should create it first.
+    NotFound,
+    /// Storing the message would take the user over their quota.
+    OverQuota,
+    /// An internal `std::io` error while storing the message.
+    Io(io::Error),
+}
+
+impl fmt::Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AppendError::*;
+
+        match *self {
+            NotFound | OverQuota => write!(f, "{}", StdError::description(self)),
+            Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl StdError for AppendError {
+    fn description(&self) -> &str {
+        use self::AppendError::*;
+
+        match *self {
+            NotFound => "The mailbox does not exist.",
+            OverQuota => "The mailbox is over quota.",
+            Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        use self::AppendError::*;
+
+        match *self {
+            NotFound | OverQuota => None,
+            Io(ref e) => e.cause(),
+        }
+    }
+}
+
+impl From<io::Error> for AppendError {
+    fn from(error: io::Error) -> AppendError {
+        AppendError::Io(error)
+    }
+}
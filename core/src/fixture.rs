@@ -0,0 +1,86 @@
+//! Directories and maildir folders written out for tests. Each one lives
+//! under the system temp directory, named after the test and the process so
+//! that test runs don't trip over each other, and is left for the test to
+//! remove once it is done.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use maildir;
+use message::{flag_letter, Flag};
+
+/// The contents given to messages whose contents don't matter
+pub const MESSAGE: &'static str = "Subject: hi\n\nHi\n";
+
+/// An empty directory for the test. Anything left at the path by an
+/// earlier run is removed first.
+pub fn dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("segimap-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A folder with the given messages in its cur/ directory, each a UID and
+/// the flags in its filename. Anything left at the path by an earlier run
+/// is removed first.
+pub fn maildir(name: &str, messages: &[(usize, &[Flag])]) -> PathBuf {
+    let folder = dir(name);
+    maildir::create_folder(&folder).unwrap();
+    for &(uid, flags) in messages {
+        write_message(&folder, "cur", uid, flags, MESSAGE);
+    }
+    folder
+}
+
+/// Write a message into the folder's cur/ or new/ directory, returning its
+/// path. Messages in cur/ are always given the `:2,` info, while those in
+/// new/ only have it if they have flags, as a delivery agent would leave
+/// them. Keywords have no letter until the folder gives them one, so they
+/// can't be written.
+pub fn write_message(folder: &Path, dir: &str, uid: usize, flags: &[Flag],
+                     contents: &str) -> PathBuf {
+    let mut letters: Vec<char> = flags.iter().map(|flag| match flag_letter(flag) {
+        Some(letter) => letter,
+        None => panic!("{:?} has no maildir flag letter", flag)
+    }).collect();
+    letters.sort();
+    let mut name = uid.to_string();
+    if dir == "cur" || !letters.is_empty() {
+        name.push_str(":2,");
+        name.extend(letters);
+    }
+    let path = folder.join(dir).join(name);
+    File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use message::Flag;
+    use super::{maildir, write_message, MESSAGE};
+
+    #[test]
+    fn test_maildir() {
+        let dir = maildir("fixture", &[(1, &[]), (2, &[Flag::Seen, Flag::Answered, Flag::Deleted])]);
+        assert!(dir.join("tmp").is_dir());
+        assert_eq!(fs::read_to_string(dir.join("cur").join("1:2,")).unwrap(), MESSAGE);
+        assert!(dir.join("cur").join("2:2,RST").is_file());
+
+        assert_eq!(write_message(&dir, "new", 3, &[], "Subject: new\n\nNew\n"),
+                   dir.join("new").join("3"));
+        assert!(write_message(&dir, "new", 4, &[Flag::Draft], MESSAGE).ends_with("new/4:2,D"));
+
+        // Asking again starts afresh.
+        let dir = maildir("fixture", &[]);
+        assert_eq!(fs::read_dir(dir.join("cur")).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.join("new")).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
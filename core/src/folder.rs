@@ -1,207 +1,399 @@
 use std::collections::{HashMap,HashSet};
 use std::fs;
-use std::io::Write;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::usize;
 
 use command::Attribute;
-use message::Message;
+use command::fetch::FetchOptions;
+use command::response::ResponseWriter;
+use command::search::{self, SearchKey};
+use command::select::Qresync;
+use command::sequence_set::{self, SequenceItem};
+use error::{Error, FolderError, ImapResult};
+use message::{Message, COMMON_KEYWORDS};
 use message::Flag;
 
 use command::store::StoreName;
+use dotlock::FileLock;
+use index;
+use keywords::{self, Keywords};
+use maildir;
+use mailstore::{MailFolder, MailMessage};
+use modseq::{self, ModSeqs};
+use uidlist::{self, UidList};
+use window::Window;
+
+/// The name of the lock held by the session which has a folder selected.
+pub const LOCK_FILE: &'static str = ".lock";
 
 /// Representation of a Folder
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Folder {
     // How many messages are in folder/new/
     recent: usize,
+    // The UIDs of the messages which were in folder/new/
+    recent_uids: HashSet<usize>,
     // How many messages are in the folder total
     exists: usize,
     // How many messages are not marked with the Seen flag
     unseen: usize,
-    // Whether the folder has been opened as read-only or not
-    readonly: bool,
+    // The folder's lock, held while the folder is selected read-write, so
+    // that the next session to select it, including this one, can have
+    // write access once it is given up. Without it the folder is read-only.
+    lock: Option<FileLock>,
+    // Whether messages are left in folder/new/ until their flags change
+    keep_new: bool,
+    // The UIDVALIDITY of the folder and the UID of the next message
+    uid_validity: usize,
+    uid_next: usize,
+    // The letters given to keywords in the folder's filenames
+    keywords: Keywords,
+    // The mod-sequences of the messages, or None if they couldn't be read,
+    // in which case they aren't kept track of
+    modseqs: Option<ModSeqs>,
+    // The root of the maildir which holds this folder
+    maildir: PathBuf,
     path: PathBuf,
-    messages: Vec<Message>,
-    // A mapping of message uids to indices in folder.messages
-    uid_to_seqnum: HashMap<usize, usize>
+    // The messages in sequence number order, which are only loaded while
+    // they are needed or have changes waiting to be written to disk
+    messages: Window
 }
 
-// Macro to handle each message in the folder
-macro_rules! handle_message(
-    ($msg_path_entry:ident, $uid_map:ident, $messages:ident, $i:ident, $unseen:ident) => ({
-        if let Ok(msg_path) = $msg_path_entry {
-            if let Ok(message) = Message::new(msg_path.path().as_path()) {
-                if $unseen == !0usize && message.is_unseen() {
-                    $unseen = $i;
-                }
-                $uid_map.insert(message.get_uid(), $i);
-                $i += 1;
-                $messages.push(message);
-            }
-        }
-    });
-);
+impl Folder {
+    /// Open the folder at `path`. Messages in folder/new/ are moved to
+    /// folder/cur/ unless `keep_new` is set, in which case they stay there,
+    /// and are reported as \Recent each time the folder is opened, until
+    /// their flags are first changed. A read-only folder leaves them where
+    /// they are, so that they are still \Recent to the next session.
+    /// Once more than `window` messages have changed flags, the changes are
+    /// written to disk, so that no more have to be held in memory.
+    pub fn new(maildir: &Path, path: PathBuf, examine: bool, keep_new: bool,
+               window: usize) -> Result<Folder, FolderError> {
+        let cur = fs::read_dir(&(path.join("cur")))?;
+        let new = fs::read_dir(&(path.join("new")))?;
 
-// Perform a rename operation on a message
-macro_rules! rename_message(
-    ($msg:ident, $curpath:expr, $new_messages:ident) => ({
-        if fs::rename($msg.get_path(), &$curpath).is_ok() {
-            // if the rename operation succeeded then clone the message,
-            // update its path and add the clone to our new list
-            $new_messages.push($msg.rename($curpath));
+        // the EXAMINE command is always read-only or we test SELECT for read-only status
+        // We use a lock file to determine write access on a folder. It is
+        // taken before the messages are gathered, as only a session with
+        // write access may move messages out of folder/new/, and given up
+        // again if opening the folder fails.
+        let lock = if examine {
+            None
         } else {
-            // if the rename failed, just add the old message to our
-            // new list
-            $new_messages.push($msg.clone());
+            match FileLock::try_acquire(&path.join(LOCK_FILE)) {
+                // None if another session has the folder selected.
+                Ok(lock) => lock,
+                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => None,
+                Err(_) => return Err(FolderError::Locked)
+            }
+        };
+        let readonly = lock.is_none();
+
+        // Gather every message in the folder, noting which ones are new.
+        let mut entries = Vec::new();
+        for (listing, is_new) in vec![(cur, false), (new, true)] {
+            for entry in listing {
+                let msg_path = entry?.path();
+                if maildir::message_filename(&msg_path).is_none() {
+                    if msg_path.file_name().and_then(|name| name.to_str()).is_none() {
+                        warn!("Ignoring {}, as its name is not UTF-8", msg_path.display());
+                    }
+                    continue;
+                }
+                entries.push((msg_path, is_new));
+            }
         }
-    })
-);
 
-impl Folder {
-    pub fn new(path: PathBuf, examine: bool) -> Option<Folder> {
-        // the EXAMINE command is always read-only or we test SELECT for read-only status
-        // We use a lock file to determine write access on a folder
-        let readonly = if examine || fs::File::open(&path.join(".lock")).is_ok() {
-            true
-        } else {
-            if let Ok(mut file) = fs::File::create(&path.join(".lock")) {
-                // Get the compiler to STFU with this match
-                let _ = file.write(b"selected");
-                false
-            } else {
-                true
+        // Look up the UIDs of the messages, assigning UIDs to any which
+        // haven't been seen before, and keep the list up to date.
+        let filenames: Vec<String> = entries.iter().map(|&(ref msg_path, _)| {
+            path_filename_to_str!(msg_path).to_string()
+        }).collect();
+        let (mut uidlist, uids) = {
+            let _lock = uidlist::lock(&path)?;
+            let mut uidlist = UidList::load(&path)?;
+            let uids = uidlist.assign_all(&filenames);
+            uidlist.retain(&filenames);
+            if let Err(e) = uidlist.save(&path) {
+                warn!("Failed to save {}: {}", uidlist::UIDLIST_FILE, e);
             }
+            (uidlist, uids)
         };
 
-        if let Ok(cur) = fs::read_dir(&(path.join("cur"))) {
-            if let Ok(new) = fs::read_dir(&(path.join("new"))) {
-                let mut messages = Vec::new();
-                let mut uid_to_seqnum: HashMap<usize, usize> = HashMap::new();
-                let mut i = 0usize;
-                let mut unseen = !0usize;
+        // Sequence numbers must be in ascending UID order.
+        let mut entries: Vec<(usize, PathBuf, bool)> = uids.into_iter().zip(entries)
+            .map(|(uid, (msg_path, is_new))| (uid, msg_path, is_new))
+            .collect();
+        entries.sort_by_key(|&(uid, _, _)| uid);
 
-                // populate messages
-                for msg_path in cur {
-                    handle_message!(msg_path, uid_to_seqnum, messages, i, unseen);
-                }
+        let mut keywords = Keywords::load(&path)?;
+        let mut messages = Window::new(&path, window);
+        let mut recent_uids = HashSet::new();
+        for (uid, msg_path, is_new) in entries {
+            if is_new {
+                recent_uids.insert(uid);
+            }
+            messages.push(uid, &msg_path);
+        }
+        // Sequence numbers are 1-indexed
+        let unseen = messages.messages(&keywords).position(|msg| msg.is_unseen())
+            .map(|i| i + 1).unwrap_or(!0usize);
 
-                let old = i;
-                for msg_path in new {
-                    handle_message!(msg_path, uid_to_seqnum, messages, i, unseen);
-                }
+        // Move the messages from folder/new to folder/cur
+        if !keep_new && !readonly {
+            move_new(&mut messages, 0, path.as_path(), &mut keywords);
+        }
 
-                // Move the messages from folder/new to folder/cur
-                messages = move_new(&messages, path.as_path(), unseen);
-                return Some(Folder {
-                    path: path,
-                    recent: i-old,
-                    unseen: unseen,
-                    exists: i,
-                    messages: messages,
-                    readonly: readonly,
-                    uid_to_seqnum: uid_to_seqnum,
-                });
+        let uids = messages.uids_from(0);
+        let modseqs = match load_modseqs(&path, &mut uidlist, &uids, readonly) {
+            Ok(modseqs) => Some(modseqs),
+            Err(e) => {
+                warn!("Failed to load {} in {}: {}", modseq::MODSEQ_FILE, path.display(), e);
+                None
             }
+        };
+
+        Ok(Folder {
+            maildir: maildir.to_path_buf(),
+            path: path,
+            recent: recent_uids.len(),
+            recent_uids: recent_uids,
+            unseen: unseen,
+            exists: messages.len(),
+            messages: messages,
+            lock: lock,
+            keep_new: keep_new,
+            uid_validity: uidlist.uid_validity,
+            uid_next: uidlist.next_uid,
+            keywords: keywords,
+            modseqs: modseqs,
+        })
+    }
+
+    /// The untagged responses which bring a client up to date with the
+    /// folder when it last saw it at the given mod-sequence: the UIDs of the
+    /// messages it knows of which have been expunged since, followed by the
+    /// flags of every message which has changed since. Nothing is sent if the
+    /// client's UIDVALIDITY is out of date, as it must then start afresh.
+    fn resync(&self, qresync: &Qresync) -> String {
+        if qresync.uid_validity != self.uid_validity || self.modseqs.is_none() {
+            return String::new();
         }
-        None
+        let mut res = self.vanished_response(qresync.modseq, qresync.known_uids.as_ref());
+        for index in self.changed_since(qresync.modseq) {
+            match self.fetch(index, &[Attribute::UID, Attribute::Flags, Attribute::ModSeq],
+                             FetchOptions::default()) {
+                Ok(fetch) => res.push_str(&fetch[..]),
+                Err(e) => warn!("Failed to fetch message {}: {}", index + 1, e)
+            }
+        }
+        res
     }
 
-    /// Generate the SELECT/EXAMINE response based on data in the folder
-    pub fn select_response(&self, tag: &str) -> String {
-        let unseen_res = if self.unseen <= self.exists {
-            let unseen_str = self.unseen.to_string();
-            let mut res = "* OK [UNSEEN ".to_string();
-            res.push_str(&unseen_str[..]);
-            res.push_str("] Message ");
-            res.push_str(&unseen_str[..]);
-            res.push_str("th is the first unseen\r\n");
-            res
-        } else {
-            "".to_string()
+    /// The UIDs of the messages expunged after the given mod-sequence.
+    pub fn vanished_since(&self, modseq: u64) -> Vec<usize> {
+        match self.modseqs {
+            Some(ref modseqs) => modseqs.vanished_since(modseq),
+            None => Vec::new()
+        }
+    }
+
+    /// The indices of the messages changed after the given mod-sequence.
+    fn changed_since(&self, modseq: u64) -> Vec<usize> {
+        (0..self.messages.len())
+            .filter(|&index| self.modseq(index).map_or(false, |changed| changed > modseq))
+            .collect()
+    }
+
+    // Whether the folder has been opened as read-only or not
+    fn readonly(&self) -> bool {
+        self.lock.is_none()
+    }
+
+    /// The common client keywords and every keyword set on a message in the
+    /// folder, in sorted order.
+    fn keywords(&self) -> Vec<String> {
+        let mut keywords: Vec<String> = self.messages.messages(&self.keywords)
+            .flat_map(|msg| msg.keywords())
+            .chain(COMMON_KEYWORDS.iter().map(|keyword| keyword.to_string()))
+            .collect();
+        keywords.sort();
+        keywords.dedup();
+        keywords
+    }
+
+}
+
+impl MailFolder for Folder {
+    /// Generate the SELECT/EXAMINE response based on data in the folder.
+    /// The folder's highest mod-sequence is included if the client has
+    /// enabled CONDSTORE, followed by what has changed since the client last
+    /// saw the folder if it asked for QRESYNC.
+    fn select_response(&self, tag: &str, condstore: bool, qresync: Option<&Qresync>) -> String {
+        // The system flags should match the values in enum Flag in
+        // message.rs. Keywords already in use are listed after them.
+        let mut flags = "\\Answered \\Deleted \\Draft \\Flagged \\Seen".to_string();
+        for keyword in self.keywords() {
+            flags.push(' ');
+            flags.push_str(&keyword[..]);
+        }
+
+        // * FLAGS
+        // * <n> EXISTS
+        // * <n> RECENT
+        // * OK UNSEEN
+        // * OK PERMANENTFLAGS - \* allows clients to create new keywords
+        // * OK UIDVALIDITY
+        // * OK UIDNEXT
+        // * OK HIGHESTMODSEQ or NOMODSEQ
+        // * VANISHED and * FETCH for QRESYNC
+        let mut res = ResponseWriter::new()
+            .untagged(&format!("FLAGS ({})", flags))
+            .untagged(&format!("{} EXISTS", self.exists))
+            .untagged(&format!("{} RECENT", self.recent));
+        if self.unseen <= self.exists {
+            res = res.untagged(&format!("OK [UNSEEN {0}] Message {0} is the first unseen",
+                                        self.unseen));
+        }
+        res = res
+            .untagged(&format!("OK [PERMANENTFLAGS ({} \\*)] Permanent flags", flags))
+            .untagged(&format!("OK [UIDVALIDITY {}] UIDs valid", self.uid_validity))
+            .untagged(&format!("OK [UIDNEXT {}] Predicted next UID", self.uid_next));
+        res = match (condstore, &self.modseqs) {
+            (false, _) => res,
+            (true, &Some(ref modseqs)) =>
+                res.untagged(&format!("OK [HIGHESTMODSEQ {}] Highest mod-sequence",
+                                      modseqs.highest())),
+            (true, &None) => res.untagged("OK [NOMODSEQ] Mod-sequences are not kept for this folder")
         };
+        if let Some(qresync) = qresync {
+            res = res.responses(&self.resync(qresync));
+        }
 
-        let read_status = if self.readonly {
+        let read_status = if self.readonly() {
             "[READ-ONLY]"
         } else {
             "[READ-WRITE]"
         };
+        res.tagged_ok(tag, &format!("{} SELECT command was successful", read_status))
+            .into_string()
+    }
 
-        // * <n> EXISTS
-        // * <n> RECENT
-        // * OK UNSEEN
-        // * Flags - Should match values in enum Flag in message.rs
-        // * OK PERMANENTFLAG - Should match values in enum Flag in message.rs
-        // * OK UIDNEXT
-        // * OK UIDVALIDITY
-        format!("* {} EXISTS\r\n* {} RECENT\r\n{}* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen)\r\n* OK [PERMANENTFLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen)] Permanent flags\r\n{} OK {} SELECT command was successful\r\n", 
-                 self.exists, self.recent, unseen_res, tag, read_status)
+    fn message_count(&self) -> usize {
+        self.messages.len()
     }
 
-    /// Delete on disk all the messages marked for deletion
-    /// Returns the list of sequence numbers which have been deleted on disk
-    /// Per RFC 3501, the later sequence numbers are calculated based on the
-    /// sequence numbers at the time of the deletion not at the start of the function
-    pub fn expunge(&self) -> Vec<usize> {
-        let mut result = Vec::new();
-        // We can't perform the deletion if the folder has been opened as
-        // read-only
-        if !self.readonly {
-            // Vectors are 0-indexed
-            let mut index = 0usize;
+    /// The UIDs of every message from the given index onwards
+    fn uids_from_index(&self, start: usize) -> Vec<usize> {
+        self.messages.uids_from(start)
+    }
 
-            // self.messages will get smaller as we go through it
-            while index < self.messages.len() {
-                if self.messages[index].remove_if_deleted() {
-                    // Sequence numbers are 1-indexed
-                    result.push(index + 1);
-                } else {
-                    index += 1;
-                }
+    /// The mod-sequence of the message at `index`, if mod-sequences are
+    /// kept for the folder.
+    fn modseq(&self, index: usize) -> Option<u64> {
+        match (&self.modseqs, self.messages.uid(index)) {
+            (&Some(ref modseqs), Some(uid)) => modseqs.get(uid),
+            _ => None
+        }
+    }
+
+    fn highest_modseq(&self) -> Option<u64> {
+        self.modseqs.as_ref().map(|modseqs| modseqs.highest())
+    }
+
+    /// The mod-sequences are read again first, since another session may
+    /// have changed the messages since this one last looked.
+    fn modified_since(&mut self, sequence_set: &[usize], seq_uid: bool,
+                      modseq: u64) -> Vec<usize> {
+        update_modseqs(&mut self.modseqs, &self.path, |_| {});
+        sequence_set.iter().cloned().filter(|num| {
+            let index = if seq_uid { self.get_index_from_uid(num) } else { num.checked_sub(1) };
+            index.and_then(|index| self.modseq(index)).map_or(false, |changed| changed > modseq)
+        }).collect()
+    }
+
+    /// Set the Seen flag on the message at `index` as a side effect of
+    /// reading it. Returns whether the message's flags changed, which they
+    /// can't if the folder is read-only or there is no such message.
+    fn mark_seen(&mut self, index: usize) -> bool {
+        if self.readonly() || !self.messages.get(index, &self.keywords).map_or(false, |msg| msg.is_unseen()) {
+            return false;
+        }
+        let uid = match self.messages.change(index, &self.keywords) {
+            Some(message) => {
+                let mut seen_flag_set = HashSet::new();
+                seen_flag_set.insert(Flag::Seen);
+                message.store(&StoreName::Add, seen_flag_set);
+                message.get_uid()
             }
-            // Get the compiler to STFU with empty match block
-            match fs::remove_file(&self.path.join(".lock")) { _ => {} }
+            None => return false
+        };
+        update_modseqs(&mut self.modseqs, &self.path, |modseqs| { modseqs.bump(uid); });
+        if self.unseen == index + 1 {
+            self.unseen = self.messages.messages(&self.keywords).position(|msg| msg.is_unseen())
+                .map(|i| i + 1).unwrap_or(!0usize);
         }
-        result
+        if self.messages.is_full() {
+            self.check();
+        }
+        true
     }
 
-    pub fn message_count(&self) -> usize {
-        self.messages.len()
+    /// Turn a UID into a sequence number
+    fn get_index_from_uid(&self, uid: &usize) -> Option<usize> {
+        self.messages.index_of(*uid)
     }
 
-    /// Perform a fetch of the specified attributes on self.messsages[index]
+    /// Perform a fetch of the specified attributes on the message at `index`
+    /// If `options.report_flags` is set the message's FLAGS are included as
+    /// well, since the client wasn't the one to change them.
     /// Return the FETCH response string to be sent back to the client
-    pub fn fetch(&self, index: usize, attributes: &[Attribute]) -> String {
+    fn fetch(&self, index: usize, attributes: &[Attribute],
+             options: FetchOptions) -> ImapResult<String> {
+        // The message may have been expunged since the client last heard
+        // about the folder's contents.
+        let message = match self.messages.get(index, &self.keywords) {
+            Some(message) => message,
+            None => return Err(Error::NoSuchMessage)
+        };
         let mut res = "* ".to_string();
         res.push_str(&(index+1).to_string()[..]);
         res.push_str(" FETCH (");
-        res.push_str(&self.messages[index].fetch(attributes)[..]);
+        res.push_str(&message.fetch(attributes, options.decode_headers, options.crlf)?[..]);
+        if options.report_flags {
+            res.push(' ');
+            res.push_str(&message.fetch(&[Attribute::Flags], options.decode_headers,
+                                        options.crlf)?[..]);
+        }
+        if attributes.contains(&Attribute::ModSeq) {
+            if let Some(modseq) = self.modseq(index) {
+                if !res.ends_with('(') {
+                    res.push(' ');
+                }
+                res.push_str(&format!("MODSEQ ({})", modseq)[..]);
+            }
+        }
         res.push_str(")\r\n");
-        res
+        Ok(res)
     }
 
-    /// Turn a UID into a sequence number
-    pub fn get_index_from_uid(&self, uid: &usize) -> Option<&usize> {
-        self.uid_to_seqnum.get(uid)
-    }
-
-    /// Perform a STORE on the specified set of sequence numbers
-    /// This modifies the flags of the specified messages
-    /// Returns the String response to be sent back to the client.
-    pub fn store(&mut self, sequence_set: Vec<usize>, flag_name: &StoreName,
-                 silent: bool, flags: HashSet<Flag>, seq_uid: bool,
-                 tag: &str) -> String {
-        let mut responses = String::new();
+    /// Perform a STORE on the specified set of sequence numbers, or UIDs if
+    /// `seq_uid` is set. This modifies the flags of the specified messages
+    /// Returns the untagged FETCH responses to be sent back to the client,
+    /// which give the messages' new mod-sequences if `condstore` is set.
+    fn store(&mut self, sequence_set: Vec<usize>, flag_name: &StoreName,
+             silent: bool, flags: HashSet<Flag>, seq_uid: bool, condstore: bool) -> String {
+        // The sequence number, UID and new flags of each message changed
+        let mut changed = Vec::new();
         for num in &sequence_set {
-            let (uid, i) = if seq_uid {
-                match self.get_index_from_uid(num) {
-                    // 0 is an invalid sequence number
-                    // Return it if the UID isn't found
-                    None => (*num, 0usize),
-                    Some(ind) => (*num, *ind+1)
-                }
+            let i = if seq_uid {
+                // 0 is an invalid sequence number
+                // Return it if the UID isn't found
+                self.get_index_from_uid(num).map_or(0, |ind| ind+1)
             } else {
-                (0usize, *num)
+                *num
             };
 
             // if i == 0 then the UID wasn't in the sequence number map
@@ -209,79 +401,1146 @@ impl Folder {
                 continue;
             }
 
-            // Create the FETCH response for this STORE operation.
-            if let Some(mut message) = self.messages.get_mut(i-1) {
-                responses.push_str("* ");
-                responses.push_str(&i.to_string()[..]);
-                responses.push_str(" FETCH (FLAGS ");
-                responses.push_str(&message.store(flag_name, flags.clone())[..]);
-
-                // UID STORE needs to respond with the UID for each FETCH response
-                if seq_uid {
-                    let uid_res = format!(" UID {}", uid);
-                    responses.push_str(&uid_res[..]);
-                }
-                responses.push_str(" )\r\n");
+            if let Some(message) = self.messages.change(i-1, &self.keywords) {
+                let new_flags = message.store(flag_name, flags.clone());
+                changed.push((i, message.get_uid(), new_flags));
+            }
+            // Only so many changed messages are held before they are written.
+            if self.messages.is_full() {
+                self.check();
             }
         }
 
+        update_modseqs(&mut self.modseqs, &self.path, |modseqs| {
+            for &(_, uid, _) in &changed {
+                modseqs.bump(uid);
+            }
+        });
+
         // Return an empty string if the client wanted the STORE to be SILENT
         if silent {
-            responses = String::new();
+            return String::new();
+        }
+        // Create the FETCH response for each message stored to.
+        let mut responses = String::new();
+        for (seqnum, uid, new_flags) in changed {
+            responses.push_str(&format!("* {} FETCH (FLAGS {}", seqnum, new_flags)[..]);
+            // UID STORE needs to respond with the UID for each FETCH response
+            if seq_uid {
+                responses.push_str(&format!(" UID {}", uid)[..]);
+            }
+            let modseq = self.modseqs.as_ref().and_then(|modseqs| modseqs.get(uid));
+            if let (true, Some(modseq)) = (condstore, modseq) {
+                responses.push_str(&format!(" MODSEQ ({})", modseq)[..]);
+            }
+            responses.push_str(" )\r\n");
         }
-        responses.push_str(tag);
-        responses.push_str(" OK STORE complete\r\n");
         responses
     }
 
+    /// Delete on disk all the messages marked for deletion
+    /// Returns the list of sequence numbers which have been deleted on disk
+    /// Per RFC 3501, the later sequence numbers are calculated based on the
+    /// sequence numbers at the time of the deletion not at the start of the function
+    fn expunge(&mut self) -> Vec<usize> {
+        self.expunge_where(|_| true)
+    }
+
+    fn uid_expunge(&mut self, uids: &[usize]) -> Vec<usize> {
+        let uids: HashSet<usize> = uids.iter().cloned().collect();
+        self.expunge_where(|uid| uids.contains(&uid))
+    }
+
+    /// Find the messages which match every one of the search keys. Returns
+    /// their UIDs if `uid` is set and their sequence numbers otherwise.
+    fn search(&self, keys: &[SearchKey], uid: bool) -> Vec<usize> {
+        let max_seqnum = self.messages.len();
+        let max_uid = max_seqnum.checked_sub(1).and_then(|i| self.messages.uid(i)).unwrap_or(0);
+        let messages = self.messages.messages(&self.keywords).enumerate().map(|(index, message)| {
+            let position = search::Position {
+                seqnum: index + 1,
+                recent: self.recent_uids.contains(&message.get_uid()),
+                max_seqnum: max_seqnum,
+                max_uid: max_uid,
+                modseq: self.modseq(index)
+            };
+            (message, position)
+        });
+        search::search_messages(messages, keys, uid)
+    }
+
+    /// Pick up any messages which have arrived since the folder was
+    /// selected, giving them UIDs and, unless they are kept in folder/new/,
+    /// moving them to folder/cur/. As well as mail delivered to folder/new/
+    /// this finds messages which another session has already moved to
+    /// folder/cur/, or stored there as APPEND and COPY do, though only those
+    /// found in folder/new/ are \Recent. Returns the untagged EXISTS and
+    /// RECENT responses the client needs to hear about them, or an empty
+    /// string if there was no new mail.
+    fn poll_new(&mut self) -> io::Result<String> {
+        let known: HashSet<String> = self.messages.messages(&self.keywords)
+            .map(|msg| msg.get_path().to_path_buf())
+            .map(|msg_path| uidlist::base_name(path_filename_to_str!(msg_path)).to_string())
+            .collect();
+        let mut filenames = Vec::new();
+        let mut is_new = Vec::new();
+        for dir in &["cur", "new"] {
+            for entry in fs::read_dir(&self.path.join(dir))? {
+                let msg_path = entry?.path();
+                if let Some(filename) = maildir::message_filename(&msg_path) {
+                    if !known.contains(uidlist::base_name(filename)) {
+                        filenames.push(filename.to_string());
+                        is_new.push(*dir == "new");
+                    }
+                }
+            }
+        }
+        if filenames.is_empty() {
+            return Ok(String::new());
+        }
+
+        let (uidlist, uids) = {
+            let _lock = uidlist::lock(&self.path)?;
+            let mut uidlist = UidList::load(&self.path)?;
+            let uids = uidlist.assign_all(&filenames);
+            if let Err(e) = uidlist.save(&self.path) {
+                warn!("Failed to save {}: {}", uidlist::UIDLIST_FILE, e);
+            }
+            (uidlist, uids)
+        };
+
+        let mut entries: Vec<(usize, String, bool)> = uids.into_iter().zip(filenames).zip(is_new)
+            .map(|((uid, filename), is_new)| (uid, filename, is_new))
+            .collect();
+        entries.sort_by_key(|&(uid, _, _)| uid);
+
+        let first = self.messages.len();
+        for (uid, filename, is_new) in entries {
+            let dir = if is_new { "new" } else { "cur" };
+            self.messages.push(uid, &self.path.join(dir).join(filename));
+            if is_new {
+                self.recent += 1;
+                self.recent_uids.insert(uid);
+            }
+        }
+        if self.messages.len() == first {
+            return Ok(String::new());
+        }
+        // Sequence numbers are 1-indexed
+        if self.unseen > self.exists {
+            self.unseen = (first..self.messages.len())
+                .find(|&i| self.messages.get(i, &self.keywords).map_or(false, |msg| msg.is_unseen()))
+                .map(|i| i + 1).unwrap_or(!0usize);
+        }
+        self.exists = self.messages.len();
+        if !self.keep_new && !self.readonly() {
+            move_new(&mut self.messages, first, self.path.as_path(), &mut self.keywords);
+        }
+        self.uid_next = uidlist.next_uid;
+        let uids = self.uids_from_index(first);
+        update_modseqs(&mut self.modseqs, &self.path, |modseqs| modseqs.assign_all(&uids));
+
+        Ok(ResponseWriter::new()
+            .untagged(&format!("{} EXISTS", self.exists))
+            .untagged(&format!("{} RECENT", self.recent))
+            .into_string())
+    }
+
+    /// Catch up with changes other sessions have made to the folder since
+    /// the client last heard about it, as a poll such as NOOP asks for. A
+    /// message whose file has gone is reported with `* n EXPUNGE`, or in a
+    /// `* VANISHED` response if `vanished` is set as the client has enabled
+    /// QRESYNC, and one renamed with different flags with an untagged FETCH
+    /// of its FLAGS, along with its MODSEQ if `condstore` is set. Any new
+    /// mail is then picked up as `poll_new` describes.
+    fn poll_changes(&mut self, condstore: bool, vanished: bool) -> io::Result<String> {
+        let mut on_disk = HashMap::new();
+        for dir in &["cur", "new"] {
+            for entry in fs::read_dir(&self.path.join(dir))? {
+                let msg_path = entry?.path();
+                let base = maildir::message_filename(&msg_path)
+                    .map(|filename| uidlist::base_name(filename).to_string());
+                if let Some(base) = base {
+                    on_disk.insert(base, msg_path);
+                }
+            }
+        }
+
+        let mut res = ResponseWriter::new();
+        let mut expunged = Vec::new();
+        let mut gone = Vec::new();
+        let mut changed = Vec::new();
+        let mut index = 0;
+        while let Some(message) = self.messages.get(index, &self.keywords) {
+            let uid = message.get_uid();
+            let msg_path = message.get_path();
+            let msg_path = match on_disk.get(uidlist::base_name(path_filename_to_str!(msg_path))) {
+                Some(msg_path) => msg_path,
+                None => {
+                    self.messages.remove(index);
+                    self.exists -= 1;
+                    if self.recent_uids.remove(&uid) {
+                        self.recent -= 1;
+                    }
+                    gone.push(uid);
+                    if vanished {
+                        expunged.push(uid);
+                    } else {
+                        // Sequence numbers are 1-indexed
+                        res = res.untagged(&format!("{} EXPUNGE", index + 1));
+                    }
+                    continue;
+                }
+            };
+            if msg_path != message.get_path() && self.messages.renamed(index, msg_path, &self.keywords) {
+                changed.push(uid);
+            }
+            index += 1;
+        }
+
+        if vanished && !expunged.is_empty() {
+            res = res.untagged(&format!("VANISHED {}",
+                                        sequence_set::compress_to_sequence_set(&expunged)));
+        }
+        self.unseen = self.messages.messages(&self.keywords).position(|msg| msg.is_unseen())
+            .map(|i| i + 1).unwrap_or(!0usize);
+        update_modseqs(&mut self.modseqs, &self.path, |modseqs| {
+            for &uid in &changed {
+                modseqs.bump(uid);
+            }
+            modseqs.expunge(&gone);
+        });
+
+        let attributes = if condstore {
+            vec![Attribute::Flags, Attribute::ModSeq]
+        } else {
+            vec![Attribute::Flags]
+        };
+        for uid in changed {
+            let index = match self.messages.index_of(uid) {
+                Some(index) => index,
+                None => continue
+            };
+            if let Ok(fetch) = self.fetch(index, &attributes, FetchOptions::default()) {
+                res = res.responses(&fetch);
+            }
+        }
+        Ok(res.responses(&self.poll_new()?).into_string())
+    }
+
     /// Reconcile the internal state of the folder with the disk.
-    pub fn check(&mut self) {
+    /// Only the messages whose flags have changed are renamed, and their
+    /// changes are merged with the flags they have on disk as
+    /// `message::merge_flags` describes rather than overwriting them.
+    fn check(&mut self) {
         // If it is read-only we can't write any changes to disk
-        if self.readonly {
+        let changed = self.messages.changed();
+        if self.readonly() || changed.is_empty() {
             return;
         }
 
-        // We need to create a new list of messages because the compiler will
-        // yell at us for inspecting the internal state of the message and
-        // modifying that state at the same time
-        let mut new_messages = Vec::new();
-        for msg in &self.messages {
-            // Grab the new filename composed of this message's UID and its current flags.
-            let filename = msg.get_new_filename();
+        // Grab the new filenames composed of each changed message's UID and
+        // its current flags. Messages kept in folder/new/ join folder/cur/
+        // here. Any keywords which are new to the folder are
+        // given letters, which must be saved before they appear in a
+        // filename.
+        // Another session may have renamed a message since it was read, so
+        // its current flags are read back first and only the changes made
+        // here are applied to them.
+        // Letters are only given out with the mapping locked and up to date.
+        let lock = match keywords::lock(&self.path) {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Failed to lock {}: {}", keywords::KEYWORDS_FILE, e);
+                return;
+            }
+        };
+        if let Err(e) = self.keywords.reload(&self.path) {
+            warn!("Failed to read {}: {}", keywords::KEYWORDS_FILE, e);
+            return;
+        }
+        let mut renames = Vec::new();
+        for index in changed {
+            let msg = match self.messages.change(index, &self.keywords) {
+                Some(msg) => msg,
+                None => continue
+            };
+            let keywords = &self.keywords;
+            let on_disk = current_path(&self.path, msg.get_path())
+                .and_then(|path| Message::new(&path, msg.get_uid(), keywords).ok());
+            match on_disk {
+                Some(on_disk) => msg.merge_flags(on_disk),
+                // The message is gone, which the next poll will notice.
+                None => continue
+            }
+            renames.push((index, msg.get_new_filename(&mut self.keywords)));
+        }
+        if self.keywords.is_changed() {
+            if let Err(e) = self.keywords.save(&self.path) {
+                warn!("Failed to save {}: {}", keywords::KEYWORDS_FILE, e);
+                return;
+            }
+        }
+        drop(lock);
+
+        for (index, filename) in renames {
             let curpath = self.path.join("cur").join(filename);
+            let msg_path = match self.messages.get(index, &self.keywords) {
+                Some(msg) => msg.get_path().to_path_buf(),
+                None => continue
+            };
+            // Otherwise try again at the next CHECK.
+            if curpath == msg_path || fs::rename(&msg_path, &curpath).is_ok() {
+                self.messages.saved(index, &curpath);
+            }
+        }
+    }
 
-            // If the new filename is the same as the current filename, add the
-            // current message to our new list and move on to the next message
-            if curpath == msg.get_path() {
-                new_messages.push(msg.clone());
-                continue;
+    /// The `* VANISHED (EARLIER)` response listing the messages expunged
+    /// since the given mod-sequence, limited to the UIDs in `uids` if it is
+    /// given. Returns an empty string if there are none.
+    fn vanished_response(&self, modseq: u64, uids: Option<&Vec<SequenceItem>>) -> String {
+        // The expunged messages may have had higher UIDs than any left, so
+        // `*` is taken to cover them all.
+        let vanished: Vec<usize> = self.vanished_since(modseq).into_iter()
+            .filter(|&uid| uids.map_or(true, |uids| sequence_set::contains(uids, uid, usize::MAX)))
+            .collect();
+        if vanished.is_empty() {
+            return String::new();
+        }
+        ResponseWriter::new()
+            .untagged(&format!("VANISHED (EARLIER) {}",
+                               sequence_set::compress_to_sequence_set(&vanished)))
+            .into_string()
+    }
+}
+
+impl Folder {
+    /// Delete on disk the messages marked for deletion whose UIDs `include`
+    /// accepts, returning their sequence numbers as `expunge` does.
+    fn expunge_where<P: Fn(usize) -> bool>(&mut self, include: P) -> Vec<usize> {
+        let mut result = Vec::new();
+        // We can't perform the deletion if the folder has been opened as
+        // read-only
+        if !self.readonly() {
+            // Vectors are 0-indexed
+            let mut index = 0usize;
+            let mut removed_bytes = 0i64;
+            let mut gone = Vec::new();
+
+            // self.messages will get smaller as we go through it
+            while let Some(message) = self.messages.get(index, &self.keywords) {
+                if !include(message.get_uid()) {
+                    index += 1;
+                    continue;
+                }
+                // Grab the size before the file is gone.
+                let size = if message.is_deleted() {
+                    message.disk_size()
+                } else {
+                    0
+                };
+                if message.remove_if_deleted() {
+                    removed_bytes += size as i64;
+                    gone.push(self.messages.remove(index));
+                    self.exists -= 1;
+                    // Sequence numbers are 1-indexed
+                    result.push(index + 1);
+                } else {
+                    index += 1;
+                }
+            }
+
+            // Keep the maildir's quota accounting up to date.
+            if !result.is_empty() {
+                if let Err(e) = maildir::update_size(&self.maildir, -removed_bytes,
+                                                     -(result.len() as i64)) {
+                    warn!("Failed to update maildirsize: {}", e);
+                }
+                if let Err(e) = index::rebuild(&self.path) {
+                    warn!("Failed to rebuild folder index: {}", e);
+                }
+                update_modseqs(&mut self.modseqs, &self.path, |modseqs| modseqs.expunge(&gone));
             }
-            rename_message!(msg, curpath, new_messages);
         }
+        result
+    }
+}
+
+/// Find where the message last seen at `msg_path` is now. If it isn't
+/// there, another session may have renamed it to change its flags, in which
+/// case it is looked for in folder/cur by the part of its filename before
+/// the flags.
+fn current_path(path: &Path, msg_path: &Path) -> Option<PathBuf> {
+    if msg_path.is_file() {
+        return Some(msg_path.to_path_buf());
+    }
+    let base = uidlist::base_name(msg_path.file_name()?.to_str()?).to_string();
+    fs::read_dir(path.join("cur")).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|entry| entry.file_name()
+              .and_then(|name| name.to_str())
+              .map_or(false, |name| uidlist::base_name(name) == &base[..]))
+}
+
+/// Read the mod-sequences of the folder at `path`, whose messages have the
+/// given UIDs, and bring them up to date. A list which can't be read is
+/// started afresh under a new UIDVALIDITY, so that clients don't trust the
+/// mod-sequences they were given before, as is a list which remembers too
+/// many expunged messages. Neither is done while another session has the
+/// folder selected.
+fn load_modseqs(path: &Path, uidlist: &mut UidList, uids: &[usize],
+                readonly: bool) -> io::Result<ModSeqs> {
+    let _lock = modseq::lock(path)?;
+    let mut modseqs = match ModSeqs::load(path, uidlist.uid_validity) {
+        Ok(modseqs) => modseqs,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData && !readonly => {
+            warn!("Replacing malformed {} in {}", modseq::MODSEQ_FILE, path.display());
+            *uidlist = renew_uid_validity(path)?;
+            let mut modseqs = ModSeqs::new(uidlist.uid_validity);
+            modseqs.save(path)?;
+            modseqs
+        }
+        Err(e) => return Err(e)
+    };
+    modseqs.retain(uids);
+    if modseqs.needs_pruning() && !readonly {
+        match renew_uid_validity(path) {
+            Ok(renewed) => {
+                *uidlist = renewed;
+                modseqs.reset(uidlist.uid_validity);
+            }
+            Err(e) => warn!("Failed to save {}: {}", uidlist::UIDLIST_FILE, e)
+        }
+    }
+    modseqs.assign_all(uids);
+    if modseqs.is_changed() {
+        modseqs.save(path)?;
+    }
+    Ok(modseqs)
+}
 
-        // Set the current list of messages to the new list of messages
-        // The compiler *should* make this discard the old list...
-        self.messages = new_messages;
+/// Give the folder at `path` a new UIDVALIDITY. The UID list is read again
+/// under its lock so that UIDs given out since it was last read aren't lost.
+/// The folder's index is rebuilt, as STATUS reports the UIDVALIDITY from it.
+fn renew_uid_validity(path: &Path) -> io::Result<UidList> {
+    let uidlist = {
+        let _lock = uidlist::lock(path)?;
+        let mut uidlist = UidList::load(path)?;
+        uidlist.renew_uid_validity();
+        uidlist.save(path)?;
+        uidlist
+    };
+    if let Err(e) = index::rebuild(path) {
+        warn!("Failed to rebuild folder index: {}", e);
     }
+    Ok(uidlist)
 }
 
-/// This moves a list of messages from folder/new/ to folder/cur/ and returns a
-/// new list of messages
-fn move_new(messages: &[Message], path: &Path,
-            start_index: usize) -> Vec<Message> {
-    let mut new_messages = Vec::new();
-
-    // Go over the messages by index
-    for (i, msg) in messages.iter().enumerate() {
-        // messages before start_index are already in folder/cur/
-        if i+1 < start_index {
-            new_messages.push(msg.clone());
+/// Make a change to the folder's mod-sequences, if they are kept, as
+/// `ModSeqs::update` describes.
+fn update_modseqs<F>(modseqs: &mut Option<ModSeqs>, path: &Path, f: F)
+    where F: FnOnce(&mut ModSeqs) {
+    if let Some(ref mut modseqs) = *modseqs {
+        if let Err(e) = modseqs.update(path, f) {
+            warn!("Failed to save {}: {}", modseq::MODSEQ_FILE, e);
+        }
+    }
+}
+
+/// This moves the messages from the given index onwards which are in
+/// folder/new/ to folder/cur/. A message which can't be moved is left where
+/// it is.
+fn move_new(messages: &mut Window, start: usize, path: &Path, keywords: &mut Keywords) {
+    let newdir_path = path.join("new");
+
+    for index in start..messages.len() {
+        let msg = match messages.get(index, keywords) {
+            Some(msg) => msg,
+            None => continue
+        };
+        // Only messages in folder/new/ need to move
+        if msg.get_path().parent() != Some(newdir_path.as_path()) {
             continue;
         }
-        let curpath = path.join("cur").join(msg.get_uid().to_string());
-        rename_message!(msg, curpath, new_messages);
+        let curpath = path.join("cur").join(msg.get_new_filename(keywords));
+        if fs::rename(msg.get_path(), &curpath).is_ok() {
+            messages.saved(index, &curpath);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use command::Attribute;
+    use command::fetch::FetchOptions;
+    use command::select::Qresync;
+    use command::sequence_set::SequenceItem::Number;
+    use command::store::StoreName;
+    use dotlock::FileLock;
+    use fixture;
+    use mailstore::{MailFolder, MailMessage};
+    use message::{parse_flag, Flag, Message, COMMON_KEYWORDS};
+    use uidlist;
+    use super::Folder;
+
+    // The message at `index`, as the folder sees it
+    fn message(folder: &Folder, index: usize) -> Message {
+        folder.messages.get(index, &folder.keywords).unwrap()
+    }
+
+    #[test]
+    fn test_uids_from_uidlist() {
+        let dir = fixture::maildir("folder-uids", &[]);
+        let mut uidlist = File::create(dir.join("dovecot-uidlist")).unwrap();
+        uidlist.write_all(b"3 V1234 N8\n7 :1500000000.M2P9.host\n3 :1400000000.M1P9.host\n")
+            .unwrap();
+        for name in &["cur/1500000000.M2P9.host:2,S", "cur/1400000000.M1P9.host:2,",
+                      "new/1600000000.M3P9.host"] {
+            File::create(dir.join(name)).unwrap().write_all(b"Subject: hi\n\nHi\n").unwrap();
+        }
+
+        // Listed messages keep their UIDs, new ones are numbered after them
+        // and sequence numbers follow UID order.
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![3, 7, 8]);
+        assert!(dir.join("cur").join("1600000000.M3P9.host").exists());
+        drop(folder);
+
+        // The assignment survives the message moving to cur/.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![3, 7, 8]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_new_keeps_flags() {
+        let dir = fixture::maildir("folder-move-flags", &[]);
+        fixture::write_message(&dir, "new", 40, &[Flag::Flagged, Flag::Seen], fixture::MESSAGE);
+        fixture::write_message(&dir, "new", 50, &[], fixture::MESSAGE);
+
+        // EXAMINE leaves new mail where it is, and still \Recent.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(dir.join("new/40:2,FS").is_file());
+        assert!(dir.join("new/50").is_file());
+        assert_eq!(fs::read_dir(dir.join("cur")).unwrap().count(), 0);
+        assert!(message(&folder, 0).has_flag(&Flag::Flagged));
+        assert_eq!(folder.recent, 2);
+        drop(folder);
+
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(dir.join("cur/40:2,FS").is_file());
+        assert!(dir.join("cur/50").is_file());
+        assert_eq!(fs::read_dir(dir.join("new")).unwrap().count(), 0);
+        assert!(message(&folder, 0).has_flag(&Flag::Flagged));
+        assert!(message(&folder, 0).has_flag(&Flag::Seen));
+        assert_eq!(folder.recent, 2);
+        drop(folder);
+
+        // The flags are still there the next time the folder is opened.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(message(&folder, 0).has_flag(&Flag::Flagged));
+        assert!(!message(&folder, 1).has_flag(&Flag::Seen));
+        assert_eq!(folder.recent, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    // Return the new list of messages
-    new_messages
+    #[test]
+    fn test_window_limit() {
+        let dir = fixture::maildir("folder-window", &[(10, &[]), (20, &[]), (30, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 1).unwrap();
+
+        // One changed message is held until CHECK.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        folder.store(vec![1], &StoreName::Add, true, flags.clone(), false, false);
+        assert_eq!(folder.messages.changed(), vec![0]);
+        assert!(dir.join("cur/10:2,").is_file());
+
+        // Any more are written to disk straight away.
+        assert!(folder.mark_seen(1));
+        assert!(folder.messages.changed().is_empty());
+        assert!(dir.join("cur/10:2,F").is_file());
+        assert!(dir.join("cur/20:2,S").is_file());
+        folder.store(vec![2, 3], &StoreName::Add, true, flags, false, false);
+        assert!(folder.messages.changed().is_empty());
+        assert!(dir.join("cur/20:2,FS").is_file());
+        assert!(dir.join("cur/30:2,F").is_file());
+        assert!(message(&folder, 2).has_flag(&Flag::Flagged));
+
+        folder.check();
+        assert!(dir.join("cur/10:2,F").is_file());
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_new_among_cur() {
+        // The first unseen message comes before a new one which is seen and
+        // after one which isn't.
+        let dir = fixture::maildir("folder-move-mixed",
+                                   &[(10, &[Flag::Seen]), (30, &[]), (50, &[Flag::Seen])]);
+        fixture::write_message(&dir, "new", 20, &[], fixture::MESSAGE);
+        fixture::write_message(&dir, "new", 40, &[Flag::Seen], fixture::MESSAGE);
+
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![10, 20, 30, 40, 50]);
+        assert_eq!(folder.unseen, 2);
+        // Exactly the messages from new/ are moved and recent.
+        assert_eq!(folder.recent, 2);
+        assert!(folder.recent_uids.contains(&20) && folder.recent_uids.contains(&40));
+        assert_eq!(fs::read_dir(dir.join("new")).unwrap().count(), 0);
+        let mut names: Vec<String> = fs::read_dir(dir.join("cur")).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["10:2,S", "20", "30:2,", "40:2,S", "50:2,S"]);
+        for (index, msg) in folder.messages.messages(&folder.keywords).enumerate() {
+            assert_eq!(msg.get_path(), dir.join("cur").join(&names[index]).as_path());
+        }
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_poll_new_finds_cur() {
+        let dir = fixture::maildir("folder-poll-cur", &[(10, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+
+        // Mail another session has moved to cur/, or stored there, is found
+        // too, but only that still in new/ is recent.
+        fixture::write_message(&dir, "cur", 20, &[Flag::Seen], fixture::MESSAGE);
+        fixture::write_message(&dir, "new", 30, &[], fixture::MESSAGE);
+        assert_eq!(folder.poll_new().unwrap(), "* 3 EXISTS\r\n* 1 RECENT\r\n");
+        assert_eq!(folder.uids_from_index(0).len(), 3);
+        assert!(folder.recent_uids.contains(&folder.uids_from_index(2)[0]));
+        assert_eq!(folder.poll_new().unwrap(), "");
+
+        // A message which has only changed its flags is nothing new.
+        fs::rename(dir.join("cur/10:2,"), dir.join("cur/10:2,F")).unwrap();
+        assert_eq!(folder.poll_new().unwrap(), "");
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_new_until_seen() {
+        let dir = fixture::maildir("folder-keep-new", &[(10, &[Flag::Seen])]);
+        fixture::write_message(&dir, "new", 20, &[], fixture::MESSAGE);
+        fixture::write_message(&dir, "new", 30, &[], fixture::MESSAGE);
+
+        // Without any flags changing, the new messages are recent every time
+        // the folder is selected.
+        for _ in 0..2 {
+            let folder = Folder::new(&dir, dir.clone(), false, true, 100).unwrap();
+            assert!(folder.select_response("a1", false, None).contains("* 2 RECENT\r\n"));
+            assert!(folder.recent_uids.contains(&20) && folder.recent_uids.contains(&30));
+            assert!(dir.join("new").join("20").exists());
+            assert!(dir.join("new").join("30").exists());
+        }
+
+        // Mail delivered while the folder is selected stays in new/ too.
+        let mut folder = Folder::new(&dir, dir.clone(), false, true, 100).unwrap();
+        fixture::write_message(&dir, "new", 40, &[], fixture::MESSAGE);
+        assert_eq!(folder.poll_new().unwrap(), "* 4 EXISTS\r\n* 3 RECENT\r\n");
+        assert_eq!(folder.poll_new().unwrap(), "");
+        assert!(dir.join("new").join("40").exists());
+
+        // Setting a flag moves the message to cur/, after which it is no
+        // longer recent.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Seen);
+        folder.store(vec![2], &StoreName::Add, true, flags, false, false);
+        folder.check();
+        assert!(!dir.join("new").join("20").exists());
+        assert!(dir.join("cur").join("20:2,S").exists());
+        drop(folder);
+
+        let folder = Folder::new(&dir, dir.clone(), false, true, 100).unwrap();
+        assert!(folder.select_response("a2", false, None).contains("* 2 RECENT\r\n"));
+        assert!(!folder.recent_uids.contains(&20));
+        assert_eq!(folder.uids_from_index(0), vec![10, 20, 30, 40]);
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keywords_persist() {
+        let dir = fixture::maildir("folder-keywords", &[(100, &[Flag::Seen]), (101, &[])]);
+
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let response = folder.select_response("a1", false, None);
+        assert!(response.starts_with("* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+                                      $Forwarded $Junk $MDNSent $NotJunk)\r\n"));
+        assert!(response.contains("* OK [UNSEEN 2] "));
+        assert!(response.contains("[PERMANENTFLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+                                   $Forwarded $Junk $MDNSent $NotJunk \\*)]"));
+        assert!(response.contains("* OK [UIDNEXT 102] "));
+
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Keyword("Work".to_string()));
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        folder.check();
+        folder.expunge();
+
+        // The keyword survives reopening the folder and is then listed with
+        // the folder's flags.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(folder.select_response("a3", false, None).starts_with(
+            "* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+             $Forwarded $Junk $MDNSent $NotJunk Work)\r\n"));
+        assert!(dir.join("cur").join("100:2,Sa").exists());
+        let mut mapping = String::new();
+        File::open(dir.join("dovecot-keywords")).unwrap().read_to_string(&mut mapping).unwrap();
+        assert_eq!(mapping, "0 Work\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_utf8_filename() {
+        let dir = fixture::maildir("folder-non-utf8", &[(10, &[Flag::Seen]), (20, &[])]);
+        let name = OsStr::from_bytes(b"15\xff:2,S");
+        File::create(dir.join("cur").join(name)).unwrap();
+        File::create(dir.join("new").join(name)).unwrap();
+
+        // The files are left alone and aren't given UIDs.
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![10, 20]);
+        assert_eq!(folder.poll_changes(false, false).unwrap(), "");
+        assert!(dir.join("new").join(name).is_file());
+        let mut uidlist = String::new();
+        File::open(dir.join("dovecot-uidlist")).unwrap().read_to_string(&mut uidlist).unwrap();
+        assert_eq!(uidlist.lines().count(), 3);
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_keywords_on_disk() {
+        let dir = fixture::maildir("folder-select-keywords", &[(101, &[Flag::Seen])]);
+        File::create(dir.join("dovecot-keywords")).unwrap().write_all(b"0 Work\n1 Unused\n")
+            .unwrap();
+        File::create(dir.join("cur/100:2,Sa")).unwrap().write_all(fixture::MESSAGE.as_bytes())
+            .unwrap();
+
+        // Only keywords which are set on a message are listed.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let response = folder.select_response("a1", false, None);
+        assert!(response.starts_with("* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+                                      $Forwarded $Junk $MDNSent $NotJunk Work)\r\n"), "{}", response);
+        assert!(response.contains("[PERMANENTFLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+                                   $Forwarded $Junk $MDNSent $NotJunk Work \\*)]"), "{}", response);
+        assert!(!response.contains("Unused"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_common_keywords() {
+        let dir = fixture::maildir("folder-common", &[(100, &[Flag::Seen])]);
+
+        // Clients' spellings are normalised when the flags are parsed.
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let flags: HashSet<Flag> = ["$forwarded", "$JUNK", "$MDNSent", "$notjunk"].iter()
+            .map(|flag| parse_flag(flag).unwrap())
+            .collect();
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        folder.check();
+        folder.expunge();
+
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let response = folder.fetch(0, &[Attribute::Flags], FetchOptions::default()).unwrap();
+        for keyword in &COMMON_KEYWORDS {
+            assert!(response.contains(keyword), "{} missing from {}", keyword, response);
+        }
+        // $Forwarded has a maildir letter of its own.
+        assert!(dir.join("cur").join("100:2,PSabc").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_modseq() {
+        let dir = fixture::maildir("folder-modseq", &[(10, &[Flag::Seen]), (20, &[])]);
+
+        // The line is only sent to clients which have enabled CONDSTORE.
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(!folder.select_response("a1", false, None).contains("MODSEQ"));
+        assert!(folder.select_response("a1", true, None).contains("* OK [HIGHESTMODSEQ 3] "));
+
+        // Changing a message's flags moves the folder on.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        folder.store(vec![2], &StoreName::Add, true, flags, false, false);
+        folder.check();
+        drop(folder);
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(folder.select_response("a3", true, None).contains("* OK [HIGHESTMODSEQ 4] "));
+        drop(folder);
+
+        // Without a usable mod-sequence list the client is told there are
+        // none.
+        fs::remove_file(dir.join("segimap.modseq")).unwrap();
+        fs::create_dir(dir.join("segimap.modseq")).unwrap();
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let response = folder.select_response("a4", true, None);
+        assert!(response.contains("* OK [NOMODSEQ] "));
+        assert!(!response.contains("HIGHESTMODSEQ"));
+        let uid_validity = folder.uid_validity;
+        drop(folder);
+
+        // One which can't be parsed is started afresh under a new
+        // UIDVALIDITY, so that clients don't trust what they were told.
+        fs::remove_dir(dir.join("segimap.modseq")).unwrap();
+        File::create(dir.join("segimap.modseq")).unwrap().write_all(b"V1 Hx\n").unwrap();
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(folder.select_response("a5", true, None).contains("* OK [NOMODSEQ] "));
+        assert_eq!(folder.uid_validity, uid_validity);
+        drop(folder);
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(folder.uid_validity > uid_validity);
+        assert!(folder.select_response("a6", true, None).contains("* OK [HIGHESTMODSEQ 3] "));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_modseqs() {
+        let dir = fixture::maildir("folder-shared-modseqs", &[(10, &[]), (20, &[])]);
+        let mut first = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let mut second = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert_eq!(first.modseq(1), Some(3));
+
+        // Each session carries on from the other's changes rather than
+        // giving out the same mod-sequence twice.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        first.store(vec![1], &StoreName::Add, true, flags, false, false);
+        assert_eq!(first.modseq(0), Some(4));
+        fixture::write_message(&dir, "new", 30, &[], fixture::MESSAGE);
+        second.poll_new().unwrap();
+        assert_eq!(second.modseq(2), Some(5));
+        first.poll_new().unwrap();
+        assert_eq!(first.modseq(2), Some(5));
+        assert!(first.select_response("a1", true, None).contains("* OK [HIGHESTMODSEQ 5] "));
+
+        drop(second);
+        drop(first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_qresync() {
+        let dir = fixture::maildir("folder-qresync", &[(10, &[]), (20, &[]), (30, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let uids = folder.uids_from_index(0);
+        let uid_validity = folder.uid_validity;
+        assert_eq!(folder.modseq(0), Some(2));
+
+        // One message is flagged and another expunged.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Deleted);
+        folder.store(vec![3], &StoreName::Add, true, flags, false, false);
+        folder.expunge();
+        folder.check();
+        drop(folder);
+
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let qresync = Qresync { uid_validity: uid_validity, modseq: 4, known_uids: None };
+        let response = folder.select_response("a3", true, Some(&qresync));
+        assert!(response.contains(&format!("* VANISHED (EARLIER) {}\r\n", uids[2])[..]));
+        assert!(response.contains(&format!("* 1 FETCH (UID {} FLAGS (\\Flagged) MODSEQ (5))\r\n",
+                                           uids[0])[..]));
+        assert!(!response.contains("* 2 FETCH"));
+
+        // Nothing is sent for UIDs the client doesn't know of, nor if the
+        // UIDs it knows of have been reassigned.
+        let qresync = Qresync {
+            uid_validity: uid_validity,
+            modseq: 5,
+            known_uids: Some(vec![Number(uids[0])])
+        };
+        let response = folder.select_response("a4", true, Some(&qresync));
+        assert!(!response.contains("VANISHED"));
+        assert!(!response.contains("FETCH"));
+        let qresync = Qresync { uid_validity: uid_validity + 1, modseq: 1, known_uids: None };
+        let response = folder.select_response("a5", true, Some(&qresync));
+        assert!(!response.contains("VANISHED"));
+        assert!(!response.contains("FETCH"));
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_expunged() {
+        let dir = fixture::maildir("folder-prune", &[(10, &[])]);
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        let uid_validity = folder.uid_validity;
+        let uid = message(&folder, 0).get_uid();
+        drop(folder);
+
+        // Pretend that a great many messages have been expunged.
+        let mut contents = format!("V{} H20000\n{} 2\n", uid_validity, uid);
+        for expunged in 1..10002 {
+            contents.push_str(&format!("-{} 3\n", uid + expunged)[..]);
+        }
+        File::create(dir.join("segimap.modseq")).unwrap().write_all(contents.as_bytes()).unwrap();
+
+        // They are only forgotten when the folder is selected read-write.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert_eq!(folder.uid_validity, uid_validity);
+        assert_eq!(folder.vanished_since(2).len(), 10001);
+        drop(folder);
+        let folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(folder.uid_validity > uid_validity);
+        assert!(folder.vanished_since(1).is_empty());
+        assert_eq!(message(&folder, 0).get_uid(), uid);
+        assert_eq!(folder.modseq(0), Some(20001));
+        drop(folder);
+
+        // The new UIDVALIDITY is kept.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(folder.uid_validity > uid_validity);
+        assert_eq!(folder.modseq(0), Some(20001));
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_examine_lock() {
+        let dir = fixture::maildir("folder-lock", &[]);
+
+        // Whether a session holds the lock, as another would see it
+        let selected = |dir: &Path| FileLock::try_acquire(&dir.join(".lock")).unwrap().is_none();
+
+        // EXAMINE never takes the lock.
+        let folder = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        assert!(folder.readonly());
+        assert!(!dir.join(".lock").exists());
+        drop(folder);
+
+        // Nor does it give up a lock held by a SELECT.
+        let first = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(!first.readonly());
+        assert!(selected(&dir));
+        let examined = Folder::new(&dir, dir.clone(), true, false, 100).unwrap();
+        drop(examined);
+        assert!(selected(&dir));
+
+        // A second SELECT is read-only and leaves the lock alone too.
+        let second = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(second.readonly());
+        drop(second);
+        assert!(selected(&dir));
+
+        drop(first);
+        assert!(!selected(&dir));
+
+        // A lock file left by a session which has gone doesn't get in the
+        // way, even when its PID now belongs to a running process.
+        fs::write(dir.join(".lock"), "1").unwrap();
+        let first = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(!first.readonly());
+        assert_eq!(fs::read_to_string(dir.join(".lock")).unwrap(), process::id().to_string());
+        drop(first);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_uidlist_lock() {
+        let dir = fixture::maildir("folder-uidlist-lock", &[(1, &[])]);
+        fixture::write_message(&dir, "new", 2, &[], fixture::MESSAGE);
+
+        // Opening the folder waits for whoever is giving out UIDs.
+        let lock = uidlist::lock(&dir).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let opened = dir.clone();
+        let handle = thread::spawn(move || {
+            let folder = Folder::new(&opened, opened.clone(), true, false, 100).unwrap();
+            tx.send(folder.uids_from_index(0)).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+        drop(lock);
+        assert_eq!(rx.recv().unwrap().len(), 2);
+        handle.join().unwrap();
+        assert!(!dir.join(uidlist::LOCK_FILE).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_store() {
+        let dir = fixture::maildir("folder-concurrent", &[(10, &[Flag::Seen])]);
+
+        // Both folders can write, as though the lock had been ignored.
+        let mut first = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        fs::remove_file(dir.join(".lock")).unwrap();
+        let mut second = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert!(!first.readonly() && !second.readonly());
+
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        first.store(vec![1], &StoreName::Add, true, flags, false, false);
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Answered);
+        second.store(vec![1], &StoreName::Add, true, flags, false, false);
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Seen);
+        second.store(vec![1], &StoreName::Sub, true, flags, false, false);
+
+        // Each folder's changes are applied on top of the other's.
+        first.check();
+        assert!(dir.join("cur/10:2,FS").is_file());
+        second.check();
+        assert!(dir.join("cur/10:2,FR").is_file());
+        assert!(message(&second, 0).has_flag(&Flag::Flagged));
+        assert!(!message(&second, 0).has_flag(&Flag::Seen));
+
+        // The first folder finds the message under its new name.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Draft);
+        first.store(vec![1], &StoreName::Add, true, flags, false, false);
+        first.check();
+        assert!(dir.join("cur/10:2,DFR").is_file());
+        assert_eq!(fs::read_dir(dir.join("cur")).unwrap().count(), 1);
+
+        drop(first);
+        drop(second);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_sequence_numbers() {
+        let dir = fixture::maildir("folder-stale", &[(10, &[]), (20, &[]), (30, &[])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Deleted);
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        assert_eq!(folder.expunge(), vec![1]);
+
+        // The client may still think there are three messages.
+        assert!(folder.fetch(2, &[Attribute::Flags], FetchOptions::default()).is_err());
+        assert!(!folder.mark_seen(2));
+        assert_eq!(folder.store(vec![3], &StoreName::Add, false, HashSet::new(), false, false),
+                   "");
+
+        // UIDs still lead to the right messages.
+        assert_eq!(folder.get_index_from_uid(&30), Some(1));
+        assert_eq!(folder.get_index_from_uid(&10), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_renames_changed_messages() {
+        let dir = fixture::maildir("folder-check", &[(10, &[Flag::Seen])]);
+        // The second message's flags aren't in the order we would write them.
+        File::create(dir.join("cur/20:2,SF")).unwrap().write_all(fixture::MESSAGE.as_bytes())
+            .unwrap();
+
+        // Nothing has changed, so nothing is renamed.
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        folder.check();
+        assert!(dir.join("cur").join("20:2,SF").exists());
+
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Answered);
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        folder.check();
+        assert!(dir.join("cur").join("10:2,RS").exists());
+        assert!(dir.join("cur").join("20:2,SF").exists());
+
+        // Each change is only written once.
+        fs::rename(dir.join("cur").join("10:2,RS"), dir.join("cur").join("10:2,SR")).unwrap();
+        folder.check();
+        assert!(dir.join("cur").join("10:2,SR").exists());
+
+        folder.expunge();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_poll_changes() {
+        let dir = fixture::maildir("folder-poll", &[(10, &[]), (20, &[]), (30, &[Flag::Seen])]);
+        let mut folder = Folder::new(&dir, dir.clone(), false, false, 100).unwrap();
+        assert_eq!(folder.poll_changes(false, false).unwrap(), "");
+
+        // Another session expunges one message, flags another and new mail
+        // arrives.
+        fs::remove_file(dir.join("cur/10:2,")).unwrap();
+        fs::rename(dir.join("cur/20:2,"), dir.join("cur/20:2,F")).unwrap();
+        File::create(dir.join("new/40")).unwrap().write_all(b"Subject: hi\n\nHi\n").unwrap();
+        assert_eq!(folder.poll_changes(false, false).unwrap(),
+                   "* 1 EXPUNGE\r\n* 1 FETCH (FLAGS (\\Flagged))\r\n* 3 EXISTS\r\n* 1 RECENT\r\n");
+        assert_eq!(folder.uids_from_index(0), vec![20, 30, 40]);
+        assert_eq!(folder.get_index_from_uid(&30), Some(1));
+        assert_eq!(folder.poll_changes(false, false).unwrap(), "");
+
+        // QRESYNC clients hear about expunged messages by UID.
+        fs::remove_file(dir.join("cur/20:2,F")).unwrap();
+        fs::remove_file(dir.join("cur/40")).unwrap();
+        assert_eq!(folder.poll_changes(false, true).unwrap(), "* VANISHED 20,40\r\n");
+        assert_eq!(folder.uids_from_index(0), vec![30]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use fixture;
+    use self::test::Bencher;
+    use super::Folder;
+
+    const MESSAGES: usize = 1000;
+
+    #[bench]
+    fn bench_folder_new(b: &mut Bencher) {
+        let dir = fixture::maildir("bench-folder", &[]);
+        for i in 0..MESSAGES {
+            let flags = if i % 2 == 0 { "S" } else { "" };
+            let name = format!("{}.M{}P1.bench:2,{}", 1500000000 + i, i, flags);
+            File::create(dir.join("cur").join(name)).unwrap()
+                .write_all(format!("Subject: message {}\nFrom: a@example.com\n\nHello\n", i).as_bytes())
+                .unwrap();
+        }
+
+        // The UID list is written on the first load, so later ones measure
+        // opening a folder which is already known.
+        b.iter(|| Folder::new(&dir, dir.clone(), true, false, 100).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,273 @@
+//! A small per-folder index file caching the counts needed to answer STATUS
+//! without opening every message in the folder.
+//!
+//! The index records the modification times of the folder's cur/ and new/
+//! directories when it was written. Any change to the folder which wasn't
+//! recorded through this module changes one of those times, in which case
+//! the index is rebuilt from the directory listings. The index is only
+//! changed while its lock is held, so that concurrent deliveries each add
+//! their message to it.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde_json;
+
+use dotlock::{self, DotLock};
+use keywords::Keywords;
+use maildir;
+use message::{self, Flag};
+use uidlist::{self, UidList};
+
+/// The name of the index file within each folder.
+pub const INDEX_FILE: &'static str = "segimap.index";
+
+// The lock taken while the index is changed
+const LOCK_FILE: &'static str = "segimap.index.lock";
+
+/// The cached state of a folder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FolderStatus {
+    // How many messages are in the folder total
+    pub messages: usize,
+    // How many messages are in folder/new/
+    pub recent: usize,
+    // How many messages are not marked with the Seen flag
+    pub unseen: usize,
+    // The UID which will be assigned to the next message
+    pub uidnext: usize,
+    // The folder's UIDVALIDITY, from its UID list
+    pub uid_validity: usize,
+    // Modification times of cur/ and new/ as (seconds, nanoseconds)
+    cur_mtime: (u64, u32),
+    new_mtime: (u64, u32)
+}
+
+/// Get the status of the folder, from its index if the index is up to date
+/// and by rebuilding the index otherwise.
+pub fn status(folder: &Path) -> io::Result<FolderStatus> {
+    if let Some(cached) = read_index(folder) {
+        if cached.cur_mtime == mtime(&folder.join("cur"))? &&
+            cached.new_mtime == mtime(&folder.join("new"))? {
+            return Ok(cached);
+        }
+    }
+    rebuild(folder)
+}
+
+/// Rebuild the folder's index from the names of the messages in cur/ and
+/// new/.
+pub fn rebuild(folder: &Path) -> io::Result<FolderStatus> {
+    let _lock = lock(folder)?;
+    rebuild_locked(folder)
+}
+
+fn rebuild_locked(folder: &Path) -> io::Result<FolderStatus> {
+    let uidlist = load_uidlist(folder)?;
+    let mut status = FolderStatus::default();
+    status.uidnext = uidlist.next_uid;
+    status.uid_validity = uidlist.uid_validity;
+    for (dir, recent) in &[("cur", false), ("new", true)] {
+        for entry in fs::read_dir(folder.join(dir))? {
+            let path = entry?.path();
+            let filename = match maildir::message_filename(&path) {
+                Some(filename) => filename,
+                None => continue
+            };
+            status.messages += 1;
+            if *recent {
+                status.recent += 1;
+            }
+            // Keyword letters don't matter to whether the message is seen.
+            if !message::filename_flags(filename, &Keywords::new()).contains(&Flag::Seen) {
+                status.unseen += 1;
+            }
+            // Messages which aren't in the UID list yet will be given their
+            // filename as their UID if it is a number.
+            if uidlist.get(filename).is_none() {
+                if let Ok(uid) = uidlist::base_name(filename).parse::<usize>() {
+                    if uid >= status.uidnext {
+                        status.uidnext = uid + 1;
+                    }
+                }
+            }
+        }
+    }
+    write_index(folder, status)
+}
+
+/// Record that a message with the given UID was delivered to folder/new/.
+pub fn record_delivery(folder: &Path, uid: usize) -> io::Result<FolderStatus> {
+    let _lock = lock(folder)?;
+    // Delivery only touches new/, so if cur/ has changed since the index was
+    // written something else happened to the folder as well. If new/ doesn't
+    // hold just the one message more than the index knows of, some other
+    // change to it was never recorded.
+    let mut status = match read_index(folder) {
+        Some(cached) => {
+            if cached.cur_mtime != mtime(&folder.join("cur"))? ||
+                message_count(&folder.join("new"))? != cached.recent + 1 {
+                return rebuild_locked(folder);
+            }
+            cached
+        }
+        None => return rebuild_locked(folder)
+    };
+    status.messages += 1;
+    status.recent += 1;
+    status.unseen += 1;
+    if uid >= status.uidnext {
+        status.uidnext = uid + 1;
+    }
+    write_index(folder, status)
+}
+
+// Read the folder's UID list. A folder which doesn't have one yet is given
+// one, so that the UIDVALIDITY reported from the index is the one the
+// folder keeps once it is selected.
+fn load_uidlist(folder: &Path) -> io::Result<UidList> {
+    if folder.join(uidlist::UIDLIST_FILE).exists() {
+        return UidList::load(folder);
+    }
+    let _lock = uidlist::lock(folder)?;
+    let uidlist = UidList::load(folder)?;
+    uidlist.save(folder)?;
+    Ok(uidlist)
+}
+
+fn read_index(folder: &Path) -> Option<FolderStatus> {
+    let mut contents = String::new();
+    let mut file = File::open(folder.join(INDEX_FILE)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents[..]).ok()
+}
+
+fn write_index(folder: &Path, mut status: FolderStatus) -> io::Result<FolderStatus> {
+    status.cur_mtime = mtime(&folder.join("cur"))?;
+    status.new_mtime = mtime(&folder.join("new"))?;
+    let encoded = serde_json::to_string(&status)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    // Replace the index in one step so that readers never see a partially
+    // written one.
+    dotlock::replace_file(&folder.join(INDEX_FILE), encoded.as_bytes())?;
+    Ok(status)
+}
+
+fn lock(folder: &Path) -> io::Result<DotLock> {
+    DotLock::acquire(folder.join(LOCK_FILE))
+}
+
+/// The number of messages in a folder's cur/ or new/ directory.
+fn message_count(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        if maildir::message_filename(&entry?.path()).is_some() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn mtime(dir: &Path) -> io::Result<(u64, u32)> {
+    let modified = fs::metadata(dir)?.modified()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::thread;
+
+    use fixture;
+    use message::Flag::{Answered, Flagged, Seen};
+    use uidlist::UidList;
+    use super::{record_delivery, rebuild, status, INDEX_FILE};
+
+    #[test]
+    fn test_rebuild() {
+        let folder = fixture::maildir("index-rebuild",
+                                      &[(100, &[Seen]), (105, &[Flagged, Answered])]);
+        File::create(folder.join("cur").join(".hidden")).unwrap();
+        fixture::write_message(&folder, "new", 110, &[], fixture::MESSAGE);
+
+        let status = rebuild(&folder).unwrap();
+        assert_eq!(status.messages, 3);
+        assert_eq!(status.recent, 1);
+        assert_eq!(status.unseen, 2);
+        assert_eq!(status.uidnext, 111);
+        assert!(folder.join(INDEX_FILE).exists());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_empty_folder() {
+        let folder = fixture::maildir("index-empty", &[]);
+        let status = status(&folder).unwrap();
+        assert_eq!(status.messages, 0);
+        assert_eq!(status.uidnext, 1);
+        // The UIDVALIDITY given is kept for when the folder is selected.
+        assert_eq!(status.uid_validity, UidList::load(&folder).unwrap().uid_validity);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_delivery_and_invalidation() {
+        let folder = fixture::maildir("index-delivery", &[(100, &[Seen])]);
+        rebuild(&folder).unwrap();
+
+        fixture::write_message(&folder, "new", 200, &[], fixture::MESSAGE);
+        let delivered = record_delivery(&folder, 200).unwrap();
+        assert_eq!(delivered.messages, 2);
+        assert_eq!(delivered.recent, 1);
+        assert_eq!(delivered.unseen, 1);
+        assert_eq!(delivered.uidnext, 201);
+        assert_eq!(status(&folder).unwrap(), delivered);
+
+        // Changes made behind the index's back cause a rebuild.
+        fs::remove_file(folder.join("cur").join("100:2,S")).unwrap();
+        let current = status(&folder).unwrap();
+        assert_eq!(current.messages, 1);
+        assert_eq!(current.unseen, 1);
+
+        // So does a message which reached new/ without being recorded.
+        fixture::write_message(&folder, "new", 300, &[], fixture::MESSAGE);
+        fixture::write_message(&folder, "new", 301, &[], fixture::MESSAGE);
+        let delivered = record_delivery(&folder, 301).unwrap();
+        assert_eq!(delivered.messages, 3);
+        assert_eq!(delivered.recent, 3);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_deliveries() {
+        let folder = fixture::maildir("index-concurrent", &[]);
+        rebuild(&folder).unwrap();
+
+        let threads: Vec<_> = (1..9).map(|uid| {
+            let folder = folder.clone();
+            thread::spawn(move || {
+                fixture::write_message(&folder, "new", uid, &[], fixture::MESSAGE);
+                record_delivery(&folder, uid).unwrap();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every delivery is counted, and no temporary file is left behind
+        // beside the index and the UID list.
+        let status = status(&folder).unwrap();
+        assert_eq!(status.messages, 8);
+        assert_eq!(status.uidnext, 9);
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 5);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
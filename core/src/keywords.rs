@@ -0,0 +1,264 @@
+//! Reading and writing of Dovecot's `dovecot-keywords` file, which maps the
+//! lowercase letters `a` to `z` used in maildir filenames to the names of
+//! custom keyword flags.
+//!
+//! Each line of the file holds the index of a letter, counting from 0 for
+//! `a`, followed by a space and the keyword's name.
+//!
+//! Letters are only given out while the UID list's lock is held, as Dovecot
+//! does, and after the mapping has been read again under it, so that two
+//! sessions never give one letter to different keywords.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use dotlock::{self, DotLock};
+use uidlist;
+
+/// The name of the keyword mapping within each folder.
+pub const KEYWORDS_FILE: &'static str = "dovecot-keywords";
+
+// There is one letter for each keyword.
+const MAX_KEYWORDS: usize = 26;
+
+/// The keywords which have been given letters in a folder.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Keywords {
+    // The keyword for each letter, indexed from 'a'
+    names: Vec<Option<String>>,
+    // Whether a keyword has been added since the mapping was loaded
+    changed: bool
+}
+
+impl Keywords {
+    /// Create an empty mapping.
+    pub fn new() -> Keywords {
+        Keywords::default()
+    }
+
+    /// Read the folder's keyword mapping. A missing mapping is treated as
+    /// empty, as is one which can't be parsed.
+    pub fn load(folder: &Path) -> io::Result<Keywords> {
+        let mut contents = String::new();
+        match File::open(folder.join(KEYWORDS_FILE)) {
+            Ok(mut file) => { file.read_to_string(&mut contents)?; }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Keywords::new()),
+            Err(e) => return Err(e)
+        }
+        match Keywords::parse(&contents[..]) {
+            Some(keywords) => Ok(keywords),
+            None => {
+                warn!("Ignoring malformed {} in {}", KEYWORDS_FILE, folder.display());
+                Ok(Keywords::new())
+            }
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Keywords> {
+        let mut keywords = Keywords::new();
+        for line in contents.lines() {
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let index: usize = parts.next()?.parse().ok()?;
+            let name = parts.next()?;
+            if index >= MAX_KEYWORDS || name.is_empty() {
+                return None;
+            }
+            if keywords.names.len() <= index {
+                keywords.names.resize(index + 1, None);
+            }
+            keywords.names[index] = Some(name.to_string());
+        }
+        Some(keywords)
+    }
+
+    /// Get the keyword represented by a letter in a filename.
+    pub fn keyword(&self, letter: char) -> Option<&str> {
+        if !letter.is_ascii_lowercase() {
+            return None;
+        }
+        let index = letter as usize - 'a' as usize;
+        match self.names.get(index) {
+            Some(&Some(ref name)) => Some(&name[..]),
+            _ => None
+        }
+    }
+
+    /// Get the letter which represents a keyword in filenames, giving the
+    /// keyword the first free letter if it doesn't have one yet. Returns None
+    /// if every letter is taken.
+    pub fn letter(&mut self, keyword: &str) -> Option<char> {
+        let index = match self.position(keyword) {
+            Some(index) => index,
+            None => {
+                let index = match self.names.iter().position(|name| name.is_none()) {
+                    Some(index) => index,
+                    None if self.names.len() < MAX_KEYWORDS => {
+                        self.names.push(None);
+                        self.names.len() - 1
+                    }
+                    None => return None
+                };
+                self.names[index] = Some(keyword.to_string());
+                self.changed = true;
+                index
+            }
+        };
+        Some((b'a' + index as u8) as char)
+    }
+
+    fn position(&self, keyword: &str) -> Option<usize> {
+        // Keywords are case-insensitive.
+        self.names.iter().position(|name| match *name {
+            Some(ref name) => name.eq_ignore_ascii_case(keyword),
+            None => false
+        })
+    }
+
+    /// Whether a keyword has been added since the mapping was loaded.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Read the mapping again, taking in the letters other sessions have
+    /// given out since it was loaded. Any keyword given a letter here which
+    /// was never saved keeps it if it is still free, and is otherwise given
+    /// another one. This should be done with the lock held.
+    pub fn reload(&mut self, folder: &Path) -> io::Result<()> {
+        let unsaved: Vec<(usize, String)> = if self.changed {
+            self.names.iter().enumerate()
+                .filter_map(|(index, name)| name.clone().map(|name| (index, name)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut keywords = Keywords::load(folder)?;
+        for (index, name) in unsaved {
+            let free = keywords.names.get(index).map_or(true, |other| other.is_none());
+            if free && keywords.position(&name).is_none() {
+                if keywords.names.len() <= index {
+                    keywords.names.resize(index + 1, None);
+                }
+                keywords.names[index] = Some(name);
+                keywords.changed = true;
+            } else {
+                keywords.letter(&name);
+            }
+        }
+        *self = keywords;
+        Ok(())
+    }
+
+    /// Write the mapping out, replacing it in one step so readers never see
+    /// half of it. This should be done with the lock held.
+    pub fn save(&mut self, folder: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        for (index, name) in self.names.iter().enumerate() {
+            if let Some(ref name) = *name {
+                contents.push_str(&format!("{} {}\n", index, name)[..]);
+            }
+        }
+
+        dotlock::replace_file(&folder.join(KEYWORDS_FILE), contents.as_bytes())?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Take the lock which is held while letters are given out, which is the
+/// UID list's.
+pub fn lock(folder: &Path) -> io::Result<DotLock> {
+    uidlist::lock(folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use fixture;
+    use super::{lock, Keywords};
+
+    #[test]
+    fn test_parse() {
+        let keywords = Keywords::parse("0 $Forwarded\n2 NonJunk\n").unwrap();
+        assert_eq!(keywords.keyword('a'), Some("$Forwarded"));
+        assert_eq!(keywords.keyword('b'), None);
+        assert_eq!(keywords.keyword('c'), Some("NonJunk"));
+        assert_eq!(keywords.keyword('S'), None);
+
+        assert_eq!(Keywords::parse("x $Junk\n"), None);
+        assert_eq!(Keywords::parse("26 $Junk\n"), None);
+    }
+
+    #[test]
+    fn test_letter() {
+        let mut keywords = Keywords::parse("0 $Forwarded\n2 NonJunk\n").unwrap();
+        assert_eq!(keywords.letter("$forwarded"), Some('a'));
+        assert!(!keywords.is_changed());
+        // New keywords fill the gaps first.
+        assert_eq!(keywords.letter("$Junk"), Some('b'));
+        assert_eq!(keywords.letter("work"), Some('d'));
+        assert!(keywords.is_changed());
+
+        for i in 4..26 {
+            assert!(keywords.letter(&format!("k{}", i)[..]).is_some());
+        }
+        assert_eq!(keywords.letter("one-too-many"), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let folder = fixture::dir("keywords");
+        let mut keywords = Keywords::load(&folder).unwrap();
+        assert_eq!(keywords, Keywords::new());
+
+        keywords.letter("$Forwarded");
+        keywords.letter("NonJunk");
+        keywords.save(&folder).unwrap();
+        assert!(!keywords.is_changed());
+        assert_eq!(fs::read_to_string(folder.join("dovecot-keywords")).unwrap(),
+                   "0 $Forwarded\n1 NonJunk\n");
+        assert_eq!(Keywords::load(&folder).unwrap(), keywords);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_letters() {
+        let folder = fixture::dir("keywords-merge");
+        let mut first = Keywords::load(&folder).unwrap();
+        let mut second = Keywords::load(&folder).unwrap();
+
+        // Each session reads the mapping again before giving out a letter,
+        // so the second doesn't reuse the one the first gave out.
+        {
+            let _lock = lock(&folder).unwrap();
+            first.reload(&folder).unwrap();
+            assert_eq!(first.letter("Work"), Some('a'));
+            first.save(&folder).unwrap();
+        }
+        {
+            let _lock = lock(&folder).unwrap();
+            second.reload(&folder).unwrap();
+            assert_eq!(second.letter("Home"), Some('b'));
+            assert_eq!(second.letter("work"), Some('a'));
+            second.save(&folder).unwrap();
+        }
+        assert_eq!(fs::read_to_string(folder.join("dovecot-keywords")).unwrap(),
+                   "0 Work\n1 Home\n");
+
+        // A letter given out but never saved moves if another session has
+        // taken it since.
+        first.letter("Later");
+        assert_eq!(first.keyword('b'), Some("Later"));
+        first.reload(&folder).unwrap();
+        assert_eq!(first.keyword('b'), Some("Home"));
+        assert_eq!(first.letter("Later"), Some('c'));
+        assert!(first.is_changed());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+// Helpers for operations on a user's maildir as a whole, rather than on a
+// single folder within it.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use time;
+use walkdir::WalkDir;
+
+use dotlock;
+use index;
+
+// Distinguishes temporary files written by different threads of this process.
+static DELIVERY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How the folders of a maildir are laid out on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    /// Each folder is a directory nested inside its parent folder, so
+    /// `Work/Projects` is stored in `<maildir>/Work/Projects`.
+    #[serde(rename = "fs")]
+    Fs,
+    /// The Maildir++ layout used by Courier and Dovecot. Every folder is a
+    /// directory in the root of the maildir named after the full folder name
+    /// with a leading dot, so `Work/Projects` is stored in
+    /// `<maildir>/.Work.Projects`.
+    #[serde(rename = "maildir++")]
+    MaildirPlusPlus
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Fs
+    }
+}
+
+/// What a mailbox is used for, which clients are told about in LIST
+/// responses so that they can find the user's special folders (RFC 6154).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpecialUse {
+    #[serde(rename = "archive")]
+    Archive,
+    #[serde(rename = "drafts")]
+    Drafts,
+    #[serde(rename = "junk")]
+    Junk,
+    #[serde(rename = "sent")]
+    Sent,
+    #[serde(rename = "trash")]
+    Trash
+}
+
+impl SpecialUse {
+    /// The mailbox attribute which marks a mailbox with this use in LIST
+    pub fn attribute(&self) -> &'static str {
+        match *self {
+            SpecialUse::Archive => "\\Archive",
+            SpecialUse::Drafts => "\\Drafts",
+            SpecialUse::Junk => "\\Junk",
+            SpecialUse::Sent => "\\Sent",
+            SpecialUse::Trash => "\\Trash"
+        }
+    }
+}
+
+/// Translate an IMAP mailbox name into the directory holding that mailbox.
+/// Returns None if the name can't be represented in the given layout, or if
+/// it would refer to a directory outside of the maildir.
+pub fn mailbox_path(maildir: &Path, mailbox: &str, layout: Layout) -> Option<PathBuf> {
+    let mailbox = mailbox.trim_matches('/');
+    match layout {
+        Layout::Fs => {
+            let relative = PathBuf::from(mailbox.replace("INBOX", "."));
+            if relative.components().any(|c| match c {
+                Component::Normal(_) | Component::CurDir => false,
+                _ => true
+            }) {
+                return None;
+            }
+            Some(maildir.join(relative))
+        }
+        Layout::MaildirPlusPlus => {
+            if mailbox.eq_ignore_ascii_case("INBOX") {
+                return Some(maildir.to_path_buf());
+            }
+            // Children of INBOX live alongside the other folders.
+            let mailbox = if mailbox.len() > 6 &&
+                mailbox[..6].eq_ignore_ascii_case("INBOX/") {
+                &mailbox[6..]
+            } else {
+                mailbox
+            };
+            // A dot would be read back as a hierarchy separator, and empty
+            // levels can't be represented.
+            if mailbox.is_empty() || mailbox.contains('.') ||
+                mailbox.split('/').any(str::is_empty) {
+                return None;
+            }
+            Some(maildir.join(format!(".{}", mailbox.replace('/', "."))))
+        }
+    }
+}
+
+/// The filename of the message at `path`, or `None` if the file isn't one
+/// to be treated as a message. Hidden files aren't messages, and a file
+/// whose name isn't UTF-8 can't be given a UID.
+pub fn message_filename(path: &Path) -> Option<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.starts_with('.'))
+}
+
+/// Create the cur/, new/ and tmp/ directories which make the given
+/// directory a mail folder. Directories which already exist are left alone.
+pub fn create_folder(folder: &Path) -> io::Result<()> {
+    for dir in &["cur", "new", "tmp"] {
+        let path = folder.join(dir);
+        if path.is_dir() {
+            continue;
+        }
+        fs::create_dir_all(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Deliver a message to the folder's new/ directory without it ever being
+/// visible in a partially written state.
+///
+/// The message is written to tmp/ and synced to disk before being linked
+/// into new/ under its UID. The UID is the current time, or the folder's
+/// next UID if that is later, and is bumped until it doesn't clash with an
+/// existing message. Returns the path of the delivered message.
+pub fn deliver_atomic(folder: &Path, data: &[u8]) -> io::Result<PathBuf> {
+    deliver_flagged(folder, data, "", None)
+}
+
+/// Deliver a message as `deliver_atomic` does, with the maildir flag letters
+/// in `flags` added to its filename. If `internal_date` is given it becomes
+/// the modification time of the message, which is where its INTERNALDATE is
+/// read from.
+pub fn deliver_flagged(folder: &Path, data: &[u8], flags: &str,
+                       internal_date: Option<SystemTime>) -> io::Result<PathBuf> {
+    let now = time::get_time().sec as usize;
+    let tmp_dir = folder.join("tmp");
+    fs::create_dir_all(&tmp_dir)?;
+
+    // Write out the message under a name which is unique to this delivery.
+    let tmp_path = tmp_dir.join(format!("{}.{}_{}", now, process::id(),
+                                        DELIVERY_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp_path)?;
+        file.write_all(data)?;
+        if let Some(internal_date) = internal_date {
+            file.set_modified(internal_date)?;
+        }
+        file.sync_all()?;
+    }
+
+    let uidnext = index::status(folder).map(|status| status.uidnext).unwrap_or(0);
+    let mut uid = cmp::max(now, uidnext);
+    // Unlike rename, linking fails rather than replacing an existing message.
+    let res = loop {
+        let filename = if flags.is_empty() {
+            uid.to_string()
+        } else {
+            format!("{}:2,{}", uid, flags)
+        };
+        let new_path = folder.join("new").join(filename);
+        match fs::hard_link(&tmp_path, &new_path) {
+            Ok(()) => break Ok(new_path),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => uid += 1,
+            Err(e) => break Err(e)
+        }
+    };
+    // Once the link is made the message has been delivered, and reporting
+    // a failure would only have it delivered again.
+    if let Err(e) = fs::remove_file(&tmp_path) {
+        warn!("Failed to remove {}: {}", tmp_path.display(), e);
+    }
+    res
+}
+
+/// Translate the name of a Maildir++ folder directory, such as
+/// `.Work.Projects`, into its IMAP mailbox name. Returns None if the
+/// directory is not a Maildir++ folder.
+pub fn maildirplusplus_mailbox_name(dir_name: &str) -> Option<String> {
+    if dir_name.len() < 2 || !dir_name.starts_with('.') || dir_name == ".." {
+        return None;
+    }
+    let name = &dir_name[1..];
+    if name.split('.').any(str::is_empty) {
+        return None;
+    }
+    Some(name.replace('.', "/"))
+}
+
+/// Name of the Maildir++ quota accounting file, which is kept in the root of
+/// the maildir.
+static MAILDIRSIZE: &'static str = "maildirsize";
+
+/// Per the Maildir++ specification, `maildirsize` is recalculated from
+/// scratch once it grows beyond this many bytes.
+const MAILDIRSIZE_MAX_LEN: u64 = 5120;
+
+/// Record that `bytes` bytes and `count` messages have been added to (or, if
+/// negative, removed from) the maildir.
+///
+/// If `maildirsize` does not exist yet, nothing is recorded; it is rebuilt
+/// from the contents of the maildir the next time the usage is requested.
+pub fn update_size(maildir: &Path, bytes: i64, count: i64) -> io::Result<()> {
+    let path = maildir.join(MAILDIRSIZE);
+    let mut file = match OpenOptions::new().append(true).open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    // Each update is a single write so that concurrent appends from
+    // different sessions don't interleave.
+    file.write_all(format!("{} {}\n", bytes, count).as_bytes())
+}
+
+/// Returns the total size in bytes and the total number of messages in the
+/// maildir.
+///
+/// This is read from `maildirsize`, which is rebuilt by scanning the maildir
+/// if it is missing, unreadable or has grown too large.
+pub fn quota_usage(maildir: &Path) -> io::Result<(u64, u64)> {
+    let path = maildir.join(MAILDIRSIZE);
+    match fs::metadata(&path) {
+        Ok(ref md) if md.len() <= MAILDIRSIZE_MAX_LEN => {
+            if let Some(usage) = read_maildirsize(&path)? {
+                return Ok(usage);
+            }
+        }
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e)
+    }
+    rebuild_maildirsize(maildir)
+}
+
+/// Returns whether adding a message of `size` bytes would take the maildir
+/// over the quota given in its `maildirsize` file. A maildir without a quota
+/// definition is never over quota.
+pub fn over_quota(maildir: &Path, size: u64) -> io::Result<bool> {
+    let definition = match read_definition(&maildir.join(MAILDIRSIZE)) {
+        Some(definition) => definition,
+        None => return Ok(false)
+    };
+
+    // The quota definition is a comma separated list such as "1000S,20C",
+    // where S limits the total size and C the number of messages.
+    let mut max_bytes = None;
+    let mut max_count = None;
+    for limit in definition.split(',') {
+        let limit = limit.trim();
+        if limit.len() < 2 {
+            continue;
+        }
+        let (value, kind) = limit.split_at(limit.len() - 1);
+        match (kind, value.parse::<u64>()) {
+            ("S", Ok(v)) if v > 0 => max_bytes = Some(v),
+            ("C", Ok(v)) if v > 0 => max_count = Some(v),
+            _ => {}
+        }
+    }
+    if max_bytes.is_none() && max_count.is_none() {
+        return Ok(false);
+    }
+
+    let (bytes, count) = quota_usage(maildir)?;
+    Ok(max_bytes.map_or(false, |max| bytes + size > max) ||
+       max_count.map_or(false, |max| count + 1 > max))
+}
+
+/// Returns the quota definition, which is the first line of `maildirsize`.
+fn read_definition(path: &Path) -> Option<String> {
+    match File::open(path) {
+        Ok(file) => {
+            match BufReader::new(file).lines().next() {
+                Some(Ok(line)) => Some(line),
+                _ => None
+            }
+        }
+        Err(_) => None
+    }
+}
+
+/// Sum up the lines of a `maildirsize` file. Returns None if the file is
+/// malformed and should be rebuilt.
+fn read_maildirsize(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    // The first line holds the quota definition rather than a size.
+    if lines.next().is_none() {
+        return Ok(None);
+    }
+
+    let mut bytes = 0i64;
+    let mut count = 0i64;
+    for line in lines {
+        let line = line?;
+        let mut parts = line.split_whitespace();
+        match (parts.next().map(str::parse::<i64>),
+               parts.next().map(str::parse::<i64>)) {
+            (Some(Ok(b)), Some(Ok(c))) => {
+                bytes += b;
+                count += c;
+            }
+            _ => return Ok(None)
+        }
+    }
+
+    if bytes < 0 || count < 0 {
+        return Ok(None);
+    }
+    Ok(Some((bytes as u64, count as u64)))
+}
+
+/// Recalculate the size of the maildir by adding up the sizes of every
+/// message in every folder, then write the result out to `maildirsize`.
+fn rebuild_maildirsize(maildir: &Path) -> io::Result<(u64, u64)> {
+    let mut bytes = 0u64;
+    let mut count = 0u64;
+    for entry in WalkDir::new(maildir) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue
+        };
+        // Messages are the files directly inside a cur/ or new/ directory.
+        let in_mail_dir = match entry.path().parent() {
+            Some(parent) => {
+                let name = path_filename_to_str!(parent);
+                name == "cur" || name == "new"
+            }
+            None => false
+        };
+        if !in_mail_dir || !entry.file_type().is_file() {
+            continue;
+        }
+        if let Ok(md) = entry.metadata() {
+            bytes += md.len();
+            count += 1;
+        }
+    }
+
+    // Keep the existing quota definition, if there is one.
+    let path = maildir.join(MAILDIRSIZE);
+    let definition = read_definition(&path).unwrap_or_else(String::new);
+
+    // Replace the file in one step so that readers never see a partially
+    // written maildirsize.
+    dotlock::replace_file(&path, format!("{}\n{} {}\n", definition, bytes, count).as_bytes())?;
+
+    Ok((bytes, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use fixture;
+    use folder::Folder;
+    use mailstore::MailFolder;
+    use super::{create_folder, deliver_atomic, mailbox_path, maildirplusplus_mailbox_name,
+                over_quota, quota_usage, update_size, Layout, MAILDIRSIZE};
+
+    fn temp_maildir(name: &str) -> PathBuf {
+        let path = fixture::maildir(&format!("maildir-{}", name), &[]);
+        create_folder(&path.join("Sub")).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_deliver_atomic() {
+        let folder = fixture::maildir("deliver", &[]);
+
+        let first = deliver_atomic(&folder, b"Subject: one\n\nOne\n").unwrap();
+        let second = deliver_atomic(&folder, b"Subject: two\n\nTwo\n").unwrap();
+        assert_eq!(first.parent(), Some(folder.join("new").as_path()));
+        assert!(first != second);
+        assert_eq!(fs::read(&second).unwrap(), b"Subject: two\n\nTwo\n".to_vec());
+        // Nothing is left behind in tmp/.
+        assert_eq!(fs::read_dir(folder.join("tmp")).unwrap().count(), 0);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_tmp_not_visible() {
+        let folder = fixture::maildir("tmp", &[]);
+        // A message which would be shown if it were in new/.
+        fs::write(folder.join("tmp").join("1000"), "Subject: partial\n\nHal").unwrap();
+
+        let scanned = Folder::new(&folder, folder.clone(), true, false, 100).unwrap();
+        assert_eq!(scanned.message_count(), 0);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_fs_mailbox_path() {
+        let maildir = PathBuf::from("/mail");
+        assert_eq!(mailbox_path(&maildir, "INBOX", Layout::Fs), Some(maildir.join(".")));
+        assert_eq!(mailbox_path(&maildir, "Sent", Layout::Fs), Some(maildir.join("Sent")));
+        assert_eq!(mailbox_path(&maildir, "Work/Projects", Layout::Fs),
+                   Some(maildir.join("Work").join("Projects")));
+        assert_eq!(mailbox_path(&maildir, "INBOX/Sub", Layout::Fs),
+                   Some(maildir.join(".").join("Sub")));
+        assert_eq!(mailbox_path(&maildir, "../other", Layout::Fs), None);
+    }
+
+    #[test]
+    fn test_maildirplusplus_mailbox_path() {
+        let maildir = PathBuf::from("/mail");
+        let layout = Layout::MaildirPlusPlus;
+        assert_eq!(mailbox_path(&maildir, "INBOX", layout), Some(maildir.clone()));
+        assert_eq!(mailbox_path(&maildir, "inbox", layout), Some(maildir.clone()));
+        assert_eq!(mailbox_path(&maildir, "Sent", layout), Some(maildir.join(".Sent")));
+        assert_eq!(mailbox_path(&maildir, "Work/Projects", layout),
+                   Some(maildir.join(".Work.Projects")));
+        assert_eq!(mailbox_path(&maildir, "INBOX/Sub", layout), Some(maildir.join(".Sub")));
+        assert_eq!(mailbox_path(&maildir, "a.b", layout), None);
+        assert_eq!(mailbox_path(&maildir, "a//b", layout), None);
+        assert_eq!(mailbox_path(&maildir, "..", layout), None);
+    }
+
+    #[test]
+    fn test_maildirplusplus_mailbox_name() {
+        assert_eq!(maildirplusplus_mailbox_name(".Sent"), Some("Sent".to_string()));
+        assert_eq!(maildirplusplus_mailbox_name(".Work.Projects"),
+                   Some("Work/Projects".to_string()));
+        assert_eq!(maildirplusplus_mailbox_name("cur"), None);
+        assert_eq!(maildirplusplus_mailbox_name("."), None);
+        assert_eq!(maildirplusplus_mailbox_name(".."), None);
+        assert_eq!(maildirplusplus_mailbox_name(".a..b"), None);
+    }
+
+    fn write_file(path: PathBuf, len: usize) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(&vec![b'a'; len][..]).unwrap();
+    }
+
+    #[test]
+    fn test_quota_usage_rebuilds() {
+        let maildir = temp_maildir("rebuild");
+        write_file(maildir.join("cur").join("1"), 10);
+        write_file(maildir.join("new").join("2"), 20);
+        write_file(maildir.join("Sub").join("cur").join("3"), 30);
+
+        assert_eq!(quota_usage(&maildir).unwrap(), (60, 3));
+        assert!(maildir.join(MAILDIRSIZE).exists());
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[test]
+    fn test_update_size() {
+        let maildir = temp_maildir("update");
+        write_file(maildir.join("cur").join("1"), 10);
+
+        // Without a maildirsize, updates are dropped until it is rebuilt.
+        update_size(&maildir, 100, 1).unwrap();
+        assert_eq!(quota_usage(&maildir).unwrap(), (10, 1));
+
+        // Once it exists, the usage is the running total of the updates.
+        update_size(&maildir, 100, 1).unwrap();
+        update_size(&maildir, -10, -1).unwrap();
+        assert_eq!(quota_usage(&maildir).unwrap(), (100, 1));
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_maildirsize() {
+        let maildir = temp_maildir("malformed");
+        write_file(maildir.join("cur").join("1"), 10);
+        File::create(maildir.join(MAILDIRSIZE)).unwrap()
+            .write_all(b"1000S\n5 1\nnonsense\n").unwrap();
+
+        assert_eq!(quota_usage(&maildir).unwrap(), (10, 1));
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[test]
+    fn test_over_quota() {
+        let maildir = temp_maildir("over");
+        write_file(maildir.join("cur").join("1"), 10);
+
+        // No maildirsize means no quota.
+        assert!(!over_quota(&maildir, 1000).unwrap());
+
+        File::create(maildir.join(MAILDIRSIZE)).unwrap()
+            .write_all(b"100S,2C\n10 1\n").unwrap();
+        assert!(!over_quota(&maildir, 90).unwrap());
+        assert!(over_quota(&maildir, 91).unwrap());
+
+        update_size(&maildir, 10, 1).unwrap();
+        assert!(over_quota(&maildir, 1).unwrap());
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+//! The interface between the IMAP session and wherever the user's mail is
+//! kept. Maildirs are the only store at the moment, but the session only
+//! needs what is described here.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mime::{imap_literal, Message as MIME_Message};
+use time::Timespec;
+
+use command::Attribute;
+use command::Attribute::{
+    Envelope,
+    Flags,
+    InternalDate,
+    ModSeq,
+    RFC822,
+    Body,
+    BodyPeek,
+    BodySection,
+    BodyStructure,
+    UID
+};
+use command::RFC822Attribute::{
+    AllRFC822,
+    HeaderRFC822,
+    SizeRFC822,
+    TextRFC822
+};
+use command::fetch::FetchOptions;
+use command::response::ResponseWriter;
+use command::search::SearchKey;
+use command::select::Qresync;
+use command::sequence_set::{self, SequenceItem};
+use command::store::StoreName;
+use error::{AppendError, FolderError, ImapResult};
+use folder::Folder;
+use index;
+use keywords::{self, Keywords};
+use maildir::{self, Layout};
+use message::{self, format_internal_date, print_flags, Flag};
+use uidlist;
+use util;
+
+/// A store of mailboxes belonging to one user.
+pub trait MailStore {
+    /// The name of every mailbox in the store, sorted, including INBOX.
+    fn mailboxes(&self) -> Vec<String>;
+
+    /// The LIST attribute which says whether the named mailbox can be
+    /// selected and, if it can, whether it holds new mail: `\Noselect`,
+    /// `\Marked` or `\Unmarked`. None if the name can't be a mailbox.
+    fn mailbox_flags(&self, mailbox: &str) -> Option<String>;
+
+    /// Open the named mailbox for SELECT or EXAMINE, read-only if `examine`
+    /// is set.
+    fn open(&self, mailbox: &str, examine: bool) -> Result<Box<dyn MailFolder>, FolderError>;
+
+    /// Add a message to the named mailbox with the given flags. `date` is
+    /// the INTERNALDATE to give it rather than the current time.
+    fn append(&self, mailbox: &str, message: &[u8], flags: &[Flag],
+              date: Option<Timespec>) -> Result<(), AppendError>;
+}
+
+/// A message of a mailbox, as SEARCH and FETCH see it.
+pub trait MailMessage {
+    fn get_uid(&self) -> usize;
+
+    fn flags(&self) -> &HashSet<Flag>;
+
+    /// The time the message was delivered, which is its INTERNALDATE.
+    fn internal_date(&self) -> Timespec;
+
+    /// The size of the message as it is stored.
+    fn size(&self) -> u64;
+
+    /// Parse the contents of the message, unless `cache` already holds them
+    /// from an earlier call. If `crlf` is set, bare LF line endings are read
+    /// as CRLF.
+    fn load<'a>(&self, cache: &'a mut Option<MIME_Message>,
+                crlf: bool) -> ImapResult<&'a MIME_Message>;
+
+    /// Whether the message has the flag set. Keywords are compared
+    /// case-insensitively.
+    fn has_flag(&self, flag: &Flag) -> bool {
+        match *flag {
+            Flag::Keyword(ref keyword) => self.flags().iter().any(|set| match *set {
+                Flag::Keyword(ref set) => set.eq_ignore_ascii_case(keyword),
+                _ => false
+            }),
+            _ => self.flags().contains(flag)
+        }
+    }
+
+    /// Goes through the list of attributes, constructing a FETCH response for
+    /// this message containing the values of the requested attributes
+    /// If `decode_headers` is set, encoded-words in the ENVELOPE are decoded,
+    /// and if `crlf` is set the contents are sent with CRLF line endings.
+    /// The contents are only read if an attribute needs them.
+    fn fetch(&self, attributes: &[Attribute], decode_headers: bool,
+             crlf: bool) -> ImapResult<String> {
+        let mut mime_message = None;
+        let mut res = String::new();
+        let mut first = true;
+        for attr in attributes.iter() {
+            // Mod-sequences are kept by the folder, which adds them.
+            if *attr == ModSeq {
+                continue;
+            }
+            // We need to space separate the attribute values
+            if first {
+                first = false;
+            } else {
+                res.push(' ');
+            }
+
+            // Provide the attribute name followed by the attribute value
+            match *attr {
+                Envelope => {
+                    res.push_str("ENVELOPE ");
+                    res.push_str(&self.load(&mut mime_message, crlf)?.get_envelope(decode_headers)[..]);
+                },
+                Flags => {
+                    res.push_str("FLAGS ");
+                    res.push_str(&print_flags(self.flags())[..]);
+                },
+                InternalDate => {
+                    res.push_str("INTERNALDATE \"");
+                    res.push_str(&format_internal_date(self.internal_date())[..]);
+                    res.push('"');
+                }
+                RFC822(ref attr) => {
+                    res.push_str("RFC822");
+                    match *attr {
+                        AllRFC822 => {
+                            res.push(' ');
+                            res.push_str(&imap_literal(self.load(&mut mime_message, crlf)?.get_raw_contents())[..]);
+                        },
+                        HeaderRFC822 => {
+                            res.push_str(".HEADER ");
+                            res.push_str(&imap_literal(self.load(&mut mime_message, crlf)?.get_header_block())[..]);
+                        },
+                        TextRFC822 => {
+                            res.push_str(".TEXT ");
+                            res.push_str(&imap_literal(self.load(&mut mime_message, crlf)?.get_text())[..]);
+                        },
+                        SizeRFC822 => {
+                            res.push_str(".SIZE ");
+                            // Once the contents have been read, report their
+                            // size so it agrees with any literal sent for them
+                            // even if the message has changed since. The size
+                            // stored won't do if line endings are to be added.
+                            let size = match mime_message {
+                                Some(ref mime_message) => mime_message.get_size(),
+                                None if crlf => self.load(&mut mime_message, crlf)?.get_size(),
+                                None => self.size().to_string()
+                            };
+                            res.push_str(&size[..]) },
+                    };
+                },
+                Body => {
+                    res.push_str("BODY ");
+                    res.push_str(&self.load(&mut mime_message, crlf)?.get_bodystructure(false)[..]);
+                },
+                BodyStructure => {
+                    res.push_str("BODYSTRUCTURE ");
+                    res.push_str(&self.load(&mut mime_message, crlf)?.get_bodystructure(true)[..]);
+                },
+                BodySection(ref section, ref octets) |
+                    BodyPeek(ref section, ref octets) => {
+                        res.push_str(&self.load(&mut mime_message, crlf)?.get_body(section, octets)[..]) },
+                UID => {
+                    res.push_str("UID ");
+                    res.push_str(&self.get_uid().to_string()[..])
+                }
+                ModSeq => {}
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// The messages of an opened mailbox, addressed by their 0-indexed position
+/// in it.
+pub trait MailFolder {
+    /// The untagged responses to SELECT or EXAMINE, followed by the tagged
+    /// OK. The highest mod-sequence is included if `condstore` is set, and
+    /// what has changed since the client last saw the folder if it sent
+    /// `qresync`.
+    fn select_response(&self, tag: &str, condstore: bool, qresync: Option<&Qresync>) -> String;
+
+    fn message_count(&self) -> usize;
+
+    /// The UIDs of every message from the given index onwards
+    fn uids_from_index(&self, start: usize) -> Vec<usize>;
+
+    /// The mod-sequence of the message, if mod-sequences are kept.
+    fn modseq(&self, index: usize) -> Option<u64>;
+
+    /// The highest mod-sequence in the folder, or None if mod-sequences
+    /// aren't kept for it.
+    fn highest_modseq(&self) -> Option<u64>;
+
+    /// Those of the given messages, by UID if `seq_uid` is set and by
+    /// sequence number otherwise, which any session has changed since the
+    /// given mod-sequence, as a conditional STORE must leave them alone.
+    fn modified_since(&mut self, sequence_set: &[usize], seq_uid: bool,
+                      modseq: u64) -> Vec<usize>;
+
+    /// Set the \Seen flag on the message as a side effect of reading it,
+    /// returning whether its flags changed.
+    fn mark_seen(&mut self, index: usize) -> bool;
+
+    /// The index of the message with the given UID, if it is in the folder
+    fn get_index_from_uid(&self, uid: &usize) -> Option<usize>;
+
+    /// The messages which match every one of the search keys, by UID if
+    /// `uid` is set and by sequence number otherwise.
+    fn search(&self, keys: &[SearchKey], uid: bool) -> Vec<usize>;
+
+    /// The untagged FETCH response for the given attributes of a message,
+    /// given as `options` says.
+    fn fetch(&self, index: usize, attributes: &[Attribute],
+             options: FetchOptions) -> ImapResult<String>;
+
+    /// Change the flags of the messages at the given sequence numbers, or
+    /// UIDs if `seq_uid` is set, returning the untagged FETCH responses for
+    /// them. Their new mod-sequences are included if `condstore` is set.
+    fn store(&mut self, sequence_set: Vec<usize>, flag_name: &StoreName, silent: bool,
+             flags: HashSet<Flag>, seq_uid: bool, condstore: bool) -> String;
+
+    /// Remove the messages marked \Deleted, returning the sequence numbers
+    /// which the client should be told have gone.
+    fn expunge(&mut self) -> Vec<usize>;
+
+    /// Remove only those of the messages marked \Deleted which have one of
+    /// the given UIDs, as UID EXPUNGE does (RFC 4315).
+    fn uid_expunge(&mut self, uids: &[usize]) -> Vec<usize>;
+
+    /// Pick up any messages which have arrived since the folder was last
+    /// looked at, returning the untagged EXISTS and RECENT responses for
+    /// them, or an empty string if there are none.
+    fn poll_new(&mut self) -> io::Result<String>;
+
+    /// Bring the folder up to date with any changes made by other sessions
+    /// as well as new mail, returning the untagged responses for them. The
+    /// expunged messages are given in a `* VANISHED` response if `vanished`
+    /// is set, and the changed flags with their MODSEQ if `condstore` is.
+    fn poll_changes(&mut self, condstore: bool, vanished: bool) -> io::Result<String>;
+
+    /// Save any changes to the flags which haven't been saved yet.
+    fn check(&mut self);
+
+    /// The `* VANISHED (EARLIER)` response listing the messages expunged
+    /// since the given mod-sequence, limited to the UIDs in `uids` if it is
+    /// given. Returns an empty string if there are none.
+    fn vanished_response(&self, modseq: u64, uids: Option<&Vec<SequenceItem>>) -> String;
+
+    /// Expunge the messages marked for deletion, returning the untagged
+    /// responses which tell the client they have gone: `* n EXPUNGE` for
+    /// each of them, or one `* VANISHED` listing their UIDs if `vanished` is
+    /// set as the client has enabled QRESYNC.
+    fn expunge_response(&mut self, vanished: bool) -> String {
+        expunge_response(self, vanished, |folder| folder.expunge())
+    }
+
+    /// Expunge those of the messages marked for deletion which have one of
+    /// the given UIDs, returning the responses as `expunge_response` does.
+    fn uid_expunge_response(&mut self, uids: &[usize], vanished: bool) -> String {
+        expunge_response(self, vanished, |folder| folder.uid_expunge(uids))
+    }
+}
+
+fn expunge_response<F, E>(folder: &mut F, vanished: bool, expunge: E) -> String
+    where F: MailFolder + ?Sized, E: FnOnce(&mut F) -> Vec<usize> {
+    let mut res = ResponseWriter::new();
+    if vanished {
+        let before = folder.uids_from_index(0);
+        expunge(folder);
+        let after: HashSet<usize> = folder.uids_from_index(0).into_iter().collect();
+        let uids: Vec<usize> = before.into_iter().filter(|uid| !after.contains(uid)).collect();
+        if !uids.is_empty() {
+            res = res.untagged(&format!("VANISHED {}",
+                                        sequence_set::compress_to_sequence_set(&uids)));
+        }
+    } else {
+        for seqnum in expunge(folder) {
+            res = res.untagged(&format!("{} EXPUNGE", seqnum));
+        }
+    }
+    res.into_string()
+}
+
+/// The mailboxes kept in a maildir, laid out as `layout` describes.
+#[derive(Clone, Debug)]
+pub struct MaildirStore {
+    maildir: PathBuf,
+    layout: Layout,
+    // Whether messages are left in folder/new/ until their flags change
+    keep_new: bool,
+    // How many messages of an open folder may have unsaved changes
+    window: usize,
+}
+
+impl MaildirStore {
+    pub fn new(maildir: &Path, layout: Layout, keep_new: bool, window: usize) -> MaildirStore {
+        MaildirStore {
+            maildir: maildir.to_path_buf(),
+            layout: layout,
+            keep_new: keep_new,
+            window: window,
+        }
+    }
+
+    // Whether other mailboxes are named below `mailbox`
+    fn has_children(&self, mailbox: &str) -> bool {
+        let prefix = format!("{}/", mailbox);
+        self.mailboxes().iter().any(|name| name.starts_with(&prefix[..]))
+    }
+}
+
+impl MailStore for MaildirStore {
+    fn mailboxes(&self) -> Vec<String> {
+        match self.layout {
+            Layout::Fs => util::mailbox_names(&self.maildir),
+            Layout::MaildirPlusPlus => util::mailbox_names_maildirplusplus(&self.maildir)
+        }
+    }
+
+    fn mailbox_flags(&self, mailbox: &str) -> Option<String> {
+        maildir::mailbox_path(&self.maildir, mailbox, self.layout)
+            .map(|path| util::mail_flags(&path))
+    }
+
+    fn open(&self, mailbox: &str, examine: bool) -> Result<Box<dyn MailFolder>, FolderError> {
+        match maildir::mailbox_path(&self.maildir, mailbox, self.layout) {
+            None => Err(FolderError::NotFound),
+            // The mailbox only holds other folders.
+            Some(ref path) if !path.join("cur").is_dir()
+                && (path.is_dir() || self.has_children(mailbox)) => Err(FolderError::NoSelect),
+            Some(path) => {
+                let folder = Folder::new(&self.maildir, path, examine, self.keep_new, self.window)?;
+                Ok(Box::new(folder))
+            }
+        }
+    }
+
+    fn append(&self, mailbox: &str, message: &[u8], flags: &[Flag],
+              date: Option<Timespec>) -> Result<(), AppendError> {
+        let folder = match maildir::mailbox_path(&self.maildir, mailbox, self.layout) {
+            Some(ref folder) if folder.join("cur").is_dir() => folder.clone(),
+            _ => return Err(AppendError::NotFound)
+        };
+        match maildir::over_quota(&self.maildir, message.len() as u64) {
+            Ok(true) => return Err(AppendError::OverQuota),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to check quota for {}: {}", self.maildir.display(), e)
+        }
+
+        let flags = flag_letters(&folder, flags)?;
+        let path = maildir::deliver_flagged(&folder, message, &flags[..], date.map(system_time))?;
+        if let Err(e) = maildir::update_size(&self.maildir, message.len() as i64, 1) {
+            warn!("Failed to update maildirsize for {}: {}", self.maildir.display(), e);
+        }
+        // A message delivered with flags may not be unseen, so the counts are
+        // worked out again rather than bumped.
+        let uid = path.file_name().and_then(|name| name.to_str())
+            .map(uidlist::base_name)
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(0);
+        let res = if flags.is_empty() {
+            index::record_delivery(&folder, uid)
+        } else {
+            index::rebuild(&folder)
+        };
+        if let Err(e) = res {
+            warn!("Failed to update folder index for {}: {}", folder.display(), e);
+        }
+        Ok(())
+    }
+}
+
+fn system_time(date: Timespec) -> SystemTime {
+    if date.sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(date.sec as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs((-date.sec) as u64)
+    }
+}
+
+// The maildir flag letters for the flags, in alphabetical order. Keywords
+// new to the folder are given letters, which are saved before any message
+// carries them, with the mapping locked and up to date as Folder does.
+fn flag_letters(folder: &Path, flags: &[Flag]) -> io::Result<String> {
+    let mut letters: Vec<char> = flags.iter().filter_map(message::flag_letter).collect();
+    let custom: Vec<&str> = flags.iter()
+        .filter_map(|flag| match *flag {
+            Flag::Keyword(ref keyword) if message::flag_letter(flag).is_none() => Some(&keyword[..]),
+            _ => None
+        })
+        .collect();
+    if !custom.is_empty() {
+        let _lock = keywords::lock(folder)?;
+        let mut keywords = Keywords::new();
+        keywords.reload(folder)?;
+        for keyword in custom {
+            match keywords.letter(keyword) {
+                Some(letter) => letters.push(letter),
+                None => return Err(io::Error::new(io::ErrorKind::Other,
+                                                  format!("no letter is free for keyword {}", keyword)))
+            }
+        }
+        if keywords.is_changed() {
+            keywords.save(folder)?;
+        }
+    }
+    letters.sort();
+    letters.dedup();
+    Ok(letters.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use command::Attribute::Flags;
+    use command::fetch::FetchOptions;
+    use command::store::StoreName;
+    use error::{AppendError, FolderError};
+    use fixture;
+    use maildir::{self, Layout};
+    use message::Flag;
+    use super::{MailStore, MaildirStore};
+
+    #[test]
+    fn test_maildir_store() {
+        let dir = fixture::maildir("mailstore", &[(10, &[Flag::Seen])]);
+        maildir::create_folder(&dir.join("Work")).unwrap();
+        maildir::create_folder(&dir.join("Archive").join("2017")).unwrap();
+
+        let store = MaildirStore::new(&dir, Layout::Fs, false, 100);
+        assert_eq!(store.mailboxes(), vec!["Archive", "Archive/2017", "INBOX", "Work"]);
+        match store.open("Archive", false) {
+            Err(FolderError::NoSelect) => {}
+            other => panic!("Archive should not be selectable: {:?}", other.map(|_| ()))
+        }
+        match store.open("Lost", false) {
+            Err(FolderError::NotFound) => {}
+            other => panic!("Lost should not exist: {:?}", other.map(|_| ()))
+        }
+
+        match store.append("Lost", b"Subject: hi\n\nHi\n", &[], None) {
+            Err(AppendError::NotFound) => {}
+            other => panic!("Lost should not exist: {:?}", other)
+        }
+        store.append("INBOX", b"Subject: hi\n\nHi\n", &[Flag::Flagged], None).unwrap();
+
+        // Keywords are given letters in the folder's mapping.
+        let flags = [Flag::Keyword("NonJunk".to_string()), Flag::Keyword("$Forwarded".to_string())];
+        store.append("INBOX", b"Subject: hi\n\nHi\n", &flags, None).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("dovecot-keywords")).unwrap(), "0 NonJunk\n");
+
+        let mut folder = store.open("INBOX", false).unwrap();
+        assert_eq!(folder.message_count(), 3);
+        assert_eq!(folder.fetch(1, &[Flags], FetchOptions::default()).unwrap(),
+                   "* 2 FETCH (FLAGS (\\Flagged))\r\n");
+        let res = folder.fetch(2, &[Flags], FetchOptions::default()).unwrap();
+        assert!(res.contains("NonJunk") && res.contains("$Forwarded"), "{}", res);
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Deleted);
+        folder.store(vec![1], &StoreName::Add, true, flags, false, false);
+        assert_eq!(folder.expunge(), vec![1]);
+        assert_eq!(folder.uids_from_index(0).len(), 2);
+
+        drop(folder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
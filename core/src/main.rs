@@ -7,6 +7,7 @@
 extern crate bufstream;
 extern crate crypto;
 extern crate env_logger;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate mime;
@@ -14,6 +15,7 @@ extern crate mime;
 extern crate nom;
 extern crate num;
 extern crate openssl;
+extern crate openssl_sys;
 extern crate rand;
 extern crate regex;
 extern crate serde;
@@ -24,21 +26,37 @@ extern crate time;
 extern crate toml;
 extern crate walkdir;
 
-use server::{lmtp_serve, imap_serve, Server};
+use server::{change_password, check_config, lmtp_serve, imap_serve, repair_mailboxes, Server};
 
+use std::env;
+use std::io::{self, BufRead, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::sync::Arc;
 use std::thread::spawn;
 
 mod command;
+mod dotlock;
 mod error;
-mod folder;
 mod parser;
 #[macro_use]
 mod util;
+#[cfg(test)]
+mod fixture;
+mod folder;
 #[macro_use]
 mod server;
+mod index;
+mod keywords;
+mod maildir;
+mod mailstore;
+#[cfg(test)]
+mod memstore;
 mod message;
+mod modseq;
+mod uidlist;
+mod window;
 
 fn listen_generic(v: TcpListener, serv: Arc<Server>, prot: &str, serve_func: (fn(Arc<Server>, TcpStream))) {
     for stream in v.incoming() {
@@ -54,6 +72,18 @@ fn listen_generic(v: TcpListener, serv: Arc<Server>, prot: &str, serve_func: (fn
     }
 }
 
+/// The listener, if the port is enabled and could be bound.
+fn bind(listener: Option<io::Result<TcpListener>>, prot: &str) -> Option<TcpListener> {
+    match listener {
+        Some(Err(e)) => {
+            error!("Error listening on {} port: {}", prot, e);
+            None
+        }
+        Some(Ok(v)) => Some(v),
+        None => None
+    }
+}
+
 fn listen_lmtp(v: TcpListener, serv: Arc<Server>) {
     listen_generic(v, serv, "LMTP", lmtp_serve);
 }
@@ -62,87 +92,308 @@ fn listen_imap(v: TcpListener, serv: Arc<Server>) {
     listen_generic(v, serv, "IMAP", imap_serve);
 }
 
+type Listen = fn(TcpListener, Arc<Server>);
+
+/// A bound port, named for logging, along with what serves it.
+type Listener = (&'static str, TcpListener, Listen);
+
+/// Bind every enabled port. A port which can't be bound is logged and left
+/// out, so that the server can still run on the others.
+fn bind_all(serv: &Server) -> Vec<Listener> {
+    let ports: Vec<(&'static str, Option<io::Result<TcpListener>>, Listen)> = vec![
+        ("LMTP", serv.lmtp_listener(), listen_lmtp),
+        ("LMTP SSL", serv.lmtp_ssl_listener(), listen_lmtp),
+        ("IMAP", serv.imap_listener(), listen_imap),
+        ("IMAP SSL", serv.imap_ssl_listener(), listen_imap),
+    ];
+    ports.into_iter()
+        .filter_map(|(prot, listener, listen)| bind(listener, prot).map(|v| (prot, v, listen)))
+        .collect()
+}
+
+static USAGE: &'static str = "Usage: segimap [--config <path>] [--check]
+       segimap [--config <path>] passwd <email>
+       segimap [--config <path>] repair <email> [<mailbox>]";
+
+/// What the command-line arguments ask for.
+#[derive(Debug, PartialEq)]
+struct Options {
+    /// The config file to use instead of searching for one
+    config_path: Option<PathBuf>,
+    mode: Mode
+}
+
+#[derive(Debug, PartialEq)]
+enum Mode {
+    /// Run the server
+    Serve,
+    /// Check the config and exit rather than serving
+    Check,
+    /// Set a new password for the user with the given email
+    Passwd(String),
+    /// Repair the UID lists of the user's mailboxes, or of just the one given
+    Repair(String, Option<String>)
+}
+
+fn parse_args<I: Iterator<Item=String>>(args: I) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let mut options = Options { config_path: None, mode: Mode::Serve };
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            match args.next() {
+                Some(path) => options.config_path = Some(PathBuf::from(path)),
+                None => return Err(format!("{} requires a path", arg))
+            }
+        } else if arg.starts_with("--config=") {
+            options.config_path = Some(PathBuf::from(&arg["--config=".len()..]));
+        } else if arg == "--check" && options.mode == Mode::Serve {
+            options.mode = Mode::Check;
+        } else if arg == "passwd" && options.mode == Mode::Serve {
+            match args.next() {
+                Some(email) => options.mode = Mode::Passwd(email),
+                None => return Err("passwd requires an email".to_string())
+            }
+        } else if arg == "repair" && options.mode == Mode::Serve {
+            let email = match args.next() {
+                Some(email) => email,
+                None => return Err("repair requires an email".to_string())
+            };
+            let mailbox = match args.peek() {
+                Some(mailbox) if !mailbox.starts_with('-') => Some(mailbox.clone()),
+                _ => None
+            };
+            if mailbox.is_some() {
+                args.next();
+            }
+            options.mode = Mode::Repair(email, mailbox);
+        } else {
+            return Err(format!("Unexpected argument: {}", arg));
+        }
+    }
+    Ok(options)
+}
+
+/// Prompt on the terminal for a line of input without echoing it.
+fn read_password(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    // stty fails harmlessly when the password is piped in.
+    let set_echo = |flag: &str| {
+        let _ = Command::new("stty").arg(flag).stdin(Stdio::inherit())
+            .stderr(Stdio::null()).status();
+    };
+    set_echo("-echo");
+    let mut password = String::new();
+    let res = io::stdin().lock().read_line(&mut password);
+    set_echo("echo");
+    eprintln!();
+    res?;
+    Ok(password.trim_end_matches(|c| c == '\r' || c == '\n').to_string())
+}
+
+fn passwd(config_path: Option<&Path>, email: &str) -> Result<(), String> {
+    let password = read_password("New password: ").map_err(|e| e.to_string())?;
+    if password.is_empty() {
+        return Err("The password can't be empty".to_string());
+    }
+    if read_password("Retype new password: ").map_err(|e| e.to_string())? != password {
+        return Err("The passwords don't match".to_string());
+    }
+    match change_password(config_path, email, password) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("There is no user {}", email)),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+fn repair(config_path: Option<&Path>, email: &str, mailbox: Option<&str>) -> Result<(), String> {
+    let repaired = match repair_mailboxes(config_path, email, mailbox) {
+        Ok(Some(repaired)) => repaired,
+        Ok(None) => return Err(format!("There is no user {}", email)),
+        Err(e) => return Err(e.to_string())
+    };
+    if let (true, Some(mailbox)) = (repaired.is_empty(), mailbox) {
+        return Err(format!("There is no mailbox {}", mailbox));
+    }
+    for (name, repair) in repaired {
+        println!("{}: {} messages, {} given UIDs, {} stale UIDs removed, {} renumbered",
+                 name, repair.messages, repair.assigned, repair.removed, repair.renumbered);
+    }
+    Ok(())
+}
+
 fn main() {
     let _ = env_logger::init().unwrap();
     info!("Application started");
 
-    // Create the server. We wrap it so that it is atomically reference
-    // counted. This allows us to safely share it across threads
-
-    let serv = match Server::new() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
         Err(e) => {
-            error!("Error starting server: {}", e);
-            return;
-        },
-        Ok(s) => Arc::new(s)
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
     };
+    let config_path = options.config_path.as_ref().map(|path| path.as_path());
 
-    // Spawn a separate thread for listening for LMTP connections
-    let lmtp_h = if let Some(lmtp_listener) = serv.lmtp_listener() {
-        match lmtp_listener {
-            Err(e) => {
-                error!("Error listening on LMTP port: {}", e);
-                None
+    // Report whether the server could start, without starting it.
+    if options.mode == Mode::Check {
+        match check_config(config_path) {
+            Ok(summary) => {
+                println!("Configuration is valid");
+                for line in summary {
+                    println!("  {}", line);
+                }
+                return;
             }
-            Ok(v) => {
-                let lmtp_serv = serv.clone();
-                Some(spawn(move || listen_lmtp(v, lmtp_serv)))
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
-    } else { None };
+    }
 
-    let lmtp_ssl_h = if let Some(lmtp_listener) = serv.lmtp_ssl_listener() {
-        match lmtp_listener {
-            Err(e) => {
-                error!("Error listening on LMTP SSL port: {}", e);
-                None
+    if let Mode::Passwd(ref email) = options.mode {
+        match passwd(config_path, email) {
+            Ok(()) => {
+                println!("Password changed for {}", email);
+                return;
             }
-            Ok(v) => {
-                let lmtp_serv = serv.clone();
-                Some(spawn(move || listen_lmtp(v, lmtp_serv)))
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
-    } else { None };
+    }
 
-    // The main thread handles listening for IMAP connections
-    let imap_h = if let Some(imap_listener) = serv.imap_listener() {
-        match imap_listener {
-            Err(e) => {
-                error!("Error listening on IMAP port: {}", e);
-                None
-            }
-            Ok(v) => {
-                let imap_serv = serv.clone();
-                Some(spawn(move || listen_imap(v, imap_serv)))
-            }
+    if let Mode::Repair(ref email, ref mailbox) = options.mode {
+        if let Err(e) = repair(config_path, email, mailbox.as_ref().map(|mailbox| &mailbox[..])) {
+            eprintln!("{}", e);
+            process::exit(1);
         }
-    } else { None };
+        return;
+    }
 
-    let imap_ssl_h = if let Some(imap_listener) = serv.imap_ssl_listener() {
-        match imap_listener {
-            Err(e) => {
-                error!("Error listening on IMAP port: {}", e);
-                None
-            }
-            Ok(v) => {
-                Some(spawn(move || listen_imap(v, serv)))
-            }
+    // Create the server.
+    let mut serv = match Server::new(config_path) {
+        Err(e) => {
+            error!("Error starting server: {}", e);
+            process::exit(1);
+        },
+        Ok(s) => s
+    };
+
+    // Every port is bound before privileges are dropped, as the usual ports
+    // can only be bound by root. There's no point carrying on without any.
+    let listeners = bind_all(&serv);
+    if listeners.is_empty() {
+        error!("None of the configured ports could be bound, exiting");
+        process::exit(1);
+    }
+    if let Err(e) = serv.drop_privileges() {
+        error!("Error dropping privileges: {}", e);
+        process::exit(1);
+    }
+
+    // We wrap the server so that it is atomically reference counted. This
+    // allows us to safely share it across threads
+    let serv = Arc::new(serv);
+
+    // Spawn a separate thread listening on each port
+    let handles: Vec<_> = listeners.into_iter().map(|(prot, v, listen)| {
+        let listen_serv = serv.clone();
+        (prot, spawn(move || listen(v, listen_serv)))
+    }).collect();
+
+    // The listeners only stop if something has gone badly wrong, in which
+    // case the others are left to carry on.
+    for (prot, handle) in handles {
+        if handle.join().is_err() {
+            error!("Stopped listening on {} port", prot);
         }
-    } else { None };
+    }
+}
 
-    if let Some(lh) = lmtp_h {
-        return_on_err!(lh.join());
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+
+    use fixture;
+    use server::Server;
+    use super::{bind_all, parse_args, Mode, Options};
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
     }
 
-    if let Some(lsh) = lmtp_ssl_h {
-        return_on_err!(lsh.join());
+    fn config(path: Option<&str>, mode: Mode) -> Result<Options, String> {
+        Ok(Options { config_path: path.map(PathBuf::from), mode: mode })
     }
 
-    if let Some(ih) = imap_h {
-        return_on_err!(ih.join());
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse(&[]), config(None, Mode::Serve));
+        assert_eq!(parse(&["--config", "/etc/imap.toml"]), config(Some("/etc/imap.toml"), Mode::Serve));
+        assert_eq!(parse(&["--config=imap.toml"]), config(Some("imap.toml"), Mode::Serve));
+        assert_eq!(parse(&["-c", "imap.toml"]), config(Some("imap.toml"), Mode::Serve));
+        assert_eq!(parse(&["--check", "-c", "imap.toml"]), config(Some("imap.toml"), Mode::Check));
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
     }
 
-    if let Some(ish) = imap_ssl_h {
-        return_on_err!(ish.join());
+    #[test]
+    fn test_parse_passwd() {
+        let passwd = Mode::Passwd("user@example.com".to_string());
+        assert_eq!(parse(&["passwd", "user@example.com"]), config(None, passwd));
+        let passwd = Mode::Passwd("user@example.com".to_string());
+        assert_eq!(parse(&["-c", "imap.toml", "passwd", "user@example.com"]),
+                   config(Some("imap.toml"), passwd));
+        assert!(parse(&["passwd"]).is_err());
+        assert!(parse(&["--check", "passwd", "user@example.com"]).is_err());
+        assert!(parse(&["passwd", "user@example.com", "other@example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_repair() {
+        let repair = Mode::Repair("user@example.com".to_string(), None);
+        assert_eq!(parse(&["repair", "user@example.com"]), config(None, repair));
+        let repair = Mode::Repair("user@example.com".to_string(), Some("Work/Projects".to_string()));
+        assert_eq!(parse(&["repair", "user@example.com", "Work/Projects", "-c", "imap.toml"]),
+                   config(Some("imap.toml"), repair));
+        let repair = Mode::Repair("user@example.com".to_string(), None);
+        assert_eq!(parse(&["repair", "user@example.com", "--config=imap.toml"]),
+                   config(Some("imap.toml"), repair));
+        assert!(parse(&["repair"]).is_err());
+        assert!(parse(&["repair", "user@example.com", "INBOX", "Work"]).is_err());
+    }
+
+    #[test]
+    fn test_bind_all_port_in_use() {
+        let dir = fixture::dir("bind");
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let also_taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let free = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server = |imap_port: u16, lmtp_port: u16| {
+            let config = dir.join("config.toml");
+            fs::write(&config, format!("host = \"127.0.0.1\"\nimap_port = {}\nlmtp_port = {}\n\
+                                        users = \"{}\"\npkcs_file = \"\"\npkcs_pass = \"\"\n",
+                                       imap_port, lmtp_port, dir.join("users.json").display()))
+                .unwrap();
+            Server::new(Some(Path::new(&config))).unwrap()
+        };
+
+        // The server runs on the ports which could be bound.
+        let serv = server(taken.local_addr().unwrap().port(), free);
+        let listeners = bind_all(&serv);
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].0, "LMTP");
+        assert_eq!(listeners[0].1.local_addr().unwrap().port(), free);
+        drop(listeners);
+
+        let serv = server(taken.local_addr().unwrap().port(), also_taken.local_addr().unwrap().port());
+        assert!(bind_all(&serv).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
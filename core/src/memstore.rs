@@ -0,0 +1,400 @@
+//! A `MailStore` which keeps its mailboxes in memory, so that the command
+//! handlers can be tested without touching the filesystem. Being the
+//! simplest store, it also shows what a store has to provide.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::rc::Rc;
+
+use mime::{crlf_line_endings, Message as MIME_Message};
+use time::{self, Timespec};
+
+use command::Attribute;
+use command::fetch::FetchOptions;
+use command::response::ResponseWriter;
+use command::search::{self, SearchKey};
+use command::select::Qresync;
+use command::sequence_set::SequenceItem;
+use command::store::StoreName;
+use error::{AppendError, Error, FolderError, ImapResult};
+use mailstore::{MailFolder, MailMessage, MailStore};
+use message::{print_flags, Flag, COMMON_KEYWORDS};
+
+/// A message held in memory
+#[derive(Clone, Debug)]
+struct MemoryMessage {
+    uid: usize,
+    flags: HashSet<Flag>,
+    date: Timespec,
+    contents: Vec<u8>
+}
+
+/// The messages of a mailbox, in ascending UID order
+#[derive(Debug)]
+struct Mailbox {
+    messages: Vec<MemoryMessage>,
+    next_uid: usize
+}
+
+/// The mailboxes of a store held in memory. Folders opened from it share
+/// their mailbox with the store, so changes made through them are seen the
+/// next time the mailbox is opened.
+#[derive(Debug)]
+pub struct MemoryStore {
+    mailboxes: RefCell<BTreeMap<String, Rc<RefCell<Mailbox>>>>
+}
+
+impl MemoryStore {
+    /// A store holding only an empty INBOX
+    pub fn new() -> MemoryStore {
+        let store = MemoryStore { mailboxes: RefCell::new(BTreeMap::new()) };
+        store.create("INBOX");
+        store
+    }
+
+    /// Add an empty mailbox, unless it already exists.
+    pub fn create(&self, mailbox: &str) {
+        self.mailboxes.borrow_mut().entry(mailbox.to_string())
+            .or_insert_with(|| Rc::new(RefCell::new(Mailbox { messages: Vec::new(), next_uid: 1 })));
+    }
+
+    fn mailbox(&self, mailbox: &str) -> Option<Rc<RefCell<Mailbox>>> {
+        self.mailboxes.borrow().get(mailbox).cloned()
+    }
+}
+
+impl MailStore for MemoryStore {
+    fn mailboxes(&self) -> Vec<String> {
+        self.mailboxes.borrow().keys().cloned().collect()
+    }
+
+    // No message is ever new.
+    fn mailbox_flags(&self, mailbox: &str) -> Option<String> {
+        self.mailbox(mailbox).map(|_| "\\Unmarked".to_string())
+    }
+
+    fn open(&self, mailbox: &str, examine: bool) -> Result<Box<dyn MailFolder>, FolderError> {
+        match self.mailbox(mailbox) {
+            Some(mailbox) => {
+                let exists = mailbox.borrow().messages.len();
+                Ok(Box::new(MemoryFolder { mailbox: mailbox, readonly: examine, exists: exists }))
+            }
+            None => Err(FolderError::NotFound)
+        }
+    }
+
+    fn append(&self, mailbox: &str, message: &[u8], flags: &[Flag],
+              date: Option<Timespec>) -> Result<(), AppendError> {
+        let mailbox = match self.mailbox(mailbox) {
+            Some(mailbox) => mailbox,
+            None => return Err(AppendError::NotFound)
+        };
+        let mut mailbox = mailbox.borrow_mut();
+        let uid = mailbox.next_uid;
+        mailbox.next_uid += 1;
+        mailbox.messages.push(MemoryMessage {
+            uid: uid,
+            flags: flags.iter().cloned().collect(),
+            date: date.unwrap_or_else(time::get_time),
+            contents: message.to_vec()
+        });
+        Ok(())
+    }
+}
+
+impl MailMessage for MemoryMessage {
+    fn get_uid(&self) -> usize {
+        self.uid
+    }
+
+    fn flags(&self) -> &HashSet<Flag> {
+        &self.flags
+    }
+
+    fn internal_date(&self) -> Timespec {
+        self.date
+    }
+
+    fn size(&self) -> u64 {
+        self.contents.len() as u64
+    }
+
+    // The contents must be UTF-8, as they must be in a message file.
+    fn load<'a>(&self, cache: &'a mut Option<MIME_Message>,
+                crlf: bool) -> ImapResult<&'a MIME_Message> {
+        if cache.is_none() {
+            let contents = String::from_utf8(self.contents.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let contents = if crlf { crlf_line_endings(contents) } else { contents };
+            *cache = Some(MIME_Message::parse(contents)?);
+        }
+        Ok(cache.as_ref().unwrap())
+    }
+}
+
+/// A mailbox of a `MemoryStore` which has been opened
+#[derive(Debug)]
+pub struct MemoryFolder {
+    mailbox: Rc<RefCell<Mailbox>>,
+    readonly: bool,
+    // How many messages the client has been told about
+    exists: usize
+}
+
+impl MailFolder for MemoryFolder {
+    // Mod-sequences aren't kept, so there is nothing to resync, and no
+    // message is ever \Recent.
+    fn select_response(&self, tag: &str, condstore: bool, _: Option<&Qresync>) -> String {
+        let mailbox = self.mailbox.borrow();
+        let mut keywords: Vec<String> = mailbox.messages.iter()
+            .flat_map(|msg| msg.flags.iter().filter_map(|flag| match *flag {
+                Flag::Keyword(ref keyword) => Some(keyword.clone()),
+                _ => None
+            }))
+            .chain(COMMON_KEYWORDS.iter().map(|keyword| keyword.to_string()))
+            .collect();
+        keywords.sort();
+        keywords.dedup();
+        let flags = format!("\\Answered \\Deleted \\Draft \\Flagged \\Seen {}", keywords.join(" "));
+
+        let mut res = ResponseWriter::new()
+            .untagged(&format!("FLAGS ({})", flags))
+            .untagged(&format!("{} EXISTS", mailbox.messages.len()))
+            .untagged("0 RECENT");
+        let unseen = mailbox.messages.iter().position(|msg| !msg.flags.contains(&Flag::Seen));
+        if let Some(index) = unseen {
+            res = res.untagged(&format!("OK [UNSEEN {0}] Message {0} is the first unseen",
+                                        index + 1));
+        }
+        res = res
+            .untagged(&format!("OK [PERMANENTFLAGS ({} \\*)] Permanent flags", flags))
+            .untagged("OK [UIDVALIDITY 1] UIDs valid")
+            .untagged(&format!("OK [UIDNEXT {}] Predicted next UID", mailbox.next_uid));
+        if condstore {
+            res = res.untagged("OK [NOMODSEQ] Mod-sequences are not kept for this folder");
+        }
+        let read_status = if self.readonly { "[READ-ONLY]" } else { "[READ-WRITE]" };
+        res.tagged_ok(tag, &format!("{} SELECT command was successful", read_status))
+            .into_string()
+    }
+
+    fn message_count(&self) -> usize {
+        self.mailbox.borrow().messages.len()
+    }
+
+    fn uids_from_index(&self, start: usize) -> Vec<usize> {
+        self.mailbox.borrow().messages.iter().skip(start).map(|msg| msg.uid).collect()
+    }
+
+    // Mod-sequences aren't kept.
+    fn modseq(&self, _: usize) -> Option<u64> {
+        None
+    }
+
+    fn highest_modseq(&self) -> Option<u64> {
+        None
+    }
+
+    fn modified_since(&mut self, _: &[usize], _: bool, _: u64) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn mark_seen(&mut self, index: usize) -> bool {
+        if self.readonly {
+            return false;
+        }
+        match self.mailbox.borrow_mut().messages.get_mut(index) {
+            Some(message) => message.flags.insert(Flag::Seen),
+            None => false
+        }
+    }
+
+    fn get_index_from_uid(&self, uid: &usize) -> Option<usize> {
+        self.mailbox.borrow().messages.iter().position(|msg| msg.uid == *uid)
+    }
+
+    fn search(&self, keys: &[SearchKey], uid: bool) -> Vec<usize> {
+        let mailbox = self.mailbox.borrow();
+        let max_seqnum = mailbox.messages.len();
+        let max_uid = mailbox.messages.last().map_or(0, |msg| msg.uid);
+        let messages = mailbox.messages.iter().cloned().enumerate().map(|(index, message)| {
+            let position = search::Position {
+                seqnum: index + 1,
+                recent: false,
+                max_seqnum: max_seqnum,
+                max_uid: max_uid,
+                modseq: None
+            };
+            (message, position)
+        });
+        search::search_messages(messages, keys, uid)
+    }
+
+    fn fetch(&self, index: usize, attributes: &[Attribute],
+             options: FetchOptions) -> ImapResult<String> {
+        let mailbox = self.mailbox.borrow();
+        let message = match mailbox.messages.get(index) {
+            Some(message) => message,
+            None => return Err(Error::NoSuchMessage)
+        };
+        let mut res = format!("* {} FETCH ({}", index + 1,
+                              message.fetch(attributes, options.decode_headers, options.crlf)?);
+        if options.report_flags {
+            res.push_str(&format!(" FLAGS {}", print_flags(&message.flags))[..]);
+        }
+        res.push_str(")\r\n");
+        Ok(res)
+    }
+
+    fn store(&mut self, sequence_set: Vec<usize>, flag_name: &StoreName, silent: bool,
+             flags: HashSet<Flag>, seq_uid: bool, _: bool) -> String {
+        let mut responses = String::new();
+        for num in sequence_set {
+            let index = if seq_uid {
+                match self.get_index_from_uid(&num) {
+                    Some(index) => index,
+                    None => continue
+                }
+            } else {
+                num - 1
+            };
+            let mut mailbox = self.mailbox.borrow_mut();
+            let message = match mailbox.messages.get_mut(index) {
+                Some(message) => message,
+                None => continue
+            };
+            match *flag_name {
+                StoreName::Add => message.flags.extend(flags.iter().cloned()),
+                StoreName::Sub => message.flags.retain(|flag| !flags.contains(flag)),
+                StoreName::Replace => message.flags = flags.clone()
+            }
+            responses.push_str(&format!("* {} FETCH (FLAGS {}", index + 1,
+                                        print_flags(&message.flags))[..]);
+            if seq_uid {
+                responses.push_str(&format!(" UID {}", message.uid)[..]);
+            }
+            responses.push_str(" )\r\n");
+        }
+        if silent { String::new() } else { responses }
+    }
+
+    fn expunge(&mut self) -> Vec<usize> {
+        self.expunge_where(|_| true)
+    }
+
+    fn uid_expunge(&mut self, uids: &[usize]) -> Vec<usize> {
+        self.expunge_where(|uid| uids.contains(&uid))
+    }
+
+    fn poll_new(&mut self) -> io::Result<String> {
+        let count = self.message_count();
+        if count <= self.exists {
+            return Ok(String::new());
+        }
+        self.exists = count;
+        Ok(ResponseWriter::new().untagged(&format!("{} EXISTS", count)).into_string())
+    }
+
+    // Other sessions share the mailbox, so their changes are seen at once.
+    fn poll_changes(&mut self, _: bool, _: bool) -> io::Result<String> {
+        self.poll_new()
+    }
+
+    // Changes are made to the mailbox as they happen.
+    fn check(&mut self) {}
+
+    fn vanished_response(&self, _: u64, _: Option<&Vec<SequenceItem>>) -> String {
+        String::new()
+    }
+}
+
+impl MemoryFolder {
+    /// Remove the messages marked \Deleted whose UIDs `include` accepts.
+    fn expunge_where<P: Fn(usize) -> bool>(&mut self, include: P) -> Vec<usize> {
+        let mut result = Vec::new();
+        if self.readonly {
+            return result;
+        }
+        // Later sequence numbers are given as they are once the earlier
+        // messages have gone.
+        let mut mailbox = self.mailbox.borrow_mut();
+        let mut index = 0;
+        while index < mailbox.messages.len() {
+            let message = &mailbox.messages[index];
+            if include(message.uid) && message.flags.contains(&Flag::Deleted) {
+                mailbox.messages.remove(index);
+                self.exists -= 1;
+                result.push(index + 1);
+            } else {
+                index += 1;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use command::Attribute::{Flags, RFC822, UID};
+    use command::RFC822Attribute::SizeRFC822;
+    use command::fetch::FetchOptions;
+    use command::store::StoreName;
+    use error::{AppendError, FolderError};
+    use mailstore::MailStore;
+    use message::Flag;
+    use super::MemoryStore;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new();
+        store.create("Work");
+        assert_eq!(store.mailboxes(), vec!["INBOX", "Work"]);
+        match store.open("Lost", false) {
+            Err(FolderError::NotFound) => {}
+            _ => panic!("Lost should not exist")
+        }
+        match store.append("Lost", b"Subject: hi\n\nHi\n", &[], None) {
+            Err(AppendError::NotFound) => {}
+            other => panic!("Lost should not exist: {:?}", other)
+        }
+        store.append("INBOX", b"Subject: one\n\nOne\n", &[Flag::Seen], None).unwrap();
+        store.append("INBOX", b"Subject: two\n\nTwo\n", &[Flag::Deleted], None).unwrap();
+
+        // Changes made through a folder are kept by the store.
+        let mut folder = store.open("INBOX", false).unwrap();
+        assert_eq!(folder.uids_from_index(0), vec![1, 2]);
+        assert_eq!(folder.fetch(1, &[Flags, UID], FetchOptions::default()).unwrap(),
+                   "* 2 FETCH (FLAGS (\\Deleted) UID 2)\r\n");
+        assert!(folder.mark_seen(1));
+        assert!(!folder.mark_seen(1));
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Deleted);
+        assert_eq!(folder.store(vec![2], &StoreName::Sub, false, flags.clone(), true, false),
+                   "* 2 FETCH (FLAGS (\\Seen) UID 2 )\r\n");
+        assert_eq!(folder.store(vec![1], &StoreName::Replace, false, flags, true, false),
+                   "* 1 FETCH (FLAGS (\\Deleted) UID 1 )\r\n");
+        assert_eq!(folder.expunge_response(true), "* VANISHED 1\r\n");
+        drop(folder);
+
+        // Nothing can be changed through a read-only folder.
+        let mut folder = store.open("INBOX", true).unwrap();
+        assert_eq!(folder.get_index_from_uid(&2), Some(0));
+        assert_eq!(folder.fetch(0, &[Flags], FetchOptions::default()).unwrap(),
+                   "* 1 FETCH (FLAGS (\\Seen))\r\n");
+        assert!(!folder.mark_seen(0));
+        assert_eq!(folder.expunge(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_memory_store_8bit() {
+        let store = MemoryStore::new();
+        let contents = b"Subject: caf\xe9\n\nHi\n";
+        store.append("INBOX", contents, &[], None).unwrap();
+        let folder = store.open("INBOX", true).unwrap();
+        assert_eq!(folder.fetch(0, &[RFC822(SizeRFC822)], FetchOptions::default()).unwrap(),
+                   format!("* 1 FETCH (RFC822.SIZE {})\r\n", contents.len()));
+    }
+}
@@ -3,28 +3,14 @@ use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
+use std::time::UNIX_EPOCH;
 
-use command::Attribute;
-use command::Attribute::{
-    Envelope,
-    Flags,
-    InternalDate,
-    RFC822,
-    Body,
-    BodyPeek,
-    BodySection,
-    BodyStructure,
-    UID
-};
-use command::RFC822Attribute::{
-    AllRFC822,
-    HeaderRFC822,
-    SizeRFC822,
-    TextRFC822
-};
 use command::store::StoreName;
 
 use error::{Error, ImapResult};
+use keywords::Keywords;
+use mailstore::MailMessage;
+use uidlist;
 
 use mime::Message as MIME_Message;
 
@@ -38,21 +24,125 @@ pub enum Flag {
     Draft,
     Flagged,
     Seen,
-    Deleted
+    Deleted,
+    // A client defined flag such as $Forwarded
+    Keyword(String)
 }
 
-/// Takes a flag argument and returns the corresponding enum.
-pub fn parse_flag(flag: &str) -> Option<Flag> {
-    match flag {
-        "\\Deleted" => Some(Flag::Deleted),
-        "\\Seen" => Some(Flag::Seen),
-        "\\Draft" => Some(Flag::Draft),
-        "\\Answered" => Some(Flag::Answered),
-        "\\Flagged" => Some(Flag::Flagged),
+/// Keywords which major clients use to keep track of forwarded messages, sent
+/// read receipts and junk mail. They are always offered to clients and are
+/// given this spelling whatever case the client uses.
+pub const COMMON_KEYWORDS: [&'static str; 4] = ["$Forwarded", "$Junk", "$MDNSent", "$NotJunk"];
+
+/// Merge the flags of a message which two sessions have changed at once.
+/// `ours` are the flags one session wants, having started from `original`,
+/// and `theirs` are the flags the message has on disk, as set by the other.
+/// The flags set and cleared in `ours` are applied on top of `theirs`, so
+/// that each session's changes survive unless both changed the same flag,
+/// in which case `ours` wins as it is written last.
+pub fn merge_flags(original: &HashSet<Flag>, ours: &HashSet<Flag>,
+                   theirs: &HashSet<Flag>) -> HashSet<Flag> {
+    let mut flags = theirs.clone();
+    for flag in ours.difference(original) {
+        flags.insert(flag.clone());
+    }
+    for flag in original.difference(ours) {
+        flags.remove(flag);
+    }
+    flags
+}
+
+/// The maildir info letter which stands for the flag, if it has one other
+/// than the lowercase letters keywords are given. `T` (trashed) is the
+/// maildir form of \Deleted and `P` (passed) that of $Forwarded.
+pub fn flag_letter(flag: &Flag) -> Option<char> {
+    match *flag {
+        Flag::Draft => Some('D'),
+        Flag::Flagged => Some('F'),
+        Flag::Keyword(ref keyword) if keyword == "$Forwarded" => Some('P'),
+        Flag::Answered => Some('R'),
+        Flag::Seen => Some('S'),
+        Flag::Deleted => Some('T'),
+        Flag::Keyword(_) => None
+    }
+}
+
+/// The flag which an uppercase maildir info letter stands for, the reverse
+/// of `flag_letter`.
+pub fn letter_flag(letter: char) -> Option<Flag> {
+    match letter {
+        'D' => Some(Flag::Draft),
+        'F' => Some(Flag::Flagged),
+        'P' => Some(Flag::Keyword("$Forwarded".to_string())),
+        'R' => Some(Flag::Answered),
+        'S' => Some(Flag::Seen),
+        'T' => Some(Flag::Deleted),
         _ => None
     }
 }
 
+/// The flags given by a maildir filename. The base name is separated from
+/// the info by its last colon, and the info is a 2 followed by a comma and
+/// a letter for each flag. `keywords` gives the meaning of any lowercase
+/// letters. This is the only place flags are read from filenames.
+pub fn filename_flags(filename: &str, keywords: &Keywords) -> HashSet<Flag> {
+    let letters = match split_info(filename).0 {
+        Some(letters) => letters,
+        None => return HashSet::new()
+    };
+    letters.chars().filter_map(|letter| {
+        letter_flag(letter)
+            .or_else(|| keywords.keyword(letter).map(|keyword| Flag::Keyword(keyword.to_string())))
+    }).collect()
+}
+
+// Split the info of a maildir filename into its flag letters and whatever
+// follows them, such as fields other programs have added after another
+// comma. Only a `2,` info holds flags; any other, such as the experimental
+// `1,` info, has no meaning here and is given back whole.
+fn split_info(filename: &str) -> (Option<&str>, &str) {
+    let info = match filename.rfind(':') {
+        Some(colon) => &filename[colon + 1..],
+        None => return (None, "")
+    };
+    if !info.starts_with("2,") {
+        return (None, info);
+    }
+    let letters = &info[2..];
+    match letters.find(',') {
+        Some(comma) => (Some(&letters[..comma]), &letters[comma..]),
+        None => (Some(letters), "")
+    }
+}
+
+/// Takes a flag argument and returns the corresponding enum. System flags
+/// are matched case-insensitively and any other atom is a keyword.
+pub fn parse_flag(flag: &str) -> Option<Flag> {
+    if flag.starts_with('\\') {
+        return match &flag.to_ascii_lowercase()[..] {
+            "\\deleted" => Some(Flag::Deleted),
+            "\\seen" => Some(Flag::Seen),
+            "\\draft" => Some(Flag::Draft),
+            "\\answered" => Some(Flag::Answered),
+            "\\flagged" => Some(Flag::Flagged),
+            _ => None
+        };
+    }
+
+    // Keywords are atoms, so they can't contain any of the characters which
+    // have a special meaning in the protocol.
+    let is_atom_char = |c: char| {
+        c.is_ascii() && !c.is_ascii_control() && !"(){ %*\"\\]".contains(c)
+    };
+    if flag.is_empty() || !flag.chars().all(is_atom_char) {
+        return None;
+    }
+    let keyword = COMMON_KEYWORDS.iter().find(|keyword| keyword.eq_ignore_ascii_case(flag))
+        .map(|keyword| keyword.to_string())
+        .unwrap_or_else(|| flag.to_string());
+    Some(Flag::Keyword(keyword))
+}
+
 /// Representation of a Message
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -62,84 +152,71 @@ pub struct Message {
     // filename
     path: PathBuf,
 
-    mime_message: MIME_Message,
-
     // contains the message's flags
     flags: HashSet<Flag>,
 
+    // the flags as they were in the filename when it was last read or
+    // written, so that changes made since then can be told apart
+    disk_flags: HashSet<Flag>,
+
     // marks the message for deletion
     deleted: bool,
 
 }
 
 impl Message {
-    pub fn new(arg_path: &Path) -> ImapResult<Message> {
-        let mime_message = MIME_Message::new(arg_path)?;
-
-        // Grab the string in the filename representing the flags
-        let mut path = path_filename_to_str!(arg_path).splitn(2, ':');
-        let filename = match path.next() {
-            Some(fname) => fname,
-            None => { return Err(Error::MessageBadFilename); }
-        };
-        let path_flags = path.next();
-
-        // Retrieve the UID from the provided filename.
-        let uid = filename.parse().map_err(|_| Error::MessageUidDecode)?;
-
-        // Parse the flags from the filename.
-        let flags = match path_flags {
-            // if there are no flags, create an empty set
-            None => HashSet::new(),
-            Some(flags) =>
-                // The uid is separated from the flag part of the filename by a
-                // colon. The flag part consists of a 2 followed by a comma and
-                // then some letters. Those letters represent the message flags
-                match flags.splitn(2, ',').nth(1) {
-                    None => HashSet::new(),
-                    Some(unparsed_flags) => {
-                        let mut set_flags: HashSet<Flag> = HashSet::new();
-                        for flag in unparsed_flags.chars() {
-                            let parsed_flag = match flag {
-                                'D' => Some(Flag::Draft),
-                                'F' => Some(Flag::Flagged),
-                                'R' => Some(Flag::Answered),
-                                'S' => Some(Flag::Seen),
-                                _ => None
-                            };
-                            if let Some(enum_flag) = parsed_flag {
-                                set_flags.insert(enum_flag);
-                            }
-                        }
-                        set_flags
-                    }
-                }
+    /// Create the message at the given path. Its UID comes from the folder's
+    /// UID list rather than the filename, and the folder's keyword mapping
+    /// gives the meaning of any lowercase flag letters. Only the filename is
+    /// looked at; the contents are read when they are fetched. A filename
+    /// which isn't UTF-8 can't be read, so gives `Error::MessageUidDecode`.
+    pub fn new(arg_path: &Path, uid: usize, keywords: &Keywords) -> ImapResult<Message> {
+        let filename = match arg_path.file_name().and_then(|name| name.to_str()) {
+            Some(filename) => filename,
+            None => return Err(Error::MessageUidDecode(arg_path.to_path_buf()))
         };
+        let flags = filename_flags(filename, keywords);
 
         let message = Message {
             uid: uid,
             path: arg_path.to_path_buf(),
-            mime_message: mime_message,
-            flags: flags,
-            deleted: false
+            disk_flags: flags.clone(),
+            deleted: flags.contains(&Flag::Deleted),
+            flags: flags
         };
 
         Ok(message)
     }
 
-    /// convenience method for determining if Seen is in this message's flags
+    /// convenience method for determining if Seen is missing from this
+    /// message's flags
     pub fn is_unseen(&self) -> bool {
-        self.flags.contains(&Flag::Seen)
+        !self.flags.contains(&Flag::Seen)
     }
 
-    pub fn rename(&self, pb: PathBuf) -> Message {
-        Message {
-            uid: self.uid,
-            path: pb,
-            mime_message: self.mime_message.clone(),
-            flags: self.flags.clone(),
-            deleted: self.deleted
-        }
+    /// The keywords set on this message, in sorted order.
+    pub fn keywords(&self) -> Vec<String> {
+        let mut keywords: Vec<String> = self.flags.iter().filter_map(|flag| match *flag {
+            Flag::Keyword(ref keyword) => Some(keyword.clone()),
+            _ => None
+        }).collect();
+        keywords.sort();
+        keywords
+    }
+
+    /// Bring the message up to date with `on_disk`, the same message as it
+    /// now is on disk, which another session may have renamed to change its
+    /// flags. The flags set and cleared here since the filename was last
+    /// read are applied on top of the flags it has now.
+    pub fn merge_flags(&mut self, on_disk: Message) {
+        self.flags = merge_flags(&self.disk_flags, &self.flags, &on_disk.flags);
+        self.disk_flags = on_disk.flags;
+        self.path = on_disk.path;
+        self.deleted = self.flags.contains(&Flag::Deleted);
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
     }
 
     pub fn remove_if_deleted(&self) -> bool {
@@ -152,12 +229,13 @@ impl Message {
         self.deleted
     }
 
-    pub fn get_path(&self) -> &Path {
-        self.path.as_path()
+    /// The size of the message file on disk, or 0 if it can't be determined.
+    pub fn disk_size(&self) -> u64 {
+        fs::metadata(self.path.as_path()).map(|md| md.len()).unwrap_or(0)
     }
 
-    pub fn get_uid(&self) -> usize {
-        self.uid
+    pub fn get_path(&self) -> &Path {
+        self.path.as_path()
     }
 
     pub fn store(&mut self, flag_name: &StoreName,
@@ -180,181 +258,361 @@ impl Message {
         self.print_flags()
     }
 
-    /// Goes through the list of attributes, constructing a FETCH response for
-    /// this message containing the values of the requested attributes
-    pub fn fetch(&self, attributes: &[Attribute]) -> String {
-        let mut res = String::new();
-        let mut first = true;
-        for attr in attributes.iter() {
-            // We need to space separate the attribute values
-            if first {
-                first = false;
-            } else {
-                res.push(' ');
-            }
-
-            // Provide the attribute name followed by the attribute value
-            match *attr {
-                Envelope => {
-                    res.push_str("ENVELOPE ");
-                    res.push_str(&self.mime_message.get_envelope()[..]);
-                },
-                Flags => {
-                    res.push_str("FLAGS ");
-                    res.push_str(&self.print_flags()[..]);
-                },
-                InternalDate => {
-                    res.push_str("INTERNALDATE \"");
-                    res.push_str(&self.date_received()[..]);
-                    res.push('"');
-                }
-                RFC822(ref attr) => {
-                    res.push_str("RFC822");
-                    match *attr {
-                        AllRFC822 | TextRFC822 => {},
-                        HeaderRFC822 => {
-                            res.push_str(".HEADER {");
-                            res.push_str(&self.mime_message.get_header_boundary()[..]);
-                            res.push_str("}\r\n");
-                            res.push_str(self.mime_message.get_header());
-                        },
-                        SizeRFC822 => {
-                            res.push_str(".SIZE ");
-                            res.push_str(&self.mime_message.get_size()[..]) },
-                    };
-                },
-                Body | BodyStructure => {},
-                BodySection(ref section, ref octets) |
-                    BodyPeek(ref section, ref octets) => {
-                        res.push_str(&self.mime_message.get_body(section, octets)[..]) },
-                /*
-                BodyStructure => {
-                    let content_type: Vec<&str> = (&self.headers["CONTENT-TYPE".to_string()][..]).splitn(2, ';').take(1).collect();
-                    let content_type: Vec<&str> = content_type[0].splitn(2, '/').collect();
-
-                    // Retrieve the subtype of the content type.
-                    let mut subtype = String::new();
-                    if content_type.len() > 1 { subtype = content_type[1].to_ascii_uppercase() }
-
-                    let content_type = content_type[0].to_ascii_uppercase();
-                    println!("Content-type: {}/{}", content_type, subtype);
-                    match &content_type[..] {
-                        "MESSAGE" => {
-                            match &subtype[..] {
-                                "RFC822" => {
-                                    // Immediately after the basic fields, add the envelope
-                                    // structure, body structure, and size in text lines of
-                                    // the encapsulated message.
-                                },
-                                _ => { },
-                            }
-                        },
-                        "TEXT" => {
-                            // Immediately after the basic fields, add the size of the body
-                            // in text lines. This is the size in the content transfer
-                            // encoding and not the size after any decoding.
-                        },
-                        "MULTIPART" => {
-
-                        },
-                        _ => { },
-                    }
-                },
-                */
-                UID => {
-                    res.push_str("UID ");
-                    res.push_str(&self.uid.to_string()[..])
-                }
-            }
-        }
-        res
-    }
-
     // Creates a string of the current set of flags based on what is in
     // self.flags.
     fn print_flags(&self) -> String {
-        let mut res = "(".to_string();
-        let mut first = true;
-        for flag in &self.flags {
-            // The flags should be space separated.
-            if first {
-                first = false;
-            } else {
-                res.push(' ');
-            }
-            let flag_str = match *flag {
-                Flag::Answered => { "\\Answered" },
-                Flag::Draft => { "\\Draft" },
-                Flag::Flagged => { "\\Flagged" },
-                Flag::Seen => { "\\Seen" }
-                Flag::Deleted => { "\\Deleted" }
-            };
-            res.push_str(flag_str);
-        }
-        res.push(')');
-        res
+        print_flags(&self.flags)
     }
 
     /// Creates a new filename using the convention that we use while parsing
-    /// the message's filename. UID followed by a colon, then 2, then the
-    /// single character per flag representation of the current set of flags.
-    pub fn get_new_filename(&self) -> String {
-        let mut res = self.uid.to_string();
+    /// the message's filename. The base of the current filename followed by
+    /// a colon, then 2, then the single character per flag representation of
+    /// the current set of flags. Keywords are represented by the lowercase
+    /// letters they are given in the folder's keyword mapping. Anything in
+    /// the info which isn't a flag letter is kept, unless the info isn't a
+    /// `2,` one and flags have to replace it.
+    pub fn get_new_filename(&self, keywords: &mut Keywords) -> String {
+        let path = self.path.as_path();
+        let filename = path_filename_to_str!(path);
+        let mut res = uidlist::base_name(filename).to_string();
+        let (info_letters, rest) = split_info(filename);
 
         // it is just the UID if no flags are set.
         if self.flags.is_empty() {
+            if !rest.is_empty() {
+                res.push_str(if info_letters.is_some() { ":2," } else { ":" });
+                res.push_str(rest);
+            }
             return res;
         }
+        if info_letters.is_none() && !rest.is_empty() {
+            warn!("The info of {} will be replaced by its flags", filename);
+        }
 
         // Add the prelud which separates the flags
         res.push_str(":2,");
 
         // As per the Maildir standard, the flags are to be written in
-        // alphabetical order
-        if self.flags.contains(&Flag::Draft) {
-            res.push('D');
+        // alphabetical order, which puts the keywords' lowercase letters
+        // last.
+        let mut letters: Vec<char> = self.flags.iter().filter_map(flag_letter).collect();
+        letters.sort();
+        res.extend(letters);
+        let mut letters = Vec::new();
+        for keyword in self.keywords() {
+            if flag_letter(&Flag::Keyword(keyword.clone())).is_some() {
+                continue;
+            }
+            match keywords.letter(&keyword[..]) {
+                Some(letter) => letters.push(letter),
+                None => warn!("No letter left for keyword {}, it will not be saved", keyword)
+            }
         }
-        if self.flags.contains(&Flag::Flagged) {
-            res.push('F');
+        letters.sort();
+        res.extend(letters);
+        if info_letters.is_some() {
+            res.push_str(rest);
         }
-        if self.flags.contains(&Flag::Answered) {
-            res.push('R');
+        res
+    }
+
+}
+
+impl MailMessage for Message {
+    fn get_uid(&self) -> usize {
+        self.uid
+    }
+
+    fn flags(&self) -> &HashSet<Flag> {
+        &self.flags
+    }
+
+    fn internal_date(&self) -> Timespec {
+        // The file's modification time is the time it was delivered. Fall
+        // back on the UID, which is the delivery time for messages we
+        // delivered ourselves.
+        let sec = fs::metadata(self.path.as_path())
+            .and_then(|md| md.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+            .unwrap_or(self.uid as i64);
+        Timespec { sec: sec, nsec: 0i32 }
+    }
+
+    fn size(&self) -> u64 {
+        self.disk_size()
+    }
+
+    /// Read and parse the message file, unless `cache` already holds it
+    /// from an earlier call. Nothing is kept between FETCH commands, so a
+    /// file modified on disk is always reread with its new size.
+    fn load<'a>(&self, cache: &'a mut Option<MIME_Message>,
+                crlf: bool) -> ImapResult<&'a MIME_Message> {
+        let loaded = match cache.take() {
+            Some(mime_message) => mime_message,
+            None if crlf => MIME_Message::new_crlf(self.path.as_path())?,
+            None => MIME_Message::new(self.path.as_path())?
+        };
+        Ok(cache.get_or_insert(loaded))
+    }
+}
+
+/// The parenthesized list of flags given in FETCH and STORE responses
+pub fn print_flags(flags: &HashSet<Flag>) -> String {
+    let mut res = "(".to_string();
+    let mut first = true;
+    for flag in flags {
+        // The flags should be space separated.
+        if first {
+            first = false;
+        } else {
+            res.push(' ');
         }
-        if self.flags.contains(&Flag::Seen) {
-            res.push('S');
+        let flag_str = match *flag {
+            Flag::Answered => { "\\Answered" },
+            Flag::Draft => { "\\Draft" },
+            Flag::Flagged => { "\\Flagged" },
+            Flag::Seen => { "\\Seen" }
+            Flag::Deleted => { "\\Deleted" }
+            Flag::Keyword(ref keyword) => { &keyword[..] }
+        };
+        res.push_str(flag_str);
+    }
+    res.push(')');
+    res
+}
+
+/// Format an INTERNALDATE as FETCH reports it, in UTC.
+pub fn format_internal_date(date: Timespec) -> String {
+    let date_received_tm = time::at_utc(date);
+
+    let month = match date_received_tm.tm_mon {
+        0 => "Jan",
+        1 => "Feb",
+        2 => "Mar",
+        3 => "Apr",
+        4 => "May",
+        5 => "Jun",
+        6 => "Jul",
+        7 => "Aug",
+        8 => "Sep",
+        9 => "Oct",
+        10 => "Nov",
+        11 => "Dec",
+        // NOTE: this should never happen.
+        _ => panic!("Unable to determine month!")
+    };
+
+    format!(
+        "{:0>2}-{}-{:0>2} {:0>2}:{:0>2}:{:0>2} -0000",
+        date_received_tm.tm_mday,
+        month,
+        date_received_tm.tm_year + 1900i32,
+        date_received_tm.tm_hour,
+        date_received_tm.tm_min,
+        date_received_tm.tm_sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use command::Attribute::{Body, BodyStructure, Flags, RFC822};
+    use command::RFC822Attribute::{AllRFC822, HeaderRFC822, SizeRFC822, TextRFC822};
+    use command::store::StoreName;
+    use error::Error;
+    use fixture;
+    use keywords::Keywords;
+    use mailstore::MailMessage;
+    use super::{filename_flags, merge_flags, parse_flag, Flag, Message};
+
+    #[test]
+    fn test_parse_flag() {
+        assert_eq!(parse_flag("\\Seen"), Some(Flag::Seen));
+        assert_eq!(parse_flag("\\flagged"), Some(Flag::Flagged));
+        assert_eq!(parse_flag("$Forwarded"), Some(Flag::Keyword("$Forwarded".to_string())));
+        assert_eq!(parse_flag("$mdnsent"), Some(Flag::Keyword("$MDNSent".to_string())));
+        assert_eq!(parse_flag("NonJunk"), Some(Flag::Keyword("NonJunk".to_string())));
+        assert_eq!(parse_flag("\\Recent"), None);
+        assert_eq!(parse_flag("\\Unknown"), None);
+        assert_eq!(parse_flag("bad]keyword"), None);
+        assert_eq!(parse_flag(""), None);
+    }
+
+    #[test]
+    fn test_new_filenames() {
+        let keywords = Keywords::new();
+        // A name without a colon has no flags.
+        let message = Message::new(Path::new("cur/100"), 100, &keywords).unwrap();
+        assert!(message.flags().is_empty());
+        assert_eq!(message.get_uid(), 100);
+
+        let message = Message::new(Path::new("cur/100.M1P2.host:2,FS"), 100, &keywords).unwrap();
+        let flags: HashSet<Flag> = vec![Flag::Flagged, Flag::Seen].into_iter().collect();
+        assert_eq!(message.flags(), &flags);
+
+        let path = Path::new("cur").join(OsStr::from_bytes(b"100\xff:2,S"));
+        assert_eq!(Message::new(&path, 100, &keywords).unwrap_err(),
+                   Error::MessageUidDecode(path.clone()));
+    }
+
+    #[test]
+    fn test_flag_letters_round_trip() {
+        let dir = fixture::dir("message-letters");
+        let mut keywords = Keywords::new();
+        assert_eq!(keywords.letter("Work"), Some('a'));
+
+        for name in &["100:2,DFPRSTa", "100:2,S", "100"] {
+            let message = Message::new(&dir.join(name), 100, &keywords).unwrap();
+            assert_eq!(&message.get_new_filename(&mut keywords)[..], *name);
         }
-        res
+
+        // Externally trashed and passed messages are \Deleted and
+        // $Forwarded.
+        let message = Message::new(&dir.join("100:2,PT"), 100, &keywords).unwrap();
+        let flags: HashSet<Flag> = vec![Flag::Deleted, Flag::Keyword("$Forwarded".to_string())]
+            .into_iter().collect();
+        assert_eq!(message.flags(), &flags);
+        assert!(message.is_deleted());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn date_received(&self) -> String {
-        // Retrieve the date received from the UID.
-        let date_received = Timespec { sec: self.uid as i64, nsec: 0i32 };
-        let date_received_tm = time::at_utc(date_received);
-
-        let month = match date_received_tm.tm_mon {
-            0 => "Jan",
-            1 => "Feb",
-            2 => "Mar",
-            3 => "Apr",
-            4 => "May",
-            5 => "Jun",
-            6 => "Jul",
-            7 => "Aug",
-            8 => "Sep",
-            9 => "Oct",
-            10 => "Nov",
-            11 => "Dec",
-            // NOTE: this should never happen.
-            _ => panic!("Unable to determine month!")
+    #[test]
+    fn test_filename_flags() {
+        let dir = fixture::maildir("message-seen", &[(100, &[Flag::Seen])]);
+        let message = Message::new(&dir.join("cur/100:2,S"), 100, &Keywords::new()).unwrap();
+        assert!(!message.is_unseen());
+        assert_eq!(message.fetch(&[Flags], false, false).unwrap(), "FLAGS (\\Seen)");
+
+        let seen: HashSet<Flag> = vec![Flag::Seen].into_iter().collect();
+        assert_eq!(filename_flags("1500000000.M1P2.host:2,S", &Keywords::new()), seen);
+        // Letters without a keyword are ignored.
+        assert_eq!(filename_flags("100:2,Sz", &Keywords::new()), seen);
+        assert!(filename_flags("100", &Keywords::new()).is_empty());
+        // Only the last colon starts the info, and only a 2, info has flags.
+        assert_eq!(filename_flags("100:host:2,S", &Keywords::new()), seen);
+        assert!(filename_flags("100:1,S", &Keywords::new()).is_empty());
+        assert!(filename_flags("100:S", &Keywords::new()).is_empty());
+        // Fields after the flags aren't flags themselves.
+        assert_eq!(filename_flags("100:2,S,D=1", &Keywords::new()), seen);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_filename_keeps_info() {
+        let mut keywords = Keywords::new();
+        let renamed = |name: &str, flags: &[Flag], keywords: &mut Keywords| {
+            let mut message = Message::new(Path::new(name), 100, keywords).unwrap();
+            message.store(&StoreName::Replace, flags.iter().cloned().collect());
+            message.get_new_filename(keywords)
         };
 
-        format!(
-            "{:0>2}-{}-{:0>2} {:0>2}:{:0>2}:{:0>2} -0000",
-            date_received_tm.tm_mday,
-            month,
-            date_received_tm.tm_year + 1900i32,
-            date_received_tm.tm_hour,
-            date_received_tm.tm_min,
-            date_received_tm.tm_sec)
+        // Fields after the flags stay after them.
+        assert_eq!(renamed("100:2,S,D=1", &[Flag::Seen, Flag::Flagged], &mut keywords),
+                   "100:2,FS,D=1");
+        assert_eq!(renamed("100:2,S,D=1", &[], &mut keywords), "100:2,,D=1");
+        // Other info is kept as long as there are no flags to write.
+        assert_eq!(renamed("100:1,xyz", &[], &mut keywords), "100:1,xyz");
+        assert_eq!(renamed("100:1,xyz", &[Flag::Seen], &mut keywords), "100:2,S");
+        assert_eq!(renamed("100:host:2,", &[Flag::Draft], &mut keywords), "100:host:2,D");
+        assert_eq!(renamed("100:2,S", &[], &mut keywords), "100");
+    }
+
+    #[test]
+    fn test_merge_flags() {
+        let set = |flags: &[Flag]| flags.iter().cloned().collect::<HashSet<Flag>>();
+        let original = set(&[Flag::Seen, Flag::Answered]);
+
+        // Each side's changes are kept.
+        let ours = set(&[Flag::Seen, Flag::Answered, Flag::Flagged]);
+        let theirs = set(&[Flag::Answered]);
+        assert_eq!(merge_flags(&original, &ours, &theirs), set(&[Flag::Answered, Flag::Flagged]));
+
+        // A flag cleared here stays cleared even if the other side kept it,
+        // while one left alone here follows the other side.
+        let ours = set(&[Flag::Seen, Flag::Draft]);
+        let theirs = set(&[Flag::Answered, Flag::Keyword("$Junk".to_string())]);
+        assert_eq!(merge_flags(&original, &ours, &theirs),
+                   set(&[Flag::Draft, Flag::Keyword("$Junk".to_string())]));
+
+        // A flag only set on this side is kept, and nothing changed means
+        // the disk is taken as it is.
+        let ours = set(&[Flag::Seen, Flag::Answered, Flag::Deleted]);
+        assert_eq!(merge_flags(&original, &ours, &set(&[])), set(&[Flag::Deleted]));
+        assert_eq!(merge_flags(&original, &original, &theirs), theirs);
+    }
+
+    #[test]
+    fn test_rfc822_literals() {
+        let dir = fixture::maildir("message", &[]);
+        let raw = "Subject: caf\u{e9}\n\nBody\n";
+        let path = fixture::write_message(&dir, "cur", 100, &[Flag::Seen], raw);
+        let message = Message::new(&path, 100, &Keywords::new()).unwrap();
+
+        assert_eq!(message.fetch(&[RFC822(AllRFC822)], false, false).unwrap(),
+                   format!("RFC822 {{{}}}\r\n{}", raw.len(), raw));
+        assert_eq!(message.fetch(&[RFC822(HeaderRFC822)], false, false).unwrap(),
+                   "RFC822.HEADER {16}\r\nSubject: caf\u{e9}\n\n");
+        assert_eq!(message.fetch(&[RFC822(TextRFC822), RFC822(SizeRFC822)], false, false).unwrap(),
+                   "RFC822.TEXT {5}\r\nBody\n RFC822.SIZE 21");
+
+        // The text's length is counted in octets rather than characters, so
+        // that it covers every byte sent.
+        let text = "Caf\u{e9} \u{2615}\n";
+        let text_path = fixture::write_message(&dir, "cur", 200, &[],
+                                               &format!("Subject: hi\n\n{}", text));
+        let text_message = Message::new(&text_path, 200, &Keywords::new()).unwrap();
+        assert_eq!(text.chars().count(), 7);
+        assert_eq!(text_message.fetch(&[RFC822(TextRFC822)], false, false).unwrap(),
+                   format!("RFC822.TEXT {{10}}\r\n{}", text));
+
+        // A message rewritten on disk is sent with its new contents and size.
+        let raw = "Subject: caf\u{e9}\n\nA longer body\n";
+        fixture::write_message(&dir, "cur", 100, &[Flag::Seen], raw);
+        assert_eq!(message.fetch(&[RFC822(AllRFC822), RFC822(SizeRFC822)], false, false).unwrap(),
+                   format!("RFC822 {{{}}}\r\n{} RFC822.SIZE {}", raw.len(), raw, raw.len()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        let dir = fixture::maildir("crlf", &[]);
+        let path = fixture::write_message(&dir, "cur", 100, &[Flag::Seen],
+                                          "Subject: hi\n\nOne\r\nTwo\n");
+        let message = Message::new(&path, 100, &Keywords::new()).unwrap();
+        let attributes = [RFC822(TextRFC822), RFC822(SizeRFC822)];
+
+        // The message is sent as it is stored unless asked otherwise.
+        assert_eq!(message.fetch(&attributes, false, false).unwrap(),
+                   "RFC822.TEXT {9}\r\nOne\r\nTwo\n RFC822.SIZE 22");
+        assert_eq!(message.fetch(&attributes, false, true).unwrap(),
+                   "RFC822.TEXT {10}\r\nOne\r\nTwo\r\n RFC822.SIZE 25");
+        // The size agrees with the literal even when nothing else is sent.
+        assert_eq!(message.fetch(&[RFC822(SizeRFC822)], false, true).unwrap(), "RFC822.SIZE 25");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_body_and_bodystructure() {
+        let dir = fixture::maildir("structure", &[]);
+        let raw = "Subject: hi\nContent-Type: text/plain; charset=utf-8\n\nHello\n";
+        let path = fixture::write_message(&dir, "new", 100, &[], raw);
+        let message = Message::new(&path, 100, &Keywords::new()).unwrap();
+
+        // BODYSTRUCTURE carries the extension data which BODY leaves out.
+        assert_eq!(message.fetch(&[Body, BodyStructure], false, false).unwrap(),
+                   "BODY (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 6 1) \
+                    BODYSTRUCTURE (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 6 1 NIL NIL NIL NIL)");
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
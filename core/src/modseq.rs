@@ -0,0 +1,336 @@
+//! Reading and writing of the `segimap.modseq` file, which records the
+//! mod-sequence (RFC 7162) of each message in a folder along with the highest
+//! mod-sequence given out so far.
+//!
+//! The first line holds `V<uidvalidity> H<highest modseq>` and each line
+//! after it a UID followed by a space and that message's mod-sequence.
+//! Messages which have been expunged are listed the same way after a `-`,
+//! with the mod-sequence at which they were expunged, so that clients can be
+//! told what has vanished since they last looked. All of this is forgotten if
+//! the folder's UIDVALIDITY changes, since the UIDs are then reassigned.
+//!
+//! Every session with the folder open changes the list, so each change is
+//! made under `segimap.modseq.lock` to the list as it is on disk, and saved
+//! before the lock is given up.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use dotlock::{self, DotLock};
+
+/// The name of the mod-sequence list within each folder.
+pub const MODSEQ_FILE: &'static str = "segimap.modseq";
+
+/// The name of the lock on the mod-sequence list.
+pub const LOCK_FILE: &'static str = "segimap.modseq.lock";
+
+/// How many expunged messages are remembered before the folder is given a
+/// new UIDVALIDITY so that they can be forgotten.
+const MAX_EXPUNGED: usize = 10000;
+
+/// The mod-sequences of the messages of a folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModSeqs {
+    uid_validity: usize,
+    highest: u64,
+    // Maps the UID of each message to its mod-sequence
+    modseqs: HashMap<usize, u64>,
+    // The UIDs of expunged messages and the mod-sequence of their expunging,
+    // in the order they were expunged
+    expunged: Vec<(usize, u64)>,
+    // Whether a mod-sequence has been given out since the list was loaded
+    changed: bool
+}
+
+impl ModSeqs {
+    /// Create an empty list for a folder with the given UIDVALIDITY.
+    pub fn new(uid_validity: usize) -> ModSeqs {
+        ModSeqs {
+            uid_validity: uid_validity,
+            // Mod-sequences are never 0, even in an empty folder.
+            highest: 1,
+            modseqs: HashMap::new(),
+            expunged: Vec::new(),
+            changed: false
+        }
+    }
+
+    /// Read the folder's mod-sequences. A missing list is treated as empty,
+    /// while one which can't be parsed is an `InvalidData` error, as the
+    /// mod-sequences clients have already been given can't be known.
+    pub fn load(folder: &Path, uid_validity: usize) -> io::Result<ModSeqs> {
+        let mut contents = String::new();
+        match File::open(folder.join(MODSEQ_FILE)) {
+            Ok(mut file) => { file.read_to_string(&mut contents)?; }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(ModSeqs::new(uid_validity)),
+            Err(e) => return Err(e)
+        }
+        let mut modseqs = match ModSeqs::parse(&contents[..]) {
+            Some(modseqs) => modseqs,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("malformed {}", MODSEQ_FILE)))
+        };
+        // Counting carries on from where it was so that clients never see
+        // the folder go back in time.
+        if modseqs.uid_validity != uid_validity {
+            modseqs.reset(uid_validity);
+        }
+        Ok(modseqs)
+    }
+
+    /// Make changes to the list as it is on disk, so that those made by
+    /// other sessions since it was read aren't lost: the lock is taken, the
+    /// list read again, `f` applied to it and the result saved before the
+    /// lock is given up. Returns what `f` does.
+    pub fn update<F, T>(&mut self, folder: &Path, f: F) -> io::Result<T>
+        where F: FnOnce(&mut ModSeqs) -> T {
+        let _lock = lock(folder)?;
+        *self = ModSeqs::load(folder, self.uid_validity)?;
+        let res = f(self);
+        if self.changed {
+            self.save(folder)?;
+        }
+        Ok(res)
+    }
+
+    /// Forget every message, and every expunged one, as the folder has a
+    /// new UIDVALIDITY.
+    pub fn reset(&mut self, uid_validity: usize) {
+        self.uid_validity = uid_validity;
+        self.modseqs.clear();
+        self.expunged.clear();
+        self.changed = true;
+    }
+
+    fn parse(contents: &str) -> Option<ModSeqs> {
+        let mut lines = contents.lines();
+        let mut modseqs = ModSeqs::new(0);
+        for field in lines.next()?.split(' ') {
+            if field.starts_with('V') {
+                modseqs.uid_validity = field[1..].parse().ok()?;
+            } else if field.starts_with('H') {
+                modseqs.highest = field[1..].parse().ok()?;
+            }
+        }
+
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let (line, expunged) = if line.starts_with('-') {
+                (&line[1..], true)
+            } else {
+                (line, false)
+            };
+            let mut parts = line.splitn(2, ' ');
+            let uid = parts.next()?.parse().ok()?;
+            let modseq = parts.next()?.parse().ok()?;
+            if modseq > modseqs.highest {
+                modseqs.highest = modseq;
+            }
+            if expunged {
+                modseqs.expunged.push((uid, modseq));
+            } else {
+                modseqs.modseqs.insert(uid, modseq);
+            }
+        }
+        Some(modseqs)
+    }
+
+    /// The highest mod-sequence given to any message.
+    pub fn highest(&self) -> u64 {
+        self.highest
+    }
+
+    /// Get the mod-sequence of the message with the given UID, if it has one.
+    pub fn get(&self, uid: usize) -> Option<u64> {
+        self.modseqs.get(&uid).cloned()
+    }
+
+    /// Give the message with the given UID the next mod-sequence, as it is
+    /// new or its flags have changed. Returns the new mod-sequence.
+    pub fn bump(&mut self, uid: usize) -> u64 {
+        self.highest += 1;
+        self.modseqs.insert(uid, self.highest);
+        self.changed = true;
+        self.highest
+    }
+
+    /// Give every message which doesn't have a mod-sequence yet the next one,
+    /// in UID order.
+    pub fn assign_all(&mut self, uids: &[usize]) {
+        let mut missing: Vec<usize> = uids.iter().cloned()
+            .filter(|uid| !self.modseqs.contains_key(uid))
+            .collect();
+        missing.sort();
+        for uid in missing {
+            self.bump(uid);
+        }
+    }
+
+    /// Record every message which is no longer in the folder as expunged,
+    /// whether it was expunged by us or removed by something else, all with
+    /// the same new mod-sequence.
+    pub fn retain(&mut self, uids: &[usize]) {
+        let present: HashSet<&usize> = uids.iter().collect();
+        let gone: Vec<usize> = self.modseqs.keys()
+            .filter(|uid| !present.contains(uid))
+            .cloned()
+            .collect();
+        self.expunge(&gone);
+    }
+
+    /// Record the messages with the given UIDs as expunged, all with the
+    /// same new mod-sequence. UIDs which aren't in the list are ignored.
+    pub fn expunge(&mut self, uids: &[usize]) {
+        let mut gone: Vec<usize> = uids.iter().cloned()
+            .filter(|uid| self.modseqs.contains_key(uid))
+            .collect();
+        if gone.is_empty() {
+            return;
+        }
+        gone.sort();
+        self.highest += 1;
+        for uid in gone {
+            self.modseqs.remove(&uid);
+            self.expunged.push((uid, self.highest));
+        }
+        self.changed = true;
+    }
+
+    /// The UIDs of the messages expunged after the given mod-sequence, in
+    /// ascending order.
+    pub fn vanished_since(&self, modseq: u64) -> Vec<usize> {
+        let mut uids: Vec<usize> = self.expunged.iter()
+            .filter(|&&(_, expunged)| expunged > modseq)
+            .map(|&(uid, _)| uid)
+            .collect();
+        uids.sort();
+        uids.dedup();
+        uids
+    }
+
+    /// Whether so many expunged messages are remembered that they should be
+    /// forgotten by giving the folder a new UIDVALIDITY.
+    pub fn needs_pruning(&self) -> bool {
+        self.expunged.len() > MAX_EXPUNGED
+    }
+
+    /// Whether anything has changed since the list was loaded or last
+    /// saved.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Write the list out, replacing it in one step so readers never see
+    /// half of it.
+    pub fn save(&mut self, folder: &Path) -> io::Result<()> {
+        let mut entries: Vec<(&usize, &u64)> = self.modseqs.iter().collect();
+        entries.sort();
+
+        let mut contents = format!("V{} H{}\n", self.uid_validity, self.highest);
+        for (uid, modseq) in entries {
+            contents.push_str(&format!("{} {}\n", uid, modseq)[..]);
+        }
+        for &(uid, modseq) in &self.expunged {
+            contents.push_str(&format!("-{} {}\n", uid, modseq)[..]);
+        }
+
+        dotlock::replace_file(&folder.join(MODSEQ_FILE), contents.as_bytes())?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Take the lock on the folder's mod-sequence list, waiting for anyone else
+/// who holds it.
+pub fn lock(folder: &Path) -> io::Result<DotLock> {
+    DotLock::acquire(folder.join(LOCK_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+
+    use fixture;
+    use super::ModSeqs;
+
+    #[test]
+    fn test_parse() {
+        let modseqs = ModSeqs::parse("V1234 H20\n5 7\n9 12\n-3 15\n").unwrap();
+        assert_eq!(modseqs.uid_validity, 1234);
+        assert_eq!(modseqs.highest(), 20);
+        assert_eq!(modseqs.modseqs.get(&5), Some(&7));
+        assert_eq!(modseqs.modseqs.get(&6), None);
+        assert_eq!(modseqs.modseqs.get(&3), None);
+        assert_eq!(modseqs.vanished_since(14), vec![3]);
+        assert!(modseqs.vanished_since(15).is_empty());
+
+        assert_eq!(ModSeqs::parse("V1 H2\n3 30\n").unwrap().highest(), 30);
+        assert_eq!(ModSeqs::parse("V1 Hx\n"), None);
+        assert_eq!(ModSeqs::parse("V1 H2\n3\n"), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let folder = fixture::dir("modseq");
+        let mut modseqs = ModSeqs::load(&folder, 100).unwrap();
+        assert_eq!(modseqs.highest(), 1);
+
+        // New messages are numbered in UID order and changes come after them.
+        modseqs.assign_all(&[30, 10, 20]);
+        assert_eq!(modseqs.modseqs.get(&10), Some(&2));
+        assert_eq!(modseqs.modseqs.get(&30), Some(&4));
+        assert_eq!(modseqs.bump(10), 5);
+        modseqs.retain(&[10, 30]);
+        assert_eq!(modseqs.highest(), 6);
+        assert_eq!(modseqs.vanished_since(5), vec![20]);
+        modseqs.save(&folder).unwrap();
+        assert!(!modseqs.is_changed());
+
+        let loaded = ModSeqs::load(&folder, 100).unwrap();
+        assert_eq!(loaded, modseqs);
+        assert_eq!(loaded.modseqs.get(&20), None);
+        assert_eq!(loaded.vanished_since(1), vec![20]);
+
+        // A new UIDVALIDITY starts the messages afresh but not the count.
+        let mut reset = ModSeqs::load(&folder, 200).unwrap();
+        assert_eq!(reset.modseqs.get(&10), None);
+        assert!(reset.vanished_since(1).is_empty());
+        assert!(reset.is_changed());
+        reset.assign_all(&[10]);
+        assert_eq!(reset.modseqs.get(&10), Some(&7));
+
+        // Changes are made to the list as it is on disk.
+        let mut stale = ModSeqs::new(100);
+        assert_eq!(stale.update(&folder, |modseqs| modseqs.bump(30)).unwrap(), 7);
+        assert_eq!(ModSeqs::load(&folder, 100).unwrap(), stale);
+        assert!(!folder.join(super::LOCK_FILE).exists());
+
+        // A list which can't be parsed isn't taken to be empty.
+        fs::write(folder.join(super::MODSEQ_FILE), "V100 H\n").unwrap();
+        assert_eq!(ModSeqs::load(&folder, 100).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_needs_pruning() {
+        let mut modseqs = ModSeqs::new(100);
+        let uids: Vec<usize> = (1..super::MAX_EXPUNGED + 2).collect();
+        modseqs.assign_all(&uids);
+        modseqs.retain(&uids[super::MAX_EXPUNGED..]);
+        assert!(!modseqs.needs_pruning());
+        modseqs.retain(&[]);
+        assert!(modseqs.needs_pruning());
+
+        let highest = modseqs.highest();
+        modseqs.reset(200);
+        assert!(!modseqs.needs_pruning());
+        assert!(modseqs.vanished_since(1).is_empty());
+        assert_eq!(modseqs.highest(), highest);
+    }
+}
@@ -0,0 +1,69 @@
+use std::str;
+
+use command::append::{parse_date_time, AppendCommand};
+use parser::grammar::{astring_value, flag_list, is_quoted_char, literal, sp};
+use time::Timespec;
+
+// append = "APPEND" SP mailbox [SP flag-list] [SP date-time] SP literal
+named!(pub append<AppendCommand>,
+    do_parse!(
+        tag_no_case!("APPEND")                           >>
+        sp                                               >>
+        mailbox: astring_value                           >>
+        flags: opt!(complete!(preceded!(sp, flag_list))) >>
+        date: opt!(complete!(preceded!(sp, date_time)))  >>
+        complete!(sp)                                    >>
+        message: literal                                 >>
+        eof!()                                           >>
+
+        (AppendCommand {
+            mailbox: mailbox,
+            flags: flags.unwrap_or_else(Vec::new),
+            date: date,
+            message: message.to_vec()
+        })
+    )
+);
+
+// date-time = DQUOTE date-day-fixed "-" date-month "-" date-year SP time SP zone DQUOTE
+named!(date_time<Timespec>,
+    delimited!(
+        tag!("\""),
+        map_opt!(map_res!(take_while1!(is_quoted_char), str::from_utf8), parse_date_time),
+        tag!("\"")
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult::Done;
+    use time::Timespec;
+
+    use command::append::AppendCommand;
+    use message::Flag::{Keyword, Seen};
+    use super::append;
+
+    #[test]
+    fn test_append() {
+        assert_eq!(append(b"APPEND INBOX {11}\r\nHi\r\n\r\nThere"),
+                   Done(&b""[..], AppendCommand {
+                       mailbox: "INBOX".to_string(),
+                       flags: vec![],
+                       date: None,
+                       message: b"Hi\r\n\r\nThere".to_vec()
+                   }));
+        assert_eq!(append(b"append \"Sent Mail\" (\\Seen $Forwarded) \" 7-Feb-1994 21:52:25 -0800\" {2}\r\nHi"),
+                   Done(&b""[..], AppendCommand {
+                       mailbox: "Sent Mail".to_string(),
+                       flags: vec![Seen, Keyword("$Forwarded".to_string())],
+                       date: Some(Timespec::new(760686745, 0)),
+                       message: b"Hi".to_vec()
+                   }));
+        assert_eq!(append(b"APPEND INBOX () {2}\r\nHi").map(|cmd| cmd.flags), Done(&b""[..], vec![]));
+
+        assert!(append(b"APPEND INBOX").is_err());
+        assert!(append(b"APPEND INBOX (\\Recent) {2}\r\nHi").is_err());
+        assert!(append(b"APPEND INBOX \"7-Feb-1994\" {2}\r\nHi").is_err());
+        assert!(append(b"APPEND INBOX {2}\r\nHi there").is_err());
+    }
+}
@@ -7,6 +7,7 @@ use command::Attribute::{
     Envelope,
     Flags,
     InternalDate,
+    ModSeq,
     RFC822,
     UID
 };
@@ -21,7 +22,7 @@ use mime::Msgtext::{
     MimeMsgtext,
     TextMsgtext,
 };
-use parser::grammar::{astring, number, nz_number, whitespace};
+use parser::grammar::{astring, mod_sequence_value, number, nz_number, sp, whitespace};
 use parser::grammar::sequence::sequence_set;
 use std::ascii::AsciiExt;
 use std::str;
@@ -29,33 +30,83 @@ use std::str;
 named!(pub fetch<FetchCommand>,
     do_parse!(
         tag_no_case!("FETCH") >>
-        whitespace >>
+        sp >>
         set: sequence_set >>
-        whitespace >>
+        sp >>
         attrs: alt!(
             delimited!(
                 tag!("("),
                 do_parse!(
-                    a: fetch_att                                >>
-                    b: many0!(preceded!(whitespace, fetch_att)) >>
+                    a: fetch_item                        >>
+                    b: many0!(preceded!(sp, fetch_item)) >>
 
                     ({
-                        let mut attrs = vec![a];
-                        for attr in b {
-                            attrs.push(attr);
+                        // An attribute named twice, perhaps once through a
+                        // macro, is only sent once.
+                        let mut attrs = Vec::new();
+                        for attr in a.into_iter().chain(b.into_iter().flat_map(|item| item)) {
+                            if !attrs.contains(&attr) {
+                                attrs.push(attr);
+                            }
                         }
                         attrs
                     })
                 ),
                 tag!(")")
             ) |
-            map!(fetch_att, |attr| { vec![attr] }) |
-            map!(tag_no_case!("ALL"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope] }) |
-            map!(tag_no_case!("FULL"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope, Body] }) |
-            map!(tag_no_case!("FAST"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822)] })
+            fetch_item
         ) >>
+        modifiers: opt!(complete!(preceded!(sp, fetch_modifiers))) >>
+
+        ({
+            let mut cmd = FetchCommand::new(set, attrs);
+            for modifier in modifiers.unwrap_or_else(Vec::new) {
+                match modifier {
+                    FetchModifier::ChangedSince(modseq) => cmd.changed_since = Some(modseq),
+                    FetchModifier::Vanished => cmd.vanished = true
+                }
+            }
+            cmd
+        })
+    )
+);
+
+enum FetchModifier {
+    ChangedSince(u64),
+    Vanished
+}
+
+// fetch-modifiers = "(" fetch-modifier *(SP fetch-modifier) ")"
+named!(fetch_modifiers<Vec<FetchModifier>>,
+    delimited!(
+        tag!("("),
+        separated_nonempty_list!(sp, fetch_modifier),
+        tag!(")")
+    )
+);
+
+named!(fetch_modifier<FetchModifier>,
+    alt!(
+        do_parse!(
+            tag_no_case!("CHANGEDSINCE") >>
+            sp                           >>
+            modseq: mod_sequence_value   >>
 
-        ({ FetchCommand::new(set, attrs) })
+            (FetchModifier::ChangedSince(modseq))
+        ) |
+        tag_no_case!("VANISHED") => { |_| { FetchModifier::Vanished } }
+    )
+);
+
+// RFC 3501 only allows the ALL, FULL and FAST macros in place of the whole
+// list of attributes, but some clients send them inside a list such as
+// (FAST UID). They are expanded wherever they appear.
+named!(fetch_item<Vec<Attribute>>,
+    alt!(
+        map!(fetch_att, |attr| { vec![attr] }) |
+        map!(tag_no_case!("ALL"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope] }) |
+        map!(tag_no_case!("FULL"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope, Body] }) |
+        map!(tag_no_case!("FAST"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822)] })
     )
 );
 
@@ -64,12 +115,13 @@ named!(fetch_att<Attribute>,
         complete!(tag_no_case!("ENVELOPE")) => { |_| { Envelope } } |
         complete!(tag_no_case!("FLAGS")) => { |_| { Flags } } |
         complete!(tag_no_case!("INTERNALDATE")) => { |_| { InternalDate } } |
+        complete!(tag_no_case!("MODSEQ")) => { |_| { ModSeq } } |
         do_parse!(
             tag_no_case!("RFC822")                            >>
             sub_attr: opt!(alt!(
-                tag!(".HEADER") => { |_| { HeaderRFC822 } } |
-                tag!(".SIZE") => { |_| { SizeRFC822 } } |
-                tag!(".TEST") => { |_| { TextRFC822 } }
+                tag_no_case!(".HEADER") => { |_| { HeaderRFC822 } } |
+                tag_no_case!(".SIZE") => { |_| { SizeRFC822 } } |
+                tag_no_case!(".TEXT") => { |_| { TextRFC822 } }
             ))                                                >>
 
             ({ RFC822(sub_attr.unwrap_or(AllRFC822)) })
@@ -92,7 +144,7 @@ named!(fetch_att<Attribute>,
                     ({ BodySection(section, octets) })
                 ) |
                 do_parse!(
-                    sub_attr: opt!(tag!("STRUCTURE")) >>
+                    sub_attr: opt!(tag_no_case!("STRUCTURE")) >>
 
                     ({
                         if sub_attr.is_some() {
@@ -171,12 +223,12 @@ named!(section_msgtext<Msgtext>,
 named!(header_list<Vec<String>>,
     delimited!(
         tag!("("),
-        separated_nonempty_list!(whitespace, header_fld_name),
+        separated_nonempty_list!(sp, header_fld_name),
         tag!(")")
     )
 );
 
-named!(header_fld_name<String>,
+named!(pub header_fld_name<String>,
     map!(
         map_res!(astring, str::from_utf8),
         AsciiExt::to_ascii_uppercase
@@ -206,7 +258,9 @@ mod tests {
         Envelope,
         Flags,
         InternalDate,
+        ModSeq,
         RFC822,
+        UID,
     };
     use command::FetchCommand;
     use mime::BodySectionType::{
@@ -290,6 +344,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_fetch_macro_in_list() {
+        assert_eq!(fetch(b"FETCH 1 (FAST UID)"), Done(&b""[..],
+            FetchCommand::new(vec![Number(1)], vec![Flags, InternalDate, RFC822(SizeRFC822), UID])
+        ));
+        assert_eq!(fetch(b"FETCH 1 (all)"), fetch(b"FETCH 1 ALL"));
+        // Attributes which a macro also stands for aren't repeated.
+        assert_eq!(fetch(b"FETCH 1 (FLAGS FULL ENVELOPE)"), Done(&b""[..],
+            FetchCommand::new(vec![Number(1)],
+                              vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope, Body])
+        ));
+    }
+
+    #[test]
+    fn test_fetch_modifiers() {
+        assert_eq!(fetch(b"FETCH 1:* (FLAGS MODSEQ)"), Done(&b""[..],
+            FetchCommand::new(vec![Range(Box::new(Number(1)), Box::new(Wildcard))],
+                              vec![Flags, ModSeq])
+        ));
+        let mut cmd = FetchCommand::new(vec![Wildcard], vec![Flags]);
+        cmd.changed_since = Some(12345);
+        assert_eq!(fetch(b"FETCH * FLAGS (CHANGEDSINCE 12345)"), Done(&b""[..], cmd));
+        let mut cmd = FetchCommand::new(vec![Wildcard], vec![Flags]);
+        cmd.changed_since = Some(12345);
+        cmd.vanished = true;
+        assert_eq!(fetch(b"FETCH * (FLAGS) (changedsince 12345 vanished)"), Done(&b""[..], cmd));
+
+        assert!(fetch(b"FETCH * FLAGS (CHANGEDSINCE 0)").unwrap().0 != &b""[..]);
+        assert!(fetch(b"FETCH * FLAGS ()").unwrap().0 != &b""[..]);
+    }
+
+    #[test]
+    fn test_fetch_extra_spaces() {
+        assert_eq!(fetch(b"FETCH  1:2   (FLAGS  UID BODY.PEEK[HEADER.FIELDS (DATE   FROM)])"),
+                   fetch(b"FETCH 1:2 (FLAGS UID BODY.PEEK[HEADER.FIELDS (DATE FROM)])"));
+        assert_eq!(fetch(b"FETCH 1  FAST"), fetch(b"FETCH 1 FAST"));
+
+        // Spaces aren't allowed within a section.
+        assert!(fetch(b"FETCH 1 BODY[HEADER.FIELDS  (DATE)]") !=
+                fetch(b"FETCH 1 BODY[HEADER.FIELDS (DATE)]"));
+    }
+
     #[test]
     fn test_fetch_case_insensitivity() {
         assert_eq!(
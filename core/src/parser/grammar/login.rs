@@ -0,0 +1,38 @@
+use parser::grammar::{astring_value, sp};
+
+// login = "LOGIN" SP userid SP password
+named!(pub login<(String, String)>,
+    do_parse!(
+        tag_no_case!("LOGIN")   >>
+        sp                      >>
+        userid: astring_value   >>
+        complete!(sp)           >>
+        password: astring_value >>
+        eof!()                  >>
+
+        ((userid, password))
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult::Done;
+    use super::login;
+
+    fn creds(userid: &str, password: &str) -> (String, String) {
+        (userid.to_string(), password.to_string())
+    }
+
+    #[test]
+    fn test_login() {
+        assert_eq!(login(b"LOGIN user@example.com secret"),
+                   Done(&b""[..], creds("user@example.com", "secret")));
+        assert_eq!(login(b"login \"user@example.com\" \"two \\\"words\\\"\""),
+                   Done(&b""[..], creds("user@example.com", "two \"words\"")));
+        assert_eq!(login(b"LOGIN {16}\r\nuser@example.com {7}\r\nsec ret"),
+                   Done(&b""[..], creds("user@example.com", "sec ret")));
+
+        assert!(login(b"LOGIN user@example.com").is_err());
+        assert!(login(b"LOGIN user@example.com secret extra").is_err());
+    }
+}
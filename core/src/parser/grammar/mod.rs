@@ -1,10 +1,21 @@
+use message::{parse_flag, Flag};
 use nom::{crlf, Slice};
 use std::str;
 
+pub use self::append::append;
 pub use self::fetch::fetch;
+pub use self::login::login;
+pub use self::search::search;
+pub use self::select::select_params;
+pub use self::store::store;
 
+mod append;
 mod fetch;
+mod login;
+mod search;
+mod select;
 mod sequence;
+mod store;
 
 const DIGITS: &'static str = "0123456789";
 const NZ_DIGITS: &'static str = "123456789";
@@ -68,12 +79,46 @@ fn is_eol_char(chr: u8) -> bool {
     chr == b'\r' || chr == b'\n'
 }
 
+// tag = 1*<any ASTRING-CHAR except "+">
+// CHAR is 7-bit, so other bytes aren't allowed either.
+pub fn is_tag(tag: &[u8]) -> bool {
+    !tag.is_empty() && tag.iter().all(|&chr| chr < 0x80 && chr != b'+' && is_astring_char(chr))
+}
+
 /* String parsing */
 
 named!(astring<&[u8], &[u8]>, alt!(take_while1!(is_astring_char) | string));
 
 named!(string<&[u8], &[u8]>, alt!(quoted | literal));
 
+// The value an astring stands for, with the backslashes which escape
+// characters in a quoted string removed.
+named!(astring_value<String>,
+    map_res!(
+        alt!(
+            take_while1!(is_astring_char) => { |s: &[u8]| s.to_vec() } |
+            quoted => { unescape } |
+            literal => { |s: &[u8]| s.to_vec() }
+        ),
+        String::from_utf8
+    )
+);
+
+fn unescape(value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(value.len());
+    let mut chars = value.iter();
+    while let Some(&c) = chars.next() {
+        if c == b'\\' {
+            if let Some(&escaped) = chars.next() {
+                res.push(escaped);
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
 named!(quoted<&[u8], &[u8]>,
     delimited!(
         tag!("\""),
@@ -93,23 +138,36 @@ named!(literal<&[u8], &[u8]>,
     do_parse!(
         // The "number" is used to indicate the number of octets.
         number: terminated!(delimited!(tag!("{"), number, tag!("}")), crlf) >>
-        v: recognize!(
-            count!(
-                do_parse!(
-                    // any OCTET except NUL ('%x00')
-                    not!(char!('\x00')) >>
-                    chr: take!(1) >>
-
-                    (chr)
-                ),
-                number
-            )
-        ) >>
+        // any OCTET except NUL ('%x00'). The octets are taken as a single
+        // slice, as a literal can be as large as a whole message.
+        v: map_opt!(take!(number), without_nul) >>
 
         (v)
     )
 );
 
+fn without_nul(value: &[u8]) -> Option<&[u8]> {
+    if value.contains(&0) { None } else { Some(value) }
+}
+
+/* Flag parsing */
+
+// flag-list = "(" [flag *(SP flag)] ")"
+named!(flag_list<Vec<Flag>>,
+    delimited!(tag!("("), separated_list!(sp, flag), tag!(")"))
+);
+
+// flag = "\Answered" / "\Flagged" / "\Deleted" / "\Seen" / "\Draft" /
+//        flag-keyword / flag-extension
+// Flags are atoms, so they can't hold NUL, CR or LF. Unknown flag
+// extensions and \Recent, which can't be set, are rejected.
+named!(flag<Flag>,
+    map_opt!(
+        map_res!(recognize!(pair!(opt!(tag!("\\")), take_while1!(is_atom_char))), str::from_utf8),
+        parse_flag
+    )
+);
+
 /* RFC 3501 Boilerplate */
 
 /// Recognizes an non-zero unsigned 32-bit integer.
@@ -130,6 +188,29 @@ named!(nz_number<usize>,
     )
 );
 
+// Recognizes a non-zero unsigned 63-bit mod-sequence (RFC 7162).
+named!(mod_sequence_value<u64>,
+    flat_map!(
+        recognize!(
+            tuple!(
+                digit_nz,
+                many0!(one_of!(DIGITS))
+            )
+        ),
+        parse_to!(u64)
+    )
+);
+
+// Recognizes a mod-sequence which may be 0, as UNCHANGEDSINCE and the
+// SEARCH MODSEQ key take.
+// mod-sequence-valzer = "0" / mod-sequence-value
+named!(mod_sequence_valzer<u64>,
+    alt!(
+        mod_sequence_value |
+        tag!("0") => { |_| 0 }
+    )
+);
+
 /// Recognizes exactly one non-zero numerical character: 1-9.
 // digit-nz = %x31-39
 //    ; 1-9
@@ -138,22 +219,45 @@ named!(digit_nz<char>, one_of!(NZ_DIGITS));
 /// Recognizes exactly one ASCII whitespace.
 named!(whitespace<char>, char!(' '));
 
+// Recognizes one or more ASCII whitespaces. RFC 3501 asks for exactly one
+// between tokens but some clients send more, so this is used wherever a
+// run of spaces can't be mistaken for anything else.
+named!(sp<&[u8], &[u8]>, take_while1!(is_sp));
+
 #[cfg(test)]
 mod tests {
-    use nom::ErrorKind::{Alt, Char, Count, OneOf, TakeWhile1, MapOpt, Tag};
+    use nom::ErrorKind::{Alt, Char, OneOf, TakeWhile1, MapOpt, Tag};
     use nom::Needed::Size;
     use nom::IResult::{Done, Error, Incomplete};
+    use message::Flag::{Deleted, Keyword, Seen};
     use super::{
         astring,
+        astring_value,
         digit_nz,
+        flag,
+        flag_list,
+        is_tag,
         literal,
         number,
         nz_number,
         quoted,
+        sp,
         string,
         whitespace
     };
 
+    #[test]
+    fn test_is_tag() {
+        assert!(is_tag(b"a1"));
+        assert!(is_tag(b"A.001]"));
+        assert!(!is_tag(b""));
+        assert!(!is_tag(b"a+1"));
+        assert!(!is_tag(b"a*"));
+        assert!(!is_tag(b"\"a\""));
+        assert!(!is_tag(b"a\x01"));
+        assert!(!is_tag(b"caf\xc3\xa9"));
+    }
+
     #[test]
     fn test_astring() {
         assert_eq!(astring(b""), Incomplete(Size(1)));
@@ -165,6 +269,14 @@ mod tests {
         assert_eq!(astring(b"{3}\r\nabc\x00"), Done(&b"\x00"[..], &b"abc"[..]));
     }
 
+    #[test]
+    fn test_astring_value() {
+        assert_eq!(astring_value(b"abc "), Done(&b" "[..], "abc".to_string()));
+        assert_eq!(astring_value(b"\"a \\\"b\\\\\""), Done(&b""[..], "a \"b\\".to_string()));
+        // Backslashes in a literal are part of its value.
+        assert_eq!(astring_value(b"{4}\r\na\\ b"), Done(&b""[..], "a\\ b".to_string()));
+    }
+
     #[test]
     fn test_string() {
         assert_eq!(string(b"\"test\""), Done(&b""[..], &b"test"[..]));
@@ -190,7 +302,7 @@ mod tests {
         assert_eq!(literal(b"{0}\r\n"), Done(&b""[..], &b""[..]));
         assert_eq!(literal(b"{1}\r\na"), Done(&b""[..], &b"a"[..]));
         assert_eq!(literal(b"{2}\r\na"), Incomplete(Size(7)));
-        assert_eq!(literal(b"{2}\r\na\x00a"), Error(Count));
+        assert_eq!(literal(b"{2}\r\na\x00a"), Error(MapOpt));
     }
 
     #[test]
@@ -232,4 +344,29 @@ mod tests {
         assert_eq!(whitespace(b" "), Done(&b""[..], ' '));
         assert_eq!(whitespace(b"\t"), Error(Char));
     }
+
+    #[test]
+    fn test_sp() {
+        assert_eq!(sp(b""), Incomplete(Size(1)));
+        assert_eq!(sp(b"a"), Error(TakeWhile1));
+        assert_eq!(sp(b" a"), Done(&b"a"[..], &b" "[..]));
+        assert_eq!(sp(b"   a"), Done(&b"a"[..], &b"   "[..]));
+        assert_eq!(sp(b"\t"), Error(TakeWhile1));
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(flag(b"\\seen)"), Done(&b")"[..], Seen));
+        assert_eq!(flag(b"$Forwarded "), Done(&b" "[..], Keyword("$Forwarded".to_string())));
+        assert!(flag(b"\\Recent ").is_err());
+        assert!(flag(b"\\Unknown ").is_err());
+
+        assert_eq!(flag_list(b"(\\Seen $Junk \\DELETED)"),
+                   Done(&b""[..], vec![Seen, Keyword("$Junk".to_string()), Deleted]));
+        assert_eq!(flag_list(b"()"), Done(&b""[..], vec![]));
+        assert!(!flag_list(b"(\\Seen").is_done());
+        // Flags are atoms, which can't hold NUL, CR or LF.
+        assert!(flag_list(b"($Ju\0nk)").is_err());
+        assert!(flag_list(b"($Ju\r\nnk)").is_err());
+    }
 }
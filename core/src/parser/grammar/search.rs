@@ -0,0 +1,327 @@
+use command::search::{parse_imap_date, DateComparison, SearchCommand, SearchKey, SearchReturn};
+use command::search::SearchKey::{
+    All,
+    And,
+    Body,
+    Date,
+    Header,
+    Larger,
+    ModSeq,
+    Not,
+    Or,
+    Recent,
+    Sequence,
+    Smaller,
+    Text,
+    Uid
+};
+use message::Flag::{self, Answered, Deleted, Draft, Flagged, Keyword, Seen};
+use parser::grammar::{astring_value, is_atom_char, mod_sequence_valzer, number, sp};
+use parser::grammar::fetch::header_fld_name;
+use parser::grammar::sequence::sequence_set;
+use std::str;
+use time::Tm;
+
+named!(pub search<SearchCommand>,
+    do_parse!(
+        tag_no_case!("SEARCH")                             >>
+        return_options: opt!(complete!(preceded!(
+            sp,
+            search_return_opts
+        )))                                                >>
+        charset: opt!(complete!(preceded!(
+            tuple!(sp, tag_no_case!("CHARSET"), sp),
+            search_string
+        )))                                                >>
+        keys: many1!(complete!(preceded!(sp, search_key))) >>
+        eof!()                                             >>
+
+        ({
+            let mut cmd = SearchCommand::new(charset, keys);
+            cmd.return_options = return_options;
+            cmd
+        })
+    )
+);
+
+// search-return-opts = SP "RETURN" SP "(" [search-return-opt
+//                      *(SP search-return-opt)] ")"
+named!(search_return_opts<Vec<SearchReturn>>,
+    delimited!(
+        tuple!(tag_no_case!("RETURN"), sp, tag!("(")),
+        separated_list!(sp, search_return_opt),
+        tag!(")")
+    )
+);
+
+named!(search_return_opt<SearchReturn>,
+    alt!(
+        tag_no_case!("MIN") => { |_| SearchReturn::Min } |
+        tag_no_case!("MAX") => { |_| SearchReturn::Max } |
+        tag_no_case!("COUNT") => { |_| SearchReturn::Count } |
+        tag_no_case!("ALL") => { |_| SearchReturn::All }
+    )
+);
+
+named!(search_key<SearchKey>,
+    alt!(
+        complete!(tag_no_case!("ALL")) => { |_| All } |
+        complete!(tag_no_case!("ANSWERED")) => { |_| SearchKey::Flag(Answered) } |
+        complete!(tag_no_case!("DELETED")) => { |_| SearchKey::Flag(Deleted) } |
+        complete!(tag_no_case!("DRAFT")) => { |_| SearchKey::Flag(Draft) } |
+        complete!(tag_no_case!("FLAGGED")) => { |_| SearchKey::Flag(Flagged) } |
+        complete!(tag_no_case!("NEW")) => {
+            |_| And(vec![Recent, not_flag(Seen)])
+        } |
+        complete!(tag_no_case!("OLD")) => { |_| Not(Box::new(Recent)) } |
+        complete!(tag_no_case!("RECENT")) => { |_| Recent } |
+        complete!(tag_no_case!("SEEN")) => { |_| SearchKey::Flag(Seen) } |
+        complete!(tag_no_case!("UNANSWERED")) => { |_| not_flag(Answered) } |
+        complete!(tag_no_case!("UNDELETED")) => { |_| not_flag(Deleted) } |
+        complete!(tag_no_case!("UNDRAFT")) => { |_| not_flag(Draft) } |
+        complete!(tag_no_case!("UNFLAGGED")) => { |_| not_flag(Flagged) } |
+        complete!(tag_no_case!("UNSEEN")) => { |_| not_flag(Seen) } |
+        preceded!(pair!(tag_no_case!("KEYWORD"), sp), flag_keyword) => {
+            |flag| SearchKey::Flag(flag)
+        } |
+        preceded!(pair!(tag_no_case!("UNKEYWORD"), sp), flag_keyword) => {
+            |flag| not_flag(flag)
+        } |
+        preceded!(pair!(tag_no_case!("NOT"), sp), search_key) => {
+            |key| Not(Box::new(key))
+        } |
+        do_parse!(
+            tag_no_case!("OR") >>
+            sp                 >>
+            a: search_key      >>
+            sp                 >>
+            b: search_key      >>
+
+            (Or(Box::new(a), Box::new(b)))
+        ) |
+        // A parenthesized list of keys must all match.
+        delimited!(
+            tag!("("),
+            separated_nonempty_list!(sp, search_key),
+            tag!(")")
+        ) => { |keys| And(keys) } |
+        preceded!(pair!(tag_no_case!("BEFORE"), sp), date) => {
+            |date| Date(DateComparison::Before, date)
+        } |
+        preceded!(pair!(tag_no_case!("ON"), sp), date) => {
+            |date| Date(DateComparison::On, date)
+        } |
+        preceded!(pair!(tag_no_case!("SINCE"), sp), date) => {
+            |date| Date(DateComparison::Since, date)
+        } |
+        preceded!(pair!(tag_no_case!("LARGER"), sp), number) => { |n| Larger(n) } |
+        preceded!(pair!(tag_no_case!("SMALLER"), sp), number) => { |n| Smaller(n) } |
+        address_key |
+        do_parse!(
+            tag_no_case!("HEADER") >>
+            sp                     >>
+            field: header_fld_name >>
+            sp                     >>
+            value: search_string   >>
+
+            (Header(field, value))
+        ) |
+        preceded!(pair!(tag_no_case!("BODY"), sp), search_string) => { |s| Body(s) } |
+        preceded!(pair!(tag_no_case!("TEXT"), sp), search_string) => { |s| Text(s) } |
+        preceded!(pair!(tag_no_case!("UID"), sp), sequence_set) => { |set| Uid(set) } |
+        do_parse!(
+            tag_no_case!("MODSEQ")          >>
+            opt!(complete!(modseq_entry))   >>
+            sp                              >>
+            modseq: mod_sequence_valzer     >>
+
+            (ModSeq(modseq))
+        ) |
+        sequence_set => { |set| Sequence(set) }
+    )
+);
+
+// The metadata item a MODSEQ key names, which is ignored as each message
+// has only the one mod-sequence.
+// search-modseq-ext = SP entry-name SP entry-type-req
+named!(modseq_entry<()>,
+    do_parse!(
+        sp            >>
+        search_string >>
+        sp            >>
+        alt!(tag_no_case!("priv") | tag_no_case!("shared") | tag_no_case!("all")) >>
+
+        (())
+    )
+);
+
+fn not_flag(flag: Flag) -> SearchKey {
+    Not(Box::new(SearchKey::Flag(flag)))
+}
+
+// FROM, TO, CC, BCC and SUBJECT are searches of that header field.
+named!(address_key<SearchKey>,
+    do_parse!(
+        field: alt!(
+            tag_no_case!("BCC") |
+            tag_no_case!("CC") |
+            tag_no_case!("FROM") |
+            tag_no_case!("SUBJECT") |
+            tag_no_case!("TO")
+        )                    >>
+        sp                   >>
+        value: search_string >>
+
+        ({
+            let field = str::from_utf8(field).unwrap_or("").to_ascii_uppercase();
+            Header(field, value)
+        })
+    )
+);
+
+named!(flag_keyword<Flag>,
+    map!(
+        map_res!(take_while1!(is_atom_char), str::from_utf8),
+        |keyword: &str| Keyword(keyword.to_string())
+    )
+);
+
+// date = date-text / DQUOTE date-text DQUOTE
+named!(date<Tm>,
+    alt!(
+        delimited!(tag!("\""), date_text, tag!("\"")) |
+        date_text
+    )
+);
+
+named!(date_text<Tm>,
+    map_opt!(
+        map_res!(take_while1!(is_date_char), str::from_utf8),
+        parse_imap_date
+    )
+);
+
+fn is_date_char(chr: u8) -> bool {
+    (chr as char).is_ascii_alphanumeric() || chr == b'-'
+}
+
+named!(search_string<String>, call!(astring_value));
+
+#[cfg(test)]
+mod tests {
+    use command::search::DateComparison::{Before, Since};
+    use command::search::SearchKey::{All, And, Body, Date, Header, Larger, Not, Or, Recent, Text};
+    use command::search::SearchKey::{ModSeq, Sequence, Uid};
+    use command::search::{parse_imap_date, SearchCommand, SearchKey};
+    use command::search::SearchReturn::{Count, Max, Min};
+    use command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+    use message::Flag::{Deleted, Draft, Flagged, Keyword, Seen};
+    use nom::IResult::Done;
+    use super::{not_flag, search};
+
+    fn keys(keys: Vec<SearchKey>) -> SearchCommand {
+        SearchCommand::new(None, keys)
+    }
+
+    #[test]
+    fn test_search() {
+        assert_eq!(search(b"SEARCH ALL"), Done(&b""[..], keys(vec![All])));
+        assert_eq!(search(b"search unseen Undeleted KEYWORD $Junk LARGER 100"),
+                   Done(&b""[..], keys(vec![not_flag(Seen), not_flag(Deleted),
+                                       SearchKey::Flag(Keyword("$Junk".to_string())),
+                                       Larger(100)])));
+        assert_eq!(search(b"SEARCH SINCE 1-Feb-1994 BEFORE \"10-Mar-1994\""),
+                   Done(&b""[..], keys(vec![Date(Since, parse_imap_date("1-Feb-1994").unwrap()),
+                                       Date(Before, parse_imap_date("10-Mar-1994").unwrap())])));
+
+        // Unknown keys, bad dates and trailing garbage aren't accepted.
+        assert!(search(b"SEARCH").is_err());
+        assert!(search(b"SEARCH BOGUS").is_err());
+        assert!(search(b"SEARCH ON 31-Feb-2000").is_err());
+        assert!(search(b"SEARCH ALLX").is_err());
+    }
+
+    #[test]
+    fn test_search_strings() {
+        assert_eq!(search(b"SEARCH FROM smith SUBJECT \"a \\\"quoted\\\" word\""),
+                   Done(&b""[..], keys(vec![Header("FROM".to_string(), "smith".to_string()),
+                                       Header("SUBJECT".to_string(),
+                                              "a \"quoted\" word".to_string())])));
+        assert_eq!(search(b"SEARCH HEADER Message-ID \"<1@example.com>\""),
+                   Done(&b""[..], keys(vec![Header("MESSAGE-ID".to_string(),
+                                              "<1@example.com>".to_string())])));
+        assert_eq!(search(b"SEARCH BODY hello TEXT \"\""),
+                   Done(&b""[..], keys(vec![Body("hello".to_string()), Text("".to_string())])));
+    }
+
+    #[test]
+    fn test_search_charset_and_sets() {
+        assert_eq!(search(b"SEARCH CHARSET utf-8 SEEN"),
+                   Done(&b""[..], SearchCommand::new(Some("utf-8".to_string()),
+                                                     vec![SearchKey::Flag(Seen)])));
+        assert_eq!(search(b"SEARCH UID 2,4:* 1:3"),
+                   Done(&b""[..], keys(vec![
+                       Uid(vec![Number(2), Range(Box::new(Number(4)), Box::new(Wildcard))]),
+                       Sequence(vec![Range(Box::new(Number(1)), Box::new(Number(3)))])
+                   ])));
+        assert_eq!(search(b"SEARCH 5 UNSEEN"),
+                   Done(&b""[..], keys(vec![Sequence(vec![Number(5)]), not_flag(Seen)])));
+        assert!(search(b"SEARCH CHARSET").is_err());
+        assert!(search(b"SEARCH UID").is_err());
+    }
+
+    #[test]
+    fn test_search_modseq() {
+        assert_eq!(search(b"SEARCH MODSEQ 620162338 SEEN"),
+                   Done(&b""[..], keys(vec![ModSeq(620162338), SearchKey::Flag(Seen)])));
+        assert_eq!(search(b"SEARCH modseq \"/flags/\\\\draft\" all 0"),
+                   Done(&b""[..], keys(vec![ModSeq(0)])));
+        assert!(search(b"SEARCH MODSEQ").is_err());
+        assert!(search(b"SEARCH MODSEQ \"/flags/\\\\draft\" 5").is_err());
+    }
+
+    #[test]
+    fn test_search_combinators() {
+        assert_eq!(search(b"SEARCH OR SEEN FLAGGED"),
+                   Done(&b""[..], keys(vec![Or(Box::new(SearchKey::Flag(Seen)),
+                                               Box::new(SearchKey::Flag(Flagged)))])));
+        assert_eq!(search(b"SEARCH NOT (DELETED FROM bob) NEW"),
+                   Done(&b""[..], keys(vec![
+                       Not(Box::new(And(vec![SearchKey::Flag(Deleted),
+                                             Header("FROM".to_string(), "bob".to_string())]))),
+                       And(vec![Recent, not_flag(Seen)])
+                   ])));
+        // OR nests, taking exactly two keys each time.
+        assert_eq!(search(b"SEARCH OR OR SEEN DRAFT NOT ALL"),
+                   Done(&b""[..], keys(vec![
+                       Or(Box::new(Or(Box::new(SearchKey::Flag(Seen)),
+                                      Box::new(SearchKey::Flag(Draft)))),
+                          Box::new(Not(Box::new(All))))
+                   ])));
+
+        assert!(search(b"SEARCH OR SEEN").is_err());
+        assert!(search(b"SEARCH NOT").is_err());
+        assert!(search(b"SEARCH (SEEN").is_err());
+        assert!(search(b"SEARCH ()").is_err());
+    }
+
+    #[test]
+    fn test_search_return() {
+        let mut cmd = keys(vec![SearchKey::Flag(Seen)]);
+        cmd.return_options = Some(vec![Min, Max, Count]);
+        assert_eq!(search(b"SEARCH RETURN (MIN max COUNT) SEEN"), Done(&b""[..], cmd));
+        let mut cmd = SearchCommand::new(Some("UTF-8".to_string()), vec![All]);
+        cmd.return_options = Some(vec![]);
+        assert_eq!(search(b"SEARCH RETURN () CHARSET UTF-8 ALL"), Done(&b""[..], cmd));
+
+        assert!(search(b"SEARCH RETURN (MIN)").is_err());
+        assert!(search(b"SEARCH RETURN (FIRST) ALL").is_err());
+        assert!(search(b"SEARCH RETURN MIN ALL").is_err());
+    }
+
+    #[test]
+    fn test_search_extra_spaces() {
+        assert_eq!(search(b"SEARCH  OR   SEEN  (FLAGGED   FROM  bob)"),
+                   search(b"SEARCH OR SEEN (FLAGGED FROM bob)"));
+    }
+}
@@ -0,0 +1,66 @@
+use command::select::{Qresync, SelectParams};
+use command::sequence_set::SequenceItem;
+use parser::grammar::{mod_sequence_value, nz_number, sp};
+use parser::grammar::sequence::sequence_set;
+
+// select-params = "(" select-param *(SP select-param) ")"
+named!(pub select_params<SelectParams>,
+    do_parse!(
+        params: delimited!(
+            tag!("("),
+            separated_nonempty_list!(sp, select_param),
+            tag!(")")
+        )      >>
+        eof!() >>
+
+        ({
+            let mut res = SelectParams::default();
+            for param in params {
+                res.condstore = res.condstore || param.condstore;
+                if param.qresync.is_some() {
+                    res.qresync = param.qresync;
+                }
+            }
+            res
+        })
+    )
+);
+
+named!(select_param<SelectParams>,
+    alt!(
+        complete!(tag_no_case!("CONDSTORE")) => { |_| {
+            SelectParams { condstore: true, qresync: None }
+        } } |
+        do_parse!(
+            tag_no_case!("QRESYNC")                                  >>
+            sp                                                       >>
+            tag!("(")                                                >>
+            uid_validity: nz_number                                  >>
+            sp                                                       >>
+            modseq: mod_sequence_value                               >>
+            known_uids: opt!(complete!(preceded!(sp, sequence_set))) >>
+            // Which UIDs the client has for some sequence numbers only helps
+            // a server which doesn't know what has been expunged.
+            opt!(complete!(preceded!(sp, seq_match_data)))           >>
+            tag!(")")                                                >>
+
+            (SelectParams {
+                condstore: false,
+                qresync: Some(Qresync {
+                    uid_validity: uid_validity,
+                    modseq: modseq,
+                    known_uids: known_uids
+                })
+            })
+        )
+    )
+);
+
+// seq-match-data = "(" known-sequence-set SP known-uid-set ")"
+named!(seq_match_data<(Vec<SequenceItem>, Vec<SequenceItem>)>,
+    delimited!(
+        tag!("("),
+        separated_pair!(sequence_set, sp, sequence_set),
+        tag!(")")
+    )
+);
@@ -0,0 +1,144 @@
+use command::store::StoreCommand;
+use command::store::StoreName::{Add, Replace, Sub};
+use message::Flag;
+use parser::grammar::{flag, flag_list, mod_sequence_valzer, sp};
+use parser::grammar::sequence::sequence_set;
+
+// store = "STORE" SP sequence-set [store-modifiers] SP store-att-flags
+// store-modifiers = SP "(" "UNCHANGEDSINCE" SP mod-sequence-valzer ")"
+// store-att-flags = (["+" / "-"] "FLAGS" [".SILENT"]) SP
+//                   (flag-list / (flag *(SP flag)))
+named!(pub store<StoreCommand>,
+    do_parse!(
+        tag_no_case!("STORE")                             >>
+        sp                                                >>
+        set: sequence_set                                 >>
+        unchanged_since: opt!(complete!(unchanged_since)) >>
+        sp                                                >>
+        action: alt!(
+            tag!("+") => { |_| Add } |
+            tag!("-") => { |_| Sub } |
+            value!(Replace)
+        )                                                 >>
+        tag_no_case!("FLAGS")                             >>
+        silent: opt!(complete!(tag_no_case!(".SILENT")))  >>
+        sp                                                >>
+        flags: store_flags                                >>
+
+        (StoreCommand {
+            sequence_set: set,
+            action: action,
+            silent: silent.is_some(),
+            flags: flags,
+            unchanged_since: unchanged_since
+        })
+    )
+);
+
+// The only store modifier is UNCHANGEDSINCE (RFC 7162).
+named!(unchanged_since<u64>,
+    delimited!(
+        tuple!(sp, tag!("("), tag_no_case!("UNCHANGEDSINCE"), sp),
+        mod_sequence_valzer,
+        tag!(")")
+    )
+);
+
+// The flags may be given as a flag-list or separated by spaces, and make up
+// the rest of the command.
+named!(store_flags<Vec<Flag>>,
+    terminated!(
+        alt!(flag_list | separated_nonempty_list!(complete!(sp), flag)),
+        eof!()
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use command::store::StoreCommand;
+    use command::store::StoreName::{Add, Replace, Sub};
+    use command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+    use message::Flag::{Deleted, Keyword, Seen};
+    use nom::IResult::Done;
+    use super::store;
+
+    #[test]
+    fn test_store() {
+        assert_eq!(store(b"STORE 1:* +FLAGS (\\Seen $Junk)"), Done(&b""[..], StoreCommand {
+            sequence_set: vec![Range(Box::new(Number(1)), Box::new(Wildcard))],
+            action: Add,
+            silent: false,
+            flags: vec![Seen, Keyword("$Junk".to_string())],
+            unchanged_since: None
+        }));
+        assert_eq!(store(b"store 2,4 -flags.silent \\Deleted"), Done(&b""[..], StoreCommand {
+            sequence_set: vec![Number(2), Number(4)],
+            action: Sub,
+            silent: true,
+            flags: vec![Deleted],
+            unchanged_since: None
+        }));
+        // Replacing the flags with none clears them.
+        assert_eq!(store(b"STORE 3 FLAGS ()"), Done(&b""[..], StoreCommand {
+            sequence_set: vec![Number(3)],
+            action: Replace,
+            silent: false,
+            flags: vec![],
+            unchanged_since: None
+        }));
+        assert_eq!(store(b"STORE 3 FLAGS \\Seen  $Junk"),
+                   store(b"STORE 3 FLAGS (\\Seen $Junk)"));
+        // A conditional STORE (RFC 7162)
+        assert_eq!(store(b"STORE 1,3 (UNCHANGEDSINCE 320162338) +FLAGS.SILENT (\\Deleted)"),
+                   Done(&b""[..], StoreCommand {
+                       sequence_set: vec![Number(1), Number(3)],
+                       action: Add,
+                       silent: true,
+                       flags: vec![Deleted],
+                       unchanged_since: Some(320162338)
+                   }));
+        assert_eq!(store(b"STORE 1 (unchangedsince 0) FLAGS ()").map(|cmd| cmd.unchanged_since),
+                   Done(&b""[..], Some(0)));
+
+        assert!(!store(b"STORE 3 FLAGS").is_done());
+        assert!(!store(b"STORE 0 FLAGS (\\Seen)").is_done());
+        assert!(!store(b"STORE 3 *FLAGS (\\Seen)").is_done());
+        assert!(!store(b"STORE 3 FLAGS.LOUD (\\Seen)").is_done());
+        assert!(!store(b"STORE 3 (UNCHANGEDSINCE 01) FLAGS (\\Seen)").is_done());
+        assert!(!store(b"STORE 3 (CHANGEDSINCE 1) FLAGS (\\Seen)").is_done());
+        assert!(!store(b"STORE 3 FLAGS (\\Recent)").is_done());
+        assert!(!store(b"STORE 3 FLAGS (\\Seen) $Junk").is_done());
+        // Flags are atoms, which can't hold NUL, CR or LF.
+        assert!(!store(b"STORE 3 FLAGS (\\Se\0en)").is_done());
+        assert!(!store(b"STORE 3 FLAGS ($Ju\r\nnk)").is_done());
+    }
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+
+    use command::store::StoreCommand;
+    use command::store::StoreName::Add;
+    use command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+    use message::Flag::{Flagged, Keyword, Seen};
+    use nom::IResult::Done;
+    use self::test::Bencher;
+    use super::store;
+
+    #[bench]
+    fn bench_store(b: &mut Bencher) {
+        const STORE_STR: &'static str = "STORE 4,5:3,* +FLAGS.SILENT (\\Seen \\Flagged $Forwarded)";
+
+        b.iter(|| {
+            assert_eq!(store(STORE_STR.as_bytes()), Done(&b""[..], StoreCommand {
+                sequence_set: vec![Number(4), Range(Box::new(Number(5)), Box::new(Number(3))),
+                                   Wildcard],
+                action: Add,
+                silent: true,
+                flags: vec![Seen, Flagged, Keyword("$Forwarded".to_string())],
+                unchanged_since: None
+            }));
+        });
+    }
+}
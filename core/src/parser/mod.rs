@@ -1,8 +1,13 @@
 use command::FetchCommand;
+use command::append::AppendCommand;
+use command::search::SearchCommand;
+use command::select::SelectParams;
+use command::store::StoreCommand;
 
 mod error;
 mod grammar;
 
+pub use self::grammar::is_tag;
 pub use self::error::Error as ParserError;
 pub use self::error::Result as ParserResult;
 
@@ -15,3 +20,53 @@ pub fn fetch(input: &[u8]) -> ParserResult<FetchCommand> {
         Error(err) => Err(err).map_err(ParserError::from),
     }
 }
+
+pub fn search(input: &[u8]) -> ParserResult<SearchCommand> {
+    use nom::IResult::{Done, Error, Incomplete};
+
+    match self::grammar::search(input) {
+        Done(_, v) => Ok(v),
+        Incomplete(_) => Err(ParserError::Incomplete),
+        Error(err) => Err(err).map_err(ParserError::from),
+    }
+}
+
+pub fn select_params(input: &[u8]) -> ParserResult<SelectParams> {
+    use nom::IResult::{Done, Error, Incomplete};
+
+    match self::grammar::select_params(input) {
+        Done(_, v) => Ok(v),
+        Incomplete(_) => Err(ParserError::Incomplete),
+        Error(err) => Err(err).map_err(ParserError::from),
+    }
+}
+
+pub fn store(input: &[u8]) -> ParserResult<StoreCommand> {
+    use nom::IResult::{Done, Error, Incomplete};
+
+    match self::grammar::store(input) {
+        Done(_, v) => Ok(v),
+        Incomplete(_) => Err(ParserError::Incomplete),
+        Error(err) => Err(err).map_err(ParserError::from),
+    }
+}
+
+pub fn login(input: &[u8]) -> ParserResult<(String, String)> {
+    use nom::IResult::{Done, Error, Incomplete};
+
+    match self::grammar::login(input) {
+        Done(_, v) => Ok(v),
+        Incomplete(_) => Err(ParserError::Incomplete),
+        Error(err) => Err(err).map_err(ParserError::from),
+    }
+}
+
+pub fn append(input: &[u8]) -> ParserResult<AppendCommand> {
+    use nom::IResult::{Done, Error, Incomplete};
+
+    match self::grammar::append(input) {
+        Done(_, v) => Ok(v),
+        Incomplete(_) => Err(ParserError::Incomplete),
+        Error(err) => Err(err).map_err(ParserError::from),
+    }
+}
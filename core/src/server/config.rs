@@ -1,17 +1,47 @@
-use error::ImapResult;
+use error::{Error, ImapResult};
 use openssl::error::ErrorStack;
 use openssl::pkcs12::Pkcs12;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod};
-use std::io::{Read, Error as IoError, Write};
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{NameType, SslAcceptor, SslContext, SslContextBuilder, SslMethod, SslOptions};
+use openssl::x509::X509;
+use openssl_sys::{self as ffi, SSL_CTX};
+use libc::{c_long, c_void};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io::{Read, Error as IoError, ErrorKind};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use toml;
 
+use maildir::{self, Layout, SpecialUse};
+#[cfg(unix)]
+use super::privileges;
+use super::proxy::AddressRange;
+use super::user::UserBackend;
+
+#[derive(Debug)]
 pub enum PkcsError {
     Io(IoError),
     Ssl(ErrorStack),
-    PortsDisabled
+    PortsDisabled,
+    BadTicketKey(String),
+    NoIdentity(String)
+}
+
+impl fmt::Display for PkcsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PkcsError::Io(ref e) => e.fmt(f),
+            PkcsError::Ssl(ref e) => e.fmt(f),
+            PkcsError::PortsDisabled => write!(f, "No TLS port is enabled"),
+            PkcsError::BadTicketKey(ref file) =>
+                write!(f, "tls_ticket_key_file {} must hold exactly {} bytes", file, TICKET_KEY_LEN),
+            PkcsError::NoIdentity(ref file) =>
+                write!(f, "{} must hold a private key and a certificate", file)
+        }
+    }
 }
 
 impl From<IoError> for PkcsError {
@@ -26,6 +56,87 @@ impl From<ErrorStack> for PkcsError {
     }
 }
 
+/// The oldest TLS version clients may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls1_0,
+    #[serde(rename = "1.1")]
+    Tls1_1,
+    #[serde(rename = "1.2")]
+    Tls1_2
+}
+
+impl Default for TlsVersion {
+    fn default() -> Self {
+        TlsVersion::Tls1_2
+    }
+}
+
+/// A PKCS #12 archive holding a certificate, its chain and private key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Certificate {
+    // Filename of PKCS #12 archive
+    pub pkcs_file: String,
+    // Password for PKCS #12 archive
+    pub pkcs_pass: String,
+}
+
+/// A folder which is created for each user when they first log in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DefaultFolder {
+    // Name of the mailbox
+    pub name: String,
+    // What the folder is for, which LIST tells clients
+    #[serde(default)]
+    pub special_use: Option<SpecialUse>,
+}
+
+fn default_folders() -> Vec<DefaultFolder> {
+    [("Sent", SpecialUse::Sent), ("Drafts", SpecialUse::Drafts),
+     ("Trash", SpecialUse::Trash), ("Junk", SpecialUse::Junk)].iter()
+        .map(|&(name, special_use)| DefaultFolder {
+            name: name.to_string(),
+            special_use: Some(special_use)
+        })
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_literal_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_command_size() -> usize {
+    1024 * 1024
+}
+
+fn default_folder_window() -> usize {
+    1000
+}
+
+fn default_auth_command_timeout() -> u64 {
+    10
+}
+
+fn default_tls_session_lifetime() -> u64 {
+    300
+}
+
+// A session ticket key is a 16 byte name followed by a 32 byte HMAC secret
+// and a 32 byte AES key.
+const TICKET_KEY_LEN: usize = 80;
+
+// SSL_CTX_set_tlsext_ticket_keys is a macro around this control.
+const SSL_CTRL_SET_TLSEXT_TICKET_KEYS: i32 = 59;
+
+extern "C" {
+    fn SSL_CTX_set_timeout(ctx: *mut SSL_CTX, t: c_long) -> c_long;
+}
+
 /// Representation of configuration data for the server
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -39,64 +150,522 @@ pub struct Config {
     pub lmtp_ssl_port: Option<u16>,
     // SSL port on which to listen for IMAP
     pub imap_ssl_port: Option<u16>,
+    // Where user data comes from: "json" or "command"
+    #[serde(default)]
+    pub user_backend: UserBackend,
     // file in which user data is stored
     pub users: String,
+    // Helper program which checks logins for the "command" user backend
+    #[serde(default)]
+    pub auth_command: Option<String>,
+    // Seconds the auth command may take before it is killed
+    #[serde(default = "default_auth_command_timeout")]
+    pub auth_command_timeout: u64,
     // Filename of PKCS #12 archive
     pub pkcs_file: String,
     // Password for PKCS #12 archive
     pub pkcs_pass: String,
+    // Certificates presented instead of the one in pkcs_file to clients
+    // asking for these host names with SNI
+    #[serde(default)]
+    pub sni_certificates: HashMap<String, Certificate>,
+    // Oldest TLS version accepted: "1.0", "1.1" or "1.2"
+    #[serde(default)]
+    pub tls_min_version: TlsVersion,
+    // OpenSSL cipher list to use in place of the default one
+    #[serde(default)]
+    pub tls_ciphers: Option<String>,
+    // Whether to choose ciphers in the server's order of preference rather
+    // than the client's
+    #[serde(default = "default_true")]
+    pub tls_prefer_server_ciphers: bool,
+    // Whether clients may resume sessions with RFC 5077 session tickets
+    // rather than going through a full handshake on every connection
+    #[serde(default = "default_true")]
+    pub tls_session_tickets: bool,
+    // Seconds for which a session may be resumed, from its cache entry or
+    // its ticket
+    #[serde(default = "default_tls_session_lifetime")]
+    pub tls_session_lifetime: u64,
+    // File holding the 80 byte key which session tickets are encrypted
+    // with, shared between servers behind a load balancer. A random key is
+    // made whenever the certificates are loaded if unset.
+    #[serde(default)]
+    pub tls_ticket_key_file: Option<String>,
+    // Whether to decode RFC 2047 encoded-words in ENVELOPE responses
+    #[serde(default)]
+    pub decode_headers: bool,
+    // Whether messages stored with bare LF line endings are sent with CRLF
+    // endings, as IMAP requires, rather than exactly as they are stored
+    #[serde(default)]
+    pub normalize_line_endings: bool,
+    // Whether messages are expunged as soon as STORE marks them \Deleted
+    // rather than waiting for EXPUNGE or CLOSE
+    #[serde(default)]
+    pub auto_expunge_on_delete: bool,
+    // Whether messages are left in new/, and reported as \Recent whenever
+    // their folder is selected, until their flags are first changed
+    #[serde(default)]
+    pub keep_new_until_seen: bool,
+    // How many messages of a selected folder may have changed flags waiting
+    // to be written to disk. Other messages are only read from their
+    // filenames while a command needs them.
+    #[serde(default = "default_folder_window")]
+    pub folder_window: usize,
+    // How mail folders are laid out within each maildir: "fs" or "maildir++"
+    #[serde(default)]
+    pub maildir_layout: Layout,
+    // Folders created alongside INBOX when a user first logs in
+    #[serde(default = "default_folders")]
+    pub default_folders: Vec<DefaultFolder>,
+    // Directory which relative user maildir paths are taken to be within
+    #[serde(default)]
+    pub maildir_root: Option<String>,
+    // Largest message in bytes accepted over LMTP or by APPEND, or no limit
+    // if unset
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+    // Largest literal in bytes accepted in an IMAP command other than APPEND
+    #[serde(default = "default_max_literal_size")]
+    pub max_literal_size: usize,
+    // Largest IMAP command in bytes, counting its lines and literals but not
+    // the message given to APPEND
+    #[serde(default = "default_max_command_size")]
+    pub max_command_size: usize,
+    // Whether LMTP deliveries get a Delivered-To header naming the recipient
+    #[serde(default = "default_true")]
+    pub delivered_to_header: bool,
+    // Address ranges of load balancers which announce the client's address
+    // with a PROXY protocol header at the start of each connection
+    #[serde(default)]
+    pub trust_proxy_from: Vec<String>,
+    // User and group to switch to once the listeners have been bound, so
+    // that only binding the ports is done as root. The group defaults to
+    // the user's primary group.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    // Whether to chroot to maildir_root before switching user. Maildirs in
+    // the users file must then be relative paths, the users file and
+    // certificates must be within maildir_root so that they can be reloaded,
+    // and the command user backend can't be used.
+    #[serde(default)]
+    pub chroot: bool,
 }
 
 impl Config {
-    pub fn new() -> ImapResult<Config> {
-        let path = Path::new("./config.toml");
-
-        let config = match File::open(&path) {
-            Ok(mut file) => {
-                let mut encoded: String = String::new();
-                match file.read_to_string(&mut encoded) {
-                    Ok(_) => match toml::from_str(&encoded) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            // Use default values if parsing failed.
-                            warn!("Failed to parse config.toml.\nUsing default values: {}", e);
-                            Config::default()
-                        },
-                    },
-                    Err(e) => {
-                        // Use default values if reading failed.
-                        warn!("Failed to read config.toml.\nUsing default values: {}", e);
-                        Config::default()
-                    },
-                }
-            },
-            Err(e) => {
-                // Create a default config file if it doesn't exist
-                warn!("Failed to open config.toml; creating from defaults: {}", e);
-                let config = Config::default();
-                let encoded = toml::to_string(&config)?;
-                let mut file = File::create(&path)?;
-                file.write_all(encoded.as_bytes())?;
-                config
-            },
+    /// Load the config from `path`, or from the first of the usual places
+    /// which holds a config file if no path is given.
+    pub fn new(path: Option<&Path>) -> ImapResult<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => find_config(config_paths())?
         };
+        info!("Reading config from {}", path.display());
+
+        let mut encoded = String::new();
+        File::open(&path)?.read_to_string(&mut encoded)?;
+        let config: Config = match toml::from_str(&encoded) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::InvalidConfig(format!("{}: {}", path.display(), e)))
+        };
+        if let Err(problems) = config.validate() {
+            return Err(Error::InvalidConfig(format!("{}: {}", path.display(), problems.join("; "))));
+        }
 
         Ok(config)
     }
 
+    /// Find a user's maildir. Relative paths are within `maildir_root` if it
+    /// is set and are otherwise left as they are.
+    pub fn resolve_maildir(&self, maildir: &str) -> PathBuf {
+        match self.maildir_root {
+            Some(ref root) if Path::new(maildir).is_relative() => Path::new(root).join(maildir),
+            _ => PathBuf::from(maildir)
+        }
+    }
+
+    /// The special uses of the default folders, by mailbox name.
+    pub fn special_use(&self) -> HashMap<String, SpecialUse> {
+        self.default_folders.iter()
+            .filter_map(|folder| folder.special_use.map(|special_use| (folder.name.clone(), special_use)))
+            .collect()
+    }
+
+    /// Check that the settings make sense together, so that the server fails
+    /// at startup rather than misbehaving later. Returns a description of
+    /// each problem found.
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let ports = [("imap_port", self.imap_port), ("imap_ssl_port", self.imap_ssl_port),
+                     ("lmtp_port", self.lmtp_port), ("lmtp_ssl_port", self.lmtp_ssl_port)];
+
+        if ports.iter().all(|&(_, port)| port.is_none()) {
+            problems.push("no listener is enabled; set at least one of imap_port, \
+                           imap_ssl_port, lmtp_port or lmtp_ssl_port".to_string());
+        }
+        for (i, &(name, port)) in ports.iter().enumerate() {
+            let port = match port {
+                Some(port) => port,
+                None => continue
+            };
+            if port == 0 {
+                problems.push(format!("{} must be between 1 and 65535", name));
+            } else if let Some(&(other, _)) = ports[..i].iter().find(|&&(_, other)| other == Some(port)) {
+                problems.push(format!("{} and {} are both set to {}", other, name, port));
+            }
+            if name.ends_with("_ssl_port") && self.pkcs_file.is_empty() {
+                problems.push(format!("{} set but no pkcs_file configured", name));
+            }
+        }
+        if !self.pkcs_file.is_empty() {
+            if let Err(e) = File::open(&self.pkcs_file) {
+                problems.push(format!("pkcs_file {} can't be read: {}", self.pkcs_file, e));
+            }
+        }
+        let mut hosts: Vec<&String> = self.sni_certificates.keys().collect();
+        hosts.sort();
+        for host in hosts {
+            let pkcs_file = &self.sni_certificates[host].pkcs_file;
+            if let Err(e) = File::open(pkcs_file) {
+                problems.push(format!("pkcs_file {} for {} can't be read: {}", pkcs_file, host, e));
+            }
+        }
+
+        if let Some(ref ciphers) = self.tls_ciphers {
+            if ciphers.trim().is_empty() {
+                problems.push("tls_ciphers is empty".to_string());
+            }
+        }
+
+        if let Some(ref file) = self.tls_ticket_key_file {
+            match read_ticket_key(file) {
+                Ok(_) => {}
+                Err(PkcsError::Io(e)) =>
+                    problems.push(format!("tls_ticket_key_file {} can't be read: {}", file, e)),
+                Err(e) => problems.push(e.to_string())
+            }
+        }
+        if self.tls_session_lifetime == 0 {
+            problems.push("tls_session_lifetime must be at least 1 second".to_string());
+        }
+
+        for range in &self.trust_proxy_from {
+            if AddressRange::parse(range).is_none() {
+                problems.push(format!("trust_proxy_from has an invalid address range {}", range));
+            }
+        }
+
+        for folder in &self.default_folders {
+            if folder.name.eq_ignore_ascii_case("INBOX") ||
+                maildir::mailbox_path(Path::new("."), &folder.name, self.maildir_layout).is_none() {
+                problems.push(format!("default_folders has an invalid folder name {}", folder.name));
+            }
+        }
+
+        if let Some(ref root) = self.maildir_root {
+            if !Path::new(root).is_dir() {
+                problems.push(format!("maildir_root {} is not a directory", root));
+            }
+        }
+        if self.chroot {
+            match self.maildir_root {
+                None => problems.push("chroot is set but no maildir_root configured".to_string()),
+                Some(ref root) => {
+                    // The helper and everything it needs would have to be
+                    // copied into the maildir root.
+                    if self.user_backend == UserBackend::Command {
+                        problems.push("chroot can't be used with user_backend \"command\"".to_string());
+                    }
+                    for file in self.reloaded_files() {
+                        if chroot_path(Path::new(root), file).is_none() {
+                            problems.push(format!("{} is outside maildir_root {}, which chroot \
+                                                   confines the server to", file, root));
+                        }
+                    }
+                }
+            }
+        }
+        self.validate_run_as(&mut problems);
+
+        match self.user_backend {
+            UserBackend::Json => match File::open(&self.users) {
+                Ok(_) => {}
+                // A default users file is created if there isn't one.
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                    let dir = match Path::new(&self.users).parent() {
+                        Some(dir) if dir != Path::new("") => dir,
+                        _ => Path::new(".")
+                    };
+                    if !dir.is_dir() {
+                        problems.push(format!("users file {} can't be created: {} is not a directory",
+                                              self.users, dir.display()));
+                    }
+                }
+                Err(e) => problems.push(format!("users file {} can't be read: {}", self.users, e))
+            },
+            UserBackend::Command => {
+                if self.auth_command.is_none() {
+                    problems.push("user_backend is \"command\" but no auth_command configured".to_string());
+                }
+                if self.auth_command_timeout == 0 {
+                    problems.push("auth_command_timeout must be at least 1 second".to_string());
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    #[cfg(unix)]
+    fn validate_run_as(&self, problems: &mut Vec<String>) {
+        if let Some(ref user) = self.run_as_user {
+            if let Err(e) = privileges::user_ids(user) {
+                problems.push(format!("run_as_user {} can't be used: {}", user, e));
+            }
+        }
+        if let Some(ref group) = self.run_as_group {
+            if let Err(e) = privileges::group_id(group) {
+                problems.push(format!("run_as_group {} can't be used: {}", group, e));
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn validate_run_as(&self, problems: &mut Vec<String>) {
+        if self.run_as_user.is_some() || self.run_as_group.is_some() || self.chroot {
+            problems.push("run_as_user, run_as_group and chroot are only supported on Unix".to_string());
+        }
+    }
+
     pub fn get_ssl_acceptor(&self) -> Result<SslAcceptor, PkcsError> {
         if self.imap_ssl_port == None && self.lmtp_ssl_port == None {
             return Err(PkcsError::PortsDisabled);
         }
-        let mut buf = vec![];
-        let mut file = File::open(&self.pkcs_file)?;
-        file.read_to_end(&mut buf)?;
-        let p = Pkcs12::from_der(&buf)?;
-        let identity = p.parse(&self.pkcs_pass)?;
-        let builder = SslAcceptorBuilder::mozilla_intermediate(
-            SslMethod::tls(), &identity.pkey, &identity.cert, &identity.chain)?;
+        let identity = load_identity(&self.pkcs_file, &self.pkcs_pass)?;
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        use_identity(&mut builder, identity)?;
+        self.configure_tls(&mut builder)?;
+        self.configure_sessions(&mut builder)?;
+
+        // Clients asking for another host get its certificate, and anyone
+        // else gets the default one.
+        let mut contexts = HashMap::new();
+        for (host, certificate) in &self.sni_certificates {
+            let identity = load_identity(&certificate.pkcs_file, &certificate.pkcs_pass)?;
+            contexts.insert(host.to_ascii_lowercase(), certificate_context(identity)?);
+        }
+        if !contexts.is_empty() {
+            builder.set_servername_callback(move |ssl, _| {
+                let host = match ssl.servername(NameType::HOST_NAME) {
+                    Some(host) => host.to_ascii_lowercase(),
+                    None => return Ok(())
+                };
+                if let Some(context) = contexts.get(&host) {
+                    if let Err(e) = ssl.set_ssl_context(context) {
+                        warn!("Failed to use the certificate for {}: {}", host, e);
+                    }
+                }
+                Ok(())
+            });
+        }
         Ok(builder.build())
     }
+
+    /// Apply the configured protocol versions and ciphers on top of the
+    /// acceptor defaults.
+    fn configure_tls(&self, ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        let mut disabled = SslOptions::empty();
+        if self.tls_min_version > TlsVersion::Tls1_0 {
+            disabled |= SslOptions::NO_TLSV1;
+        }
+        if self.tls_min_version > TlsVersion::Tls1_1 {
+            disabled |= SslOptions::NO_TLSV1_1;
+        }
+        ctx.set_options(disabled);
+        if self.tls_prefer_server_ciphers {
+            ctx.set_options(SslOptions::CIPHER_SERVER_PREFERENCE);
+        } else {
+            ctx.clear_options(SslOptions::CIPHER_SERVER_PREFERENCE);
+        }
+        if let Some(ref ciphers) = self.tls_ciphers {
+            ctx.set_cipher_list(ciphers)?;
+        }
+        Ok(())
+    }
+
+    /// Set how long sessions can be resumed for and how tickets for them are
+    /// issued.
+    ///
+    /// A ticket holds the session's secrets encrypted with the ticket key,
+    /// so anyone who gets hold of the key can decrypt every connection made
+    /// with a ticket it encrypted while that ticket could still be used. The
+    /// random key used by default never leaves the process and is replaced
+    /// whenever the certificates are reloaded, and the lifetime is kept
+    /// short. A key shared through `tls_ticket_key_file` should be replaced
+    /// regularly; the certificates are reloaded when it changes.
+    fn configure_sessions(&self, ctx: &mut SslContextBuilder) -> Result<(), PkcsError> {
+        unsafe {
+            SSL_CTX_set_timeout(ctx.as_ptr(), self.tls_session_lifetime as c_long);
+        }
+        if !self.tls_session_tickets {
+            ctx.set_options(SslOptions::NO_TICKET);
+            return Ok(());
+        }
+        if let Some(ref file) = self.tls_ticket_key_file {
+            let mut key = read_ticket_key(file)?;
+            let res = unsafe {
+                ffi::SSL_CTX_ctrl(ctx.as_ptr(), SSL_CTRL_SET_TLSEXT_TICKET_KEYS,
+                                  key.len() as c_long, key.as_mut_ptr() as *mut c_void)
+            };
+            if res != 1 {
+                return Err(PkcsError::Ssl(ErrorStack::get()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The files the TLS certificates and keys are loaded from, which the
+    /// acceptor is built again from if any of them change.
+    pub fn tls_files(&self) -> Vec<&str> {
+        let mut files = vec![&self.pkcs_file[..]];
+        files.extend(self.sni_certificates.values().map(|certificate| &certificate.pkcs_file[..]));
+        if let Some(ref file) = self.tls_ticket_key_file {
+            files.push(&file[..]);
+        }
+        files.sort();
+        files
+    }
+
+    // The files which are read again while the server runs
+    fn reloaded_files(&self) -> Vec<&str> {
+        let mut files = self.tls_files();
+        files.retain(|file| !file.is_empty());
+        if self.user_backend == UserBackend::Json {
+            files.push(&self.users[..]);
+        }
+        files
+    }
+
+    /// Give the files read while the server runs, and the maildir root, the
+    /// paths they will have once the server is confined to maildir_root.
+    /// This must be done before the chroot, while the paths can still be
+    /// followed.
+    pub fn enter_chroot(&mut self) {
+        let root = match self.maildir_root {
+            Some(ref root) => PathBuf::from(root),
+            None => return
+        };
+        let confine = |file: &mut String| if let Some(path) = chroot_path(&root, file) {
+            *file = path;
+        };
+        confine(&mut self.users);
+        if !self.pkcs_file.is_empty() {
+            confine(&mut self.pkcs_file);
+        }
+        for certificate in self.sni_certificates.values_mut() {
+            confine(&mut certificate.pkcs_file);
+        }
+        if let Some(ref mut file) = self.tls_ticket_key_file {
+            confine(file);
+        }
+        self.maildir_root = Some("/".to_string());
+    }
+}
+
+/// Where `file` is found once the process is confined to `root`, or None if
+/// it is outside it. The file itself needn't exist yet, only its directory.
+fn chroot_path(root: &Path, file: &str) -> Option<String> {
+    let file = Path::new(file);
+    let dir = match file.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+    let dir = dir.canonicalize().ok()?;
+    let within = dir.strip_prefix(root.canonicalize().ok()?).ok()?;
+    Path::new("/").join(within).join(file.file_name()?).to_str().map(|path| path.to_string())
+}
+
+/// Read a session ticket key, which must be exactly `TICKET_KEY_LEN` bytes
+/// so that a truncated key is never used.
+fn read_ticket_key(file: &str) -> Result<Vec<u8>, PkcsError> {
+    let mut key = Vec::new();
+    File::open(file)?.read_to_end(&mut key)?;
+    if key.len() != TICKET_KEY_LEN {
+        return Err(PkcsError::BadTicketKey(file.to_string()));
+    }
+    Ok(key)
+}
+
+/// The private key, certificate and chain read from a PKCS #12 archive.
+struct Identity {
+    pkey: PKey<Private>,
+    cert: X509,
+    chain: Vec<X509>
+}
+
+/// Read and decrypt a PKCS #12 archive, which must hold both a private key
+/// and a certificate.
+fn load_identity(pkcs_file: &str, pkcs_pass: &str) -> Result<Identity, PkcsError> {
+    let mut buf = vec![];
+    let mut file = File::open(pkcs_file)?;
+    file.read_to_end(&mut buf)?;
+    let p = Pkcs12::from_der(&buf)?.parse2(pkcs_pass)?;
+    match (p.pkey, p.cert) {
+        (Some(pkey), Some(cert)) => Ok(Identity {
+            pkey: pkey,
+            cert: cert,
+            chain: p.ca.map(|ca| ca.into_iter().collect()).unwrap_or_default()
+        }),
+        _ => Err(PkcsError::NoIdentity(pkcs_file.to_string()))
+    }
+}
+
+/// Present the identity's certificate and chain from the context.
+fn use_identity(ctx: &mut SslContextBuilder, identity: Identity) -> Result<(), ErrorStack> {
+    ctx.set_private_key(&identity.pkey)?;
+    ctx.set_certificate(&identity.cert)?;
+    ctx.check_private_key()?;
+    for cert in identity.chain {
+        ctx.add_extra_chain_cert(cert)?;
+    }
+    Ok(())
+}
+
+/// Build a context which presents the given certificate. Only the
+/// certificate is taken from it when switching to it for SNI, so the
+/// protocol and cipher settings are left alone.
+fn certificate_context(identity: Identity) -> Result<SslContext, ErrorStack> {
+    let mut ctx = SslContext::builder(SslMethod::tls())?;
+    use_identity(&mut ctx, identity)?;
+    Ok(ctx.build())
+}
+
+/// The places a config file is looked for when none is given, in the order
+/// they are searched: the working directory, the user's XDG config directory
+/// and then the system-wide config directory.
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("./config.toml")];
+    let xdg_config_home = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = xdg_config_home {
+        paths.push(dir.join("segimap").join("config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/segimap/config.toml"));
+    paths
+}
+
+/// Pick the first of the paths which holds a file.
+fn find_config(paths: Vec<PathBuf>) -> ImapResult<PathBuf> {
+    match paths.iter().position(|path| path.is_file()) {
+        Some(index) => Ok(paths[index].clone()),
+        None => Err(Error::ConfigNotFound(paths))
+    }
 }
 
 impl Default for Config {
@@ -107,9 +676,279 @@ impl Default for Config {
             imap_port: Some(10000),
             lmtp_ssl_port: None,
             imap_ssl_port: Some(10001),
+            user_backend: UserBackend::Json,
             users: "./users.json".to_string(),
+            auth_command: None,
+            auth_command_timeout: default_auth_command_timeout(),
             pkcs_file: String::new(),
             pkcs_pass: String::new(),
+            sni_certificates: HashMap::new(),
+            tls_min_version: TlsVersion::Tls1_2,
+            tls_ciphers: None,
+            tls_prefer_server_ciphers: true,
+            tls_session_tickets: true,
+            tls_session_lifetime: default_tls_session_lifetime(),
+            tls_ticket_key_file: None,
+            decode_headers: false,
+            normalize_line_endings: false,
+            auto_expunge_on_delete: false,
+            keep_new_until_seen: false,
+            folder_window: default_folder_window(),
+            maildir_layout: Layout::Fs,
+            default_folders: default_folders(),
+            maildir_root: None,
+            max_message_size: None,
+            max_literal_size: default_max_literal_size(),
+            max_command_size: default_max_command_size(),
+            delivered_to_header: true,
+            trust_proxy_from: Vec::new(),
+            run_as_user: None,
+            run_as_group: None,
+            chroot: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use openssl::ssl::{SslContext, SslContextBuilder, SslMethod, SslOptions};
+
+    use error::Error;
+    use fixture;
+    use server::user::UserBackend;
+    use super::{find_config, Certificate, Config, DefaultFolder, PkcsError, TlsVersion};
+
+    #[test]
+    fn test_find_config() {
+        let dir = fixture::dir("config");
+        fs::create_dir_all(dir.join("xdg").join("segimap")).unwrap();
+        let xdg = dir.join("xdg").join("segimap").join("config.toml");
+        let etc = dir.join("etc").join("config.toml");
+
+        // Every path searched is reported when none of them exist.
+        let err = find_config(vec![xdg.clone(), etc.clone()]).unwrap_err();
+        assert_eq!(err.to_string(), format!("No config file found; searched {}, {}",
+                                            xdg.display(), etc.display()));
+
+        File::create(&xdg).unwrap().write_all(b"host = \"0.0.0.0\"\n").unwrap();
+        assert_eq!(find_config(vec![dir.join("missing.toml"), xdg.clone(), etc]).unwrap(), xdg);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_explicit_config_path() {
+        match Config::new(Some(Path::new("/nonexistent/segimap.toml"))) {
+            Err(Error::Io(_)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let dir = fixture::dir("config-invalid");
+        let path = dir.join("config.toml");
+        let load = |contents: &str| {
+            File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+            Config::new(Some(&path)).map_err(|e| e.to_string())
+        };
+
+        // Type errors name the offending key.
+        let err = load("host = \"localhost\"\nimap_port = \"143\"\nusers = \"users.json\"\n\
+                        pkcs_file = \"\"\npkcs_pass = \"\"\n").unwrap_err();
+        assert!(err.starts_with(&format!("Invalid config {}: ", path.display())), "{}", err);
+        assert!(err.contains("imap_port"), "{}", err);
+
+        let err = load("host = \"localhost\"\nimap_ssl_port = 993\nusers = \"users.json\"\n\
+                        pkcs_file = \"\"\npkcs_pass = \"\"\n").unwrap_err();
+        assert!(err.ends_with("imap_ssl_port set but no pkcs_file configured"), "{}", err);
+
+        let users = dir.join("users.json");
+        File::create(&users).unwrap().write_all(b"[]").unwrap();
+        let config = format!("host = \"localhost\"\nimap_port = 143\nusers = \"{}\"\n\
+                              pkcs_file = \"\"\npkcs_pass = \"\"\n", users.display());
+        let conf = load(&config).unwrap();
+        assert_eq!(conf.tls_min_version, TlsVersion::Tls1_2);
+        assert_eq!(conf.tls_ciphers, None);
+        assert!(conf.tls_prefer_server_ciphers);
+
+        let conf = load(&format!("{}tls_min_version = \"1.1\"\ntls_ciphers = \"HIGH:!aNULL\"\n\
+                                  tls_prefer_server_ciphers = false\n", config)).unwrap();
+        assert_eq!(conf.tls_min_version, TlsVersion::Tls1_1);
+        assert_eq!(conf.tls_ciphers, Some("HIGH:!aNULL".to_string()));
+        assert!(!conf.tls_prefer_server_ciphers);
+
+        let pkcs = dir.join("other.p12");
+        File::create(&pkcs).unwrap();
+        let conf = load(&format!("{}[sni_certificates.\"mail.example.org\"]\n\
+                                  pkcs_file = \"{}\"\npkcs_pass = \"pass\"\n",
+                                 config, pkcs.display())).unwrap();
+        assert_eq!(conf.sni_certificates["mail.example.org"].pkcs_pass, "pass");
+
+        let err = load(&format!("{}tls_min_version = \"1.3\"\n", config)).unwrap_err();
+        assert!(err.contains("tls_min_version"), "{}", err);
+        let err = load(&format!("{}tls_ciphers = \"\"\n", config)).unwrap_err();
+        assert!(err.ends_with("tls_ciphers is empty"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_maildir() {
+        let mut conf = Config::default();
+        assert_eq!(conf.resolve_maildir("mail/bob"), PathBuf::from("mail/bob"));
+
+        conf.maildir_root = Some("/srv/mail".to_string());
+        assert_eq!(conf.resolve_maildir("bob"), PathBuf::from("/srv/mail/bob"));
+        assert_eq!(conf.resolve_maildir("/home/bob/Maildir"), PathBuf::from("/home/bob/Maildir"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut conf = Config::default();
+        let dir = fixture::dir("validate");
+        conf.users = dir.join("users.json").to_str().unwrap().to_string();
+        conf.imap_ssl_port = None;
+        assert_eq!(conf.validate(), Ok(()));
+
+        conf.imap_port = None;
+        conf.lmtp_port = None;
+        assert_eq!(conf.validate().unwrap_err().len(), 1);
+
+        conf.imap_port = Some(0);
+        conf.lmtp_port = Some(143);
+        conf.lmtp_ssl_port = Some(143);
+        conf.users = "/nonexistent/users.json".to_string();
+        conf.user_backend = UserBackend::Command;
+        assert_eq!(conf.validate(), Err(vec![
+            "imap_port must be between 1 and 65535".to_string(),
+            "lmtp_port and lmtp_ssl_port are both set to 143".to_string(),
+            "lmtp_ssl_port set but no pkcs_file configured".to_string(),
+            "user_backend is \"command\" but no auth_command configured".to_string()
+        ]));
+
+        conf.user_backend = UserBackend::Json;
+        assert_eq!(conf.validate().unwrap_err().last().unwrap(),
+                   "users file /nonexistent/users.json can't be created: \
+                    /nonexistent is not a directory");
+
+        conf.trust_proxy_from = vec!["10.0.0.0/8".to_string(), "10.0.0.1/40".to_string()];
+        assert!(conf.validate().unwrap_err().contains(
+            &"trust_proxy_from has an invalid address range 10.0.0.1/40".to_string()));
+
+        conf.default_folders.push(DefaultFolder { name: "../Sent".to_string(), special_use: None });
+        assert!(conf.validate().unwrap_err().contains(
+            &"default_folders has an invalid folder name ../Sent".to_string()));
+
+        conf.sni_certificates.insert("mail.example.org".to_string(), Certificate {
+            pkcs_file: "/nonexistent/mail.p12".to_string(),
+            pkcs_pass: String::new()
+        });
+        assert!(conf.validate().unwrap_err().iter().any(|problem| {
+            problem.starts_with("pkcs_file /nonexistent/mail.p12 for mail.example.org can't be read")
+        }));
+
+        conf.chroot = true;
+        assert!(conf.validate().unwrap_err().contains(
+            &"chroot is set but no maildir_root configured".to_string()));
+        conf.run_as_user = Some("segimap-no-such-user".to_string());
+        assert!(conf.validate().unwrap_err().iter().any(|problem| {
+            problem.starts_with("run_as_user segimap-no-such-user can't be used")
+        }));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chroot() {
+        let root = fixture::dir("chroot");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        let mut conf = Config::default();
+        conf.imap_ssl_port = None;
+        conf.chroot = true;
+        conf.maildir_root = Some(root.to_str().unwrap().to_string());
+        conf.users = root.join("etc/users.json").to_str().unwrap().to_string();
+        assert_eq!(conf.validate(), Ok(()));
+
+        // The users file couldn't be reloaded from outside the root, nor
+        // could the auth command be run.
+        let users = root.parent().unwrap().join("users.json").to_str().unwrap().to_string();
+        conf.users = users.clone();
+        assert_eq!(conf.validate(), Err(vec![
+            format!("{} is outside maildir_root {}, which chroot confines the server to",
+                    users, root.display())
+        ]));
+        conf.user_backend = UserBackend::Command;
+        conf.auth_command = Some("/usr/bin/segimap-auth".to_string());
+        assert_eq!(conf.validate(),
+                   Err(vec!["chroot can't be used with user_backend \"command\"".to_string()]));
+
+        // The files are found within the root once the server is confined.
+        conf.user_backend = UserBackend::Json;
+        conf.users = root.join("etc/../etc/users.json").to_str().unwrap().to_string();
+        conf.pkcs_file = root.join("etc/segimap.p12").to_str().unwrap().to_string();
+        conf.enter_chroot();
+        assert_eq!(conf.users, "/etc/users.json");
+        assert_eq!(conf.pkcs_file, "/etc/segimap.p12");
+        assert_eq!(conf.maildir_root, Some("/".to_string()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn tickets_disabled(ctx: &SslContextBuilder) -> bool {
+        ctx.options().contains(SslOptions::NO_TICKET)
+    }
+
+    #[test]
+    fn test_tls_min_version() {
+        let mut conf = Config::default();
+        let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+        conf.configure_tls(&mut ctx).unwrap();
+        assert!(ctx.options().contains(SslOptions::NO_TLSV1 | SslOptions::NO_TLSV1_1));
+
+        conf.tls_min_version = TlsVersion::Tls1_1;
+        let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+        conf.configure_tls(&mut ctx).unwrap();
+        assert!(ctx.options().contains(SslOptions::NO_TLSV1));
+        assert!(!ctx.options().contains(SslOptions::NO_TLSV1_1));
+    }
+
+    #[test]
+    fn test_session_tickets() {
+        let dir = fixture::dir("ticket-key");
+        let key = dir.join("ticket.key");
+        let mut conf = Config::default();
+        assert!(conf.tls_session_tickets);
+        assert_eq!(conf.tls_session_lifetime, 300);
+
+        let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+        conf.configure_sessions(&mut ctx).unwrap();
+        assert!(!tickets_disabled(&ctx));
+
+        // A key of the wrong length is refused rather than truncated.
+        File::create(&key).unwrap().write_all(&[7; 48]).unwrap();
+        conf.tls_ticket_key_file = Some(key.to_str().unwrap().to_string());
+        match conf.configure_sessions(&mut ctx) {
+            Err(PkcsError::BadTicketKey(_)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("short ticket key accepted")
+        }
+        assert!(conf.validate().unwrap_err().contains(
+            &format!("tls_ticket_key_file {} must hold exactly 80 bytes", key.display())));
+
+        File::create(&key).unwrap().write_all(&[7; 80]).unwrap();
+        conf.configure_sessions(&mut ctx).unwrap();
+        assert!(conf.tls_files().contains(&key.to_str().unwrap()));
+
+        conf.tls_session_tickets = false;
+        let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+        conf.configure_sessions(&mut ctx).unwrap();
+        assert!(tickets_disabled(&ctx));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
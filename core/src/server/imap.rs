@@ -1,21 +1,25 @@
 use std::ascii::AsciiExt;
 use std::fs;
-use std::io::{BufRead, Write};
-use std::net::TcpStream;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{SocketAddr, TcpStream};
 use std::path::Path;
-use std::path::MAIN_SEPARATOR;
-use std::str::Split;
+use std::str::{self, Split};
 use std::sync::Arc;
 use bufstream::BufStream;
-use regex::Regex;
+use regex::{self, Regex};
 
-use folder::Folder;
+use maildir;
+use mailstore::{MailFolder, MaildirStore};
 use server::Server;
 use server::Stream;
 
-use command::Attribute::UID;
+use command::Attribute::{ModSeq, UID};
+use command::FetchCommand;
+use command::append;
 use command::fetch;
+use command::response::ResponseWriter;
+use command::search;
+use command::select;
 use command::store;
 use command::sequence_set;
 use command::sequence_set::SequenceItem::{
@@ -24,6 +28,7 @@ use command::sequence_set::SequenceItem::{
     Wildcard
 };
 use error::Error;
+use parser;
 use util;
 
 // Used to grab every file for removal while performing DELETE on a folder.
@@ -41,10 +46,166 @@ macro_rules! opendirlisting(
 // Standard IMAP greeting
 static GREET: &'static [u8] = b"* OK Server ready.\r\n";
 
+// Continuation request sent before reading a literal from the client
+static LITERAL_READY: &'static [u8] = b"+ Ready for literal data\r\n";
+
+// Largest message literal accepted by APPEND when there is no
+// max_message_size
+const MAX_APPEND_LITERAL: usize = 64 * 1024 * 1024;
+
+/// Throw away any input from the client which has been buffered or is
+/// waiting on the socket, without blocking. Returns how many bytes were
+/// discarded.
+fn discard_input<S: Read + Write>(stream: &mut BufStream<S>, socket: &TcpStream) -> io::Result<usize> {
+    socket.set_nonblocking(true)?;
+    let mut discarded = 0;
+    let res = loop {
+        let len = match stream.fill_buf() {
+            Ok(buf) => buf.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(discarded),
+            Err(e) => break Err(e)
+        };
+        // The client has closed the connection.
+        if len == 0 {
+            break Ok(discarded);
+        }
+        stream.consume(len);
+        discarded += len;
+    };
+    socket.set_nonblocking(false)?;
+    res
+}
+
+/// What `read_command` made of the client's input
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// A whole command of this many bytes, which is 0 once the client has
+    /// closed the connection
+    Command(usize),
+    /// A literal which was too large, and which the client wasn't asked for
+    LiteralTooLarge,
+    /// A line which went on past the largest command allowed
+    TooLong
+}
+
+/// Read a whole command from the client into `command`, including any
+/// literals it contains. The client is asked to continue before each literal
+/// and exactly the octets it announces are read, so a command pipelined
+/// after the literal is left buffered for the next call. Literals are kept
+/// byte for byte, as a message given to APPEND need not be UTF-8.
+///
+/// A literal larger than `max_literal`, or `max_append_literal` for APPEND,
+/// is refused without asking the client to send it, as is one which would
+/// take the command past `max_command` bytes. The message given to APPEND
+/// doesn't count towards those. Reading a line stops once it reaches the
+/// limit, in which case the rest of it is still to come.
+fn read_command<S: Read + Write>(stream: &mut BufStream<S>, command: &mut Vec<u8>,
+                                 max_literal: usize, max_append_literal: usize,
+                                 max_command: usize) -> io::Result<Input> {
+    let mut total = 0;
+    let mut limit = max_command;
+    loop {
+        let start = command.len();
+        let remaining = limit.saturating_sub(total);
+        let len = Read::by_ref(stream).take(remaining as u64).read_until(b'\n', command)?;
+        total += len;
+        if len == remaining && !command.ends_with(b"\n") {
+            return Ok(Input::TooLong);
+        }
+        let size = match literal_size(&command[start..]) {
+            Some(size) => size,
+            None => return Ok(Input::Command(total))
+        };
+        let is_append = command.split(|&b| b == b' ').nth(1)
+            .map_or(false, |name| name.eq_ignore_ascii_case(b"APPEND"));
+        if is_append && start == 0 {
+            limit += max_append_literal;
+        }
+        if size > if is_append { max_append_literal } else { max_literal } ||
+            size > limit.saturating_sub(total) {
+            return Ok(Input::LiteralTooLarge);
+        }
+
+        command.extend_from_slice(&request_continuation(stream, size)?);
+        total += size;
+    }
+}
+
+/// Send the client a continuation request, telling it to go on with its
+/// command, and read the `size` octets of the literal it then sends. The
+/// request is flushed before anything is read, as the client sends nothing
+/// until it sees it.
+fn request_continuation<S: Read + Write>(stream: &mut BufStream<S>, size: usize) -> io::Result<Vec<u8>> {
+    stream.write_all(LITERAL_READY)?;
+    stream.flush()?;
+    // Only what arrives is buffered, whatever size the client claims.
+    let mut literal = Vec::new();
+    let read = Read::by_ref(stream).take(size as u64).read_to_end(&mut literal)?;
+    if read < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(literal)
+}
+
+/// The number of octets in the literal announced at the end of `line`, if
+/// there is one.
+fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = line.strip_suffix(b"\n")?;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let line = line.strip_suffix(b"}")?;
+    let start = line.iter().rposition(|&b| b == b'{')?;
+    str::from_utf8(&line[start + 1..]).ok()?.parse().ok()
+}
+
+/// The arguments of a command exactly as the client sent them, without the
+/// tag, the command name or the line ending. Unlike the arguments split on
+/// spaces, these keep the contents of literals intact.
+fn raw_args(line: &[u8]) -> &[u8] {
+    let start = line.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(line.len());
+    let line = &line[start..];
+    let line = if line.ends_with(b"\r\n") {
+        &line[..line.len() - 2]
+    } else if line.ends_with(b"\n") {
+        &line[..line.len() - 1]
+    } else {
+        line
+    };
+    line.splitn(3, |&b| b == b' ').nth(2).unwrap_or(b"")
+}
+
+/// Whether EXPUNGE responses may be sent while responding to the command
+/// named `cmd`. They can't be during FETCH, STORE or SEARCH by sequence
+/// number, as the client can't tell which messages those refer to (RFC 3501
+/// section 7.4.1).
+fn may_send_expunge(cmd: &str) -> bool {
+    !["fetch", "store", "search"].iter().any(|name| cmd.eq_ignore_ascii_case(name))
+}
+
+/// Insert untagged responses into `res` just before the tagged response
+/// which ends it, so that they follow the command's own untagged responses.
+fn insert_before_tagged(res: &mut String, untagged: &str) {
+    let end = res.len().saturating_sub(2);
+    let start = res[..end].rfind("\r\n").map_or(0, |i| i + 2);
+    res.insert_str(start, untagged);
+}
+
+/// The text of the untagged CAPABILITY response, listing STARTTLS if
+/// `starttls` is set.
+fn capability(starttls: bool) -> String {
+    let mut res = "CAPABILITY IMAP4rev1 CHILDREN ENABLE CONDSTORE QRESYNC ESEARCH SPECIAL-USE"
+        .to_string();
+    if starttls {
+        res.push_str(" STARTTLS");
+    }
+    res
+}
+
 /// Representation of a session
 pub struct ImapSession {
     /// Shared wrapper for config and user data
     serv: Arc<Server>,
+    /// Address of the client, as given by a trusted proxy if there is one
+    client: SocketAddr,
     /// Whether to logout and close the connection after interpreting the
     /// latest client command
     logout: bool,
@@ -53,56 +214,106 @@ pub struct ImapSession {
     maildir: Option<String>,
     /// If None, no folder selected. Otherwise, contains the currently selected
     /// folder.
-    folder: Option<Folder>
+    folder: Option<Box<dyn MailFolder>>,
+    /// Whether the client has enabled CONDSTORE, so that mod-sequences are
+    /// reported to it
+    condstore: bool,
+    /// Whether the client has enabled QRESYNC, so that it can be told which
+    /// messages have been expunged while it was away
+    qresync: bool,
+    /// UIDs of messages which a STORE by sequence number marked \Deleted
+    /// with auto_expunge_on_delete set, to be expunged after the next
+    /// command which may send EXPUNGE responses
+    expunge_later: Vec<usize>
 }
 
 impl ImapSession {
-    pub fn new(serv: Arc<Server>) -> ImapSession {
+    pub fn new(serv: Arc<Server>, client: SocketAddr) -> ImapSession {
         ImapSession {
             serv: serv,
+            client: client,
             logout: false,
             maildir: None,
-            folder: None
+            folder: None,
+            condstore: false,
+            qresync: false,
+            expunge_later: Vec::new()
         }
     }
 
     /// Handles client commands as they come in on the stream and writes
     /// responeses back to the stream.
     pub fn handle(&mut self, orig_stream: TcpStream) {
-        let mut stream = BufStream::new(self.serv.imap_ssl(orig_stream));
+        let mut stream = match self.serv.imap_ssl(orig_stream) {
+            Some(stream) => BufStream::new(stream),
+            None => return
+        };
         // Provide the client with an IMAP greeting.
-        return_on_err!(stream.write(GREET));
+        return_on_err!(stream.write_all(GREET));
         return_on_err!(stream.flush());
 
-        let mut command = String::new();
+        let max_append_literal = self.serv.conf.max_message_size.unwrap_or(MAX_APPEND_LITERAL);
+        let mut command = Vec::new();
         loop {
             command.truncate(0);
-            match stream.read_line(&mut command) {
-                Ok(_) => {
+            let res = read_command(&mut stream, &mut command, self.serv.conf.max_literal_size,
+                                   max_append_literal, self.serv.conf.max_command_size);
+            // Everything but the literals is text, and only APPEND looks at
+            // the literals as they were sent.
+            let line = String::from_utf8_lossy(&command);
+            match res {
+                // The client is waiting to be told it may send the literal,
+                // so the next thing it sends is a new command.
+                Ok(Input::LiteralTooLarge) => {
+                    let res = match line.split(' ').next()
+                        .filter(|tag| parser::is_tag(tag.as_bytes())) {
+                        Some(tag) => ResponseWriter::new().tagged_bad(tag, "Literal too large"),
+                        None => ResponseWriter::new().untagged("BAD Literal too large")
+                    };
+                    return_on_err!(stream.write_all(res.into_string().as_bytes()));
+                    return_on_err!(stream.flush());
+                }
+                // The rest of the command can't be told apart from the
+                // next one, so the connection is closed.
+                Ok(Input::TooLong) => {
+                    let res = ResponseWriter::new().untagged("BYE Command too long");
+                    return_on_err!(stream.write_all(res.into_string().as_bytes()));
+                    return_on_err!(stream.flush());
+                    return;
+                }
+                Ok(Input::Command(_)) => {
                     // If the command is empty, exit.
                     // Exitting will close the stream for us.
                     if command.is_empty() {
                         return;
                     }
 
-                    let mut args = command.trim().split(' ');
-                    let inv_str = " BAD Invalid command\r\n";
+                    // Leading space is left in, as it means the tag is empty.
+                    let mut args = line.trim_end().split(' ');
+                    let may_expunge = args.clone().nth(1).map_or(false, may_send_expunge);
 
                     // The client will need the tag in the response in order to match up
                     // the response to the command it issued because the client does not
                     // have to wait on our response in order to issue new commands.
                     let mut starttls = false;
-                    let res = match args.next() {
-                        None => inv_str.to_string(),
+                    let mut res = match args.next() {
+                        // A response with a tag the client didn't send
+                        // could be mistaken for the response to another
+                        // command.
+                        Some(tag) if !parser::is_tag(tag.as_bytes()) =>
+                            ResponseWriter::new().untagged("BAD Invalid tag").into_string(),
+                        None => ResponseWriter::new().untagged("BAD Invalid command").into_string(),
                         Some(tag) => {
-                            let mut bad_res = tag.to_string();
-                            bad_res.push_str(inv_str);
+                            let bad_res = ResponseWriter::new()
+                                .tagged_bad(tag, "Invalid command").into_string();
 
                             // Interpret the command and generate a response
                             match args.next() {
                                 None => bad_res,
                                 Some(c) => {
-                                    warn!("Cmd: {}", command.trim());
+                                    // The arguments are left out, as they may
+                                    // hold passwords or whole messages.
+                                    warn!("Cmd: {} {}", tag, c);
                                     match &c.to_ascii_lowercase()[..] {
                                         // STARTTLS is handled here because it modifies the stream
                                         "starttls" => {
@@ -110,26 +321,68 @@ impl ImapSession {
                                                 &Stream::Tcp(_) =>
                                                     if self.serv.can_starttls() {
                                                         starttls = true;
-                                                        let mut ok_res = tag.to_string();
-                                                        ok_res.push_str(" OK Begin TLS negotiation now\r\n");
-                                                        ok_res
+                                                        ResponseWriter::new()
+                                                            .tagged_ok(tag, "Begin TLS negotiation now")
+                                                            .into_string()
                                                     } else {
                                                         bad_res
                                                     },
                                                 _ => bad_res
                                             }
                                         },
-                                        cmd => self.interpret(cmd, &mut args, tag, bad_res)
+                                        cmd => {
+                                            let tls = match *stream.get_ref() {
+                                                Stream::Ssl(_) => true,
+                                                Stream::Tcp(_) => false
+                                            };
+                                            self.interpret(cmd, &mut args, &command, tag, tls,
+                                                           &mut stream)
+                                        }
                                     }
                                 }
                             }
                         }
                     };
 
+                    // Let the client know about any mail which has arrived
+                    // in the selected folder since its last command.
+                    if !self.logout {
+                        if let Some(ref mut folder) = self.folder {
+                            // Messages left by an earlier STORE are expunged
+                            // once the command's own responses are sent.
+                            if may_expunge && !self.expunge_later.is_empty() {
+                                let expunged = folder.uid_expunge_response(&self.expunge_later,
+                                                                           self.qresync);
+                                self.expunge_later.clear();
+                                insert_before_tagged(&mut res, &expunged);
+                            }
+                            match folder.poll_new() {
+                                Ok(updates) => return_on_err!(stream.write_all(updates.as_bytes())),
+                                Err(e) => warn!("Failed to check for new messages: {}", e)
+                            }
+                        }
+                    }
+
+                    // Anything sent along with STARTTLS wasn't protected by
+                    // TLS and may have been injected by an attacker, so it is
+                    // thrown away before the client is told to begin the
+                    // handshake. The next command is read from the TLS stream.
+                    if starttls {
+                        let socket = match *stream.get_ref() {
+                            Stream::Tcp(ref socket) => socket.try_clone(),
+                            _ => return
+                        };
+                        match socket.and_then(|socket| discard_input(&mut stream, &socket)) {
+                            Ok(0) => {}
+                            Ok(len) => warn!("Discarded {} bytes sent after STARTTLS", len),
+                            Err(_) => return
+                        }
+                    }
+
                     // Log the response
                     warn!("Response:\n{}", res);
 
-                    return_on_err!(stream.write(res.as_bytes()));
+                    return_on_err!(stream.write_all(res.as_bytes()));
                     return_on_err!(stream.flush());
 
                     if starttls {
@@ -153,141 +406,177 @@ impl ImapSession {
     }
 
     /// Interprets a client command and generates a String response
-    fn interpret(&mut self, cmd: &str, args: &mut Split<char>,
-                 tag: &str, bad_res: String) -> String {
+    /// Responses which may be large, such as those to FETCH, are written to
+    /// `out` as they are generated rather than being returned. `line` is the
+    /// whole command as it was read, for commands whose literals must be
+    /// kept intact. `tls` is whether the connection is already encrypted.
+    fn interpret<W: Write>(&mut self, cmd: &str, args: &mut Split<char>, line: &[u8],
+                           tag: &str, tls: bool, out: &mut W) -> String {
+        let bad_res = ResponseWriter::new().tagged_bad(tag, "Invalid command").into_string();
         // The argument after the tag specified the command issued.
         // Additional arguments are arguments for that specific command.
         match cmd {
+            // NOOP is how clients which don't use IDLE poll for changes
+            // to the selected folder.
             "noop" => {
-                let mut res = tag.to_string();
-                res += " OK NOOP\r\n";
-                res
+                let mut res = ResponseWriter::new();
+                if let Some(ref mut folder) = self.folder {
+                    match folder.poll_changes(self.condstore, self.qresync) {
+                        Ok(updates) => res = res.responses(&updates),
+                        Err(e) => warn!("Failed to check for changes: {}", e)
+                    }
+                }
+                res.tagged_ok(tag, "NOOP").into_string()
             }
 
             // Inform the client of the supported IMAP version and
             // extension(s)
             "capability" => {
-                let mut res = "* CAPABILITY IMAP4rev1 CHILDREN\r\n"
-                    .to_string();
-                res.push_str(tag);
-                res.push_str(" OK Capability successful\r\n");
-                res
+                // STARTTLS is only offered on a plaintext connection which
+                // it could be used on.
+                ResponseWriter::new()
+                    .untagged(&capability(!tls && self.serv.can_starttls()))
+                    .tagged_ok(tag, "Capability successful")
+                    .into_string()
             }
             "login" => {
-                let login_args: Vec<&str> = args.collect();
-                if login_args.len() < 2 { return bad_res; }
-                let email = login_args[0].trim_matches('"');
-                let password = login_args[1].trim_matches('"');
-                let mut no_res  = tag.to_string();
-                no_res.push_str(" NO invalid username or password\r\n");
-                if let Some(user) = self.serv.login(email.to_string(), password.to_string()) {
-                    self.maildir = Some(user.maildir.clone());
-                } else {
-                    return no_res;
+                let mut cmd = "LOGIN".to_string();
+                for arg in args {
+                    cmd.push(' ');
+                    cmd.push_str(arg);
                 }
-                match self.maildir {
-                    Some(_) => {
-                        let mut res = tag.to_string();
-                        res.push_str(" OK logged in successfully as ");
-                        res.push_str(email);
-                        res.push_str("\r\n");
-                        res
+                let (email, password) = match parser::login(cmd.as_bytes()) {
+                    Ok(creds) => creds,
+                    Err(_) => return bad_res
+                };
+                let no_res = ResponseWriter::new()
+                    .tagged_no(tag, "invalid username or password").into_string();
+                match self.serv.login(email.clone(), password) {
+                    Ok(Some(maildir)) => {
+                        self.provision(&maildir[..]);
+                        self.maildir = Some(maildir);
+                    }
+                    Ok(None) => {
+                        warn!("Failed login as {} from {}", email, self.client);
+                        return no_res;
                     }
+                    Err(e) => {
+                        error!("Couldn't check login as {} from {}: {}", email, self.client, e);
+                        return ResponseWriter::new()
+                            .tagged_no(tag, "[UNAVAILABLE] Login temporarily unavailable")
+                            .into_string();
+                    }
+                }
+                match self.maildir {
+                    Some(_) => ResponseWriter::new()
+                        .tagged_ok(tag, &format!("logged in successfully as {}", email))
+                        .into_string(),
                     None => no_res
                 }
             }
+            // Turn on the extensions the client asks for which change how
+            // the server talks to it (RFC 5161).
+            "enable" => {
+                if self.maildir.is_none() {
+                    return bad_res;
+                }
+                let names: Vec<&str> = args.collect();
+                if names.is_empty() {
+                    return bad_res;
+                }
+                // Only what this command turned on is reported, and anything
+                // unknown is ignored.
+                let mut res = "ENABLED".to_string();
+                for name in names {
+                    if name.eq_ignore_ascii_case("CONDSTORE") && !self.condstore {
+                        self.condstore = true;
+                        res.push_str(" CONDSTORE");
+                    } else if name.eq_ignore_ascii_case("QRESYNC") && !self.qresync {
+                        // QRESYNC relies on CONDSTORE, so it is turned on
+                        // too.
+                        self.qresync = true;
+                        self.condstore = true;
+                        res.push_str(" QRESYNC");
+                    }
+                }
+                ResponseWriter::new()
+                    .untagged(&res)
+                    .tagged_ok(tag, "ENABLE completed")
+                    .into_string()
+            }
             "logout" => {
                 // Close the connection after sending the response
                 self.logout = true;
 
                 // Write out current state of selected folder (if any)
                 // to disk
-                if let Some(ref folder) = self.folder {
+                if let Some(ref mut folder) = self.folder {
+                    folder.check();
                     folder.expunge();
                 }
+                self.folder = None;
 
-                let mut res = "* BYE Server logging out\r\n"
-                    .to_string();
-                res.push_str(tag);
-                res.push_str(" OK Server logged out\r\n");
-                res
+                ResponseWriter::new()
+                    .untagged("BYE Server logging out")
+                    .tagged_ok(tag, "Server logged out")
+                    .into_string()
             }
-            // Examine and Select should be nearly identical...
-            "select" => {
+            // Examine and Select are identical except that EXAMINE opens
+            // the folder read-only.
+            "select" => self.select(args, false, tag, bad_res),
+            "examine" => self.select(args, true, tag, bad_res),
+            "status" => {
                 let maildir = match self.maildir {
                     None => { return bad_res; }
                     Some(ref maildir) => maildir
                 };
-                let (folder, res) = util::perform_select(&maildir[..],
-                                                         &args.collect::<Vec<&str>>(),
-                                                         false, tag);
-                self.folder = folder;
-                match self.folder {
+                match util::perform_status(&maildir[..],
+                                           self.serv.conf.maildir_layout,
+                                           &args.collect::<Vec<&str>>(),
+                                           &mut self.condstore, tag) {
                     None => bad_res,
-                    _ => res
-                }
-            }
-            "examine" => {
-                let maildir = match self.maildir {
-                    None => { return bad_res; }
-                    Some(ref maildir) => maildir
-                };
-                let (folder, res) = util::perform_select(&maildir[..],
-                                                         &args.collect::<Vec<&str>>(),
-                                                         true, tag);
-                self.folder = folder;
-                match self.folder {
-                    None => bad_res,
-                    _ => res
+                    Some(res) => res
                 }
             }
             "create" => {
                 let create_args: Vec<&str> = args.collect();
                 if create_args.len() < 1 { return bad_res; }
-                let mbox_name = create_args[0].trim_matches('"').replace("INBOX", "");
+                let mbox_name = create_args[0].trim_matches('"');
                 match self.maildir {
                     None => bad_res,
                     Some(ref maildir) => {
-                        let mut no_res = tag.to_string();
-                        no_res.push_str(" NO Could not create folder.\r\n");
-                        let maildir_path = Path::new(&maildir[..]).join(mbox_name);
-
-                        // Create directory for new mail
-                        let newmaildir_path = maildir_path.join("new");
-                        if fs::create_dir_all(&newmaildir_path).is_err() {
-                            return no_res;
-                        }
-                        if fs::set_permissions(&newmaildir_path,
-                                               fs::Permissions::from_mode(0o755)).is_err() {
-                            return no_res;
-                        }
+                        let no_res = ResponseWriter::new()
+                            .tagged_no(tag, "Could not create folder.").into_string();
+                        let maildir_path = match maildir::mailbox_path(Path::new(&maildir[..]),
+                                                                       mbox_name,
+                                                                       self.serv.conf.maildir_layout) {
+                            None => return no_res,
+                            Some(path) => path
+                        };
 
-                        // Create directory for current mail
-                        let curmaildir_path = maildir_path.join("cur");
-                        if fs::create_dir_all(&curmaildir_path).is_err() {
-                            return no_res;
-                        }
-                        if fs::set_permissions(&curmaildir_path,
-                                               fs::Permissions::from_mode(0o755)).is_err() {
+                        if maildir::create_folder(&maildir_path).is_err() {
                             return no_res;
                         }
 
-                        let mut ok_res = tag.to_string();
-                        ok_res.push_str(" OK CREATE successful.\r\n");
-                        ok_res
+                        ResponseWriter::new().tagged_ok(tag, "CREATE successful.").into_string()
                     }
                 }
             }
             "delete" => {
                 let delete_args: Vec<&str> = args.collect();
                 if delete_args.len() < 1 { return bad_res; }
-                let mbox_name = delete_args[0].trim_matches('"').replace("INBOX", "");
+                let mbox_name = delete_args[0].trim_matches('"');
                 match self.maildir {
                     None => bad_res,
                     Some(ref maildir) => {
-                        let mut no_res = tag.to_string();
-                        no_res.push_str(" NO Invalid folder.\r\n");
-                        let maildir_path = Path::new(&maildir[..]).join(mbox_name);
+                        let no_res = ResponseWriter::new()
+                            .tagged_no(tag, "Invalid folder.").into_string();
+                        let maildir_path = match maildir::mailbox_path(Path::new(&maildir[..]),
+                                                                       mbox_name,
+                                                                       self.serv.conf.maildir_layout) {
+                            None => return no_res,
+                            Some(path) => path
+                        };
                         let newmaildir_path = maildir_path.join("new");
                         let curmaildir_path = maildir_path.join("cur");
                         opendirlisting!(&newmaildir_path, newlist,
@@ -329,9 +618,9 @@ impl ImapSession {
                                                             // holding mail. For this reason, we
                                                             // leave the other files, and the
                                                             // folder itself, in tact.
-                                                            let mut ok_res = tag.to_string();
-                                                            ok_res.push_str(" OK DELETE successsful.\r\n");
-                                                            ok_res
+                                                            ResponseWriter::new()
+                                                                .tagged_ok(tag, "DELETE successsful.")
+                                                                .into_string()
                                                         })
                                         )
                     }
@@ -347,33 +636,26 @@ impl ImapSession {
                     None => bad_res,
                     Some(ref maildir) => {
                         if mailbox_name.is_empty() {
-                            return format!("* LIST (\\Noselect) \"/\" \"{}\"\r\n{} OK List successful\r\n",
-                                           reference, tag);
+                            return ResponseWriter::new()
+                                .untagged(&util::list_root(reference))
+                                .tagged_ok(tag, "List successful")
+                                .into_string();
                         }
-                        let mailbox_name = mailbox_name
-                            .replace("*", ".*")
+                        // Folders are matched by mailbox name rather than
+                        // by path.
+                        let pattern = regex::escape(&format!("{}{}", reference, mailbox_name))
+                            .replace("\\*", ".*")
                             .replace("%", "[^/]*");
-                        let maildir_path = Path::new(&maildir[..]);
-                        let re_opt = Regex::new
-                            (&format!
-                             ("{}{}?{}{}?{}$",
-                              path_filename_to_str!(maildir_path),
-                              MAIN_SEPARATOR, reference,
-                              MAIN_SEPARATOR,
-                              mailbox_name.replace("INBOX", ""))[..]);
-                        match re_opt {
+                        match Regex::new(&format!("^{}$", pattern)[..]) {
                             Err(_) => bad_res,
                             Ok(re) => {
-                                let list_responses = util::list(&maildir[..],
-                                                                &re);
-                                let mut ok_res = String::new();
+                                let list_responses = util::list(&self.mail_store(maildir), &re,
+                                                                &self.serv.conf.special_use());
+                                let mut ok_res = ResponseWriter::new();
                                 for list_response in &list_responses {
-                                    ok_res.push_str(&list_response[..]);
-                                    ok_res.push_str("\r\n");
+                                    ok_res = ok_res.untagged(list_response);
                                 }
-                                ok_res.push_str(tag);
-                                ok_res.push_str(" OK list successful\r\n");
-                                ok_res
+                                ok_res.tagged_ok(tag, "list successful").into_string()
                             }
                         }
                     }
@@ -389,9 +671,7 @@ impl ImapSession {
                     None => bad_res,
                     Some(ref mut folder) => {
                         folder.check();
-                        let mut ok_res = tag.to_string();
-                        ok_res.push_str(" OK Check completed\r\n");
-                        ok_res
+                        ResponseWriter::new().tagged_ok(tag, "Check completed").into_string()
                     }
                 }
             }
@@ -401,62 +681,65 @@ impl ImapSession {
                 match self.expunge() {
                     Err(_) => bad_res,
                     Ok(_) => {
-                        if let Some(ref mut folder) = self.folder {
-                            folder.check();
-                        }
-                        self.folder = None;
-                        format!("{} OK close completed\r\n", tag)
+                        self.deselect();
+                        ResponseWriter::new().tagged_ok(tag, "close completed").into_string()
                     }
                 }
             }
-            // Delete the messages currently marked for deletion.
+            // Delete the messages currently marked for deletion. Clients
+            // which have enabled QRESYNC are given the UIDs of the deleted
+            // messages rather than their sequence numbers.
             "expunge" => {
-                match self.expunge() {
-                    Err(_) => bad_res,
-                    Ok(v) => {
-                        let mut ok_res = String::new();
-                        for i in &v {
-                            ok_res.push_str("* ");
-                            ok_res.push_str(&i.to_string()[..]);
-                            ok_res.push_str(" EXPUNGE\r\n");
-                        }
-                        ok_res.push_str(tag);
-                        ok_res.push_str(" OK expunge completed\r\n");
-                        ok_res
+                match self.folder {
+                    None => bad_res,
+                    Some(ref mut folder) => {
+                        ResponseWriter::new()
+                            .responses(&folder.expunge_response(self.qresync))
+                            .tagged_ok(tag, "expunge completed")
+                            .into_string()
                     }
                 }
             }
+            "append" => {
+                let maildir = match self.maildir {
+                    None => return bad_res,
+                    Some(ref maildir) => maildir
+                };
+                match append::append(&self.mail_store(maildir), self.serv.conf.max_message_size,
+                                     raw_args(line), tag) {
+                    Some(res) => res,
+                    None => bad_res
+                }
+            }
             "fetch" => {
+                // Parse command, make sure it is validly formed. Expunged
+                // messages are only reported by UID.
+                let parsed_cmd = match fetch::fetch(args.collect()) {
+                    Ok(ref cmd) if cmd.vanished => return bad_res,
+                    Ok(cmd) => self.changed_since(cmd),
+                    _ => return bad_res
+                };
+                let options = self.fetch_options();
+
                 // Retrieve the current folder, if it exists.
                 // If it doesn't, the command is invalid.
                 let folder = match self.folder {
-                    Some(ref mut folder) => folder,
+                    Some(ref mut folder) => &mut **folder,
                     None => return bad_res
                 };
 
-                // Parse command, make sure it is validly formed.
-                let parsed_cmd = match fetch::fetch(args.collect()) {
-                    Ok(cmd) => cmd,
-                    _ => return bad_res
-                };
-
-                /*
-                 * Verify that the requested sequence set is valid.
-                 *
-                 * Per RFC 3501 seq-number definition:
-                 * "The server should respond with a tagged BAD
-                 * response to a command that uses a message
-                 * sequence number greater than the number of
-                 * messages in the selected mailbox. This
-                 * includes "*" if the selected mailbox is empty."
-                 */
+                // Naming a message beyond the end of the folder is an
+                // error, but a range or wildcard which matches no messages,
+                // such as 1:* in an empty folder, simply returns no data.
+                if !sequence_set::in_range(&parsed_cmd.sequence_set, folder.message_count()) {
+                    return bad_res;
+                }
                 let sequence_iter = sequence_set::iterator
                     (&parsed_cmd.sequence_set,
                      folder.message_count());
-                if sequence_iter.is_empty() { return bad_res }
                 fetch::fetch_loop(&parsed_cmd, folder,
                                   &sequence_iter, tag,
-                                  false)
+                                  false, options, out)
             },
             // These commands use UIDs instead of sequence numbers.
             // Sequence numbers map onto the list of messages in the
@@ -467,27 +750,44 @@ impl ImapSession {
                     Some(uidcmd) => {
                         match &uidcmd.to_ascii_lowercase()[..] {
                             "fetch" => {
+                                // Parse the command with the PEG
+                                // parser. Expunged messages can only be
+                                // asked for along with the changes since
+                                // they went, once QRESYNC is enabled.
+                                let mut parsed_cmd = match fetch::fetch(args.collect()) {
+                                    Ok(ref cmd) if cmd.vanished &&
+                                        (!self.qresync || cmd.changed_since.is_none()) =>
+                                        return bad_res,
+                                    Ok(cmd) => self.changed_since(cmd),
+                                    _ => return bad_res
+                                };
+                                // The UID always comes first in the
+                                // responses.
+                                parsed_cmd.attributes.retain(|attr| *attr != UID);
+                                parsed_cmd.attributes.insert(0, UID);
+                                let options = self.fetch_options();
+
                                 // Retrieve the current folder, if it
                                 // exists.
                                 let folder = match self.folder {
-                                    Some(ref mut folder) => folder,
+                                    Some(ref mut folder) => &mut **folder,
                                     None => return bad_res
                                 };
-                                // Parse the command with the PEG
-                                // parser.
-                                let mut parsed_cmd = match fetch::fetch(args.collect()) {
-                                    Ok(cmd) => cmd,
-                                    _ => return bad_res
-                                };
-                                parsed_cmd.attributes.push(UID);
+                                if let (true, Some(modseq)) = (parsed_cmd.vanished,
+                                                               parsed_cmd.changed_since) {
+                                    let vanished = folder.vanished_response(
+                                        modseq, Some(&parsed_cmd.sequence_set));
+                                    if out.write_all(vanished.as_bytes()).is_err() {
+                                        return bad_res;
+                                    }
+                                }
 
                                 // SPECIAL CASE FOR RANGES WITH WILDCARDS
                                 if let Range(ref a, ref b) = parsed_cmd.sequence_set[0] {
                                     if let Number(n) = **a {
                                         if let Wildcard = **b {
-                                            if folder.message_count() == 0 { return bad_res }
                                             let start = match folder.get_index_from_uid(&n) {
-                                                Some(start) => *start,
+                                                Some(start) => start,
                                                 None => {
                                                     if n == 1 {
                                                         0usize
@@ -496,13 +796,9 @@ impl ImapSession {
                                                     }
                                                 }
                                             };
-                                            let mut res = String::new();
-                                            for index in start..folder.message_count() {
-                                                res.push_str(&folder.fetch(index+1, &parsed_cmd.attributes)[..]);
-                                            }
-                                            res.push_str(tag);
-                                            res.push_str(" OK UID FETCH completed\r\n");
-                                            return res
+                                            let uids = folder.uids_from_index(start);
+                                            return fetch::fetch_loop(&parsed_cmd, folder, &uids, tag, true,
+                                                                     options, out);
                                         }
                                     }
                                 };
@@ -519,17 +815,31 @@ impl ImapSession {
                                  */
                                 let sequence_iter = sequence_set::uid_iterator(&parsed_cmd.sequence_set);
                                 if sequence_iter.is_empty() { return bad_res; }
-                                fetch::fetch_loop(&parsed_cmd, folder, &sequence_iter, tag, true)
+                                fetch::fetch_loop(&parsed_cmd, folder, &sequence_iter, tag, true,
+                                                  options, out)
+                            }
+                            "search" => {
+                                let folder = match self.folder {
+                                    None => return bad_res,
+                                    Some(ref folder) => &**folder
+                                };
+                                match search::search(folder, args.collect(), true,
+                                                     &mut self.condstore, tag) {
+                                    Some(res) => res,
+                                    _ => bad_res
+                                }
                             }
                             "store" => {
+                                let options = self.store_options();
                                 // There should be a folder selected.
                                 let folder = match self.folder {
                                     None => return bad_res,
-                                    Some(ref mut folder) => folder
+                                    Some(ref mut folder) => &mut **folder
                                 };
 
                                 match store::store(folder, &args.collect::<Vec<&str>>(),
-                                                   true, tag) {
+                                                   true, options, &mut self.condstore,
+                                                   &mut self.expunge_later, tag) {
                                     Some(res) => res,
                                     _ => bad_res
                                 }
@@ -540,14 +850,28 @@ impl ImapSession {
                     None => bad_res
                 }
             },
+            "search" => {
+                // There should be a folder selected.
+                let folder = match self.folder {
+                    None => { return bad_res; }
+                    Some(ref folder) => &**folder
+                };
+
+                match search::search(folder, args.collect(), false, &mut self.condstore, tag) {
+                    Some(res) => res,
+                    _ => bad_res
+                }
+            }
             "store" => {
+                let options = self.store_options();
                 // There should be a folder selected.
                 let folder = match self.folder {
                     None => { return bad_res; }
-                    Some(ref mut folder) => folder
+                    Some(ref mut folder) => &mut **folder
                 };
 
-                match store::store(folder, &args.collect::<Vec<&str>>(), false, tag) {
+                match store::store(folder, &args.collect::<Vec<&str>>(), false, options,
+                                   &mut self.condstore, &mut self.expunge_later, tag) {
                     Some(res) => res,
                     _ => bad_res
                 }
@@ -556,15 +880,292 @@ impl ImapSession {
         }
     }
 
+    /// The store of the mail in the logged in user's maildir
+    /// Freshly provisioned users may not have an INBOX yet. It is created
+    /// along with the configured default folders the first time they log
+    /// in.
+    fn provision(&self, maildir: &str) {
+        let layout = self.serv.conf.maildir_layout;
+        let inbox = match maildir::mailbox_path(Path::new(maildir), "INBOX", layout) {
+            Some(inbox) => inbox,
+            None => return
+        };
+        let first_login = !inbox.join("cur").is_dir();
+        if let Err(e) = maildir::create_folder(&inbox) {
+            warn!("Failed to create INBOX in {}: {}", maildir, e);
+            return;
+        }
+        if !first_login {
+            return;
+        }
+        for folder in &self.serv.conf.default_folders {
+            if let Some(path) = maildir::mailbox_path(Path::new(maildir), &folder.name, layout) {
+                if let Err(e) = maildir::create_folder(&path) {
+                    warn!("Failed to create {} in {}: {}", folder.name, maildir, e);
+                }
+            }
+        }
+    }
+
+    fn mail_store(&self, maildir: &str) -> MaildirStore {
+        MaildirStore::new(Path::new(maildir), self.serv.conf.maildir_layout,
+                          self.serv.conf.keep_new_until_seen, self.serv.conf.folder_window)
+    }
+
+    // How messages are given in FETCH responses, as configured
+    fn fetch_options(&self) -> fetch::FetchOptions {
+        fetch::FetchOptions {
+            decode_headers: self.serv.conf.decode_headers,
+            crlf: self.serv.conf.normalize_line_endings,
+            report_flags: false
+        }
+    }
+
+    // How STORE expunges messages, as configured and as the client wants to
+    // be told
+    fn store_options(&self) -> store::StoreOptions {
+        store::StoreOptions {
+            auto_expunge: self.serv.conf.auto_expunge_on_delete,
+            vanished: self.qresync
+        }
+    }
+
+    /// Perform SELECT, or EXAMINE if `examine` is set, on the mailbox named
+    /// in `args`.
+    fn select(&mut self, args: &mut Split<char>, examine: bool, tag: &str,
+              bad_res: String) -> String {
+        // The previous folder is given up first so that its lock is
+        // released if it is the one being selected again.
+        self.deselect();
+        let select_args: Vec<&str> = args.collect();
+        let params = match select::params(select_args.get(1..).unwrap_or(&[])) {
+            Some(params) => params,
+            None => return bad_res
+        };
+        // QRESYNC has to be enabled before it can be used.
+        if params.qresync.is_some() && !self.qresync {
+            return bad_res;
+        }
+        if params.condstore {
+            self.condstore = true;
+        }
+        let maildir = match self.maildir {
+            None => { return bad_res; }
+            Some(ref maildir) => maildir
+        };
+        let (folder, res) = util::perform_select(&self.mail_store(maildir), &select_args,
+                                                 examine, self.condstore,
+                                                 params.qresync.as_ref(), tag);
+        self.folder = folder;
+        if res.is_empty() { bad_res } else { res }
+    }
+
+    /// Prepare a FETCH for the messages changed since a mod-sequence, if
+    /// it is for them. Their mod-sequences are sent with them, and asking
+    /// for them enables CONDSTORE.
+    fn changed_since(&mut self, mut cmd: FetchCommand) -> FetchCommand {
+        if cmd.changed_since.is_some() {
+            self.condstore = true;
+            if !cmd.attributes.contains(&ModSeq) {
+                cmd.attributes.push(ModSeq);
+            }
+        }
+        cmd
+    }
+
+    /// Write out the selected folder's flags and deselect it, giving up its
+    /// lock. Messages still waiting to be expunged after a STORE go now, as
+    /// the client no longer needs telling.
+    fn deselect(&mut self) {
+        if let Some(ref mut folder) = self.folder {
+            folder.uid_expunge(&self.expunge_later);
+            folder.check();
+        }
+        self.expunge_later.clear();
+        self.folder = None;
+    }
+
     // should generate list of sequence numbers that were deleted
-    fn expunge(&self) -> Result<Vec<usize>, Error> {
+    fn expunge(&mut self) -> Result<Vec<usize>, Error> {
         match self.folder {
             None => {
                 Err(Error::InvalidImapState)
             }
-            Some(ref folder) => {
+            Some(ref mut folder) => {
                 Ok(folder.expunge())
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufRead, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use bufstream::BufStream;
+
+    use super::{capability, discard_input, insert_before_tagged, may_send_expunge, raw_args,
+                read_command, request_continuation, Input};
+
+    /// A client which sends each of its chunks only once it has been sent
+    /// as many continuation requests as come before it.
+    struct Client {
+        chunks: Vec<Vec<u8>>,
+        sent: usize,
+        received: Vec<u8>
+    }
+
+    impl Client {
+        fn new(chunks: &[&str]) -> Client {
+            Client {
+                chunks: chunks.iter().map(|chunk| chunk.as_bytes().to_vec()).collect(),
+                sent: 0,
+                received: Vec::new()
+            }
+        }
+
+        fn continuations(&self) -> usize {
+            self.received.split(|&b| b == b'\n').filter(|line| line.starts_with(b"+ ")).count()
+        }
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.sent >= self.chunks.len() {
+                return Ok(0);
+            }
+            if self.sent > self.continuations() {
+                return Err(io::Error::new(io::ErrorKind::Other, "read before continuation request"));
+            }
+            let len = (&self.chunks[self.sent][..]).read(buf)?;
+            self.chunks[self.sent].drain(..len);
+            if self.chunks[self.sent].is_empty() {
+                self.sent += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_discard_input() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let socket = listener.accept().unwrap().0;
+        let mut stream = BufStream::new(socket.try_clone().unwrap());
+
+        // Nothing is waiting, so nothing is discarded.
+        assert_eq!(discard_input(&mut stream, &socket).unwrap(), 0);
+
+        let injected = "a2 LOGIN user@example.com secret\r\n";
+        client.write_all(format!("a1 STARTTLS\r\n{}", injected).as_bytes()).unwrap();
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "a1 STARTTLS\r\n");
+
+        // The pipelined LOGIN is never read as a command.
+        assert_eq!(discard_input(&mut stream, &socket).unwrap(), injected.len());
+        client.write_all(b"a3 NOOP\r\n").unwrap();
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "a3 NOOP\r\n");
+    }
+
+    #[test]
+    fn test_raw_args() {
+        assert_eq!(raw_args(b"a1 APPEND INBOX {4}\r\nHi\r\n\r\n"), b"INBOX {4}\r\nHi\r\n");
+        assert_eq!(raw_args(b"a1 APPEND  INBOX\n"), b" INBOX");
+        assert_eq!(raw_args(b"a1 NOOP\r\n"), b"");
+    }
+
+    #[test]
+    fn test_may_send_expunge() {
+        assert!(may_send_expunge("NOOP"));
+        assert!(may_send_expunge("uid"));
+        assert!(!may_send_expunge("Store"));
+        assert!(!may_send_expunge("fetch"));
+        assert!(!may_send_expunge("SEARCH"));
+
+        let mut res = "* 1 FETCH (FLAGS ())\r\na1 OK NOOP\r\n".to_string();
+        insert_before_tagged(&mut res, "* 2 EXPUNGE\r\n");
+        assert_eq!(res, "* 1 FETCH (FLAGS ())\r\n* 2 EXPUNGE\r\na1 OK NOOP\r\n");
+        let mut res = "a1 OK NOOP\r\n".to_string();
+        insert_before_tagged(&mut res, "* 2 EXPUNGE\r\n");
+        assert_eq!(res, "* 2 EXPUNGE\r\na1 OK NOOP\r\n");
+    }
+
+    #[test]
+    fn test_capability() {
+        assert_eq!(capability(false),
+                   "CAPABILITY IMAP4rev1 CHILDREN ENABLE CONDSTORE QRESYNC ESEARCH SPECIAL-USE");
+        assert_eq!(capability(true),
+                   "CAPABILITY IMAP4rev1 CHILDREN ENABLE CONDSTORE QRESYNC ESEARCH SPECIAL-USE STARTTLS");
+    }
+
+    #[test]
+    fn test_request_continuation() {
+        let mut stream = BufStream::new(Client::new(&["Hello", " world"]));
+        assert_eq!(request_continuation(&mut stream, 5).unwrap(), b"Hello");
+        assert_eq!(stream.get_ref().received, b"+ Ready for literal data\r\n");
+        // The client gave up partway through the literal.
+        assert!(request_continuation(&mut stream, 10).is_err());
+    }
+
+    #[test]
+    fn test_read_command_literals() {
+        let mut stream = BufStream::new(Client::new(&["a1 LOGIN {16}\r\n", "user@example.com {6}\r\n",
+                                                      "secret\r\na2 NOOP\r\n"]));
+        let mut command = Vec::new();
+        assert_eq!(read_command(&mut stream, &mut command, 100, 100, 100).unwrap(), Input::Command(45));
+        assert_eq!(command, b"a1 LOGIN {16}\r\nuser@example.com {6}\r\nsecret\r\n");
+        assert_eq!(stream.get_ref().continuations(), 2);
+
+        // Literals which aren't UTF-8 are kept as they were sent.
+        let mut stream = BufStream::new(Client::new(&["a1 APPEND INBOX {3}\r\n"]));
+        stream.get_mut().chunks.push(b"\xe9t\xe9\r\n".to_vec());
+        command.clear();
+        assert_eq!(read_command(&mut stream, &mut command, 100, 100, 100).unwrap(), Input::Command(26));
+        assert_eq!(command, b"a1 APPEND INBOX {3}\r\n\xe9t\xe9\r\n");
+
+        // A literal which is too large is refused without asking for it.
+        let mut stream = BufStream::new(Client::new(&["a1 APPEND INBOX {200}\r\n"]));
+        command.clear();
+        assert_eq!(read_command(&mut stream, &mut command, 100, 100, 100).unwrap(),
+                   Input::LiteralTooLarge);
+        assert_eq!(stream.get_ref().continuations(), 0);
+    }
+
+    #[test]
+    fn test_read_command_too_long() {
+        // A line is only read up to the limit.
+        let mut stream = BufStream::new(Client::new(&["a1 SEARCH SUBJECT hello\r\n"]));
+        let mut command = Vec::new();
+        assert_eq!(read_command(&mut stream, &mut command, 100, 100, 10).unwrap(), Input::TooLong);
+        assert_eq!(command, b"a1 SEARCH ");
+
+        // Literals which are each small enough can't add up to more.
+        let mut stream = BufStream::new(Client::new(&["a1 LOGIN {8}\r\n", "user@exa {8}\r\n",
+                                                      "secret12\r\n"]));
+        command.clear();
+        assert_eq!(read_command(&mut stream, &mut command, 10, 100, 30).unwrap(),
+                   Input::LiteralTooLarge);
+        assert_eq!(stream.get_ref().continuations(), 1);
+
+        // The message given to APPEND doesn't count.
+        let mut stream = BufStream::new(Client::new(&["a1 APPEND INBOX {50}\r\n"]));
+        stream.get_mut().chunks.push([&[b'x'; 50][..], b"\r\n"].concat());
+        command.clear();
+        assert_eq!(read_command(&mut stream, &mut command, 10, 100, 30).unwrap(), Input::Command(74));
+    }
+}
@@ -1,17 +1,17 @@
 use std::ascii::AsciiExt;
-use std::fs::File;
-use std::io::{BufRead, Write};
-use std::io::ErrorKind::AlreadyExists;
-use std::net::TcpStream;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
 use std::path::Path;
 use std::sync::Arc;
 
 use bufstream::BufStream;
-use num::ToPrimitive;
 use time;
 
+use index;
+use maildir;
 use server::Server;
-use server::user::{Email, User};
+use server::user::Email;
 
 // Just bail if there is some error.
 // Used when performing operations on a TCP Stream generally
@@ -24,13 +24,6 @@ macro_rules! return_on_err(
     }
 );
 
-macro_rules! delivery_ioerror(
-    ($res:ident) => ({
-        $res.push_str("451 Error in processing.\r\n");
-        break;
-    })
-);
-
 macro_rules! grab_email_token(
     ($arg:expr) => {
         match $arg {
@@ -40,57 +33,180 @@ macro_rules! grab_email_token(
     }
 );
 
-struct Lmtp<'a> {
+struct Lmtp {
+    // The client's address and the name it gave in LHLO
+    peer: SocketAddr,
+    lhlo: Option<String>,
     rev_path: Option<Email>,
-    to_path: Vec<&'a User>,
-    data: String,
+    // The recipients and their maildirs
+    to_path: Vec<(Email, String)>,
+    data: Vec<u8>,
     quit: bool
 }
 
-static OK: &'static str = "250 OK\r\n";
+/// The replies to LMTP commands which carry an enhanced status code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reply {
+    Ok,
+    SenderOk,
+    RecipientOk,
+    Delivered,
+    InvalidCommand,
+    BadSequence,
+    NoSuchUser,
+    MailboxFull,
+    TooBig,
+    ProcessingError
+}
+
+impl Reply {
+    // The reply code, enhanced status code (RFC 3463) and text of each reply
+    fn parts(&self) -> (u16, &'static str, &'static str) {
+        match *self {
+            Reply::Ok => (250, "2.0.0", "OK"),
+            Reply::SenderOk => (250, "2.1.0", "Sender OK"),
+            Reply::RecipientOk => (250, "2.1.5", "Recipient OK"),
+            Reply::Delivered => (250, "2.0.0", "Delivered"),
+            Reply::InvalidCommand => (500, "5.5.2", "Invalid command"),
+            Reply::BadSequence => (503, "5.5.1", "Bad sequence - no recipients"),
+            Reply::NoSuchUser => (550, "5.1.1", "No such user"),
+            Reply::MailboxFull => (552, "5.2.2", "Mailbox full"),
+            Reply::TooBig => (552, "5.3.4", "Message size exceeds fixed maximum message size"),
+            Reply::ProcessingError => (451, "4.3.0", "Error in processing")
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (code, enhanced, text) = self.parts();
+        write!(f, "{} {} {}\r\n", code, enhanced, text)
+    }
+}
 
-impl<'a> Lmtp<'a> {
-    fn deliver(&self) -> String {
+impl Lmtp {
+    /// Deliver the message to each recipient, returning a response for each
+    /// of them. If `delivered_to` is set, each copy records the address it
+    /// was delivered to in a Delivered-To header.
+    fn deliver(&self, delivered_to: bool) -> String {
         if self.to_path.is_empty() {
-            return "503 Bad sequence - no recipients".to_string();
+            return Reply::BadSequence.to_string();
         }
         let mut res = String::new();
-        for rcpt in &self.to_path {
-            let mut timestamp = match time::get_time().sec.to_i32() {
-                Some(i) => i,
-                None => {
-                    res.push_str("555 Unix 2038 error\r\n");
-                    break;
+        for &(ref rcpt, ref maildir) in &self.to_path {
+            let maildir_path = Path::new(&maildir[..]);
+            let mut data = Vec::new();
+            if delivered_to {
+                data.extend_from_slice(format!("Delivered-To: {}\r\n", rcpt.to_string()).as_bytes());
+            }
+            data.extend_from_slice(&self.data[..]);
+            match maildir::over_quota(maildir_path, data.len() as u64) {
+                Ok(true) => {
+                    res.push_str(&Reply::MailboxFull.to_string()[..]);
+                    continue;
                 }
-            };
-            let maildir = rcpt.maildir.clone();
-            let newdir_path = Path::new(&maildir[..]).join("new");
-            loop {
-                match File::create(&newdir_path.join(timestamp.to_string())) {
-                    Err(e) => {
-                        if e.kind() == AlreadyExists {
-                            timestamp += 1;
-                        } else {
-                            delivery_ioerror!(res);
-                        }
-                    }
-                    Ok(mut file) => {
-                        if file.write(self.data.as_bytes()).is_err() {
-                            delivery_ioerror!(res);
-                        }
-                        if file.flush().is_err() {
-                            delivery_ioerror!(res);
-                        }
-                        res.push_str("250 OK\r\n");
-                        break;
-                    }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to check quota for {}: {}", maildir, e)
+            }
+            let path = match maildir::deliver_atomic(maildir_path, &data[..]) {
+                Ok(path) => path,
+                Err(e) => {
+                    error!("Failed to deliver to {}: {}", maildir, e);
+                    res.push_str(&Reply::ProcessingError.to_string()[..]);
+                    continue;
                 }
+            };
+            if let Err(e) = maildir::update_size(maildir_path, data.len() as i64, 1) {
+                warn!("Failed to update maildirsize for {}: {}", maildir, e);
             }
+            let uid = path_filename_to_str!(path).parse().unwrap_or(0);
+            if let Err(e) = index::record_delivery(maildir_path, uid) {
+                warn!("Failed to update folder index for {}: {}", maildir, e);
+            }
+            res.push_str(&Reply::Delivered.to_string()[..]);
         }
         res
     }
 }
 
+/// The address of a peer as written in the Received header: an IPv4 address
+/// or an IPv6 address literal in brackets. IPv4 clients reaching a dual-stack
+/// socket show up as IPv4-mapped IPv6 addresses, which are given as IPv4.
+fn address_literal(addr: &IpAddr) -> String {
+    match *addr {
+        IpAddr::V4(ref v4) => format!("[{}]", v4),
+        IpAddr::V6(ref v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => format!("[{}]", v4),
+            None => format!("[IPv6:{}]", v6)
+        }
+    }
+}
+
+/// The Received header recording that the message came from `peer`, which
+/// introduced itself as `lhlo`, and was accepted by `host`.
+fn received_header(lhlo: &str, peer: &SocketAddr, host: &str, date: &time::Tm) -> String {
+    format!("Received: from {} ({})\r\n\tby {} with LMTP; {}\r\n",
+            lhlo, address_literal(&peer.ip()), host, date.rfc822z())
+}
+
+/// Read the message sent after DATA, up to the line holding a lone ".".
+/// Lines are stored with CRLF line endings, which FETCH must return them
+/// with, so a client which ends lines with a bare LF has them given a CR.
+/// The dots added to lines starting with one are removed, but the message
+/// is otherwise kept byte for byte. Once the message grows beyond
+/// `max_size` the rest of it is read but not kept, and None is returned.
+fn read_data<R: BufRead>(stream: &mut R, max_size: Option<usize>) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let mut too_big = false;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if stream.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == b".\r\n" || line == b".\n" {
+            return Ok(if too_big { None } else { Some(data) });
+        }
+        if too_big {
+            continue;
+        }
+
+        let start = if line.starts_with(b".") { 1 } else { 0 };
+        let end = if line.ends_with(b"\r\n") { line.len() - 2 } else { line.len() - 1 };
+        data.extend_from_slice(&line[start..end]);
+        data.extend_from_slice(b"\r\n");
+        too_big = max_size.map_or(false, |max| data.len() > max);
+    }
+}
+
+/// Build the reply to LHLO, which lists the extensions supported one per
+/// line. A SIZE of 0 means there is no limit.
+fn lhlo_response(host: &str, max_size: Option<usize>) -> String {
+    let lines = vec![host.to_string(),
+                     "PIPELINING".to_string(),
+                     "ENHANCEDSTATUSCODES".to_string(),
+                     "8BITMIME".to_string(),
+                     format!("SIZE {}", max_size.unwrap_or(0))];
+    let mut res = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        res.push_str(if i + 1 == lines.len() { "250 " } else { "250-" });
+        res.push_str(line);
+        res.push_str("\r\n");
+    }
+    res
+}
+
+/// Find the size the client declared for its message with a SIZE=<n>
+/// parameter to MAIL. Returns Err if the parameter is malformed.
+fn declared_size<'a, I: Iterator<Item=&'a str>>(params: I) -> Result<Option<usize>, ()> {
+    for param in params {
+        if param.len() > 5 && param[..5].eq_ignore_ascii_case("SIZE=") {
+            return param[5..].parse().map(Some).map_err(|_| ());
+        }
+    }
+    Ok(None)
+}
+
 fn grab_email(arg: Option<&str>) -> Option<Email> {
     let from_path_split = match arg {
         Some(full_from_path) => {
@@ -109,27 +225,20 @@ fn grab_email(arg: Option<&str>) -> Option<Email> {
         }
         _ => { return None; }
     };
-    let mut from_parts = from_path_split.split('@');
-    let local_part = match from_parts.next() {
-        Some(part) => part.to_string(),
-        _ => { return None; }
-    };
-    let domain_part = match from_parts.next() {
-        Some(part) => part.to_string(),
-        _ => { return None; }
-    };
-    Some(Email::new(local_part, domain_part))
+    Email::parse(from_path_split)
 }
 
-pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
+pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>, peer: SocketAddr) {
     let mut l = Lmtp {
+        peer: peer,
+        lhlo: None,
         rev_path: None,
         to_path: Vec::new(),
-        data: String::new(),
+        data: Vec::new(),
         quit: false
     };
-    return_on_err!(stream.write(format!("220 {} LMTP server ready\r\n",
-                                        *serv.host()).as_bytes()));
+    return_on_err!(stream.write_all(format!("220 {} LMTP server ready\r\n",
+                                            *serv.host()).as_bytes()));
     return_on_err!(stream.flush());
     loop {
         let mut command = String::new();
@@ -140,10 +249,9 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                 }
                 let trimmed_command = (&command[..]).trim();
                 let mut args = trimmed_command.split(' ');
-                let invalid = "500 Invalid command\r\n".to_string();
-                let no_such_user = "550 No such user".to_string();
-                let data_res = b"354 Start mail input; end with <CRLF>.<CRLF>";
-                let ok_res = OK.to_string();
+                let invalid = Reply::InvalidCommand.to_string();
+                let data_res = b"354 Start mail input; end with <CRLF>.<CRLF>\r\n";
+                let ok_res = Reply::Ok.to_string();
                 let res = match args.next() {
                     Some(cmd) => {
                         warn!("LMTP Cmd: {}", trimmed_command);
@@ -151,7 +259,8 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                             "lhlo" => {
                                 match args.next() {
                                     Some(domain) => {
-                                        format!("250 {}\r\n", domain)
+                                        l.lhlo = Some(domain.to_string());
+                                        lhlo_response(serv.host(), serv.conf.max_message_size)
                                     }
                                     _ => invalid
                                 }
@@ -164,18 +273,22 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                             "noop" => ok_res,
                             "quit" => {
                                 l.quit = true;
-                                format!("221 {} Closing connection\r\n",
+                                format!("221 2.0.0 {} Closing connection\r\n",
                                         *serv.host())
                             }
                             "vrfy" => {
                                 invalid
                             }
                             "mail" => {
-                                match grab_email(args.next()) {
-                                    None => invalid,
-                                    s => {
+                                match (grab_email(args.next()), declared_size(args)) {
+                                    (None, _) | (_, Err(_)) => invalid,
+                                    // Turn away a message which is too big
+                                    // before it is sent.
+                                    (_, Ok(Some(size))) if serv.conf.max_message_size
+                                        .map_or(false, |max| size > max) => Reply::TooBig.to_string(),
+                                    (s, Ok(_)) => {
                                         l.rev_path = s;
-                                        ok_res
+                                        Reply::SenderOk.to_string()
                                     }
                                 }
                             }
@@ -186,12 +299,15 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                                         match grab_email(args.next()) {
                                             None => invalid,
                                             Some(email) => {
-                                                match serv.users.get(&email) {
-                                                    None => no_such_user,
-                                                    Some(user) => {
-                                                        l.to_path.push(user);
-                                                        ok_res
+                                                match serv.lookup_user(&email) {
+                                                    Ok(None) => Reply::NoSuchUser.to_string(),
+                                                    Ok(Some(maildir)) => {
+                                                        l.to_path.push((email, maildir));
+                                                        Reply::RecipientOk.to_string()
                                                     }
+                                                    // Try again later rather than
+                                                    // bouncing the message.
+                                                    Err(_) => Reply::ProcessingError.to_string()
                                                 }
                                             }
                                         }
@@ -199,36 +315,37 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                                 }
                             }
                             "data" => {
-                                return_on_err!(stream.write(data_res));
-                                return_on_err!(stream.flush());
-                                let mut loop_res = invalid;
-                                loop {
-                                    let mut data_command = String::new();
-                                    match stream.read_line(&mut data_command) {
-                                        Ok(_) => {
-                                            if data_command.is_empty() {
-                                                break;
-                                            }
-                                            let data_cmd = (&data_command[..]).trim();
-                                            if data_cmd == "." {
-                                                loop_res = l.deliver();
-                                                l.data = String::new();
-                                                break;
-                                            }
-                                            l.data.push_str(data_cmd);
-                                            l.data.push('\n');
+                                if l.to_path.is_empty() {
+                                    Reply::BadSequence.to_string()
+                                } else {
+                                    return_on_err!(stream.write_all(data_res));
+                                    return_on_err!(stream.flush());
+                                    let res = match read_data(&mut stream, serv.conf.max_message_size) {
+                                        Ok(Some(data)) => {
+                                            let lhlo = l.lhlo.as_ref().map_or("unknown", |lhlo| &lhlo[..]);
+                                            l.data = received_header(lhlo, &l.peer, serv.host(), &time::now())
+                                                .into_bytes();
+                                            l.data.extend_from_slice(&data[..]);
+                                            l.deliver(serv.conf.delivered_to_header)
                                         }
-                                        _ => { break; }
-                                    }
+                                        // One reply per recipient
+                                        Ok(None) => l.to_path.iter()
+                                            .map(|_| Reply::TooBig.to_string()).collect(),
+                                        Err(_) => return
+                                    };
+                                    // The transaction is over whatever happened.
+                                    l.rev_path = None;
+                                    l.to_path = Vec::new();
+                                    l.data = Vec::new();
+                                    res
                                 }
-                                loop_res
                             }
                             _ => invalid
                         }
                     }
                     None => invalid
                 };
-                return_on_err!(stream.write(res.as_bytes()));
+                return_on_err!(stream.write_all(res.as_bytes()));
                 return_on_err!(stream.flush());
                 if l.quit {
                     return;
@@ -238,3 +355,137 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Cursor, Read};
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::PathBuf;
+
+    use time;
+
+    use fixture;
+    use server::user::Email;
+    use super::{address_literal, declared_size, lhlo_response, read_data, received_header, Lmtp, Reply};
+
+    #[test]
+    fn test_lhlo_response() {
+        assert_eq!(lhlo_response("mail.example.com", Some(1000)),
+                   "250-mail.example.com\r\n250-PIPELINING\r\n250-ENHANCEDSTATUSCODES\r\n\
+                    250-8BITMIME\r\n250 SIZE 1000\r\n");
+        // Only the last line has a space after the code.
+        let res = lhlo_response("mail.example.com", None);
+        let lines: Vec<&str> = res.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[..4].iter().all(|line| line.starts_with("250-")));
+        assert_eq!(lines[4], "250 SIZE 0");
+    }
+
+    #[test]
+    fn test_read_data() {
+        let mut stream = Cursor::new(&b"Subject: hi\r\n\r\n\r\n..dot\r\nbare\rcr \xff\xfe\r\n\
+                                        .\r\nQUIT\r\n"[..]);
+        assert_eq!(read_data(&mut stream, None).unwrap(),
+                   Some(b"Subject: hi\r\n\r\n\r\n.dot\r\nbare\rcr \xff\xfe\r\n".to_vec()));
+        // Whatever follows the message is left to be read.
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "QUIT\r\n");
+
+        let mut stream = Cursor::new(&b"0123456789\r\n0123456789\r\n.\r\nQUIT\r\n"[..]);
+        assert_eq!(read_data(&mut stream, Some(15)).unwrap(), None);
+        rest.clear();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "QUIT\r\n");
+
+        // Bare LFs are given a CR.
+        let mut stream = Cursor::new(&b"Subject: hi\n\nHi\r\n..\n.\n"[..]);
+        assert_eq!(read_data(&mut stream, None).unwrap(),
+                   Some(b"Subject: hi\r\n\r\nHi\r\n.\r\n".to_vec()));
+
+        assert!(read_data(&mut Cursor::new(&b"Subject: hi\r\n"[..]), None).is_err());
+    }
+
+    #[test]
+    fn test_enhanced_codes() {
+        assert_eq!(Reply::Ok.to_string(), "250 2.0.0 OK\r\n");
+        assert_eq!(Reply::RecipientOk.to_string(), "250 2.1.5 Recipient OK\r\n");
+        assert_eq!(Reply::NoSuchUser.to_string(), "550 5.1.1 No such user\r\n");
+        assert_eq!(Reply::TooBig.to_string(),
+                   "552 5.3.4 Message size exceeds fixed maximum message size\r\n");
+    }
+
+    #[test]
+    fn test_declared_size() {
+        assert_eq!(declared_size("BODY=8BITMIME size=1200".split(' ')), Ok(Some(1200)));
+        assert_eq!(declared_size("BODY=8BITMIME".split(' ')), Ok(None));
+        assert_eq!(declared_size("".split(' ')), Ok(None));
+        assert_eq!(declared_size("SIZE=big".split(' ')), Err(()));
+    }
+
+    #[test]
+    fn test_delivered_to() {
+        let dir = fixture::dir("lmtp");
+        let (alice, bob) = (dir.join("alice"), dir.join("bob"));
+        for maildir in &[&alice, &bob] {
+            fs::create_dir_all(maildir.join("new")).unwrap();
+        }
+        let rcpt = |name: &str, maildir: &PathBuf| {
+            (Email::new(name.to_string(), "example.com".to_string()),
+             maildir.to_str().unwrap().to_string())
+        };
+        let mut l = Lmtp {
+            peer: "127.0.0.1:2525".parse().unwrap(),
+            lhlo: None,
+            rev_path: None,
+            to_path: vec![rcpt("alice", &alice), rcpt("bob", &bob)],
+            data: b"Subject: hi\r\n\r\nHello\r\n".to_vec(),
+            quit: false
+        };
+        let delivered = |maildir: &PathBuf| {
+            let mut contents = String::new();
+            for entry in fs::read_dir(maildir.join("new")).unwrap() {
+                let path = entry.unwrap().path();
+                File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+                fs::remove_file(&path).unwrap();
+            }
+            contents
+        };
+
+        // Each copy names its own recipient.
+        assert_eq!(l.deliver(true), "250 2.0.0 Delivered\r\n250 2.0.0 Delivered\r\n");
+        assert_eq!(delivered(&alice), "Delivered-To: alice@example.com\r\nSubject: hi\r\n\r\nHello\r\n");
+        assert_eq!(delivered(&bob), "Delivered-To: bob@example.com\r\nSubject: hi\r\n\r\nHello\r\n");
+
+        l.to_path.truncate(1);
+        assert_eq!(l.deliver(false), "250 2.0.0 Delivered\r\n");
+        assert_eq!(delivered(&alice), "Subject: hi\r\n\r\nHello\r\n");
+
+        // A maildir which can't be written to is a temporary failure.
+        l.to_path.push(rcpt("carol", &dir.join("carol")));
+        assert_eq!(l.deliver(false), "250 2.0.0 Delivered\r\n451 4.3.0 Error in processing\r\n");
+        l.to_path.clear();
+        assert_eq!(l.deliver(false), "503 5.5.1 Bad sequence - no recipients\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_received_header() {
+        let literal = |addr: &str| address_literal(&addr.parse::<IpAddr>().unwrap());
+        assert_eq!(literal("192.0.2.1"), "[192.0.2.1]");
+        assert_eq!(literal("2001:db8::1"), "[IPv6:2001:db8::1]");
+        assert_eq!(literal("::1"), "[IPv6:::1]");
+        assert_eq!(literal("::ffff:192.0.2.1"), "[192.0.2.1]");
+
+        let date = time::at_utc(time::Timespec::new(1500000000, 0));
+        let peer: SocketAddr = "[2001:db8::1]:2525".parse().unwrap();
+        assert_eq!(received_header("mx.example.org", &peer, "mail.example.com", &date),
+                   format!("Received: from mx.example.org ([IPv6:2001:db8::1])\r\n\
+                            \tby mail.example.com with LMTP; {}\r\n", date.rfc822z()));
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        assert!(received_header("mx.example.org", &peer, "mail.example.com", &date)
+                .starts_with("Received: from mx.example.org ([192.0.2.1])\r\n"));
+    }
+}
@@ -1,21 +1,32 @@
-use std::collections::HashMap;
-use std::io::{Read, Result, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::fs;
+use std::io::{self, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
 use bufstream::{BufStream, IntoInnerError};
 use openssl::ssl::{SslAcceptor, SslStream};
 
-use error::ImapResult;
+use error::{Error, ImapResult};
+use index;
+use maildir;
+use mailstore::{MailStore, MaildirStore};
+use uidlist::{self, Repair};
 use self::config::Config;
 use self::imap::ImapSession;
-use self::user::{load_users, Email, LoginData, User};
+use self::proxy::AddressRange;
+use self::user::{read_users, set_password, CommandUserStore, Email, JsonUserStore, LoginData, UserBackend,
+                 UserStore};
 
 mod config;
 #[macro_use]
 pub mod lmtp;
 mod imap;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
 mod user;
 
 pub enum Stream {
@@ -48,28 +59,47 @@ impl Read for Stream {
     }
 }
 
-/// Holds configuration state and email->user map
+/// Holds configuration state and the source of user data
 pub struct Server {
     conf: Config,
-    users: HashMap<Email, User>,
-    ssl_acceptor: Option<SslAcceptor>,
+    users: Box<dyn UserStore>,
+    ssl_acceptor: RwLock<Option<SslAcceptor>>,
+    // The modification times of the TLS files when the acceptor was built
+    tls_loaded: Mutex<Vec<Option<SystemTime>>>,
+    trusted_proxies: Vec<AddressRange>,
 }
 
 impl Server {
-    pub fn new() -> ImapResult<Server> {
-        Server::new_with_conf(Config::new()?)
+    /// Create a server from the config file at `config_path`, or from the
+    /// first config file found if no path is given.
+    pub fn new(config_path: Option<&Path>) -> ImapResult<Server> {
+        Server::new_with_conf(Config::new(config_path)?)
     }
 
-    /// Create server to hold the Config and User HashMap
+    /// Create server to hold the Config and UserStore
     fn new_with_conf(conf: Config) -> ImapResult<Server> {
-        // Load the user data from the specified user data file.
-        let users = load_users(&conf.users)?;
+        let users: Box<dyn UserStore> = match conf.user_backend {
+            // Load the user data from the specified user data file.
+            UserBackend::Json => Box::new(JsonUserStore::new(&conf.users)?),
+            UserBackend::Command => match conf.auth_command {
+                Some(ref command) =>
+                    Box::new(CommandUserStore::new(command.clone(),
+                                                   Duration::from_secs(conf.auth_command_timeout))),
+                None => return Err(Error::MissingAuthCommand)
+            }
+        };
+        let tls_loaded = tls_modified(&conf);
         let ssl_acceptor = conf.get_ssl_acceptor().ok();
+        let trusted_proxies = conf.trust_proxy_from.iter()
+            .filter_map(|range| AddressRange::parse(range))
+            .collect();
 
         Ok(Server {
             conf: conf,
             users: users,
-            ssl_acceptor: ssl_acceptor,
+            ssl_acceptor: RwLock::new(ssl_acceptor),
+            tls_loaded: Mutex::new(tls_loaded),
+            trusted_proxies: trusted_proxies,
         })
     }
 
@@ -102,21 +132,91 @@ impl Server {
         self.generic_listener(self.conf.lmtp_ssl_port)
     }
 
-    pub fn imap_ssl(&self, stream: TcpStream) -> Stream {
+    /// Give up root once the listeners have been bound, chrooting to the
+    /// maildir root first if the config asks for it. Maildirs are found
+    /// within the new root from then on.
+    #[cfg(unix)]
+    pub fn drop_privileges(&mut self) -> Result<()> {
+        let root = if self.conf.chroot { self.conf.maildir_root.clone() } else { None };
+        if root.is_some() {
+            self.conf.enter_chroot();
+        }
+        privileges::drop_privileges(self.conf.run_as_user.as_ref().map(|user| &user[..]),
+                                    self.conf.run_as_group.as_ref().map(|group| &group[..]),
+                                    root.as_ref().map(Path::new))?;
+        if let Some(root) = root {
+            info!("Confined to {}", root);
+            // The users are loaded again from where the file is now, which
+            // also makes sure it can still be read.
+            if self.conf.user_backend == UserBackend::Json {
+                let users = JsonUserStore::new(&self.conf.users)
+                    .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+                self.users = Box::new(users);
+            }
+        }
+        if let Some(ref user) = self.conf.run_as_user {
+            info!("Running as {}", user);
+        }
+        Ok(())
+    }
+
+    // Privileges can't be dropped elsewhere, which the config rejects.
+    #[cfg(not(unix))]
+    pub fn drop_privileges(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Build the TLS acceptor again if a certificate or the session ticket
+    /// key has changed since it was last built, so that they can be replaced
+    /// without a restart. If the new files can't be loaded the previous
+    /// acceptor is kept.
+    fn reload_tls_if_changed(&self) {
+        let current = tls_modified(&self.conf);
+        let mut loaded = self.tls_loaded.lock().unwrap_or_else(|e| e.into_inner());
+        if *loaded == current {
+            return;
+        }
+        // Record the change even if the reload fails, so that a broken file
+        // is only reported once.
+        *loaded = current;
+        match self.conf.get_ssl_acceptor() {
+            Ok(ssl_acceptor) => {
+                info!("Reloaded TLS certificates");
+                *self.ssl_acceptor.write().unwrap_or_else(|e| e.into_inner()) = Some(ssl_acceptor);
+            }
+            Err(e) => error!("Failed to reload TLS certificates, keeping previous ones: {}", e)
+        }
+    }
+
+    fn ssl_acceptor(&self) -> Option<SslAcceptor> {
+        self.reload_tls_if_changed();
+        self.ssl_acceptor.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Wrap a connection to the IMAP SSL port in TLS, leaving any other
+    /// connection as it is. `None` is returned if the handshake fails, which
+    /// drops the connection.
+    pub fn imap_ssl(&self, stream: TcpStream) -> Option<Stream> {
         if let Ok(addr) = stream.local_addr() {
             if Some(addr.port()) == self.conf.imap_ssl_port {
-                if let Some(ref ssl_acceptor) = self.ssl_acceptor {
-                    return Stream::Ssl(ssl_acceptor.accept(stream).unwrap());
+                if let Some(ref ssl_acceptor) = self.ssl_acceptor() {
+                    return match ssl_acceptor.accept(stream) {
+                        Ok(ssl_stream) => Some(Stream::Ssl(ssl_stream)),
+                        Err(e) => {
+                            warn!("TLS handshake failed: {}", e);
+                            None
+                        }
+                    };
                 }
                 error!("Listening on SSL port without SSL certificate configured.");
                 let _ = stream.shutdown(Shutdown::Both);
             }
         }
-        Stream::Tcp(stream)
+        Some(Stream::Tcp(stream))
     }
 
     pub fn can_starttls(&self) -> bool {
-        if let Some(_) = self.ssl_acceptor {
+        if let Some(_) = *self.ssl_acceptor.read().unwrap_or_else(|e| e.into_inner()) {
             true
         } else {
             false
@@ -125,7 +225,7 @@ impl Server {
 
     pub fn starttls(&self, inner_stream: StdResult<Stream, IntoInnerError<BufStream<Stream>>>) -> Option<SslStream<TcpStream>> {
         if let Ok(Stream::Tcp(stream)) = inner_stream {
-            if let Some(ref ssl_acceptor) = self.ssl_acceptor {
+            if let Some(ref ssl_acceptor) = self.ssl_acceptor() {
                 if let Ok(ssl_stream) = ssl_acceptor.accept(stream) {
                     return Some(ssl_stream);
                 }
@@ -134,27 +234,884 @@ impl Server {
         None
     }
 
+    /// Find the address of the client at the other end of `stream`. A
+    /// trusted load balancer names the client in a PROXY protocol header,
+    /// which is read from the stream before anything else.
+    pub fn client_addr(&self, stream: &mut TcpStream) -> Result<SocketAddr> {
+        let peer = stream.peer_addr()?;
+        if !self.trusted_proxies.iter().any(|range| range.contains(&peer.ip())) {
+            return Ok(peer);
+        }
+        Ok(proxy::read_header(stream)?.unwrap_or(peer))
+    }
+
     fn host(&self) -> &String {
         &self.conf.host
     }
 
-    pub fn login(&self, email: String, password: String) -> Option<&User> {
+    /// Check the user's credentials, returning their maildir on success.
+    /// An error means they couldn't be checked.
+    pub fn login(&self, email: String, password: String) -> Result<Option<String>> {
         if let Some(login_data) = LoginData::new(email, password) {
-            if let Some(user) = self.users.get(&login_data.email) {
-                if user.auth_data.verify_auth(login_data.password) {
-                    return Some(user);
-                }
+            return Ok(self.users.login(&login_data.email, &login_data.password[..])?
+                .and_then(|maildir| self.resolve_maildir(&maildir[..])));
+        }
+        Ok(None)
+    }
+
+    /// Find the maildir to which mail for the given address is delivered.
+    /// An error means the address couldn't be looked up.
+    pub fn lookup_user(&self, email: &Email) -> Result<Option<String>> {
+        Ok(self.users.lookup(email)?.and_then(|maildir| self.resolve_maildir(&maildir[..])))
+    }
+
+    /// Turn the maildir recorded for a user into the path used to reach it.
+    /// Every maildir the server works with comes through here.
+    fn resolve_maildir(&self, maildir: &str) -> Option<String> {
+        let path = self.conf.resolve_maildir(maildir);
+        match path.to_str() {
+            Some(path) => Some(path.to_string()),
+            None => {
+                error!("Maildir path {} is not valid UTF-8", path.display());
+                None
             }
         }
-        None
     }
 }
 
-pub fn lmtp_serve(serv: Arc<Server>, stream: TcpStream) {
-    lmtp::serve(serv, BufStream::new(stream))
+fn tls_modified(conf: &Config) -> Vec<Option<SystemTime>> {
+    conf.tls_files().into_iter()
+        .map(|file| fs::metadata(file).and_then(|md| md.modified()).ok())
+        .collect()
+}
+
+/// Check that the server could start with the config at `config_path`, or
+/// the first config file found, without binding any ports or creating any
+/// files. Returns a description of what it would listen on and where its
+/// users come from.
+pub fn check_config(config_path: Option<&Path>) -> ImapResult<Vec<String>> {
+    let conf = Config::new(config_path)?;
+    let mut summary = Vec::new();
+    let listeners = [("IMAP", conf.imap_port), ("IMAP over TLS", conf.imap_ssl_port),
+                     ("LMTP", conf.lmtp_port), ("LMTP over TLS", conf.lmtp_ssl_port)];
+    for &(name, port) in &listeners {
+        if let Some(port) = port {
+            summary.push(format!("{} on {}:{}", name, conf.host, port));
+        }
+    }
+
+    match conf.user_backend {
+        UserBackend::Json => {
+            let path = Path::new(&conf.users);
+            if path.exists() {
+                let users = read_users(path).map_err(|e| {
+                    Error::InvalidConfig(format!("users file {}: {}", conf.users, e))
+                })?;
+                summary.push(format!("{} users in {}", users.len(), conf.users));
+            } else {
+                summary.push(format!("{} will be created with example users", conf.users));
+            }
+        }
+        UserBackend::Command => if let Some(ref command) = conf.auth_command {
+            summary.push(format!("Logins checked by {}", command));
+        }
+    }
+
+    if conf.imap_ssl_port.is_some() || conf.lmtp_ssl_port.is_some() {
+        if let Err(e) = conf.get_ssl_acceptor() {
+            return Err(Error::InvalidConfig(format!("TLS can't be set up: {}", e)));
+        }
+    }
+    Ok(summary)
+}
+
+/// Give the user with the given email address a new password in the users
+/// file named by the config. Returns false if there is no such user.
+pub fn change_password(config_path: Option<&Path>, address: &str, password: String) -> ImapResult<bool> {
+    let conf = Config::new(config_path)?;
+    if conf.user_backend != UserBackend::Json {
+        return Err(Error::InvalidConfig(
+            "passwords can only be changed for users in a users file".to_string()));
+    }
+    match Email::parse(address) {
+        Some(email) => set_password(Path::new(&conf.users), &email, password),
+        None => Ok(false)
+    }
+}
+
+/// Bring the UID lists of the user's mailboxes, or of just the named one,
+/// into line with the messages in them and rebuild their indexes, for when
+/// the maildir has been changed by something other than the server.
+/// Returns what was found in each mailbox, which is nothing if the named
+/// mailbox doesn't exist, or `None` if there is no such user.
+pub fn repair_mailboxes(config_path: Option<&Path>, address: &str,
+                        mailbox: Option<&str>) -> ImapResult<Option<Vec<(String, Repair)>>> {
+    let serv = Server::new(config_path)?;
+    let email = match Email::parse(address) {
+        Some(email) => email,
+        None => return Ok(None)
+    };
+    let maildir = match serv.lookup_user(&email)? {
+        Some(maildir) => PathBuf::from(maildir),
+        None => return Ok(None)
+    };
+    let layout = serv.conf.maildir_layout;
+    let names = match mailbox {
+        Some(mailbox) => vec![mailbox.to_string()],
+        None => MaildirStore::new(&maildir, layout, false, serv.conf.folder_window).mailboxes()
+    };
+    let mut repaired = Vec::new();
+    for name in names {
+        // Folders which only hold other folders have no messages.
+        let path = match maildir::mailbox_path(&maildir, &name[..], layout) {
+            Some(path) => path,
+            None => continue
+        };
+        if !path.join("cur").is_dir() {
+            continue;
+        }
+        let repair = uidlist::repair(&path)?;
+        index::rebuild(&path)?;
+        repaired.push((name, repair));
+    }
+    Ok(Some(repaired))
 }
 
-pub fn imap_serve(serv: Arc<Server>, stream: TcpStream) {
-    let mut session = ImapSession::new(serv);
+pub fn lmtp_serve(serv: Arc<Server>, mut stream: TcpStream) {
+    let addr = match serv.client_addr(&mut stream) {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Dropping LMTP connection: {}", e);
+            return;
+        }
+    };
+    info!("LMTP connection from {}", addr);
+    lmtp::serve(serv, BufStream::new(stream), addr)
+}
+
+pub fn imap_serve(serv: Arc<Server>, mut stream: TcpStream) {
+    let addr = match serv.client_addr(&mut stream) {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Dropping IMAP connection: {}", e);
+            return;
+        }
+    };
+    info!("IMAP connection from {}", addr);
+    let mut session = ImapSession::new(serv, addr);
     session.handle(stream);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use {listen_imap, listen_lmtp};
+    use fixture;
+    use maildir::{self, SpecialUse};
+    use message::Flag;
+    use toml;
+    use super::{check_config, repair_mailboxes, Server};
+    use super::config::{Config, DefaultFolder};
+    use super::user::{save_users, Email, User, UserBackend};
+
+    /// Set up a maildir holding a single message and a users file with a
+    /// single user who owns it. Returns the directory holding both.
+    fn setup(name: &str) -> (PathBuf, Config) {
+        let dir = fixture::dir(&format!("server-{}", name));
+        let maildir = dir.join("maildir");
+        maildir::create_folder(&maildir).unwrap();
+        fixture::write_message(&maildir, "cur", 1000, &[Flag::Seen],
+                               "Subject: hello\n\nHello world\n");
+
+        // The user's maildir is found within the configured root.
+        let users_path = dir.join("users.json");
+        let user = User::new(Email::new("user".to_string(), "example.com".to_string()),
+                             "secret".to_string(),
+                             "maildir".to_string());
+        save_users(&users_path, &[user]).unwrap();
+
+        let mut conf = Config::default();
+        conf.users = users_path.to_str().unwrap().to_string();
+        conf.maildir_root = Some(dir.to_str().unwrap().to_string());
+        // Port 0 lets the OS pick a free port.
+        conf.imap_port = Some(0);
+        conf.imap_ssl_port = None;
+        conf.lmtp_port = None;
+        conf.lmtp_ssl_port = None;
+        (dir, conf)
+    }
+
+    /// Send a command and collect the response lines up to and including
+    /// the tagged completion response.
+    fn command(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>,
+               tag: &str, cmd: &str) -> Vec<String> {
+        stream.write_all(format!("{} {}\r\n", tag, cmd).as_bytes()).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let done = line.starts_with(tag);
+            lines.push(line);
+            if done {
+                break;
+            }
+        }
+        lines
+    }
+
+    /// Start serving IMAP with the given config, returning the address
+    /// being listened on.
+    fn serve(conf: Config) -> SocketAddr {
+        let serv = Arc::new(Server::new_with_conf(conf).unwrap());
+        let listener = serv.imap_listener().unwrap().unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || listen_imap(listener, serv));
+        addr
+    }
+
+    /// Start serving IMAP with the given config and connect to it, returning
+    /// the connection once the greeting has been read.
+    fn connect(conf: Config) -> (TcpStream, BufReader<TcpStream>) {
+        open(serve(conf))
+    }
+
+    /// Connect to the server, returning the connection once the greeting
+    /// has been read.
+    fn open(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert_eq!(greeting, "* OK Server ready.\r\n");
+        (stream, reader)
+    }
+
+    #[test]
+    fn test_check_config() {
+        let (dir, mut conf) = setup("check");
+        conf.imap_port = Some(1143);
+        let path = dir.join("config.toml");
+        let encoded = toml::Value::try_from(&conf).unwrap().to_string();
+        File::create(&path).unwrap().write_all(encoded.as_bytes()).unwrap();
+        assert_eq!(check_config(Some(&path)).unwrap(),
+                   vec!["IMAP on 127.0.0.1:1143".to_string(),
+                        format!("1 users in {}", conf.users)]);
+
+        File::create(&conf.users).unwrap().write_all(b"[{\"email\": 1}]").unwrap();
+        let err = check_config(Some(&path)).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("Invalid config users file {}: ", conf.users)), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_mailboxes() {
+        let (dir, mut conf) = setup("repair");
+        conf.imap_port = Some(1143);
+        let path = dir.join("config.toml");
+        let encoded = toml::Value::try_from(&conf).unwrap().to_string();
+        File::create(&path).unwrap().write_all(encoded.as_bytes()).unwrap();
+        let maildir = dir.join("maildir");
+        maildir::create_folder(&maildir.join("Work")).unwrap();
+        File::create(maildir.join("Work").join("new").join("2000")).unwrap();
+        fs::create_dir_all(maildir.join("Archive")).unwrap();
+
+        // Every mailbox with messages is repaired, and given an index.
+        let repaired = repair_mailboxes(Some(&path), "user@example.com", None).unwrap().unwrap();
+        let names: Vec<&str> = repaired.iter().map(|&(ref name, _)| &name[..]).collect();
+        assert_eq!(names, vec!["INBOX", "Work"]);
+        assert_eq!((repaired[1].1.messages, repaired[1].1.assigned), (1, 1));
+        assert!(maildir.join("Work").join("dovecot-uidlist").is_file());
+        assert!(maildir.join("Work").join("segimap.index").is_file());
+
+        let repaired = repair_mailboxes(Some(&path), "user@example.com", Some("INBOX")).unwrap().unwrap();
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(repaired[0].1.assigned, 0);
+        assert!(repair_mailboxes(Some(&path), "user@example.com", Some("Lost")).unwrap().unwrap()
+                .is_empty());
+        assert!(repair_mailboxes(Some(&path), "nobody@example.com", None).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_imap_session() {
+        let (dir, conf) = setup("imap");
+        let (mut stream, mut reader) = connect(conf);
+
+        let res = command(&mut stream, &mut reader, "a1", "LOGIN user@example.com wrong");
+        assert_eq!(res, vec!["a1 NO invalid username or password\r\n"]);
+        let res = command(&mut stream, &mut reader, "a2", "LOGIN user@example.com secret");
+        assert_eq!(res, vec!["a2 OK logged in successfully as user@example.com\r\n"]);
+
+        let res = command(&mut stream, &mut reader, "a3", "SELECT INBOX");
+        assert!(res.contains(&"* 1 EXISTS\r\n".to_string()));
+        assert_eq!(res.last().unwrap(), "a3 OK [READ-WRITE] SELECT command was successful\r\n");
+
+        let res = command(&mut stream, &mut reader, "a4", "FETCH 1 (UID FLAGS)");
+        assert_eq!(res, vec!["* 1 FETCH (UID 1000 FLAGS (\\Seen))\r\n",
+                             "a4 OK FETCH completed\r\n"]);
+
+        let res = command(&mut stream, &mut reader, "a5", "UID FETCH 1:* FLAGS");
+        assert_eq!(res, vec!["* 1 FETCH (UID 1000 FLAGS (\\Seen))\r\n",
+                             "a5 OK UID FETCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a5", "UID FETCH 1000 (FLAGS UID RFC822.SIZE)");
+        assert_eq!(res, vec!["* 1 FETCH (UID 1000 FLAGS (\\Seen) RFC822.SIZE 28)\r\n",
+                             "a5 OK UID FETCH completed\r\n"]);
+
+        let res = command(&mut stream, &mut reader, "a5", "FETCH 2 FLAGS");
+        assert_eq!(res, vec!["a5 BAD Invalid command\r\n"]);
+        let res = command(&mut stream, &mut reader, "a5", "FETCH 1:5 UID");
+        assert_eq!(res, vec!["* 1 FETCH (UID 1000)\r\n", "a5 OK FETCH completed\r\n"]);
+
+        let res = command(&mut stream, &mut reader, "a6", "SEARCH SEEN SUBJECT \"hello\"");
+        assert_eq!(res, vec!["* SEARCH 1\r\n", "a6 OK SEARCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a7", "UID SEARCH BODY world");
+        assert_eq!(res, vec!["* SEARCH 1000\r\n", "a7 OK UID SEARCH completed\r\n"]);
+
+        // Clients ask for the hierarchy delimiter this way.
+        let res = command(&mut stream, &mut reader, "a8", "LIST \"\" \"\"");
+        assert_eq!(res, vec!["* LIST (\\Noselect) \"/\" \"\"\r\n", "a8 OK List successful\r\n"]);
+
+        let res = command(&mut stream, &mut reader, "a9", "LOGOUT");
+        assert_eq!(res.last().unwrap(), "a9 OK Server logged out\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_literal_too_large() {
+        let (dir, mut conf) = setup("literal");
+        conf.max_literal_size = 16;
+        conf.max_message_size = Some(32);
+        let (mut stream, mut reader) = connect(conf);
+
+        // The literal is refused without the client being asked for it.
+        let res = command(&mut stream, &mut reader, "a1", "LOGIN {4000000000}");
+        assert_eq!(res, vec!["a1 BAD Literal too large\r\n"]);
+
+        // Literals within the limit are still read.
+        stream.write_all(b"a2 LOGIN {16}\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "+ Ready for literal data\r\n");
+        stream.write_all(b"user@example.com secret\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "a2 OK logged in successfully as user@example.com\r\n");
+
+        // APPEND is limited by the maximum message size instead.
+        let res = command(&mut stream, &mut reader, "a3", "APPEND INBOX {33}");
+        assert_eq!(res, vec!["a3 BAD Literal too large\r\n"]);
+        stream.write_all(b"a4 APPEND INBOX {32}\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "+ Ready for literal data\r\n");
+        stream.write_all(b"Subject: big\r\n\r\nHello there!!!\r\n\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "a4 OK APPEND completed\r\n");
+
+        command(&mut stream, &mut reader, "a5", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_command_too_long() {
+        let (dir, mut conf) = setup("command-size");
+        conf.max_command_size = 64;
+        let (mut stream, mut reader) = connect(conf);
+
+        // A line without end is cut off, along with the connection.
+        stream.write_all(format!("a1 SEARCH SUBJECT {}\r\n", "x".repeat(100)).as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "* BYE Command too long\r\n");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_enable_condstore() {
+        let (dir, conf) = setup("condstore");
+        let (mut stream, mut reader) = connect(conf);
+
+        let res = command(&mut stream, &mut reader, "a1", "ENABLE CONDSTORE");
+        assert_eq!(res, vec!["a1 BAD Invalid command\r\n"]);
+        command(&mut stream, &mut reader, "a2", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a3", "SELECT INBOX");
+        assert!(!res.iter().any(|line| line.contains("MODSEQ")));
+
+        let res = command(&mut stream, &mut reader, "a4", "ENABLE CONDSTORE X-UNKNOWN");
+        assert_eq!(res, vec!["* ENABLED CONDSTORE\r\n", "a4 OK ENABLE completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a5", "ENABLE CONDSTORE");
+        assert_eq!(res, vec!["* ENABLED\r\n", "a5 OK ENABLE completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a6", "SELECT INBOX");
+        assert!(res.contains(&"* OK [HIGHESTMODSEQ 2] Highest mod-sequence\r\n".to_string()));
+
+        command(&mut stream, &mut reader, "a7", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_qresync() {
+        let (dir, conf) = setup("qresync");
+        maildir::deliver_atomic(&dir.join("maildir"), b"Subject: second\n\nHi\n").unwrap();
+        let (mut stream, mut reader) = connect(conf);
+
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX (QRESYNC (1 1))");
+        assert_eq!(res, vec!["a2 BAD Invalid command\r\n"]);
+        let res = command(&mut stream, &mut reader, "a3", "ENABLE QRESYNC");
+        assert_eq!(res, vec!["* ENABLED QRESYNC\r\n", "a3 OK ENABLE completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "SELECT INBOX");
+        let uid_validity = res.iter()
+            .filter_map(|line| line.split("[UIDVALIDITY ").nth(1))
+            .map(|rest| rest.split(']').next().unwrap().to_string())
+            .next().unwrap();
+        assert!(res.contains(&"* OK [HIGHESTMODSEQ 3] Highest mod-sequence\r\n".to_string()));
+        let res = command(&mut stream, &mut reader, "a5", "UID FETCH 1:* UID");
+        let uids: Vec<String> = res[..2].iter()
+            .map(|line| line.split("UID ").nth(1).unwrap().trim_end_matches(")\r\n").to_string())
+            .collect();
+
+        // The second message is expunged and the first flagged.
+        command(&mut stream, &mut reader, "a6", "STORE 2 +FLAGS.SILENT (\\Deleted)");
+        let res = command(&mut stream, &mut reader, "a7", "EXPUNGE");
+        assert_eq!(res, vec![format!("* VANISHED {}\r\n", uids[1]),
+                             "a7 OK expunge completed\r\n".to_string()]);
+        command(&mut stream, &mut reader, "a8", "STORE 1 +FLAGS.SILENT (\\Flagged)");
+        let res = command(&mut stream, &mut reader, "a9",
+                          "UID FETCH 1:* FLAGS (CHANGEDSINCE 3 VANISHED)");
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0], format!("* VANISHED (EARLIER) {}\r\n", uids[1]));
+        assert!(res[1].contains("\\Flagged"), "{}", res[1]);
+        assert!(res[1].starts_with(&format!("* 1 FETCH (UID {} FLAGS (", uids[0])[..]), "{}", res[1]);
+        assert!(res[1].ends_with(") MODSEQ (6))\r\n"), "{}", res[1]);
+        let res = command(&mut stream, &mut reader, "a10", "FETCH 1 FLAGS (CHANGEDSINCE 3 VANISHED)");
+        assert_eq!(res, vec!["a10 BAD Invalid command\r\n"]);
+
+        // Selecting the folder again with what was known before catches up.
+        let cmd = format!("SELECT INBOX (QRESYNC ({} 3))", uid_validity);
+        let res = command(&mut stream, &mut reader, "a11", &cmd[..]);
+        assert!(res.contains(&format!("* VANISHED (EARLIER) {}\r\n", uids[1])));
+        let prefix = format!("* 1 FETCH (UID {} FLAGS (", uids[0]);
+        assert!(res.iter().any(|line| line.starts_with(&prefix[..]) &&
+                               line.ends_with(" MODSEQ (6))\r\n")), "{:?}", res);
+
+        command(&mut stream, &mut reader, "a12", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_logout_saves_flags() {
+        let (dir, conf) = setup("logout");
+        let addr = serve(conf);
+
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        let res = command(&mut stream, &mut reader, "a3", "STORE 1 +FLAGS.SILENT (\\Flagged)");
+        assert_eq!(res, vec!["a3 OK STORE complete\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "LOGOUT");
+        assert_eq!(res.last().unwrap(), "a4 OK Server logged out\r\n");
+        assert!(dir.join("maildir").join("cur").join("1000:2,FS").exists());
+
+        // The flag is still set in the next session.
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        let res = command(&mut stream, &mut reader, "a3", "FETCH 1 FLAGS");
+        assert_eq!(res.len(), 2);
+        assert!(res[0].contains("\\Flagged"), "{}", res[0]);
+        command(&mut stream, &mut reader, "a4", "LOGOUT");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_mail_updates() {
+        let (dir, conf) = setup("new-mail");
+        let (mut stream, mut reader) = connect(conf);
+
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert!(res.contains(&"* 0 RECENT\r\n".to_string()));
+
+        // Nothing is reported until mail arrives.
+        let res = command(&mut stream, &mut reader, "a3", "NOOP");
+        assert_eq!(res, vec!["a3 OK NOOP\r\n"]);
+
+        let delivered = maildir::deliver_atomic(&dir.join("maildir"), b"Subject: new\n\nHi\n").unwrap();
+        let uid = delivered.file_name().unwrap().to_str().unwrap().to_string();
+        let res = command(&mut stream, &mut reader, "a4", "NOOP");
+        assert_eq!(res, vec!["* 2 EXISTS\r\n", "* 1 RECENT\r\n", "a4 OK NOOP\r\n"]);
+        assert!(!delivered.exists());
+
+        let res = command(&mut stream, &mut reader, "a5", "SEARCH RECENT UNSEEN");
+        assert_eq!(res, vec!["* SEARCH 2\r\n", "a5 OK SEARCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a6", "FETCH 2 UID");
+        assert_eq!(res, vec![format!("* 2 FETCH (UID {})\r\n", uid),
+                             "a6 OK FETCH completed\r\n".to_string()]);
+
+        command(&mut stream, &mut reader, "a7", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lmtp_crlf() {
+        let (dir, mut conf) = setup("lmtp-crlf");
+        conf.lmtp_port = Some(0);
+        let serv = Arc::new(Server::new_with_conf(conf).unwrap());
+        let lmtp_listener = serv.lmtp_listener().unwrap().unwrap();
+        let lmtp_addr = lmtp_listener.local_addr().unwrap();
+        let lmtp_serv = serv.clone();
+        spawn(move || listen_lmtp(lmtp_listener, lmtp_serv));
+        let imap_listener = serv.imap_listener().unwrap().unwrap();
+        let imap_addr = imap_listener.local_addr().unwrap();
+        spawn(move || listen_imap(imap_listener, serv));
+
+        let mut stream = TcpStream::connect(lmtp_addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"LHLO mx.example.org\r\nMAIL FROM:<a@example.org>\r\n\
+                           RCPT TO:<user@example.com>\r\nDATA\r\n").unwrap();
+        let mut line = String::new();
+        while !line.starts_with("354 ") {
+            line.clear();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+        }
+        stream.write_all(b"Subject: crlf\r\n\r\nLine one\r\n..Line two\r\n.\r\nQUIT\r\n").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "250 2.0.0 Delivered\r\n");
+
+        // The message is stored with the line endings it was sent with and
+        // comes back with them.
+        let entry = fs::read_dir(dir.join("maildir/new")).unwrap().next().unwrap().unwrap();
+        assert!(fs::read_to_string(entry.path()).unwrap()
+                .ends_with("\r\nSubject: crlf\r\n\r\nLine one\r\n.Line two\r\n"));
+        let (mut stream, mut reader) = open(imap_addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        let res = command(&mut stream, &mut reader, "a3", "FETCH 2 (BODY.PEEK[TEXT])");
+        assert_eq!(res, vec!["* 2 FETCH (BODY[TEXT] {21}\r\n", "Line one\r\n", ".Line two\r\n",
+                             ")\r\n", "a3 OK FETCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "FETCH 2 (BODY.PEEK[HEADER])");
+        assert!(res[1..res.len() - 2].iter().all(|line| line.ends_with("\r\n") && !line.ends_with("\r\r\n")),
+                "{:?}", res);
+        command(&mut stream, &mut reader, "a5", "LOGOUT");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_auth_command_failure() {
+        // A helper which can't be run says nothing about the user.
+        let (dir, mut conf) = setup("auth-failure");
+        conf.user_backend = UserBackend::Command;
+        conf.auth_command = Some(dir.join("missing-auth").to_str().unwrap().to_string());
+        conf.lmtp_port = Some(0);
+        let serv = Arc::new(Server::new_with_conf(conf).unwrap());
+        let lmtp_listener = serv.lmtp_listener().unwrap().unwrap();
+        let lmtp_addr = lmtp_listener.local_addr().unwrap();
+        let lmtp_serv = serv.clone();
+        spawn(move || listen_lmtp(lmtp_listener, lmtp_serv));
+        let imap_listener = serv.imap_listener().unwrap().unwrap();
+        let imap_addr = imap_listener.local_addr().unwrap();
+        spawn(move || listen_imap(imap_listener, serv));
+
+        let (mut stream, mut reader) = open(imap_addr);
+        let res = command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        assert_eq!(res, vec!["a1 NO [UNAVAILABLE] Login temporarily unavailable\r\n"]);
+
+        // Mail is deferred rather than bounced.
+        let mut stream = TcpStream::connect(lmtp_addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"LHLO mx.example.org\r\nMAIL FROM:<a@example.org>\r\n\
+                           RCPT TO:<user@example.com>\r\n").unwrap();
+        let mut line = String::new();
+        while !line.starts_with("250 2.1.0") {
+            line.clear();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+        }
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "451 4.3.0 Error in processing\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_auto_expunge_deferred() {
+        let (dir, mut conf) = setup("auto-expunge");
+        conf.auto_expunge_on_delete = true;
+        fixture::write_message(&dir.join("maildir"), "cur", 2000, &[Flag::Seen, Flag::Deleted],
+                               "Subject: old\n\nAlready deleted\n");
+        let (mut stream, mut reader) = connect(conf);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        let res = command(&mut stream, &mut reader, "a3", "FETCH 1:2 (UID FLAGS)");
+        let seqnum = if res[0].contains("\\Deleted") { 2 } else { 1 };
+
+        // No EXPUNGE is sent in response to a STORE by sequence number.
+        let res = command(&mut stream, &mut reader, "a4",
+                          &format!("STORE {} +FLAGS.SILENT (\\Deleted)", seqnum));
+        assert_eq!(res, vec!["a4 OK STORE complete\r\n"]);
+        let res = command(&mut stream, &mut reader, "a5", "FETCH 1:2 (FLAGS)");
+        assert_eq!(res.len(), 3, "{:?}", res);
+
+        // The next command which may send it does, and only the message the
+        // STORE deleted goes.
+        let res = command(&mut stream, &mut reader, "a6", "NOOP");
+        assert_eq!(res, vec![format!("* {} EXPUNGE\r\n", seqnum), "a6 OK NOOP\r\n".to_string()]);
+        let res = command(&mut stream, &mut reader, "a7", "FETCH 1:* (FLAGS)");
+        assert_eq!(res.len(), 2, "{:?}", res);
+        assert!(res[0].contains("\\Deleted"), "{:?}", res);
+        command(&mut stream, &mut reader, "a8", "LOGOUT");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reselect() {
+        let (dir, conf) = setup("reselect");
+        let addr = serve(conf);
+        let writable = "a2 OK [READ-WRITE] SELECT command was successful\r\n";
+        let readonly = "a2 OK [READ-ONLY] SELECT command was successful\r\n";
+
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert_eq!(res.last().unwrap(), writable);
+
+        // Another session can only read the folder while it is selected.
+        let (mut other, mut other_reader) = open(addr);
+        command(&mut other, &mut other_reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut other, &mut other_reader, "a2", "SELECT INBOX");
+        assert_eq!(res.last().unwrap(), readonly);
+        command(&mut other, &mut other_reader, "a3", "LOGOUT");
+
+        let res = command(&mut stream, &mut reader, "a3", "CLOSE");
+        assert_eq!(res, vec!["a3 OK close completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert_eq!(res.last().unwrap(), writable);
+        // Selecting the folder again without closing it keeps it writable.
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert_eq!(res.last().unwrap(), writable);
+        command(&mut stream, &mut reader, "a4", "LOGOUT");
+
+        // Logging out gives the folder up too.
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert_eq!(res.last().unwrap(), writable);
+        command(&mut stream, &mut reader, "a3", "LOGOUT");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_header() {
+        let (dir, mut conf) = setup("proxy");
+        conf.trust_proxy_from = vec!["127.0.0.0/8".to_string()];
+        let addr = serve(conf);
+
+        // The greeting follows the header from the load balancer.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 143\r\n").unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert_eq!(greeting, "* OK Server ready.\r\n");
+        let res = command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        assert_eq!(res, vec!["a1 OK logged in successfully as user@example.com\r\n"]);
+        command(&mut stream, &mut reader, "a2", "LOGOUT");
+
+        // A trusted peer which doesn't send a header is hung up on.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"a1 LOGIN user@example.com secret\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pipelined_literal() {
+        let (dir, conf) = setup("literal");
+        let (mut stream, mut reader) = connect(conf);
+
+        stream.write_all(b"a1 LOGIN {16}\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "+ Ready for literal data\r\n");
+
+        // SELECT arrives along with the rest of LOGIN and is answered once
+        // LOGIN has been.
+        stream.write_all(b"user@example.com secret\r\na2 SELECT INBOX\r\n").unwrap();
+        let mut lines = Vec::new();
+        while !lines.last().map_or(false, |line: &String| line.starts_with("a2")) {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+            lines.push(line);
+        }
+        assert_eq!(lines[0], "a1 OK logged in successfully as user@example.com\r\n");
+        assert!(lines.contains(&"* 1 EXISTS\r\n".to_string()));
+        assert_eq!(lines.last().unwrap(), "a2 OK [READ-WRITE] SELECT command was successful\r\n");
+
+        command(&mut stream, &mut reader, "a3", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_login_creates_inbox() {
+        let (dir, conf) = setup("first-login");
+        let maildir = dir.join("maildir");
+        fs::remove_dir_all(&maildir).unwrap();
+        let (mut stream, mut reader) = connect(conf);
+
+        let res = command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        assert_eq!(res, vec!["a1 OK logged in successfully as user@example.com\r\n"]);
+        for subdir in &["cur", "new", "tmp"] {
+            assert!(maildir.join(subdir).is_dir());
+        }
+
+        let res = command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+        assert!(res.contains(&"* 0 EXISTS\r\n".to_string()));
+        assert_eq!(res.last().unwrap(), "a2 OK [READ-WRITE] SELECT command was successful\r\n");
+
+        // Fetching from an empty folder matches nothing, which isn't an
+        // error, but a malformed sequence set is.
+        let res = command(&mut stream, &mut reader, "a3", "FETCH 1:* FLAGS");
+        assert_eq!(res, vec!["a3 OK FETCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "UID FETCH 1:* FLAGS");
+        assert_eq!(res, vec!["a4 OK UID FETCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "FETCH * FLAGS");
+        assert_eq!(res, vec!["a4 OK FETCH completed\r\n"]);
+        let res = command(&mut stream, &mut reader, "a4", "FETCH 1 FLAGS");
+        assert_eq!(res, vec!["a4 BAD Invalid command\r\n"]);
+        let res = command(&mut stream, &mut reader, "a5", "FETCH 1:x FLAGS");
+        assert_eq!(res, vec!["a5 BAD Invalid command\r\n"]);
+
+        // The default folders are created too, and named for what they
+        // are for.
+        let res = command(&mut stream, &mut reader, "a6", "LIST \"\" \"*\"");
+        assert_eq!(res, vec!["* LIST (\\Unmarked \\HasNoChildren \\Drafts) \"/\" \"Drafts\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren) \"/\" \"INBOX\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren \\Junk) \"/\" \"Junk\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren \\Sent) \"/\" \"Sent\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren \\Trash) \"/\" \"Trash\"\r\n",
+                             "a6 OK list successful\r\n"]);
+
+        command(&mut stream, &mut reader, "a7", "LOGOUT");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_folders() {
+        let (dir, mut conf) = setup("default-folders");
+        let maildir = dir.join("maildir");
+        fs::remove_dir_all(&maildir).unwrap();
+        conf.default_folders = vec![DefaultFolder {
+            name: "Archive/Old".to_string(),
+            special_use: Some(SpecialUse::Archive)
+        }];
+        let addr = serve(conf);
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        let res = command(&mut stream, &mut reader, "a2", "LIST \"\" \"*\"");
+        assert_eq!(res, vec!["* LIST (\\Noselect \\HasChildren) \"/\" \"Archive\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren \\Archive) \"/\" \"Archive/Old\"\r\n",
+                             "* LIST (\\Unmarked \\HasNoChildren) \"/\" \"INBOX\"\r\n",
+                             "a2 OK list successful\r\n"]);
+        command(&mut stream, &mut reader, "a3", "LOGOUT");
+
+        // They are only created at the first login, so one the user has
+        // deleted stays gone.
+        fs::remove_dir_all(maildir.join("Archive")).unwrap();
+        let (mut stream, mut reader) = open(addr);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        assert!(!maildir.join("Archive").exists());
+        command(&mut stream, &mut reader, "a2", "LOGOUT");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_capability_without_tls() {
+        // STARTTLS isn't offered without a certificate.
+        let (dir, conf) = setup("capability");
+        let (mut stream, mut reader) = connect(conf);
+        let res = command(&mut stream, &mut reader, "a1", "CAPABILITY");
+        assert_eq!(res, vec!["* CAPABILITY IMAP4rev1 CHILDREN ENABLE CONDSTORE QRESYNC ESEARCH \
+                              SPECIAL-USE\r\n",
+                             "a1 OK Capability successful\r\n"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_slow_reader() {
+        let (dir, conf) = setup("slow");
+        // Far more than the socket buffers hold, so the server has to wait
+        // for the client to read.
+        let body = "x".repeat(76) + "\n";
+        let message = format!("Subject: big\n\n{}", body.repeat(64 * 1024));
+        fixture::write_message(&dir.join("maildir"), "cur", 2000, &[Flag::Seen], &message);
+        let (mut stream, mut reader) = connect(conf);
+        command(&mut stream, &mut reader, "a1", "LOGIN user@example.com secret");
+        command(&mut stream, &mut reader, "a2", "SELECT INBOX");
+
+        stream.write_all(b"a3 FETCH 2 (BODY.PEEK[])\r\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("* 2 FETCH (BODY[] {{{}}}\r\n", message.len()));
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < message.len() {
+            let len = reader.read(&mut buf[..(message.len() - received.len()).min(4096)]).unwrap();
+            assert!(len > 0, "connection closed after {} bytes", received.len());
+            received.extend_from_slice(&buf[..len]);
+            if received.len() % (256 * 1024) < 4096 {
+                sleep(Duration::from_millis(20));
+            }
+        }
+        assert!(received == message.as_bytes());
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, ")\r\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "a3 OK FETCH completed\r\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_tag() {
+        let (dir, conf) = setup("tag");
+        let (mut stream, mut reader) = connect(conf);
+        for line in &["\r\n", " NOOP\r\n", "a+1 NOOP\r\n", "a\x01 NOOP\r\n", "\"a\" NOOP\r\n"] {
+            stream.write_all(line.as_bytes()).unwrap();
+            let mut res = String::new();
+            reader.read_line(&mut res).unwrap();
+            assert_eq!(res, "* BAD Invalid tag\r\n", "{:?}", line);
+        }
+        let res = command(&mut stream, &mut reader, "a.1", "NOOP");
+        assert_eq!(res, vec!["a.1 OK NOOP\r\n"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Giving up root once the listeners have been bound. Binding the usual IMAP
+//! and LMTP ports needs root, but nothing after that does, so the server
+//! switches to an unprivileged user before it accepts any connections.
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use libc::{self, c_char, c_int, gid_t, uid_t};
+
+/// The uid and primary gid of the named user.
+pub fn user_ids(name: &str) -> Result<(uid_t, gid_t)> {
+    let name = c_string(name.as_bytes())?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    with_buffer(|buf, len| unsafe {
+        libc::getpwnam_r(name.as_ptr(), &mut passwd, buf, len, &mut found)
+    })?;
+    if found.is_null() {
+        return Err(Error::new(ErrorKind::NotFound,
+                              format!("there is no user {}", name.to_string_lossy())));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// The gid of the named group.
+pub fn group_id(name: &str) -> Result<gid_t> {
+    let name = c_string(name.as_bytes())?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    with_buffer(|buf, len| unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut group, buf, len, &mut found)
+    })?;
+    if found.is_null() {
+        return Err(Error::new(ErrorKind::NotFound,
+                              format!("there is no group {}", name.to_string_lossy())));
+    }
+    Ok(group.gr_gid)
+}
+
+/// Confine the process to `root` if it is given, then become `user` and
+/// `group`. The group defaults to the user's primary group, and the user's
+/// supplementary groups are taken on as well. The names are looked up
+/// before the chroot, as the user database is usually outside of it.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, root: Option<&Path>) -> Result<()> {
+    let user = match user {
+        Some(user) => Some((c_string(user.as_bytes())?, user_ids(user)?)),
+        None => None
+    };
+    let gid = match group {
+        Some(group) => Some(group_id(group)?),
+        None => user.as_ref().map(|&(_, (_, gid))| gid)
+    };
+
+    if let Some(root) = root {
+        let root = c_string(root.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(root.as_ptr()) })?;
+        check(unsafe { libc::chdir(b"/\0".as_ptr() as *const c_char) })?;
+    }
+    // The groups go first, as only root may change them.
+    if let Some(gid) = gid {
+        match user {
+            Some((ref name, _)) => check(unsafe { libc::initgroups(name.as_ptr(), gid) })?,
+            None => check(unsafe { libc::setgroups(1, &gid) })?
+        }
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some((_, (uid, _))) = user {
+        check(unsafe { libc::setuid(uid) })?;
+        // Make sure that root can't be got back.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::new(ErrorKind::Other, "root privileges could be regained"));
+        }
+    }
+    Ok(())
+}
+
+fn c_string(bytes: &[u8]) -> Result<CString> {
+    CString::new(bytes).map_err(|_| Error::new(ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+fn check(res: c_int) -> Result<()> {
+    if res == 0 { Ok(()) } else { Err(Error::last_os_error()) }
+}
+
+// Call one of the reentrant user database functions, growing its buffer
+// until the entry fits.
+fn with_buffer<F: FnMut(*mut c_char, usize) -> c_int>(mut lookup: F) -> Result<()> {
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        match lookup(buf.as_mut_ptr(), buf.len()) {
+            0 => return Ok(()),
+            libc::ERANGE if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            e => return Err(Error::from_raw_os_error(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{drop_privileges, group_id, user_ids};
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(user_ids("root").unwrap().0, 0);
+        assert_eq!(group_id("root").unwrap(), 0);
+        assert_eq!(user_ids("segimap-no-such-user").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(group_id("segimap-no-such-group").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(user_ids("bad\0name").unwrap_err().kind(), ErrorKind::InvalidInput);
+        // Nothing is changed when an id can't be found.
+        assert!(drop_privileges(Some("segimap-no-such-user"), None, None).is_err());
+    }
+}
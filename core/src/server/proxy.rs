@@ -0,0 +1,157 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+
+// The longest header allowed by version 1 of the PROXY protocol, including
+// the CRLF which ends it
+const MAX_HEADER_LEN: usize = 107;
+
+/// A block of addresses written as `<address>/<prefix length>`, or a single
+/// address.
+#[derive(Debug, PartialEq)]
+pub struct AddressRange {
+    network: IpAddr,
+    prefix: u32
+}
+
+impl AddressRange {
+    pub fn parse(range: &str) -> Option<AddressRange> {
+        let mut parts = range.splitn(2, '/');
+        let network: IpAddr = parts.next()?.parse().ok()?;
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok()?,
+            None => bits
+        };
+        if prefix > bits {
+            return None;
+        }
+        Some(AddressRange { network: network, prefix: prefix })
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.network, *addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = (!0u32).checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = (!0u128).checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+/// Read the PROXY protocol header sent by a load balancer ahead of the
+/// connection it is passing on. The header is read a byte at a time so that
+/// nothing after it is taken from the stream. Returns the address of the
+/// client, or None if the proxy doesn't know it.
+pub fn read_header<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = Vec::new();
+    let mut byte = [0u8];
+    while !header.ends_with(b"\r\n") {
+        if header.len() == MAX_HEADER_LEN {
+            return Err(invalid("PROXY header is too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+    match String::from_utf8(header) {
+        Ok(header) => parse_header(header.trim_end_matches("\r\n")),
+        Err(_) => Err(invalid("PROXY header is not ASCII"))
+    }
+}
+
+// PROXY TCP4|TCP6 <source> <destination> <source port> <destination port>
+// or PROXY UNKNOWN, optionally followed by anything.
+fn parse_header(header: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = header.split(' ').collect();
+    match (fields.get(0), fields.get(1)) {
+        (Some(&"PROXY"), Some(&"UNKNOWN")) => return Ok(None),
+        (Some(&"PROXY"), Some(&"TCP4")) | (Some(&"PROXY"), Some(&"TCP6")) => {}
+        _ => return Err(invalid("PROXY header is malformed"))
+    }
+    if fields.len() != 6 {
+        return Err(invalid("PROXY header is malformed"));
+    }
+
+    let source: IpAddr = match fields[2].parse() {
+        Ok(source) => source,
+        Err(_) => return Err(invalid("PROXY header has an invalid source address"))
+    };
+    if source.is_ipv4() != (fields[1] == "TCP4") {
+        return Err(invalid("PROXY header source address doesn't match its protocol"));
+    }
+    match fields[4].parse() {
+        Ok(port) => Ok(Some(SocketAddr::new(source, port))),
+        Err(_) => Err(invalid("PROXY header has an invalid source port"))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{read_header, AddressRange};
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_address_range() {
+        let range = AddressRange::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains(&ip("10.1.200.3")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("::ffff:10.1.0.1")));
+
+        let range = AddressRange::parse("192.0.2.7").unwrap();
+        assert!(range.contains(&ip("192.0.2.7")));
+        assert!(!range.contains(&ip("192.0.2.8")));
+        assert!(AddressRange::parse("0.0.0.0/0").unwrap().contains(&ip("203.0.113.9")));
+
+        let range = AddressRange::parse("2001:db8::/32").unwrap();
+        assert!(range.contains(&ip("2001:db8:1::5")));
+        assert!(!range.contains(&ip("2001:db9::5")));
+
+        assert_eq!(AddressRange::parse("10.0.0.0/33"), None);
+        assert_eq!(AddressRange::parse("10.0.0/8"), None);
+        assert_eq!(AddressRange::parse("localhost"), None);
+    }
+
+    #[test]
+    fn test_read_header() {
+        let mut stream = Cursor::new(&b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 143\r\na1 NOOP\r\n"[..]);
+        assert_eq!(read_header(&mut stream).unwrap(),
+                   Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap()));
+        // The command after the header is left to be read.
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "a1 NOOP\r\n");
+
+        let mut stream = Cursor::new(&b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 993\r\n"[..]);
+        assert_eq!(read_header(&mut stream).unwrap(),
+                   Some("[2001:db8::1]:4000".parse::<SocketAddr>().unwrap()));
+        let mut stream = Cursor::new(&b"PROXY UNKNOWN ffff:f...f:ffff 143\r\n"[..]);
+        assert_eq!(read_header(&mut stream).unwrap(), None);
+
+        for header in &[&b"a1 LOGIN user@example.com secret\r\n"[..],
+                        &b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 993\r\n"[..],
+                        &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+                        &b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 143\r\n"[..],
+                        &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 143"[..]] {
+            assert!(read_header(&mut Cursor::new(*header)).is_err());
+        }
+        let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(100));
+        assert!(read_header(&mut Cursor::new(long.as_bytes())).is_err());
+    }
+}
@@ -1,6 +1,9 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
 /// Representation of an email
 /// This helps ensure the email at least has an '@' in it...
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Email {
     pub local_part: String,
     pub domain_part: String
@@ -14,11 +17,81 @@ impl Email {
         }
     }
 
-    #[allow(dead_code)]
-    fn to_string(&self) -> String {
+    /// Split an address of the form `local@domain` into its parts. Returns
+    /// None unless there is exactly one '@' with something either side of
+    /// it.
+    pub fn parse(address: &str) -> Option<Email> {
+        let mut parts = address.split('@');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(local_part), Some(domain_part), None)
+                if !local_part.is_empty() && !domain_part.is_empty() =>
+                Some(Email::new(local_part.to_string(), domain_part.to_string())),
+            _ => None
+        }
+    }
+
+    pub fn to_string(&self) -> String {
         let mut res = self.local_part.clone();
         res.push('@');
         res.push_str(&self.domain_part[..]);
         res
     }
 }
+
+/// Emails are written out as a single `local@domain` string.
+impl Serialize for Email {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string()[..])
+    }
+}
+
+// Users files written before emails were stored as strings hold each one
+// as an object with its two parts, so both forms are read.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEmail {
+    Address(String),
+    Parts { local_part: String, domain_part: String }
+}
+
+impl<'de> Deserialize<'de> for Email {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Email, D::Error> {
+        match StoredEmail::deserialize(deserializer)? {
+            StoredEmail::Address(address) => Email::parse(&address[..]).ok_or_else(|| {
+                D::Error::custom(format!("invalid email address: {}", address))
+            }),
+            StoredEmail::Parts { local_part, domain_part } => Ok(Email::new(local_part, domain_part))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::Email;
+
+    fn email(local_part: &str, domain_part: &str) -> Email {
+        Email::new(local_part.to_string(), domain_part.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Email::parse("user@example.com"), Some(email("user", "example.com")));
+        assert_eq!(Email::parse("user"), None);
+        assert_eq!(Email::parse("@example.com"), None);
+        assert_eq!(Email::parse("user@"), None);
+        assert_eq!(Email::parse("a@b@example.com"), None);
+    }
+
+    #[test]
+    fn test_serde() {
+        let user = email("user", "example.com");
+        assert_eq!(serde_json::to_string(&user).unwrap(), "\"user@example.com\"");
+        assert_eq!(serde_json::from_str::<Email>("\"user@example.com\"").unwrap(), user);
+        assert_eq!(serde_json::from_str::<Email>(
+            "{\"local_part\": \"user\", \"domain_part\": \"example.com\"}").unwrap(), user);
+        assert!(serde_json::from_str::<Email>("\"user\"").is_err());
+        assert!(serde_json::from_str::<Email>("{\"local_part\": \"user\"}").is_err());
+    }
+}
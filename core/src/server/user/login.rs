@@ -8,20 +8,11 @@ pub struct LoginData {
 
 impl LoginData {
     pub fn new(email: String, password: String) -> Option<LoginData> {
-        let mut parts = (&email[..]).split('@');
-        if let Some(local_part) = parts.next() {
-            if let Some(domain_part) = parts.next() {
-                let login_data = LoginData {
-                    email: Email {
-                        local_part: local_part.to_string(),
-                        domain_part: domain_part.to_string()
-                    },
-                    password: password
-                };
-                return Some(login_data);
+        Email::parse(&email[..]).map(|email| {
+            LoginData {
+                email: email,
+                password: password
             }
-        }
-
-        None
+        })
     }
 }
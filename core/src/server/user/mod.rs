@@ -1,18 +1,27 @@
+use dotlock::{self, DotLock};
 use error::ImapResult;
+#[cfg(unix)]
+use libc;
 use self::auth::AuthData;
 use serde_json;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::str;
 
 pub use self::email::Email;
 pub use self::login::LoginData;
+pub use self::store::{CommandUserStore, JsonUserStore, UserBackend, UserStore};
 
 mod auth;
 mod email;
 mod login;
+mod store;
 
 /// Representation of a User.
 #[derive(Debug, Deserialize, Serialize)]
@@ -37,38 +46,96 @@ impl User {
     }
 }
 
-/// Reads a JSON file and turns it into a `HashMap` of emails to users.
+/// Reads a JSON file and turns it into a `HashMap` of emails to users,
+/// creating a file with example users if it doesn't exist.
 /// May throw an `std::io::Error`, hence the `Result<>` type.
 pub fn load_users(path_str: &str) -> ImapResult<HashMap<Email, User>> {
     let path = Path::new(&path_str[..]);
 
-    let users = match File::open(&path) {
-        Ok(mut file) => {
-            let mut file_buf: String = String::new();
-            file.read_to_string(&mut file_buf)?;
-            serde_json::from_str(&file_buf)?
-        },
-        Err(e) => {
-            warn!("Failed to open users file, creating default: {}", e);
-            create_default_users(&path)?
-        }
-    };
+    if let Err(e) = File::open(&path) {
+        warn!("Failed to open users file, creating default: {}", e);
+        return Ok(users_map(create_default_users(&path)?));
+    }
+    read_users(&path)
+}
+
+/// Reads an existing JSON file and turns it into a `HashMap` of emails to
+/// users.
+pub fn read_users(path: &Path) -> ImapResult<HashMap<Email, User>> {
+    Ok(users_map(read_user_list(path)?))
+}
+
+// The users in the file, in the order they are written.
+fn read_user_list(path: &Path) -> ImapResult<Vec<User>> {
+    let mut file = File::open(&path)?;
+    let mut file_buf: String = String::new();
+    file.read_to_string(&mut file_buf)?;
+    Ok(serde_json::from_str(&file_buf)?)
+}
 
+/// Gives the user with the given email a new password and writes the users
+/// file back out. Returns false, leaving the file alone, if there is no such
+/// user. The file is locked while it is changed, so that two changes made
+/// at once are both kept.
+pub fn set_password(path: &Path, email: &Email, password: String) -> ImapResult<bool> {
+    let _lock = DotLock::acquire(sibling(path, "lock"))?;
+    let mut users = read_user_list(path)?;
+    match users.iter_mut().find(|user| user.email == *email) {
+        Some(user) => user.auth_data = AuthData::new(password),
+        None => return Ok(false)
+    }
+    save_users(path, &users)?;
+    Ok(true)
+}
+
+fn users_map(users: Vec<User>) -> HashMap<Email, User> {
     let mut map = HashMap::<Email, User>::new();
     for user in users {
         map.insert(user.email.clone(), user);
     }
-
-    Ok(map)
+    map
 }
 
-/// Writes a list of users to a new file on the disk.
+/// Writes a list of users to a file on the disk. The file is replaced in
+/// one step so that a running server never reads it half written. A new
+/// file can only be read by its owner, as it holds the users' password
+/// hashes, while one being replaced keeps its owner and mode, so that a
+/// server which has given up root can still read it.
 pub fn save_users(path: &Path, users: &[User]) -> ImapResult<()> {
     let encoded = serde_json::to_string(&users)?;
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into())
+    };
+    dotlock::replace_file_with(path, encoded.as_bytes(), |file| keep_access(file, metadata))?;
+    Ok(())
+}
 
-    let mut file = File::create(&path)?;
-    file.write(encoded.as_bytes())?;
+/// The path of a file kept beside `path`, named after it with the given
+/// extension added.
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("users");
+    path.with_file_name(format!("{}.{}", name, extension))
+}
 
+/// Give the file which is to replace another the owner and mode given by
+/// `metadata`, or make it readable only by its owner if there was none.
+#[cfg(unix)]
+fn keep_access(file: &File, metadata: Option<fs::Metadata>) -> io::Result<()> {
+    match metadata {
+        Some(metadata) => {
+            if unsafe { libc::fchown(file.as_raw_fd(), metadata.uid(), metadata.gid()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            file.set_permissions(metadata.permissions())
+        }
+        None => file.set_permissions(fs::Permissions::from_mode(0o600))
+    }
+}
+
+#[cfg(not(unix))]
+fn keep_access(_file: &File, _metadata: Option<fs::Metadata>) -> io::Result<()> {
     Ok(())
 }
 
@@ -93,3 +160,89 @@ fn create_default_users(path: &Path) -> ImapResult<Vec<User>> {
 
     Ok(users)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::thread;
+
+    use fixture;
+    use super::{load_users, read_users, save_users, set_password, Email, User};
+
+    #[test]
+    fn test_save_and_load_users() {
+        let dir = fixture::dir("users");
+        let read = |path: &Path| {
+            let mut contents = String::new();
+            File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        // Emails are written as plain addresses.
+        let path = dir.join("users.json");
+        let email = Email::new("user".to_string(), "example.com".to_string());
+        save_users(&path, &[User::new(email.clone(), "secret".to_string(), "mail".to_string())])
+            .unwrap();
+        assert!(read(&path).contains("\"email\":\"user@example.com\""));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let users = read_users(&path).unwrap();
+        assert!(users[&email].auth_data.verify_auth("secret".to_string()));
+        assert_eq!(users[&email].maildir, "mail");
+
+        // So are the example users created when there is no users file.
+        let default_path = dir.join("default.json");
+        let users = load_users(default_path.to_str().unwrap()).unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(read(&default_path).contains("\"email\":\"will@xqz.ca\""));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_password() {
+        let dir = fixture::dir("passwd");
+        let path = dir.join("users.json");
+        let user = Email::new("user".to_string(), "example.com".to_string());
+        let other = Email::new("other".to_string(), "example.com".to_string());
+        save_users(&path, &[User::new(user.clone(), "old".to_string(), "mail".to_string()),
+                            User::new(other.clone(), "theirs".to_string(), "other".to_string())])
+            .unwrap();
+
+        assert!(set_password(&path, &user, "new".to_string()).unwrap());
+        let users = read_users(&path).unwrap();
+        assert!(users[&user].auth_data.verify_auth("new".to_string()));
+        assert!(!users[&user].auth_data.verify_auth("old".to_string()));
+        assert_eq!(users[&user].maildir, "mail");
+        assert!(users[&other].auth_data.verify_auth("theirs".to_string()));
+
+        let unknown = Email::new("nobody".to_string(), "example.com".to_string());
+        assert!(!set_password(&path, &unknown, "new".to_string()).unwrap());
+
+        // The file keeps the mode it was given, so whoever it was shared
+        // with can still read it.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(set_password(&path, &user, "newer".to_string()).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // Changes made at once are all kept.
+        let threads: Vec<_> = [(user.clone(), "mine"), (other.clone(), "yours")].iter().cloned()
+            .map(|(email, password)| {
+                let path = path.clone();
+                thread::spawn(move || set_password(&path, &email, password.to_string()).unwrap())
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        let users = read_users(&path).unwrap();
+        assert!(users[&user].auth_data.verify_auth("mine".to_string()));
+        assert!(users[&other].auth_data.verify_auth("yours".to_string()));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
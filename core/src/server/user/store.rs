@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use error::ImapResult;
+use super::{load_users, read_users, Email, User};
+
+/// The available sources of user data.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UserBackend {
+    /// Users are read from the JSON users file.
+    #[serde(rename = "json")]
+    Json,
+    /// Users are checked by running the configured auth command.
+    #[serde(rename = "command")]
+    Command
+}
+
+impl Default for UserBackend {
+    fn default() -> Self {
+        UserBackend::Json
+    }
+}
+
+/// A source of user data. Both methods return the root directory in which
+/// the user's mail is stored, or None if there is no such user. An error
+/// means the user couldn't be checked at all, and so is only temporary.
+pub trait UserStore: Send + Sync {
+    /// Verify the user's password.
+    fn login(&self, email: &Email, password: &str) -> io::Result<Option<String>>;
+
+    /// Find the user to whom mail for the given address should be delivered.
+    fn lookup(&self, email: &Email) -> io::Result<Option<String>>;
+}
+
+/// Users loaded from a JSON file. The file is reloaded whenever its
+/// modification time changes, so users can be added without a restart.
+pub struct JsonUserStore {
+    path: String,
+    // The modification time of the file when it was last loaded
+    loaded: Mutex<Option<SystemTime>>,
+    users: RwLock<HashMap<Email, User>>
+}
+
+impl JsonUserStore {
+    pub fn new(path: &str) -> ImapResult<JsonUserStore> {
+        let users = load_users(path)?;
+        Ok(JsonUserStore {
+            path: path.to_string(),
+            loaded: Mutex::new(modified(path)),
+            users: RwLock::new(users)
+        })
+    }
+
+    /// Reload the users file if it has changed since it was last loaded. If
+    /// the new contents can't be read the previous users are kept.
+    fn reload_if_changed(&self) {
+        let current = match modified(&self.path[..]) {
+            Some(current) => current,
+            None => return
+        };
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        if *loaded == Some(current) {
+            return;
+        }
+        // Record the change even if the reload fails, so that a broken file
+        // is only reported once.
+        *loaded = Some(current);
+        match read_users(Path::new(&self.path[..])) {
+            Ok(users) => {
+                info!("Reloaded users from {}", self.path);
+                *self.users.write().unwrap_or_else(|e| e.into_inner()) = users;
+            }
+            Err(e) => error!("Failed to reload users from {}, keeping previous users: {}",
+                             self.path, e)
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|md| md.modified()).ok()
+}
+
+impl UserStore for JsonUserStore {
+    fn login(&self, email: &Email, password: &str) -> io::Result<Option<String>> {
+        self.reload_if_changed();
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+        Ok(match users.get(email) {
+            Some(user) if user.auth_data.verify_auth(password.to_string()) =>
+                Some(user.maildir.clone()),
+            _ => None
+        })
+    }
+
+    fn lookup(&self, email: &Email) -> io::Result<Option<String>> {
+        self.reload_if_changed();
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+        Ok(users.get(email).map(|user| user.maildir.clone()))
+    }
+}
+
+/// Users checked by an external helper program, allowing PAM, LDAP or any
+/// other source of users to be used.
+///
+/// The helper is run as `<command> login` or `<command> lookup`. It is
+/// given the email address, followed by the password for `login`, one per
+/// line on stdin. It must exit successfully and print the user's maildir on
+/// stdout if the user exists (and, for `login`, the password is correct).
+/// Any other exit status turns the user away. A helper which can't be run,
+/// is killed by a signal or takes longer than the timeout is a failure
+/// rather than an answer, and is killed if it is still running.
+pub struct CommandUserStore {
+    command: String,
+    timeout: Duration
+}
+
+impl CommandUserStore {
+    pub fn new(command: String, timeout: Duration) -> CommandUserStore {
+        CommandUserStore {
+            command: command,
+            timeout: timeout
+        }
+    }
+
+    fn run(&self, mode: &str, input: &str) -> io::Result<Option<String>> {
+        let mut child = Command::new(&self.command)
+            .arg(mode)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                error!("Failed to run auth command {}: {}", self.command, e);
+                e
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // The helper may exit without reading its input, so only its exit
+            // status matters.
+            let _ = stdin.write_all(input.as_bytes());
+        }
+        // The output is read on another thread, so that a helper which never
+        // closes it can't hold up the session.
+        let (tx, rx) = mpsc::channel();
+        if let Some(mut stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                let _ = stdout.read_to_end(&mut output);
+                let _ = tx.send(output);
+            });
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                error!("Auth command {} took longer than {}s and was killed", self.command,
+                       self.timeout.as_secs());
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "auth command timed out"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        if status.code().is_none() {
+            error!("Auth command {} was killed: {}", self.command, status);
+            return Err(io::Error::new(io::ErrorKind::Other, "auth command was killed"));
+        }
+        if !status.success() {
+            return Ok(None);
+        }
+        let output = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| {
+                error!("Auth command {} exited without closing its output", self.command);
+                io::Error::new(io::ErrorKind::TimedOut, "auth command output not closed")
+            })?;
+        let maildir = String::from_utf8(output).ok()
+            .and_then(|stdout| stdout.lines().next().map(|line| line.trim().to_string()));
+        Ok(maildir.filter(|maildir| !maildir.is_empty()))
+    }
+}
+
+impl UserStore for CommandUserStore {
+    fn login(&self, email: &Email, password: &str) -> io::Result<Option<String>> {
+        self.run("login", &format!("{}\n{}\n", email.to_string(), password)[..])
+    }
+
+    fn lookup(&self, email: &Email) -> io::Result<Option<String>> {
+        self.run("lookup", &format!("{}\n", email.to_string())[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+
+    use fixture;
+    use super::{CommandUserStore, JsonUserStore, UserStore};
+    use super::super::{save_users, Email, User};
+
+    fn email(local_part: &str) -> Email {
+        Email::new(local_part.to_string(), "example.com".to_string())
+    }
+
+    #[test]
+    fn test_json_store() {
+        let dir = fixture::dir("json-users");
+        let path = dir.join("users.json");
+        save_users(&path, &[User::new(email("user"), "secret".to_string(),
+                                      "/var/mail/user".to_string())]).unwrap();
+        let store = JsonUserStore::new(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(store.login(&email("user"), "secret").unwrap(), Some("/var/mail/user".to_string()));
+        assert_eq!(store.login(&email("user"), "wrong").unwrap(), None);
+        assert_eq!(store.login(&email("other"), "secret").unwrap(), None);
+        assert_eq!(store.lookup(&email("user")).unwrap(), Some("/var/mail/user".to_string()));
+        assert_eq!(store.lookup(&email("other")).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_store_reload() {
+        let dir = fixture::dir("reload");
+        let path = dir.join("users.json");
+        save_users(&path, &[User::new(email("user"), "secret".to_string(),
+                                      "/var/mail/user".to_string())]).unwrap();
+        let store = JsonUserStore::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(store.lookup(&email("new")).unwrap(), None);
+
+        // New users can log in once the file has been rewritten.
+        save_users(&path, &[User::new(email("user"), "secret".to_string(),
+                                      "/var/mail/user".to_string()),
+                            User::new(email("new"), "hunter2".to_string(),
+                                      "/var/mail/new".to_string())]).unwrap();
+        assert_eq!(store.login(&email("new"), "hunter2").unwrap(), Some("/var/mail/new".to_string()));
+
+        // A broken file leaves the previous users in place.
+        File::create(&path).unwrap().write_all(b"[{\"email\":").unwrap();
+        assert_eq!(store.login(&email("new"), "hunter2").unwrap(), Some("/var/mail/new".to_string()));
+        assert_eq!(store.lookup(&email("user")).unwrap(), Some("/var/mail/user".to_string()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_command_store() {
+        let dir = fixture::dir("auth");
+        let path = dir.join("auth.sh");
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(b"#!/bin/sh\n\
+                             read email\n\
+                             if [ \"$1\" = login ]; then\n\
+                             read password\n\
+                             [ \"$password\" = secret ] || exit 1\n\
+                             fi\n\
+                             [ \"$email\" = user@example.com ] || exit 1\n\
+                             echo /var/mail/user\n").unwrap();
+        }
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let store = CommandUserStore::new(path.to_str().unwrap().to_string(), Duration::from_secs(10));
+
+        assert_eq!(store.login(&email("user"), "secret").unwrap(), Some("/var/mail/user".to_string()));
+        assert_eq!(store.login(&email("user"), "wrong").unwrap(), None);
+        assert_eq!(store.login(&email("other"), "secret").unwrap(), None);
+        assert_eq!(store.lookup(&email("user")).unwrap(), Some("/var/mail/user".to_string()));
+        assert_eq!(store.lookup(&email("other")).unwrap(), None);
+
+        // A helper which can't be run is a failure rather than a rejection.
+        let missing = CommandUserStore::new("/nonexistent/segimap-auth".to_string(),
+                                            Duration::from_secs(10));
+        assert!(missing.login(&email("user"), "secret").is_err());
+
+        // As is one which takes too long, which is killed.
+        File::create(&path).unwrap().write_all(b"#!/bin/sh\nexec sleep 10\n").unwrap();
+        let store = CommandUserStore::new(path.to_str().unwrap().to_string(), Duration::from_millis(100));
+        let start = Instant::now();
+        assert_eq!(store.lookup(&email("user")).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
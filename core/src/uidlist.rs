@@ -0,0 +1,418 @@
+//! Reading and writing of Dovecot's `dovecot-uidlist` file, which records
+//! the UID assigned to each message in a folder along with the folder's
+//! UIDVALIDITY and next UID.
+//!
+//! Messages are identified by the base of their filename: everything before
+//! the last `:`, which introduces the flags. This keeps UIDs stable as
+//! messages move from new/ to cur/ and as their flags change.
+//!
+//! Anything which gives out UIDs holds `dovecot-uidlist.lock` from reading
+//! the list until it has saved it again, so that two sessions, or a session
+//! and Dovecot, can't give the same UID to different messages.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use time;
+
+use dotlock::{self, DotLock, FileLock};
+use folder;
+use maildir;
+
+/// The name of the UID list within each folder.
+pub const UIDLIST_FILE: &'static str = "dovecot-uidlist";
+
+/// The name of the lock on the UID list.
+pub const LOCK_FILE: &'static str = "dovecot-uidlist.lock";
+
+/// The highest UID, or UIDVALIDITY, which IMAP can represent.
+pub const MAX_UID: usize = 0xffff_ffff;
+
+/// The UIDs assigned to the messages of a folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UidList {
+    pub uid_validity: usize,
+    pub next_uid: usize,
+    // Maps the base of each message's filename to its UID
+    uids: HashMap<String, usize>
+}
+
+/// What repairing a folder's UID list found and changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Repair {
+    // How many messages are in the folder
+    pub messages: usize,
+    // Messages which weren't in the list and were given UIDs
+    pub assigned: usize,
+    // Entries for messages which are no longer in the folder
+    pub removed: usize,
+    // Messages which shared a UID with another and were given a new one,
+    // which means moving to a new UIDVALIDITY
+    pub renumbered: usize
+}
+
+/// Rescan the folder and bring its UID list into line with the messages
+/// in it, for when the maildir has been changed by something which doesn't
+/// keep the list up to date. This is refused while a session has the folder
+/// selected, as the UIDs it knows the messages by could change under it.
+pub fn repair(folder: &Path) -> io::Result<Repair> {
+    // Holding the folder's lock also keeps sessions from selecting it for
+    // writing until the repair is done.
+    let _selected = match FileLock::try_acquire(&folder.join(folder::LOCK_FILE))? {
+        Some(lock) => lock,
+        None => return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          format!("{} is selected by another session",
+                                                  folder.display())))
+    };
+    let _lock = lock(folder)?;
+    let mut filenames = Vec::new();
+    for dir in &["cur", "new"] {
+        for entry in fs::read_dir(folder.join(dir))? {
+            if let Some(filename) = maildir::message_filename(&entry?.path()) {
+                filenames.push(filename.to_string());
+            }
+        }
+    }
+    let mut list = UidList::load(folder)?;
+    let repair = list.repair(&filenames);
+    list.save(folder)?;
+    Ok(repair)
+}
+
+/// Take the lock on the folder's UID list, waiting for anyone else who
+/// holds it. The list should be loaded once the lock is held, and saved
+/// before the lock is dropped.
+pub fn lock(folder: &Path) -> io::Result<DotLock> {
+    DotLock::acquire(folder.join(LOCK_FILE))
+}
+
+// Whether `uid` is a UID or UIDVALIDITY which IMAP can represent, which is
+// a non-zero 32-bit number.
+fn is_valid_uid(uid: usize) -> bool {
+    uid > 0 && uid <= MAX_UID
+}
+
+/// The part of a maildir filename which stays the same when its flags
+/// change, which is everything before the last colon.
+pub fn base_name(filename: &str) -> &str {
+    filename.rsplitn(2, ':').last().unwrap_or("")
+}
+
+impl UidList {
+    /// Create an empty list with a fresh UIDVALIDITY.
+    pub fn new() -> UidList {
+        UidList {
+            uid_validity: time::get_time().sec as usize,
+            next_uid: 1,
+            uids: HashMap::new()
+        }
+    }
+
+    /// Read the folder's UID list. A missing list is treated as empty, as is
+    /// one which can't be parsed, since all its UIDs are then reassigned
+    /// under a new UIDVALIDITY.
+    pub fn load(folder: &Path) -> io::Result<UidList> {
+        let mut contents = String::new();
+        match File::open(folder.join(UIDLIST_FILE)) {
+            Ok(mut file) => { file.read_to_string(&mut contents)?; }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(UidList::new()),
+            Err(e) => return Err(e)
+        }
+        match UidList::parse(&contents[..]) {
+            Some(list) => Ok(list),
+            None => {
+                warn!("Ignoring malformed {} in {}", UIDLIST_FILE, folder.display());
+                Ok(UidList::new())
+            }
+        }
+    }
+
+    /// Parse the contents of a version 1 or version 3 UID list. A list with
+    /// a UID or UIDVALIDITY which IMAP can't represent is refused.
+    fn parse(contents: &str) -> Option<UidList> {
+        let mut lines = contents.lines();
+        let header: Vec<&str> = lines.next()?.split(' ').collect();
+        let mut list = UidList::new();
+        match header[0] {
+            // 1 <uidvalidity> <nextuid>
+            "1" => {
+                list.uid_validity = header.get(1)?.parse().ok()?;
+                list.next_uid = header.get(2)?.parse().ok()?;
+            }
+            // 3 V<uidvalidity> N<nextuid> [other fields]
+            "3" => {
+                for field in &header[1..] {
+                    if field.starts_with('V') {
+                        list.uid_validity = field[1..].parse().ok()?;
+                    } else if field.starts_with('N') {
+                        list.next_uid = field[1..].parse().ok()?;
+                    }
+                }
+            }
+            _ => return None
+        }
+        if !is_valid_uid(list.uid_validity) || list.next_uid == 0 || list.next_uid > MAX_UID + 1 {
+            return None;
+        }
+
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let uid_end = line.find(' ')?;
+            let uid: usize = line[..uid_end].parse().ok().filter(|&uid| is_valid_uid(uid))?;
+            // Version 3 puts the filename after a colon, following any
+            // extension fields.
+            let filename = match line.find(" :") {
+                Some(start) => &line[start + 2..],
+                None => &line[uid_end + 1..]
+            };
+            list.uids.insert(base_name(filename).to_string(), uid);
+            if uid >= list.next_uid {
+                list.next_uid = uid + 1;
+            }
+        }
+        Some(list)
+    }
+
+    /// Get the UID of the message with the given filename, if it has one.
+    pub fn get(&self, filename: &str) -> Option<usize> {
+        self.uids.get(base_name(filename)).cloned()
+    }
+
+    /// Find the UIDs of every message with the given filenames, assigning
+    /// new UIDs to the messages which haven't been seen before. Filenames
+    /// which are numbers, as written by our own delivery, keep that number as
+    /// their UID where possible. Returns the UIDs in the same order as the
+    /// filenames. If the UIDs run out, every message is numbered afresh
+    /// under a new UIDVALIDITY.
+    pub fn assign_all(&mut self, filenames: &[String]) -> Vec<usize> {
+        let mut unlisted: Vec<&str> = filenames.iter()
+            .map(|name| base_name(&name[..]))
+            .filter(|base| !self.uids.contains_key(*base))
+            .collect();
+        unlisted.sort_by_key(|base| (base.parse::<usize>().unwrap_or(!0usize), base.to_string()));
+        unlisted.dedup();
+
+        for base in unlisted {
+            let uid = match base.parse::<usize>() {
+                Ok(n) if n >= self.next_uid && n <= MAX_UID => n,
+                _ => self.next_uid
+            };
+            self.next_uid = uid + 1;
+            self.uids.insert(base.to_string(), uid);
+        }
+        if self.next_uid > MAX_UID + 1 {
+            warn!("Ran out of UIDs, so numbering every message afresh");
+            self.renumber();
+        }
+
+        filenames.iter().map(|name| self.uids[base_name(&name[..])]).collect()
+    }
+
+    /// Forget the UIDs of messages which are no longer in the folder.
+    pub fn retain(&mut self, filenames: &[String]) {
+        let present: HashSet<&str> = filenames.iter().map(|name| base_name(&name[..])).collect();
+        self.uids.retain(|base, _| present.contains(&base[..]));
+    }
+
+    /// Make the list describe exactly the messages with the given filenames:
+    /// entries for messages which have gone are dropped, messages sharing a
+    /// UID are given their own, and messages missing from the list are added.
+    pub fn repair(&mut self, filenames: &[String]) -> Repair {
+        let mut repair = Repair::default();
+        let before = self.uids.len();
+        self.retain(filenames);
+        repair.removed = before - self.uids.len();
+
+        // Of the messages sharing a UID, the one whose name sorts first keeps
+        // it and the others are treated as unlisted.
+        let mut entries: Vec<(usize, String)> = self.uids.iter()
+            .map(|(base, uid)| (*uid, base.clone()))
+            .collect();
+        entries.sort();
+        for pair in entries.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                self.uids.remove(&pair[1].1);
+                repair.renumbered += 1;
+            }
+        }
+        // Clients may have seen the message under the UID it gave up.
+        if repair.renumbered > 0 {
+            self.renew_uid_validity();
+        }
+
+        let listed = self.uids.len();
+        repair.messages = self.assign_all(filenames).len();
+        repair.assigned = self.uids.len() - listed - repair.renumbered;
+        repair
+    }
+
+    /// Give every message a new UID, counting up from 1 in the order of the
+    /// old ones, under a new UIDVALIDITY.
+    fn renumber(&mut self) {
+        let mut entries: Vec<(usize, String)> = self.uids.drain()
+            .map(|(base, uid)| (uid, base))
+            .collect();
+        entries.sort();
+        self.renew_uid_validity();
+        self.next_uid = 1;
+        for (_, base) in entries {
+            self.uids.insert(base, self.next_uid);
+            self.next_uid += 1;
+        }
+    }
+
+    /// Move to a new UIDVALIDITY, telling clients to forget everything they
+    /// know of the folder. The messages keep their UIDs.
+    pub fn renew_uid_validity(&mut self) {
+        let now = time::get_time().sec as usize;
+        self.uid_validity = if now > self.uid_validity { now } else { self.uid_validity + 1 };
+    }
+
+    /// Write the list out in the version 3 format.
+    pub fn save(&self, folder: &Path) -> io::Result<()> {
+        let mut entries: Vec<(&usize, &String)> = self.uids.iter().map(|(base, uid)| (uid, base)).collect();
+        entries.sort();
+
+        let mut contents = format!("3 V{} N{}\n", self.uid_validity, self.next_uid);
+        for (uid, base) in entries {
+            contents.push_str(&format!("{} :{}\n", uid, base)[..]);
+        }
+
+        // Replace the list in one step so readers never see half of it.
+        dotlock::replace_file(&folder.join(UIDLIST_FILE), contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+
+    use dotlock::FileLock;
+    use fixture;
+    use message::Flag;
+    use super::{base_name, repair, Repair, UidList, LOCK_FILE, MAX_UID, UIDLIST_FILE};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_base_name() {
+        assert_eq!(base_name("1500000000.M1P2.host"), "1500000000.M1P2.host");
+        assert_eq!(base_name("1500000000.M1P2.host:2,S"), "1500000000.M1P2.host");
+        assert_eq!(base_name("100:2,"), "100");
+        // Only the last colon starts the info.
+        assert_eq!(base_name("100:host:2,S"), "100:host");
+    }
+
+    #[test]
+    fn test_parse() {
+        let list = UidList::parse("3 V1234 N10 Gabc\n5 :a.host:2,S\n7 W100 :b.host\n").unwrap();
+        assert_eq!(list.uid_validity, 1234);
+        assert_eq!(list.next_uid, 10);
+        assert_eq!(list.get("a.host"), Some(5));
+        assert_eq!(list.get("b.host:2,RS"), Some(7));
+        assert_eq!(list.get("c.host"), None);
+
+        let list = UidList::parse("1 99 3\n1 x.host\n2 y.host:2,S\n").unwrap();
+        assert_eq!(list.uid_validity, 99);
+        assert_eq!(list.next_uid, 3);
+        assert_eq!(list.get("y.host"), Some(2));
+
+        assert_eq!(UidList::parse("2 nonsense\n"), None);
+        assert_eq!(UidList::parse("3 V1 N2\nnot-a-uid :x\n"), None);
+        // UIDs must fit in 32 bits.
+        assert_eq!(UidList::parse("3 V1 N2\n4294967296 :x\n"), None);
+        assert_eq!(UidList::parse("3 V4294967296 N2\n"), None);
+        assert_eq!(UidList::parse("3 V1 N4294967297\n"), None);
+        assert_eq!(UidList::parse("3 V1 N4294967296\n4294967295 :x\n").unwrap().get("x"),
+                   Some(MAX_UID));
+    }
+
+    #[test]
+    fn test_assign_all() {
+        let mut list = UidList::new();
+        // Numbered files keep their numbers; others are numbered after them.
+        let uids = list.assign_all(&names(&["b.host:2,S", "200", "100:2,S", "a.host"]));
+        assert_eq!(uids, vec![202, 200, 100, 201]);
+        assert_eq!(list.next_uid, 203);
+
+        // Existing UIDs are stable, even across flag changes, and a stale
+        // numbered filename doesn't reuse an old UID.
+        let uids = list.assign_all(&names(&["b.host:2,RS", "150", "c.host"]));
+        assert_eq!(uids, vec![202, 203, 204]);
+
+        // Numbered files can't have UIDs above 32 bits.
+        let uids = list.assign_all(&names(&["4294967296"]));
+        assert_eq!(uids, vec![205]);
+
+        // Once the UIDs run out, the messages are numbered again in order.
+        list.next_uid = MAX_UID;
+        let uid_validity = list.uid_validity;
+        let uids = list.assign_all(&names(&["b.host", "d.host", "e.host"]));
+        assert_eq!(uids, vec![4, 8, 9]);
+        assert_eq!(list.get("200"), Some(2));
+        assert_eq!(list.next_uid, 10);
+        assert!(list.uid_validity > uid_validity);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let folder = fixture::dir("uidlist");
+        let mut list = UidList::load(&folder).unwrap();
+        list.assign_all(&names(&["100", "a.host:2,S", "gone.host"]));
+        list.retain(&names(&["100:2,S", "a.host:2,S"]));
+        list.save(&folder).unwrap();
+
+        let loaded = UidList::load(&folder).unwrap();
+        assert_eq!(loaded, list);
+        assert_eq!(loaded.get("gone.host"), None);
+        assert_eq!(loaded.next_uid, 103);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_repair() {
+        let folder = fixture::maildir("uidlist-repair", &[(10, &[Flag::Seen])]);
+        fixture::write_message(&folder, "new", 20, &[], fixture::MESSAGE);
+        for name in &["cur/dup.host:2,S", "cur/other.host:2,", "new/new.host"] {
+            fs::write(folder.join(name), fixture::MESSAGE).unwrap();
+        }
+        // Two messages claim UID 20 and the message given UID 25 is gone,
+        // while 20 and new.host aren't listed at all.
+        fs::write(folder.join(UIDLIST_FILE),
+                  "3 V1 N30\n10 :10\n20 :dup.host\n20 :other.host\n25 :gone.host\n").unwrap();
+
+        let res = repair(&folder).unwrap();
+        assert_eq!(res, Repair { messages: 5, assigned: 2, removed: 1, renumbered: 1 });
+        let list = UidList::load(&folder).unwrap();
+        assert!(list.uid_validity > 1);
+        assert_eq!(list.get("10:2,S"), Some(10));
+        assert_eq!(list.get("dup.host"), Some(20));
+        assert_eq!(list.get("20"), Some(30));
+        assert_eq!(list.get("new.host"), Some(31));
+        assert_eq!(list.get("other.host"), Some(32));
+        assert_eq!(list.get("gone.host"), None);
+
+        // Once repaired there is nothing left to do.
+        assert_eq!(repair(&folder).unwrap(), Repair { messages: 5, ..Repair::default() });
+        assert_eq!(UidList::load(&folder).unwrap(), list);
+        assert!(!folder.join(LOCK_FILE).exists());
+        assert!(FileLock::try_acquire(&folder.join(".lock")).unwrap().is_some());
+
+        // Nothing is changed while a session has the folder selected.
+        fs::write(folder.join(UIDLIST_FILE), "3 V1 N30\n10 :10\n").unwrap();
+        let selected = FileLock::try_acquire(&folder.join(".lock")).unwrap().unwrap();
+        assert_eq!(repair(&folder).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(UidList::load(&folder).unwrap().uid_validity, 1);
+        drop(selected);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
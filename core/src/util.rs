@@ -3,14 +3,20 @@
 // on the session (or take what they do need as arguments) and/or they are
 // called by the session in multiple places.
 
-use std::env::current_dir;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, MAIN_SEPARATOR};
+use mime::quote_imap_string;
 use regex::Regex;
 use walkdir::WalkDir;
 
-use folder::Folder;
+use command::response::ResponseWriter;
+use command::select::Qresync;
+use error::FolderError;
+use index;
+use maildir::{self, Layout, SpecialUse};
+use mailstore::{MailFolder, MailStore};
+use modseq::ModSeqs;
 
 #[macro_export]
 macro_rules! path_filename_to_str(
@@ -20,51 +26,86 @@ macro_rules! path_filename_to_str(
     });
 );
 
-fn make_absolute(dir: &Path) -> String {
-    match current_dir() {
-        Err(_) => dir.display().to_string(),
-        Ok(absp) => {
-            let mut abs_path = absp.clone();
-            abs_path.push(dir);
-            abs_path.display().to_string()
+/// Open the mailbox named in `select_args` for SELECT or EXAMINE. Returns
+/// the folder, if it could be opened, along with the response for the
+/// client. The response is empty if the arguments are invalid. `condstore`
+/// is whether the client has enabled CONDSTORE, and `qresync` what it last
+/// knew of the mailbox if it wants to be brought up to date.
+pub fn perform_select(store: &dyn MailStore, select_args: &[&str], examine: bool,
+                      condstore: bool, qresync: Option<&Qresync>,
+                      tag: &str) -> (Option<Box<dyn MailFolder>>, String) {
+    if select_args.len() < 1 { return (None, String::new()); }
+    let mbox_name = select_args[0].trim_matches('"');
+    match store.open(mbox_name, examine) {
+        Ok(folder) => {
+            let ok_res = folder.select_response(tag, condstore, qresync);
+            (Some(folder), ok_res)
+        }
+        Err(FolderError::NotFound) =>
+            (None, ResponseWriter::new().tagged_no(tag, "Mailbox does not exist").into_string()),
+        Err(FolderError::NoSelect) =>
+            (None, ResponseWriter::new().tagged_no(tag, "Mailbox is not selectable").into_string()),
+        Err(e) => {
+            error!("Failed to open {}: {}", mbox_name, e);
+            (None, ResponseWriter::new().tagged_no(tag, "Mailbox temporarily unavailable")
+                       .into_string())
         }
     }
 }
 
-pub fn perform_select(maildir: &str, select_args: &[&str], examine: bool,
-                      tag: &str) -> (Option<Folder>, String) {
-    let err_res = (None, "".to_string());
-    if select_args.len() < 1 { return err_res; }
-    let mbox_name = select_args[0].trim_matches('"').replace("INBOX", ".");
-    let mut maildir_path = PathBuf::new();
-    maildir_path.push(maildir);
-    maildir_path.push(mbox_name);
-    let folder = match Folder::new(maildir_path, examine) {
-        None => { return err_res; }
-        Some(folder) => folder.clone()
-    };
+/// Generate the STATUS response for the mailbox named in `status_args`.
+/// The counts come from the folder's index, so the messages themselves are
+/// never opened. Asking for HIGHESTMODSEQ sets `condstore`, as it enables
+/// CONDSTORE (RFC 7162).
+pub fn perform_status(maildir: &str, layout: Layout, status_args: &[&str], condstore: &mut bool,
+                      tag: &str) -> Option<String> {
+    if status_args.len() < 2 { return None; }
+    let mbox_name = status_args[0].trim_matches('"');
+    let items = status_args[1..].join(" ");
+    if !items.starts_with('(') || !items.ends_with(')') { return None; }
 
-    let ok_res = folder.select_response(tag);
-    (Some(folder), ok_res)
-}
-
-/// For the given dir, make sure it is a valid mail folder and, if it is,
-/// generate the LIST response for it.
-fn list_dir(dir: &Path, regex: &Regex, maildir_path: &Path) -> Option<String> {
-    let dir_string = dir.display().to_string();
-    let dir_name = path_filename_to_str!(dir);
+    let no_res = ResponseWriter::new().tagged_no(tag, "Invalid folder.").into_string();
+    let folder_path = match maildir::mailbox_path(Path::new(maildir), mbox_name, layout) {
+        None => return Some(no_res),
+        Some(path) => path
+    };
+    let status = match index::status(&folder_path) {
+        Err(_) => return Some(no_res),
+        Ok(status) => status
+    };
 
-    // These folder names are used to hold mail. Every other folder is
-    // valid.
-    if  dir_name == "cur" || dir_name == "new" || dir_name == "tmp" {
-        return None;
+    let mut values = Vec::new();
+    for item in items.trim_matches(|c| c == '(' || c == ')').split_whitespace() {
+        let value = match &item.to_uppercase()[..] {
+            "MESSAGES" => status.messages as u64,
+            "RECENT" => status.recent as u64,
+            "UIDNEXT" => status.uidnext as u64,
+            "UIDVALIDITY" => status.uid_validity as u64,
+            "UNSEEN" => status.unseen as u64,
+            "HIGHESTMODSEQ" => {
+                *condstore = true;
+                match ModSeqs::load(&folder_path, status.uid_validity) {
+                    Ok(modseqs) => modseqs.highest(),
+                    Err(_) => return Some(no_res)
+                }
+            }
+            _ => return None
+        };
+        values.push(format!("{} {}", item.to_uppercase(), value));
     }
 
-    let abs_dir = make_absolute(dir);
+    Some(ResponseWriter::new()
+        .untagged(&format!("STATUS {} ({})", quote_imap_string(mbox_name), values.join(" ")))
+        .tagged_ok(tag, "STATUS completed")
+        .into_string())
+}
 
+/// Determine whether the given dir is selectable and whether it holds new
+/// mail, for use in LIST responses.
+pub fn mail_flags(dir: &Path) -> String {
     // If it doesn't have any mail, then it isn't selectable as a mail
     // folder but it may contain subfolders which hold mail.
-    let mut flags = match fs::read_dir(&dir.join("cur")) {
+    match fs::read_dir(&dir.join("cur")) {
         Err(_) => "\\Noselect".to_string(),
         _ => {
             match fs::read_dir(&dir.join("new")) {
@@ -83,82 +124,315 @@ fn list_dir(dir: &Path, regex: &Regex, maildir_path: &Path) -> Option<String> {
                 }
             }
         }
-    };
+    }
+}
 
-    // Changing folders in mutt doesn't work properly if we don't indicate
-    // whether or not a given folder has subfolders. Mutt has issues
-    // selecting folders with subfolders for reading mail, unfortunately.
-    match fs::read_dir(&dir) {
-        Err(_) => { return None; }
-        Ok(dir_listing) => {
-            let mut children = false;
-            for subdir_entry in dir_listing {
-                if let Ok(subdir) = subdir_entry {
-                    if *dir == *maildir_path {
-                        break;
-                    }
-                    let subdir_path = subdir.path();
-                    let subdir_str = path_filename_to_str!(subdir_path);
-                    if subdir_str != "cur" &&
-                        subdir_str != "new" &&
-                        subdir_str != "tmp" {
-                            if fs::read_dir(&subdir.path().join("cur")).is_err() {
-                                continue;
-                            }
-                            if fs::read_dir(&subdir.path().join("new")).is_err() {
-                                continue;
-                            }
-                            children = true;
-                            break;
-                        }
-                }
-            }
-            if children {
-                flags.push_str(" \\HasChildren");
-            } else {
-                flags.push_str(" \\HasNoChildren");
+/// How far below the user's maildir folders are looked for.
+const MAX_FOLDER_DEPTH: usize = 32;
+
+/// List every mailbox in the logged in user's maildir whose name matches
+/// the given regular expression. Returns the untagged LIST responses, which
+/// give the special use of the mailboxes named in `special_use`.
+pub fn list(store: &dyn MailStore, regex: &Regex,
+            special_use: &HashMap<String, SpecialUse>) -> Vec<String> {
+    list_names(store, &store.mailboxes(), regex, special_use)
+}
+
+/// The name of every mailbox in the maildir, sorted. The maildir itself is
+/// INBOX and every maildir below it is named by its path from there.
+/// Directories which only hold other folders are included.
+pub fn mailbox_names(maildir_path: &Path) -> Vec<String> {
+    let mut names = vec!["INBOX".to_string()];
+    let walker = WalkDir::new(maildir_path)
+        .min_depth(1)
+        .max_depth(MAX_FOLDER_DEPTH)
+        .into_iter()
+        // The directories holding a folder's mail never hold other folders.
+        .filter_entry(|entry| match entry.file_name().to_str() {
+            Some("cur") | Some("new") | Some("tmp") => false,
+            _ => entry.file_type().is_dir()
+        });
+    for entry in walker.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !path.join("cur").is_dir() || !path.join("new").is_dir() {
+            continue;
+        }
+        let relative = match path.strip_prefix(maildir_path) {
+            Ok(relative) => relative,
+            Err(_) => continue
+        };
+        let name = match relative.to_str() {
+            Some(name) => name.replace(MAIN_SEPARATOR, "/"),
+            None => {
+                warn!("Skipping folder {} whose name is not valid UTF-8", path.display());
+                continue;
             }
+        };
+        // INBOX is always the maildir itself.
+        if name.eq_ignore_ascii_case("INBOX") {
+            continue;
         }
+        names.push(name);
     }
+    // Directories which only hold other folders are listed too, so that the
+    // hierarchy down to those folders is complete.
+    add_parents(&mut names);
+    names.sort();
+    names
+}
 
-    let re_path = make_absolute(maildir_path);
-    match fs::metadata(dir) {
-        Err(_) => return None,
-        Ok(md) =>
-            if !md.is_dir() {
-                return None;
+/// The name of every mailbox in the Maildir++ maildir, sorted. The parents
+/// of nested folders are included even without directories of their own,
+/// as `.Archive` needn't exist for there to be an `.Archive.2017`.
+pub fn mailbox_names_maildirplusplus(maildir_path: &Path) -> Vec<String> {
+    let mut names = vec!["INBOX".to_string()];
+    if let Ok(listing) = fs::read_dir(maildir_path) {
+        for entry in listing.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
             }
-    };
+            if let Some(name) = maildir::maildirplusplus_mailbox_name(path_filename_to_str!(path)) {
+                names.push(name);
+            }
+        }
+    }
+    add_parents(&mut names);
+    names.sort();
+    names
+}
 
-    if !regex.is_match(&dir_string[..]) {
-        return None;
+// Add the parents of every mailbox in `names` which aren't there already.
+fn add_parents(names: &mut Vec<String>) {
+    let mut parents = Vec::new();
+    for name in names.iter() {
+        let mut parts: Vec<&str> = name.split('/').collect();
+        parts.pop();
+        while !parts.is_empty() {
+            let parent = parts.join("/");
+            if names.contains(&parent) || parents.contains(&parent) {
+                break;
+            }
+            parents.push(parent);
+            parts.pop();
+        }
     }
-    let mut list_str = "* LIST (".to_string();
-    list_str.push_str(&flags[..]);
-    list_str.push_str(") \"/\" ");
-    let list_dir_string = if abs_dir.starts_with(&re_path[..]) {
-        abs_dir.replacen(&re_path[..], "", 1)
-    } else {
-        abs_dir
+    names.extend(parents);
+}
+
+/// The untagged response to a LIST with an empty mailbox name, which tells
+/// the client the hierarchy delimiter and the root of the reference name,
+/// which is everything up to and including its first delimiter (RFC 3501
+/// 6.3.8).
+pub fn list_root(reference: &str) -> String {
+    let root = match reference.find('/') {
+        Some(index) => &reference[..index + 1],
+        None => ""
     };
-    list_str.push_str(&(list_dir_string.replace("INBOX", ""))[..]);
-    Some(list_str)
+    format!("LIST (\\Noselect) \"/\" {}", quote_imap_string(root))
 }
 
-/// Go through the logged in user's maildir and list every folder matching
-/// the given regular expression. Returns a list of LIST responses.
-pub fn list(maildir: &str, regex: &Regex) -> Vec<String> {
-    let maildir_path = Path::new(maildir);
+/// Generate the untagged LIST responses for the mailboxes among `names`,
+/// which must be sorted, that match the given regular expression.
+fn list_names(store: &dyn MailStore, names: &[String], regex: &Regex,
+              special_use: &HashMap<String, SpecialUse>) -> Vec<String> {
     let mut responses = Vec::new();
-    if let Some(list_response) = list_dir(maildir_path, regex, maildir_path) {
-        responses.push(list_response);
-    }
-    for dir_res in WalkDir::new(&maildir_path) {
-        if let Ok(dir) = dir_res {
-            if let Some(list_response) = list_dir(dir.path(), regex, maildir_path) {
-                responses.push(list_response);
-            }
+    for name in names {
+        if !regex.is_match(&name[..]) {
+            continue;
+        }
+        let mut flags = match store.mailbox_flags(&name[..]) {
+            None => continue,
+            Some(flags) => flags
+        };
+        if flags == "\\Noselect" {
+            // A mailbox which can't be selected is only listed for the
+            // folders it holds.
+            responses.push(format!("LIST ({} \\HasChildren) \"/\" {}", flags,
+                                   quote_imap_string(name)));
+            continue;
+        }
+        // Other folders are named as siblings of INBOX rather than as its
+        // children, whichever directory they are stored in.
+        let prefix = format!("{}/", name);
+        if name != "INBOX" && names.iter().any(|other| other.starts_with(&prefix[..])) {
+            flags.push_str(" \\HasChildren");
+        } else {
+            flags.push_str(" \\HasNoChildren");
         }
+        if let Some(special_use) = special_use.get(name) {
+            flags.push(' ');
+            flags.push_str(special_use.attribute());
+        }
+        responses.push(format!("LIST ({}) \"/\" {}", flags, quote_imap_string(name)));
     }
     responses
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use regex::Regex;
+
+    use fixture;
+    use folder::Folder;
+    use maildir::{self, Layout, SpecialUse};
+    use mailstore::{MailFolder, MailStore, MaildirStore};
+    use memstore::MemoryStore;
+    use message::Flag;
+    use uidlist::UidList;
+    use super::{list, list_root, perform_select, perform_status};
+
+    #[test]
+    fn test_list() {
+        let maildir = fixture::maildir("list-fs", &[]);
+        for dir in &["Sent", "Work", "Work/Projects", "Archive/2017"] {
+            maildir::create_folder(&maildir.join(dir)).unwrap();
+        }
+        // Neither a folder's mail nor a directory without mail is listed.
+        fs::create_dir_all(maildir.join("cur").join("Hidden").join("cur")).unwrap();
+        fs::create_dir_all(maildir.join("cur").join("Hidden").join("new")).unwrap();
+        fs::create_dir_all(maildir.join("Notes")).unwrap();
+        fixture::write_message(&maildir.join("Sent"), "new", 1, &[], fixture::MESSAGE);
+        let store = MaildirStore::new(&maildir, Layout::Fs, false, 100);
+        let mut special_use = HashMap::new();
+        special_use.insert("Sent".to_string(), SpecialUse::Sent);
+
+        let all = list(&store, &Regex::new("^.*$").unwrap(), &special_use);
+        assert_eq!(all, vec!["LIST (\\Noselect \\HasChildren) \"/\" \"Archive\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"Archive/2017\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"INBOX\"",
+                             "LIST (\\Marked \\HasNoChildren \\Sent) \"/\" \"Sent\"",
+                             "LIST (\\Unmarked \\HasChildren) \"/\" \"Work\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"Work/Projects\""]);
+
+        let top = list(&store, &Regex::new("^[^/]*$").unwrap(), &special_use);
+        assert_eq!(top.len(), 4);
+
+        let sub = list(&store, &Regex::new("^Work/.*$").unwrap(), &special_use);
+        assert_eq!(sub, vec!["LIST (\\Unmarked \\HasNoChildren) \"/\" \"Work/Projects\""]);
+
+        // Only the folders within it can be selected.
+        let (folder, res) = perform_select(&store, &["Archive"], false, false, None, "a1");
+        assert!(folder.is_none());
+        assert_eq!(res, "a1 NO Mailbox is not selectable\r\n");
+        let (folder, _) = perform_select(&store, &["Archive/2017"], false, false, None, "a2");
+        assert!(folder.is_some());
+
+        drop(folder);
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[test]
+    fn test_list_root() {
+        assert_eq!(list_root(""), "LIST (\\Noselect) \"/\" \"\"");
+        assert_eq!(list_root("Work"), "LIST (\\Noselect) \"/\" \"\"");
+        assert_eq!(list_root("Work/Projects/"), "LIST (\\Noselect) \"/\" \"Work/\"");
+    }
+
+    #[test]
+    fn test_list_maildirplusplus() {
+        let maildir = fixture::maildir("list", &[]);
+        for dir in &[".Sent", ".Work", ".Work.Projects", ".Archive.2017"] {
+            maildir::create_folder(&maildir.join(dir)).unwrap();
+        }
+        fixture::write_message(&maildir.join(".Sent"), "new", 1, &[], fixture::MESSAGE);
+        let store = MaildirStore::new(&maildir, Layout::MaildirPlusPlus, false, 100);
+        let special_use = HashMap::new();
+
+        let all = list(&store, &Regex::new("^.*$").unwrap(), &special_use);
+        assert_eq!(all, vec!["LIST (\\Noselect \\HasChildren) \"/\" \"Archive\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"Archive/2017\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"INBOX\"",
+                             "LIST (\\Marked \\HasNoChildren) \"/\" \"Sent\"",
+                             "LIST (\\Unmarked \\HasChildren) \"/\" \"Work\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"Work/Projects\""]);
+
+        let top = list(&store, &Regex::new("^[^/]*$").unwrap(), &special_use);
+        assert_eq!(top.len(), 4);
+
+        let sub = list(&store, &Regex::new("^Work/.*$").unwrap(), &special_use);
+        assert_eq!(sub, vec!["LIST (\\Unmarked \\HasNoChildren) \"/\" \"Work/Projects\""]);
+
+        // The parent without a directory can't be selected either.
+        let (folder, res) = perform_select(&store, &["Archive"], false, false, None, "a1");
+        assert!(folder.is_none());
+        assert_eq!(res, "a1 NO Mailbox is not selectable\r\n");
+
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[test]
+    fn test_select_and_list_memory() {
+        let store = MemoryStore::new();
+        store.create("Work");
+        store.append("INBOX", b"Subject: one\n\nOne\n", &[Flag::Seen], None).unwrap();
+        store.append("INBOX", b"Subject: two\n\nTwo\n", &[], None).unwrap();
+        let flags = "\\Answered \\Deleted \\Draft \\Flagged \\Seen \
+                     $Forwarded $Junk $MDNSent $NotJunk";
+
+        let (folder, res) = perform_select(&store, &["INBOX"], false, false, None, "a1");
+        assert!(folder.is_some());
+        assert_eq!(res, format!("* FLAGS ({0})\r\n\
+                                 * 2 EXISTS\r\n\
+                                 * 0 RECENT\r\n\
+                                 * OK [UNSEEN 2] Message 2 is the first unseen\r\n\
+                                 * OK [PERMANENTFLAGS ({0} \\*)] Permanent flags\r\n\
+                                 * OK [UIDVALIDITY 1] UIDs valid\r\n\
+                                 * OK [UIDNEXT 3] Predicted next UID\r\n\
+                                 a1 OK [READ-WRITE] SELECT command was successful\r\n",
+                                flags));
+        let (_, res) = perform_select(&store, &["\"Work\""], true, false, None, "a2");
+        assert!(res.ends_with("a2 OK [READ-ONLY] SELECT command was successful\r\n"));
+        let (folder, res) = perform_select(&store, &["Lost"], false, false, None, "a3");
+        assert!(folder.is_none());
+        assert_eq!(res, "a3 NO Mailbox does not exist\r\n");
+
+        let all = list(&store, &Regex::new("^.*$").unwrap(), &HashMap::new());
+        assert_eq!(all, vec!["LIST (\\Unmarked \\HasNoChildren) \"/\" \"INBOX\"",
+                             "LIST (\\Unmarked \\HasNoChildren) \"/\" \"Work\""]);
+    }
+
+    #[test]
+    fn test_perform_status() {
+        let maildir = fixture::maildir("status", &[(10, &[Flag::Seen])]);
+        fixture::write_message(&maildir, "new", 12, &[], fixture::MESSAGE);
+        let maildir_str = maildir.to_str().unwrap();
+        let mut condstore = false;
+
+        let res = perform_status(maildir_str, Layout::Fs,
+                                 &["INBOX", "(MESSAGES", "RECENT", "UIDNEXT", "UNSEEN)"],
+                                 &mut condstore, "a1");
+        assert_eq!(res.unwrap(), "* STATUS \"INBOX\" (MESSAGES 2 RECENT 1 UIDNEXT 13 UNSEEN 1)\r\n\
+                                  a1 OK STATUS completed\r\n");
+        let res = perform_status(maildir_str, Layout::Fs, &["Missing", "(MESSAGES)"],
+                                 &mut condstore, "a2");
+        assert_eq!(res.unwrap(), "a2 NO Invalid folder.\r\n");
+        assert_eq!(perform_status(maildir_str, Layout::Fs, &["INBOX", "(BOGUS)"], &mut condstore,
+                                  "a3"), None);
+        assert_eq!(perform_status(maildir_str, Layout::Fs, &["INBOX", "MESSAGES"], &mut condstore,
+                                  "a4"), None);
+
+        // UIDVALIDITY is the one the folder has when it is selected.
+        let res = perform_status(maildir_str, Layout::Fs, &["\"INBOX\"", "(UIDVALIDITY)"],
+                                 &mut condstore, "a5");
+        let uid_validity = UidList::load(&maildir).unwrap().uid_validity;
+        assert_eq!(res.unwrap(), format!("* STATUS \"INBOX\" (UIDVALIDITY {})\r\n\
+                                          a5 OK STATUS completed\r\n", uid_validity));
+        assert!(!condstore);
+
+        // HIGHESTMODSEQ is the one the folder has when it is selected, and
+        // asking for it enables CONDSTORE.
+        let highest = Folder::new(&maildir, maildir.clone(), true, false, 100).unwrap()
+            .highest_modseq().unwrap();
+        let res = perform_status(maildir_str, Layout::Fs, &["INBOX", "(HIGHESTMODSEQ)"],
+                                 &mut condstore, "a6");
+        assert_eq!(res.unwrap(), format!("* STATUS \"INBOX\" (HIGHESTMODSEQ {})\r\n\
+                                          a6 OK STATUS completed\r\n", highest));
+        assert!(condstore);
+
+        fs::remove_dir_all(&maildir).unwrap();
+    }
+}
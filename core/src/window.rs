@@ -0,0 +1,233 @@
+//! The messages of a selected folder, in sequence number order.
+//!
+//! Only the UID and filename of each message are kept. A `Message` is made
+//! from the filename whenever a command needs one, and dropped again once
+//! it is done with, so a folder of any size is never held in memory as a
+//! whole. The exception is a message whose flags have been changed, which is
+//! held until its new flags are written to disk. How many may be held at
+//! once is limited, and the folder writes them out once there are more.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use keywords::Keywords;
+use maildir;
+use mailstore::MailMessage;
+use message::Message;
+
+// The UID and filename of a message
+#[derive(Clone, Debug)]
+struct Entry {
+    uid: usize,
+    // Whether the message is in folder/new/ rather than folder/cur/
+    new: bool,
+    filename: String
+}
+
+/// The messages of a folder, and those which have changed.
+#[derive(Debug)]
+pub struct Window {
+    // The folder's directory
+    path: PathBuf,
+    // Every message in the folder, in ascending UID order
+    entries: Vec<Entry>,
+    // The messages whose flags have changed since they were last written
+    // to disk, by UID
+    changed: HashMap<usize, Message>,
+    // How many changed messages may be held
+    limit: usize
+}
+
+impl Window {
+    /// Create an empty list for the folder at `path`, which holds at most
+    /// `limit` changed messages before `is_full` says they should be written.
+    pub fn new(path: &Path, limit: usize) -> Window {
+        Window {
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+            changed: HashMap::new(),
+            limit: limit
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Add the message at `msg_path` after the others. Its UID must be
+    /// higher than theirs, as sequence numbers are in ascending UID order.
+    /// Messages whose filenames aren't UTF-8 are left out.
+    pub fn push(&mut self, uid: usize, msg_path: &Path) {
+        if let Some(entry) = self.entry(uid, msg_path) {
+            self.entries.push(entry);
+        }
+    }
+
+    /// The UID of the message at `index`.
+    pub fn uid(&self, index: usize) -> Option<usize> {
+        self.entries.get(index).map(|entry| entry.uid)
+    }
+
+    /// The UIDs of every message from the given index onwards
+    pub fn uids_from(&self, start: usize) -> Vec<usize> {
+        self.entries.iter().skip(start).map(|entry| entry.uid).collect()
+    }
+
+    /// Turn a UID into an index.
+    pub fn index_of(&self, uid: usize) -> Option<usize> {
+        self.entries.binary_search_by_key(&uid, |entry| entry.uid).ok()
+    }
+
+    /// The message at `index`, as this session last saw it. `keywords`
+    /// gives the meaning of the letters in its filename.
+    pub fn get(&self, index: usize, keywords: &Keywords) -> Option<Message> {
+        let entry = self.entries.get(index)?;
+        match self.changed.get(&entry.uid) {
+            Some(message) => Some(message.clone()),
+            None => Message::new(&self.entry_path(entry), entry.uid, keywords).ok()
+        }
+    }
+
+    /// Every message in turn, each made only when it is reached.
+    pub fn messages<'a>(&'a self, keywords: &'a Keywords) -> impl Iterator<Item = Message> + 'a {
+        (0..self.entries.len()).filter_map(move |index| self.get(index, keywords))
+    }
+
+    /// The message at `index`, to change its flags. It is held until
+    /// `saved` is told that they have been written to disk.
+    pub fn change(&mut self, index: usize, keywords: &Keywords) -> Option<&mut Message> {
+        let uid = self.uid(index)?;
+        if !self.changed.contains_key(&uid) {
+            let message = self.get(index, keywords)?;
+            self.changed.insert(uid, message);
+        }
+        self.changed.get_mut(&uid)
+    }
+
+    /// The indices of the messages whose flags have changed, in order.
+    pub fn changed(&self) -> Vec<usize> {
+        let mut changed: Vec<usize> = self.changed.keys()
+            .filter_map(|&uid| self.index_of(uid))
+            .collect();
+        changed.sort();
+        changed
+    }
+
+    /// Whether more changed messages are held than the limit allows.
+    pub fn is_full(&self) -> bool {
+        self.changed.len() > self.limit
+    }
+
+    /// Record that the message at `index` is now at `msg_path`, with any
+    /// changes to its flags written there, so it needn't be held any more.
+    pub fn saved(&mut self, index: usize, msg_path: &Path) {
+        let entry = match self.entries.get(index) {
+            Some(entry) => self.entry(entry.uid, msg_path),
+            None => return
+        };
+        if let Some(entry) = entry {
+            self.changed.remove(&entry.uid);
+            self.entries[index] = entry;
+        }
+    }
+
+    /// Record that another session has moved the message at `index` to
+    /// `msg_path`, perhaps changing its flags. Changes made here which
+    /// haven't been written yet are kept, as `Message::merge_flags`
+    /// describes. Returns whether the message's flags are now different.
+    pub fn renamed(&mut self, index: usize, msg_path: &Path, keywords: &Keywords) -> bool {
+        let (before, uid) = match self.get(index, keywords) {
+            Some(message) => (message.flags().clone(), message.get_uid()),
+            None => return false
+        };
+        let entry = match self.entry(uid, msg_path) {
+            Some(entry) => entry,
+            None => return false
+        };
+        self.entries[index] = entry;
+        if let Some(message) = self.changed.get_mut(&uid) {
+            if let Ok(on_disk) = Message::new(msg_path, uid, keywords) {
+                message.merge_flags(on_disk);
+            }
+        }
+        self.get(index, keywords).map_or(false, |message| *message.flags() != before)
+    }
+
+    /// Remove the message at `index`, returning its UID.
+    pub fn remove(&mut self, index: usize) -> usize {
+        let uid = self.entries.remove(index).uid;
+        self.changed.remove(&uid);
+        uid
+    }
+
+    // The entry for the message with the given UID at `msg_path`.
+    fn entry(&self, uid: usize, msg_path: &Path) -> Option<Entry> {
+        let filename = maildir::message_filename(msg_path)?;
+        Some(Entry {
+            uid: uid,
+            new: msg_path.parent() == Some(self.path.join("new").as_path()),
+            filename: filename.to_string()
+        })
+    }
+
+    fn entry_path(&self, entry: &Entry) -> PathBuf {
+        let dir = if entry.new { "new" } else { "cur" };
+        self.path.join(dir).join(&entry.filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use command::store::StoreName;
+    use keywords::Keywords;
+    use mailstore::MailMessage;
+    use message::Flag;
+    use super::Window;
+
+    #[test]
+    fn test_window() {
+        let path = Path::new("/mail/INBOX");
+        let keywords = Keywords::new();
+        let mut window = Window::new(path, 1);
+        window.push(3, &path.join("cur/100.M1:2,S"));
+        window.push(7, &path.join("new/200.M2"));
+        window.push(9, &path.join("cur/300.M3:2,"));
+
+        assert_eq!(window.len(), 3);
+        assert_eq!(window.uids_from(1), vec![7, 9]);
+        assert_eq!(window.index_of(9), Some(2));
+        assert_eq!(window.index_of(8), None);
+        let message = window.get(1, &keywords).unwrap();
+        assert_eq!(message.get_uid(), 7);
+        assert_eq!(message.get_path(), path.join("new/200.M2").as_path());
+        assert_eq!(window.messages(&keywords).filter(|msg| msg.is_unseen()).count(), 2);
+
+        // Changes are held until they are saved.
+        let mut flags = HashSet::new();
+        flags.insert(Flag::Flagged);
+        window.change(2, &keywords).unwrap().store(&StoreName::Add, flags.clone());
+        assert!(window.get(2, &keywords).unwrap().has_flag(&Flag::Flagged));
+        assert_eq!(window.changed(), vec![2]);
+        assert!(!window.is_full());
+        window.change(0, &keywords).unwrap().store(&StoreName::Add, flags);
+        assert_eq!(window.changed(), vec![0, 2]);
+        assert!(window.is_full());
+
+        // Another session's changes are merged with them.
+        assert!(window.renamed(0, &path.join("cur/100.M1:2,RS"), &keywords));
+        let message = window.get(0, &keywords).unwrap();
+        assert!(message.has_flag(&Flag::Answered) && message.has_flag(&Flag::Flagged));
+        assert!(!window.renamed(1, &path.join("cur/200.M2:2,"), &keywords));
+
+        window.saved(2, &path.join("cur/300.M3:2,F"));
+        window.saved(0, &path.join("cur/100.M1:2,FRS"));
+        assert!(window.changed().is_empty());
+        assert!(window.get(2, &keywords).unwrap().has_flag(&Flag::Flagged));
+
+        assert_eq!(window.remove(1), 7);
+        assert_eq!(window.index_of(9), Some(1));
+    }
+}
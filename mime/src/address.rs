@@ -0,0 +1,250 @@
+//! The address structures of an ENVELOPE (RFC 3501 - 7.4.2), worked out from
+//! the address lists of the From, To, Cc and similar header fields
+//! (RFC 2822 - 3.4).
+
+use encoded_word::decode_encoded_words;
+use quote_imap_string;
+
+/// Describes the addresses in a header field value as a parenthesized list
+/// of `(name adl mailbox host)` address structures, or `NIL` if there are
+/// none. A group is given as an address with the group's name as its
+/// mailbox and no host, followed by its members and then an address which
+/// is all `NIL`.
+///
+/// If `decode` is set, RFC 2047 encoded-words in the display names are
+/// decoded. Each name is quoted only once it has been decoded, so that
+/// whatever it decodes to is escaped or sent as a literal as it needs to be.
+pub fn address_list(value: &str, decode: bool) -> String {
+    let mut res = String::new();
+    for item in split_items(value) {
+        match item {
+            Item::Mailbox(mailbox) => {
+                if let Some(address) = address(mailbox, decode) {
+                    res.push_str(&address[..]);
+                }
+            }
+            Item::GroupStart(name) => {
+                res.push_str(&format!("(NIL NIL {} NIL)", quote_imap_string(&phrase(name)[..]))[..]);
+            }
+            Item::GroupEnd => res.push_str("(NIL NIL NIL NIL)")
+        }
+    }
+    if res.is_empty() {
+        "NIL".to_string()
+    } else {
+        format!("({})", res)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Item<'a> {
+    Mailbox(&'a str),
+    GroupStart(&'a str),
+    GroupEnd
+}
+
+// Split an address list on the commas, colons and semicolons which separate
+// its mailboxes and groups, skipping over those in quoted strings, comments
+// and angle brackets.
+fn split_items<'a>(value: &'a str) -> Vec<Item<'a>> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0usize;
+    let mut angle = false;
+    let mut in_group = false;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            _ if quoted => {}
+            '(' => comment += 1,
+            ')' if comment > 0 => comment -= 1,
+            _ if comment > 0 => {}
+            '<' => angle = true,
+            '>' => angle = false,
+            _ if angle => {}
+            ',' => {
+                items.push(Item::Mailbox(&value[start..i]));
+                start = i + 1;
+            }
+            ':' if !in_group => {
+                items.push(Item::GroupStart(&value[start..i]));
+                in_group = true;
+                start = i + 1;
+            }
+            ';' if in_group => {
+                items.push(Item::Mailbox(&value[start..i]));
+                items.push(Item::GroupEnd);
+                in_group = false;
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(Item::Mailbox(&value[start..]));
+    // A group which is never closed still ends.
+    if in_group {
+        items.push(Item::GroupEnd);
+    }
+    items
+}
+
+// The address structure of one mailbox, or None if there is nothing there.
+fn address(mailbox: &str, decode: bool) -> Option<String> {
+    let mailbox = mailbox.trim();
+    if mailbox.is_empty() {
+        return None;
+    }
+    // name <route:local@domain>, or just local@domain with perhaps a
+    // comment which gives the name.
+    let (name, addr) = match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(open), close) => {
+            let close = close.filter(|&close| close > open).unwrap_or(mailbox.len());
+            (phrase(&mailbox[..open]), mailbox[open + 1..close].trim().to_string())
+        }
+        _ => (comments(mailbox), phrase(mailbox))
+    };
+    let name = if decode { decode_encoded_words(&name[..]) } else { name };
+
+    let (adl, addr) = match addr.find(':') {
+        Some(end) if addr.starts_with('@') =>
+            (Some(addr[..end].to_string()), addr[end + 1..].to_string()),
+        _ => (None, addr)
+    };
+    let (local, host) = match addr.rfind('@') {
+        Some(at) => (addr[..at].to_string(), addr[at + 1..].to_string()),
+        // A host of NIL would mark the start of a group.
+        None => (addr, String::new())
+    };
+    Some(format!("({} {} {} {})", nstring(name), nstring(adl.unwrap_or_default()),
+                 nstring(local), quote_imap_string(&host[..])))
+}
+
+// The text of a phrase with its quoted strings unquoted, its comments
+// dropped and its runs of whitespace collapsed.
+fn phrase(value: &str) -> String {
+    let mut res = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0usize;
+    for c in value.chars() {
+        if escaped {
+            if comment == 0 {
+                res.push(c);
+            }
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => comment -= 1,
+            _ if comment > 0 => {}
+            c if c.is_whitespace() && !quoted => {
+                if !res.is_empty() && !res.ends_with(' ') {
+                    res.push(' ');
+                }
+            }
+            c => res.push(c)
+        }
+    }
+    res.trim().to_string()
+}
+
+// The text of the comments in a value, which older mail uses to give the
+// name after the address.
+fn comments(value: &str) -> String {
+    let mut res = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0usize;
+    for c in value.chars() {
+        if escaped {
+            if comment > 0 {
+                res.push(c);
+            }
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => {
+                if comment > 0 {
+                    res.push(c);
+                } else if !res.is_empty() {
+                    res.push(' ');
+                }
+                comment += 1;
+            }
+            ')' if !quoted && comment > 0 => {
+                comment -= 1;
+                if comment > 0 {
+                    res.push(c);
+                }
+            }
+            c if comment > 0 => res.push(c),
+            _ => {}
+        }
+    }
+    res.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn nstring(value: String) -> String {
+    if value.is_empty() {
+        "NIL".to_string()
+    } else {
+        quote_imap_string(&value[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::address_list;
+
+    #[test]
+    fn test_address_list() {
+        assert_eq!(address_list("a@example.com", false),
+                   "((NIL NIL \"a\" \"example.com\"))");
+        assert_eq!(address_list("\"Smith, John\" <john@example.com>, Jane <jane@example.org>",
+                                false),
+                   "((\"Smith, John\" NIL \"john\" \"example.com\")\
+                     (\"Jane\" NIL \"jane\" \"example.org\"))");
+        assert_eq!(address_list("b@example.com (Bob  Jones)", false),
+                   "((\"Bob Jones\" NIL \"b\" \"example.com\"))");
+        assert_eq!(address_list("<@relay.example.com:c@example.com>", false),
+                   "((NIL \"@relay.example.com\" \"c\" \"example.com\"))");
+        assert_eq!(address_list("", false), "NIL");
+        assert_eq!(address_list(" , ", false), "NIL");
+    }
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(address_list("Team: a@example.com, b@example.com;, c@example.com", false),
+                   "((NIL NIL \"Team\" NIL)(NIL NIL \"a\" \"example.com\")\
+                     (NIL NIL \"b\" \"example.com\")(NIL NIL NIL NIL)\
+                     (NIL NIL \"c\" \"example.com\"))");
+        assert_eq!(address_list("undisclosed-recipients:;", false),
+                   "((NIL NIL \"undisclosed-recipients\" NIL)(NIL NIL NIL NIL))");
+    }
+
+    #[test]
+    fn test_decoded_names() {
+        // The name is escaped after decoding, not before.
+        let value = "=?US-ASCII?Q?Bob_=22the_builder=22_=5C?= <bob@example.com>";
+        assert_eq!(address_list(value, false),
+                   "((\"=?US-ASCII?Q?Bob_=22the_builder=22_=5C?=\" NIL \"bob\" \"example.com\"))");
+        assert_eq!(address_list(value, true),
+                   "((\"Bob \\\"the builder\\\" \\\\\" NIL \"bob\" \"example.com\"))");
+        // 8-bit text can't be quoted.
+        assert_eq!(address_list("=?ISO-8859-1?Q?Andr=E9?= <a@example.com>", true),
+                   "(({6}\r\nAndr\u{e9} NIL \"a\" \"example.com\"))");
+    }
+}
@@ -0,0 +1,199 @@
+//! Decoding of RFC 2047 encoded-words, such as `=?UTF-8?B?w6l0w6k=?=`, which
+//! are used to represent non-ASCII text in message header fields.
+
+use std::str;
+
+/// Decodes every encoded-word in a header field value.
+///
+/// Text which is not part of an encoded-word is left untouched, as are
+/// encoded-words which are malformed or use an unsupported charset. Per
+/// RFC 2047 - 6.2, whitespace separating two adjacent encoded-words is
+/// dropped.
+pub fn decode_encoded_words(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    // Whitespace which follows a decoded word. It is only emitted if the
+    // next token is not also an encoded-word.
+    let mut pending_ws: Option<&str> = None;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_word(candidate) {
+            Some((decoded, len)) => {
+                match pending_ws {
+                    Some(_) if before.is_empty() => {},
+                    Some(ws) => {
+                        res.push_str(ws);
+                        res.push_str(before);
+                    }
+                    None => res.push_str(before)
+                }
+                res.push_str(&decoded[..]);
+                rest = &candidate[len..];
+
+                // Hold on to any whitespace which follows the word.
+                let ws_len = rest.len() - rest.trim_left_matches(|c| c == ' ' || c == '\t').len();
+                pending_ws = Some(&rest[..ws_len]);
+                rest = &rest[ws_len..];
+            }
+            None => {
+                if let Some(ws) = pending_ws.take() {
+                    res.push_str(ws);
+                }
+                res.push_str(before);
+                res.push_str("=?");
+                rest = &candidate[2..];
+            }
+        }
+    }
+
+    if let Some(ws) = pending_ws {
+        res.push_str(ws);
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Attempts to decode the encoded-word at the start of `word`. Returns the
+/// decoded text and the number of bytes of `word` which were consumed.
+fn decode_word(word: &str) -> Option<(String, usize)> {
+    // =?charset?encoding?encoded-text?=
+    let inner = &word[2..];
+    let charset_end = inner.find('?')?;
+    let charset = &inner[..charset_end];
+    let after_charset = &inner[charset_end + 1..];
+    let encoding_end = after_charset.find('?')?;
+    let encoding = &after_charset[..encoding_end];
+    let text_and_rest = &after_charset[encoding_end + 1..];
+    let text_end = text_and_rest.find("?=")?;
+    let text = &text_and_rest[..text_end];
+
+    // Encoded-words may not contain whitespace.
+    if charset.is_empty() || text.contains(|c: char| c.is_whitespace()) {
+        return None;
+    }
+
+    let bytes = match &encoding.to_ascii_uppercase()[..] {
+        "B" => decode_base64(text)?,
+        "Q" => decode_q(text)?,
+        _ => return None
+    };
+    let decoded = decode_charset(charset, &bytes[..])?;
+    let len = 2 + charset_end + 1 + encoding_end + 1 + text_end + 2;
+    Some((decoded, len))
+}
+
+/// Converts the bytes of the given charset into a `String`.
+pub fn decode_charset(charset: &str, bytes: &[u8]) -> Option<String> {
+    // RFC 2231 allows a language to be appended to the charset: `UTF-8*en`.
+    let charset = charset.splitn(2, '*').next().unwrap_or("");
+    match &charset.to_ascii_lowercase()[..] {
+        "utf-8" | "utf8" => String::from_utf8(bytes.to_vec()).ok(),
+        "us-ascii" => {
+            if bytes.iter().all(|b| *b < 0x80) {
+                Some(bytes.iter().map(|b| *b as char).collect())
+            } else {
+                None
+            }
+        }
+        // ISO-8859-1 maps directly onto the first 256 unicode code points.
+        "iso-8859-1" | "latin1" => Some(bytes.iter().map(|b| *b as char).collect()),
+        _ => None
+    }
+}
+
+/// Decodes the "B" encoding, which is identical to base64.
+pub fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buf = 0u32;
+    let mut bits = 0u32;
+    for c in text.bytes() {
+        let val = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None
+        };
+        buf = (buf << 6) | u32::from(val);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(res)
+}
+
+/// Decodes the "Q" encoding, which is similar to quoted-printable except that
+/// underscores represent spaces.
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => res.push(b' '),
+            b'=' => {
+                if i + 2 >= bytes.len() {
+                    return None;
+                }
+                let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+                res.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            b => res.push(b)
+        }
+        i += 1;
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_encoded_words;
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(decode_encoded_words(""), "");
+        assert_eq!(decode_encoded_words("Hello world"), "Hello world");
+        assert_eq!(decode_encoded_words("=?not an encoded word"), "=?not an encoded word");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(decode_encoded_words("=?UTF-8?B?w6l0w6k=?="), "été");
+        assert_eq!(decode_encoded_words("=?utf-8?b?SGVsbG8=?= world"), "Hello world");
+        assert_eq!(decode_encoded_words("=?ISO-8859-1?B?6XTp?="), "été");
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(decode_encoded_words("=?ISO-8859-1?Q?Andr=E9?= Pirard"), "André Pirard");
+        assert_eq!(decode_encoded_words("=?UTF-8?Q?caf=C3=A9_au_lait?="), "café au lait");
+        assert_eq!(decode_encoded_words("=?US-ASCII*EN?Q?Keith_Moore?="), "Keith Moore");
+    }
+
+    #[test]
+    fn test_adjacent_words() {
+        // Whitespace between adjacent encoded-words is dropped.
+        assert_eq!(decode_encoded_words("=?UTF-8?Q?a?= =?UTF-8?Q?b?="), "ab");
+        assert_eq!(decode_encoded_words("=?UTF-8?Q?a?=  \t =?UTF-8?Q?b?="), "ab");
+        // But not between an encoded-word and ordinary text.
+        assert_eq!(decode_encoded_words("=?UTF-8?Q?a?= b =?UTF-8?Q?c?="), "a b c");
+        assert_eq!(decode_encoded_words("Re: =?UTF-8?Q?a?="), "Re: a");
+    }
+
+    #[test]
+    fn test_invalid_words() {
+        // Unknown charsets and encodings are left alone.
+        assert_eq!(decode_encoded_words("=?KOI8-R?Q?abc?="), "=?KOI8-R?Q?abc?=");
+        assert_eq!(decode_encoded_words("=?UTF-8?X?abc?="), "=?UTF-8?X?abc?=");
+        // As are words with invalid encoded text.
+        assert_eq!(decode_encoded_words("=?UTF-8?Q?ab=Z?="), "=?UTF-8?Q?ab=Z?=");
+        assert_eq!(decode_encoded_words("=?UTF-8?B?/w==?="), "=?UTF-8?B?/w==?=");
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg_attr(feature = "unstable", feature(test))]
+
 use std::ascii::AsciiExt;
 use std::collections::HashMap;
 use std::fs::File;
@@ -21,30 +23,33 @@ use self::command::Msgtext::{
     MimeMsgtext
 };
 
+pub use self::encoded_word::decode_encoded_words;
 pub use self::error::Error;
 use self::error::Result as MimeResult;
 
+mod address;
+mod encoded_word;
 mod error;
 mod command;
+mod structure;
 
 static RECEIVED: &'static str = "RECEIVED";
 
 #[derive(Debug, Clone)]
 pub struct Message {
-   // maps header field names to values
-    headers: HashMap<String, String>,
+   // maps header field names to their values, in the order they appear
+    headers: HashMap<String, Vec<String>>,
 
     // contains the MIME Parts (if more than one) of the message
     body: Vec<MIMEPart>,
 
-     // size stored in case FETCH asks for it
-    size: usize,
-
     // the raw contents of the file representing the message
     raw_contents: String,
 
-    // where in raw_contents the header ends and the body begins
-    header_boundary: usize
+    // where in raw_contents the header ends, and where the body begins
+    // after the blank line which separates them
+    header_boundary: usize,
+    text_start: usize
 }
 
 /// Representation of a MIME message part
@@ -56,19 +61,26 @@ struct MIMEPart {
 
 impl Message {
     pub fn new(arg_path: &Path) -> MimeResult<Message> {
-        // Load the file contents.
-        let mut file = File::open(arg_path)?;
-        let mut raw_contents = String::new();
-        file.read_to_string(&mut raw_contents)?;
+        Message::parse(read_file(arg_path)?)
+    }
 
-        // This slice will avoid copying later
-        let size = raw_contents.len();
+    /// Load a message as `new` does, but with any bare LF line endings
+    /// turned into CRLF so that it can be sent to IMAP clients as it is.
+    pub fn new_crlf(arg_path: &Path) -> MimeResult<Message> {
+        Message::parse(crlf_line_endings(read_file(arg_path)?))
+    }
 
-        // Find boundary between header and body.
+    /// Parse a message from its raw contents.
+    pub fn parse(raw_contents: String) -> MimeResult<Message> {
+        // Find boundary between header and body, which is the first blank
+        // line whichever line endings the message uses.
         // Use it to create &str of the raw header and raw body
-        let header_boundary = match raw_contents.find("\n\n") {
-            None => { return Err(Error::ParseMultipartBoundary); }
-            Some(n) => n + 1
+        let (header_boundary, text_start) = match (raw_contents.find("\n\n"),
+                                                   raw_contents.find("\r\n\r\n")) {
+            (Some(lf), Some(crlf)) if crlf < lf => (crlf + 2, crlf + 4),
+            (Some(lf), _) => (lf + 1, lf + 2),
+            (None, Some(crlf)) => (crlf + 2, crlf + 4),
+            (None, None) => { return Err(Error::ParseMultipartBoundary); }
         };
         let raw_header = &raw_contents[ .. header_boundary];
         let raw_body = &raw_contents[header_boundary .. ];
@@ -92,15 +104,17 @@ impl Message {
                     if !next.starts_with(' ') && !next.starts_with('\t') {
                         let split: Vec<&str> = (&trimmed_next[..])
                                                 .splitn(2, ':').collect();
-                        headers.insert(split[0].to_ascii_uppercase(),
-                                       split[1][1 .. ].to_string());
+                        headers.entry(split[0].to_ascii_uppercase())
+                            .or_insert_with(Vec::new)
+                            .insert(0, split[1][1 .. ].to_string());
                         break;
                     }
                 }
             } else {
                 let split: Vec<&str> = line.splitn(2, ':').collect();
-                headers.insert(split[0].to_ascii_uppercase(),
-                               split[1][1 .. ].to_string());
+                headers.entry(split[0].to_ascii_uppercase())
+                    .or_insert_with(Vec::new)
+                    .insert(0, split[1][1 .. ].to_string());
             }
         }
 
@@ -112,7 +126,7 @@ impl Message {
 
         // Determine whether the message is MULTIPART or not.
         let mut body = Vec::new();
-        match headers.get(&"CONTENT-TYPE".to_string()) {
+        match headers.get(&"CONTENT-TYPE".to_string()).and_then(|values| values.first()) {
             Some(content_type) => {
                 if (&content_type[..]).contains("MULTIPART") {
                     // We need the boundary to determine where this part ends
@@ -191,9 +205,9 @@ impl Message {
         let message = Message {
             headers: headers,
             body: body,
-            size: size,
             raw_contents: raw_contents.to_string(),
-            header_boundary: header_boundary
+            header_boundary: header_boundary,
+            text_start: text_start
         };
 
         // We created the message with no errors. Yay!
@@ -208,45 +222,81 @@ impl Message {
         let empty_string = "".to_string();
         let peek_attr = match *section {
             AllSection => {
-                format!("] {{{}}}\r\n{} ", (&self.raw_contents[..]).len(),
-                        self.raw_contents)
+                format!("] {}", imap_literal(&self.raw_contents[..]))
             }
             MsgtextSection(ref msgtext) => {
                 match *msgtext {
-                    HeaderMsgtext |
-                        HeaderFieldsNotMsgtext(_) |
-                        TextMsgtext |
+                    // The header includes the blank line which ends it.
+                    HeaderMsgtext => {
+                        format!("HEADER] {}", imap_literal(self.get_header_block()))
+                    },
+                    TextMsgtext => {
+                        format!("TEXT] {}", imap_literal(self.get_text()))
+                    },
+                    HeaderFieldsNotMsgtext(_) |
                         MimeMsgtext => { empty_string },
                     HeaderFieldsMsgtext(ref fields) => {
                         let mut field_keys = String::new();
                         let mut field_values = String::new();
                         let mut first = true;
                         for field in fields.iter() {
-                            match self.headers.get(field) {
-                                Some(v) => {
-                                    let field_slice = &field[..];
-                                    if first {
-                                        first = false;
-                                    } else {
-                                        field_keys.push(' ');
-                                    }
-                                    field_keys.push_str(field_slice);
-                                    field_values.push_str("\r\n");
-                                    field_values.push_str(field_slice);
-                                    field_values.push_str(": ");
-                                    field_values.push_str(&v[..]);
-                                },
-                                None => continue
+                            let values = self.get_headers(&field[..]);
+                            if values.is_empty() {
+                                continue;
+                            }
+                            let field_slice = &field[..];
+                            if first {
+                                first = false;
+                            } else {
+                                field_keys.push(' ');
+                            }
+                            field_keys.push_str(field_slice);
+                            // Repeated fields are all included.
+                            for v in values {
+                                field_values.push_str(field_slice);
+                                field_values.push_str(": ");
+                                field_values.push_str(&v[..]);
+                                field_values.push_str("\r\n");
                             }
                         }
-                        format!("HEADER.FIELDS ({})] {{{}}}{}", field_keys,
-                                &field_values[..].len(), field_values)
+                        // Like the full header, the subset ends with a blank
+                        // line.
+                        field_values.push_str("\r\n");
+                        format!("HEADER.FIELDS ({})] {}", field_keys,
+                                imap_literal(&field_values[..]))
                     },
                 }
             }
-            PartSection(_, _) => { "?]".to_string() }
+            PartSection(ref path, ref msgtext) => {
+                let numbers: Vec<String> = path.iter().map(|number| number.to_string()).collect();
+                let part = structure::find_part(&self.raw_contents[..], &path[..]);
+                // HEADER and TEXT refer to the message held in a
+                // message/rfc822 part.
+                let message = part.as_ref().and_then(|part| part.message());
+                let (suffix, contents) = match *msgtext {
+                    None => (String::new(), part.map(|part| part.body.to_string())),
+                    Some(MimeMsgtext) =>
+                        (".MIME".to_string(), part.map(|part| part.header.to_string())),
+                    Some(HeaderMsgtext) =>
+                        (".HEADER".to_string(), message.map(|(header, _)| header.to_string())),
+                    Some(TextMsgtext) =>
+                        (".TEXT".to_string(), message.map(|(_, text)| text.to_string())),
+                    Some(HeaderFieldsMsgtext(ref fields)) =>
+                        (format!(".HEADER.FIELDS ({})", fields.join(" ")),
+                         message.map(|(header, _)| structure::header_fields(header, fields, false))),
+                    Some(HeaderFieldsNotMsgtext(ref fields)) =>
+                        (format!(".HEADER.FIELDS.NOT ({})", fields.join(" ")),
+                         message.map(|(header, _)| structure::header_fields(header, fields, true)))
+                };
+                // A part which isn't there is NIL.
+                let contents = match contents {
+                    Some(contents) => imap_literal(&contents[..]),
+                    None => "NIL".to_string()
+                };
+                format!("{}{}] {}", numbers.join("."), suffix, contents)
+            }
         };
-        format!("BODY[{} ", peek_attr)
+        format!("BODY[{}", peek_attr)
     }
 
     /**
@@ -262,21 +312,29 @@ impl Message {
      * The date, subject, in-reply-to, and message-id fields are strings.
      * The from, sender, reply-to, to, cc, and bcc fields are parenthesized
      * lists of address structures.
+     *
+     * If `decode` is set, RFC 2047 encoded-words in the subject and the
+     * display names of the addresses are decoded rather than passed through
+     * to the client as-is.
      */
-    pub fn get_envelope(&self) -> String {
-        let date = self.get_field_or_nil("DATE");
-        let subject = self.get_field_or_nil("SUBJECT");
-        let from = self.get_parenthesized_addresses("FROM");
-        let sender = self.get_parenthesized_addresses("SENDER");
-        let reply_to = self.get_parenthesized_addresses("REPLY-TO");
-        let to = self.get_parenthesized_addresses("TO");
-        let cc = self.get_parenthesized_addresses("CC");
-        let bcc = self.get_parenthesized_addresses("BCC");
-        let in_reply_to = self.get_field_or_nil("IN-REPLY-TO");
-        let message_id = self.get_field_or_nil("MESSAGE-ID");
+    pub fn get_envelope(&self, decode: bool) -> String {
+        let date = self.get_quoted_field_or_nil("DATE");
+        let subject = if decode {
+            self.get_decoded_field_or_nil("SUBJECT")
+        } else {
+            self.get_quoted_field_or_nil("SUBJECT")
+        };
+        let from = self.get_parenthesized_addresses("FROM", decode);
+        let sender = self.get_parenthesized_addresses("SENDER", decode);
+        let reply_to = self.get_parenthesized_addresses("REPLY-TO", decode);
+        let to = self.get_parenthesized_addresses("TO", decode);
+        let cc = self.get_parenthesized_addresses("CC", decode);
+        let bcc = self.get_parenthesized_addresses("BCC", decode);
+        let in_reply_to = self.get_quoted_field_or_nil("IN-REPLY-TO");
+        let message_id = self.get_quoted_field_or_nil("MESSAGE-ID");
 
         format!(
-            "(\"{}\" \"{}\" {} {} {} {} {} {} \"{}\" \"{}\")",
+            "({} {} {} {} {} {} {} {} {} {})",
             date,
             subject,
             from,
@@ -289,35 +347,422 @@ impl Message {
             message_id)
     }
 
+    /// RFC3501 - 7.4.2
+    ///
+    /// Returns the parenthesized list describing the MIME structure of the
+    /// message. If `extensible` is set this is the BODYSTRUCTURE form, with
+    /// the extension data for each part, and otherwise it is the BODY form.
+    pub fn get_bodystructure(&self, extensible: bool) -> String {
+        structure::body_structure(self.get_header(), self.get_text(), extensible)
+    }
+
+    /// Every value of the header field, in the order they appear in the
+    /// message. The field name is case-insensitive.
+    pub fn get_headers(&self, key: &str) -> &[String] {
+        match self.headers.get(&key.to_ascii_uppercase()) {
+            Some(values) => &values[..],
+            None => &[]
+        }
+    }
+
+    // The first value of the header field.
+    fn get_header_value(&self, key: &str) -> Option<&String> {
+        self.headers.get(&key.to_string()).and_then(|values| values.first())
+    }
+
     pub fn get_field_or_nil(&self, key: &str) -> &str {
-        match self.headers.get(&key.to_string()) {
+        match self.get_header_value(key) {
             Some(v) => &v[..],
             None => "NIL"
         }
     }
 
+    /// Returns the value of the header field as an IMAP string, or `NIL` if
+    /// the message has no such header field.
+    fn get_quoted_field_or_nil(&self, key: &str) -> String {
+        match self.get_header_value(key) {
+            Some(v) => quote_imap_string(&v[..]),
+            None => "NIL".to_string()
+        }
+    }
+
+    /// Like `get_quoted_field_or_nil`, but decodes any RFC 2047 encoded-words
+    /// in the value first.
+    fn get_decoded_field_or_nil(&self, key: &str) -> String {
+        match self.get_header_value(key) {
+            Some(v) => quote_imap_string(&decode_encoded_words(&v[..])[..]),
+            None => "NIL".to_string()
+        }
+    }
+
     /**
      * RFC3501 - 7.4.2 - P.76
      *
-     * The RFC requests that the data be returned as a parenthesized list, but
-     * the current format is also acceptible by most mail clients.
+     * Returns the addresses in the header field as a parenthesized list of
+     * address structures, or `NIL` if there are none. Encoded-words in the
+     * display names are decoded if `decode` is set.
      */
-    pub fn get_parenthesized_addresses(&self, key: &str) -> &str {
-        match self.headers.get(&key.to_string()) {
-            Some(v) => &v[..],
-            None => "NIL"
+    pub fn get_parenthesized_addresses(&self, key: &str, decode: bool) -> String {
+        match self.get_header_value(key) {
+            Some(v) => address::address_list(&v[..], decode),
+            None => "NIL".to_string()
         }
     }
 
+    /// The size of the message in octets, which always matches the contents
+    /// returned for it.
     pub fn get_size(&self) -> String {
-        self.size.to_string()
+        self.raw_contents.len().to_string()
     }
 
-    pub fn get_header_boundary(&self) -> String {
-        self.header_boundary.to_string()
+    pub fn get_raw_contents(&self) -> &str {
+        &self.raw_contents[..]
     }
 
     pub fn get_header(&self) -> &str {
         &self.raw_contents[ .. self.header_boundary]
     }
+
+    /// The header along with the blank line separating it from the body.
+    pub fn get_header_block(&self) -> &str {
+        &self.raw_contents[ .. self.text_start]
+    }
+
+    /// Everything after the blank line separating the header from the body.
+    pub fn get_text(&self) -> &str {
+        &self.raw_contents[self.text_start .. ]
+    }
+
+    /// The body with the text in each part decoded from base64 or
+    /// quoted-printable, for searching.
+    pub fn get_decoded_text(&self) -> String {
+        structure::decoded_body(self.get_header_block(), self.get_text())
+    }
+}
+
+fn read_file(arg_path: &Path) -> MimeResult<String> {
+    let mut file = File::open(arg_path)?;
+    let mut raw_contents = String::new();
+    file.read_to_string(&mut raw_contents)?;
+    Ok(raw_contents)
+}
+
+/// Puts a CR before every LF which doesn't already have one, as IMAP
+/// requires CRLF line endings (RFC 3501 - 2.2). Contents which already use
+/// them throughout are returned untouched.
+pub fn crlf_line_endings(raw_contents: String) -> String {
+    let bare = raw_contents.bytes().enumerate()
+        .filter(|&(i, b)| b == b'\n' && (i == 0 || raw_contents.as_bytes()[i - 1] != b'\r'))
+        .count();
+    if bare == 0 {
+        return raw_contents;
+    }
+    let mut res = String::with_capacity(raw_contents.len() + bare);
+    let mut last = '\0';
+    for c in raw_contents.chars() {
+        if c == '\n' && last != '\r' {
+            res.push('\r');
+        }
+        res.push(c);
+        last = c;
+    }
+    res
+}
+
+/// Formats a value as an IMAP literal (RFC 3501 - 4.3). The declared length
+/// is the number of octets in the value, which is not the same as the
+/// number of characters once non-ASCII text is involved.
+pub fn imap_literal(value: &str) -> String {
+    format!("{{{}}}\r\n{}", value.len(), value)
+}
+
+/// Formats a value as an IMAP string (RFC 3501 - 4.3).
+///
+/// Values which can be represented as a quoted string have any backslashes
+/// and double quotes escaped. Values containing CR, LF or 8-bit characters
+/// cannot be quoted, so they are sent using the `{len}\r\n...` literal form
+/// instead.
+pub fn quote_imap_string(value: &str) -> String {
+    if value.contains('\r') || value.contains('\n') || !value.is_ascii() {
+        return imap_literal(value);
+    }
+
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+    for c in value.chars() {
+        if c == '\\' || c == '"' {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crlf_line_endings, imap_literal, quote_imap_string, BodySectionType, Message, Msgtext};
+
+    /// Check that the literal in a BODY[...] response declares exactly the
+    /// number of bytes which follow it, returning those bytes.
+    fn literal_contents(res: &str) -> &str {
+        let open = res.find('{').unwrap();
+        let close = res.find("}\r\n").unwrap();
+        let len: usize = res[open + 1 .. close].parse().unwrap();
+        let contents = &res[close + 3 ..];
+        assert_eq!(len, contents.len());
+        contents
+    }
+
+    #[test]
+    fn test_body_header_and_text() {
+        let raw = "Subject: caf\u{e9}\nFrom: a@example.com\n\nHello\nworld\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+
+        let header = message.get_body(&BodySectionType::MsgtextSection(Msgtext::HeaderMsgtext),
+                                      &None);
+        assert!(header.starts_with("BODY[HEADER] {"));
+        assert_eq!(literal_contents(&header[..]), "Subject: caf\u{e9}\nFrom: a@example.com\n\n");
+
+        let text = message.get_body(&BodySectionType::MsgtextSection(Msgtext::TextMsgtext),
+                                    &None);
+        assert_eq!(text, "BODY[TEXT] {12}\r\nHello\nworld\n");
+    }
+
+    #[test]
+    fn test_body_literal_lengths() {
+        let raw = "Subject: caf\u{e9} \u{2615}\nFrom: a@example.com\n\nBody \u{e9}\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+
+        let all = message.get_body(&BodySectionType::AllSection, &None);
+        assert_eq!(literal_contents(&all[..]), raw);
+
+        let fields = BodySectionType::MsgtextSection(
+            Msgtext::HeaderFieldsMsgtext(vec!["SUBJECT".to_string(), "X-MISSING".to_string(),
+                                              "FROM".to_string()]));
+        let res = message.get_body(&fields, &None);
+        assert!(res.starts_with("BODY[HEADER.FIELDS (SUBJECT FROM)] {"));
+        assert_eq!(literal_contents(&res[..]),
+                   "SUBJECT: caf\u{e9} \u{2615}\r\nFROM: a@example.com\r\n\r\n");
+
+        let text = message.get_body(&BodySectionType::MsgtextSection(Msgtext::TextMsgtext),
+                                    &None);
+        assert_eq!(literal_contents(&text[..]), "Body \u{e9}\n");
+    }
+
+    #[test]
+    fn test_repeated_headers() {
+        let raw = "Received: from a\nX-Tag: one\nSubject: hi\nX-Tag: two\n\nbody\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        assert_eq!(message.get_headers("x-tag"), &["one".to_string(), "two".to_string()]);
+        assert_eq!(message.get_headers("X-Missing").len(), 0);
+
+        let fields = BodySectionType::MsgtextSection(
+            Msgtext::HeaderFieldsMsgtext(vec!["X-TAG".to_string()]));
+        assert_eq!(literal_contents(&message.get_body(&fields, &None)[..]),
+                   "X-TAG: one\r\nX-TAG: two\r\n\r\n");
+    }
+
+    #[test]
+    fn test_body_nested_parts() {
+        let raw = "Subject: parts\n\
+                   Content-Type: multipart/mixed; boundary=outer\n\
+                   \n\
+                   --outer\n\
+                   \n\
+                   See below\n\
+                   --outer\n\
+                   Content-Type: multipart/alternative; boundary=inner\n\
+                   \n\
+                   --inner\n\
+                   Content-Type: text/plain\n\
+                   \n\
+                   Plain\n\
+                   --inner\n\
+                   Content-Type: text/html\n\
+                   \n\
+                   <p>HTML</p>\n\
+                   --inner--\n\
+                   --outer\n\
+                   Content-Type: message/rfc822\n\
+                   \n\
+                   Subject: forwarded\n\
+                   From: a@example.com\n\
+                   \n\
+                   Inside\n\
+                   --outer--\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        let part = |path: Vec<usize>, msgtext| {
+            message.get_body(&BodySectionType::PartSection(path, msgtext), &None)
+        };
+
+        assert_eq!(part(vec![2, 1], None), "BODY[2.1] {5}\r\nPlain");
+        assert_eq!(part(vec![2, 2], Some(Msgtext::MimeMsgtext)),
+                   "BODY[2.2.MIME] {25}\r\nContent-Type: text/html\n\n");
+        assert_eq!(part(vec![1], None), "BODY[1] {9}\r\nSee below");
+        assert_eq!(part(vec![3], Some(Msgtext::HeaderMsgtext)),
+                   "BODY[3.HEADER] {40}\r\nSubject: forwarded\nFrom: a@example.com\n\n");
+        assert_eq!(part(vec![3], Some(Msgtext::TextMsgtext)), "BODY[3.TEXT] {6}\r\nInside");
+        assert_eq!(part(vec![3], Some(Msgtext::HeaderFieldsMsgtext(vec!["FROM".to_string()]))),
+                   "BODY[3.HEADER.FIELDS (FROM)] {21}\r\nFrom: a@example.com\n\n");
+        // Only a message/rfc822 part has a header and text of its own.
+        assert_eq!(part(vec![2], Some(Msgtext::TextMsgtext)), "BODY[2.TEXT] NIL");
+        assert_eq!(part(vec![4], None), "BODY[4] NIL");
+    }
+
+    #[test]
+    fn test_imap_literal() {
+        assert_eq!(imap_literal(""), "{0}\r\n");
+        assert_eq!(imap_literal("abc\r\n"), "{5}\r\nabc\r\n");
+        // The length is in octets, not characters.
+        assert_eq!(imap_literal("\u{e9}\u{2615}"), "{5}\r\n\u{e9}\u{2615}");
+    }
+
+    #[test]
+    fn test_crlf_message() {
+        let raw = "Subject: hi\r\nFrom: a@example.com\r\n\r\nHello\r\n\r\nBye\r\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        assert_eq!(message.get_header(), "Subject: hi\r\nFrom: a@example.com\r\n");
+        assert_eq!(message.get_header_block(), "Subject: hi\r\nFrom: a@example.com\r\n\r\n");
+        assert_eq!(message.get_text(), "Hello\r\n\r\nBye\r\n");
+        assert_eq!(message.get_headers("Subject"), &["hi".to_string()]);
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        assert_eq!(crlf_line_endings("a\nb\r\nc\n\n".to_string()), "a\r\nb\r\nc\r\n\r\n");
+        assert_eq!(crlf_line_endings("\nno end".to_string()), "\r\nno end");
+        assert_eq!(crlf_line_endings("a\r\n".to_string()), "a\r\n");
+
+        // The sizes in the body structure follow the new line endings.
+        let raw = "Subject: hi\n\nHello\nworld\n";
+        let message = Message::parse(crlf_line_endings(raw.to_string())).unwrap();
+        assert_eq!(message.get_size(), "29");
+        let text = message.get_body(&BodySectionType::MsgtextSection(Msgtext::TextMsgtext),
+                                    &None);
+        assert_eq!(text, "BODY[TEXT] {14}\r\nHello\r\nworld\r\n");
+        assert_eq!(message.get_bodystructure(false),
+                   "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 14 2)");
+    }
+
+    #[test]
+    fn test_body_empty_text() {
+        let message = Message::parse("Subject: hi\n\n".to_string()).unwrap();
+        let text = message.get_body(&BodySectionType::MsgtextSection(Msgtext::TextMsgtext),
+                                    &None);
+        assert_eq!(text, "BODY[TEXT] {0}\r\n");
+    }
+
+    #[test]
+    fn test_quote_imap_string() {
+        assert_eq!(quote_imap_string(""), "\"\"");
+        assert_eq!(quote_imap_string("Hello"), "\"Hello\"");
+        assert_eq!(quote_imap_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_imap_string("C:\\temp"), "\"C:\\\\temp\"");
+        assert_eq!(quote_imap_string("two\r\nlines"), "{10}\r\ntwo\r\nlines");
+        assert_eq!(quote_imap_string("bare\nfeed"), "{9}\r\nbare\nfeed");
+        assert_eq!(quote_imap_string("caf\u{e9}"), "{5}\r\ncaf\u{e9}");
+    }
+
+    #[test]
+    fn test_envelope_quoting() {
+        let raw = "Subject: a \"quoted\" \\ subject\nMessage-ID: <1@example.com>\n\nbody\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        assert_eq!(
+            message.get_envelope(false),
+            "(NIL \"a \\\"quoted\\\" \\\\ subject\" NIL NIL NIL NIL NIL NIL NIL \"<1@example.com>\")"
+        );
+    }
+
+    #[test]
+    fn test_envelope_decoding() {
+        let raw = "Subject: =?UTF-8?Q?Caf=C3=A9?= menu\nFrom: =?ISO-8859-1?Q?Andr=E9?= <a@example.com>\n\nbody\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        assert_eq!(
+            message.get_envelope(false),
+            "(NIL \"=?UTF-8?Q?Caf=C3=A9?= menu\" ((\"=?ISO-8859-1?Q?Andr=E9?=\" NIL \"a\" \"example.com\")) \
+             NIL NIL NIL NIL NIL NIL NIL)"
+        );
+        assert_eq!(
+            message.get_envelope(true),
+            "(NIL {10}\r\nCaf\u{e9} menu (({6}\r\nAndr\u{e9} NIL \"a\" \"example.com\")) \
+             NIL NIL NIL NIL NIL NIL NIL)"
+        );
+
+        // A name which decodes to a quote is escaped rather than ending the
+        // string early.
+        let raw = "To: =?UTF-8?Q?=22Bob=22_=5C_co?= <bob@example.com>, c@example.com\n\nbody\n";
+        let message = Message::parse(raw.to_string()).unwrap();
+        assert_eq!(
+            message.get_envelope(true),
+            "(NIL NIL NIL NIL NIL ((\"\\\"Bob\\\" \\\\ co\" NIL \"bob\" \"example.com\")\
+             (NIL NIL \"c\" \"example.com\")) NIL NIL NIL NIL)"
+        );
+    }
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use self::test::Bencher;
+    use super::Message;
+
+    const PLAIN: &'static str = "Received: from mx.example.com\n\
+                                 Date: Mon, 7 Feb 1994 21:52:25 -0800\n\
+                                 From: Fred Foobar <foobar@example.com>\n\
+                                 Subject: afternoon meeting\n\
+                                 To: mooch@example.com\n\
+                                 Message-ID: <B27397-0100000@example.com>\n\
+                                 \n\
+                                 Hello Joe, do you think we can meet at 3:30 tomorrow?\n";
+
+    // A text part with an attachment, as most mail with a file attached is.
+    fn multipart() -> String {
+        let attachment = format!("{}\n", "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4".repeat(2))
+            .repeat(200);
+        format!("From: Fred Foobar <foobar@example.com>\n\
+                 Subject: photos\n\
+                 MIME-Version: 1.0\n\
+                 Content-Type: multipart/mixed; boundary=\"b1\"\n\
+                 \n\
+                 --b1\n\
+                 Content-Type: text/plain; charset=utf-8\n\
+                 \n\
+                 Here they are.\n\
+                 --b1\n\
+                 Content-Type: image/png; name=photo.png\n\
+                 Content-Disposition: attachment; filename=photo.png\n\
+                 Content-Transfer-Encoding: base64\n\
+                 \n\
+                 {}--b1--\n", attachment)
+    }
+
+    fn bench_new(b: &mut Bencher, name: &str, contents: &str) {
+        let path = env::temp_dir().join(format!("segimap-bench-{}-{}", name, process::id()));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        b.iter(|| Message::new(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[bench]
+    fn bench_new_plain(b: &mut Bencher) {
+        bench_new(b, "plain", PLAIN);
+    }
+
+    #[bench]
+    fn bench_new_multipart(b: &mut Bencher) {
+        bench_new(b, "multipart", &multipart()[..]);
+    }
+
+    #[bench]
+    fn bench_bodystructure(b: &mut Bencher) {
+        let message = Message::parse(multipart()).unwrap();
+        b.iter(|| message.get_bodystructure(true));
+    }
 }
@@ -0,0 +1,726 @@
+//! The BODY and BODYSTRUCTURE descriptions of a message (RFC 3501 - 7.4.2),
+//! worked out from the MIME header fields of the message and of each of its
+//! parts, along with the other things which need the parts told apart.
+
+use std::str;
+
+use address::address_list;
+use encoded_word::{decode_base64, decode_charset};
+use quote_imap_string;
+
+// Parts nested deeper than this are described as plain text, so that a
+// message can't make the server recurse without limit.
+const MAX_DEPTH: usize = 32;
+
+/// Describes a message, or a part of one, from its header and its body.
+///
+/// If `extensible` is set the extension data which BODYSTRUCTURE carries
+/// is included. Otherwise the non-extensible form returned for BODY is
+/// produced.
+pub fn body_structure(header: &str, body: &str, extensible: bool) -> String {
+    describe(header, body, extensible, false, 0)
+}
+
+/// A body part found by its part number.
+#[derive(Debug)]
+pub struct Part<'a> {
+    /// The MIME header fields of the part along with the blank line which
+    /// ends them
+    pub header: &'a str,
+    pub body: &'a str,
+    in_digest: bool
+}
+
+impl<'a> Part<'a> {
+    /// The header block and body of the message encapsulated in the part, if
+    /// it is a message/rfc822 part.
+    pub fn message(&self) -> Option<(&'a str, &'a str)> {
+        match content_type(self.header, self.in_digest) {
+            (ref media_type, ref subtype, _) if media_type == "MESSAGE" && subtype == "RFC822" =>
+                Some(split_block(self.body)),
+            _ => None
+        }
+    }
+}
+
+/// Finds the part of a message at `path`, the numbers of a section part
+/// such as 2.1 (RFC 3501 - 6.4.5). The parts of a message/rfc822 part are
+/// those of the message it holds, and anything other than a multipart is
+/// its own part 1.
+pub fn find_part<'a>(message: &'a str, path: &[usize]) -> Option<Part<'a>> {
+    let (header, body) = split_block(message);
+    find(header, body, path, false, 0)
+}
+
+fn find<'a>(header: &'a str, body: &'a str, path: &[usize], in_digest: bool,
+            depth: usize) -> Option<Part<'a>> {
+    let (&number, rest) = match path.split_first() {
+        Some(first) => first,
+        None => return Some(Part { header: header, body: body, in_digest: in_digest })
+    };
+    if depth >= MAX_DEPTH {
+        return None;
+    }
+    let (media_type, subtype, params) = content_type(header, in_digest);
+    if media_type == "MULTIPART" {
+        let parts = match params.iter().find(|&&(ref name, _)| name == "BOUNDARY") {
+            Some(&(_, ref boundary)) => split_multipart(body, &boundary[..]),
+            None => Vec::new()
+        };
+        // A multipart body which can't be split up is text, as in its
+        // description.
+        if !parts.is_empty() {
+            let part = parts.get(number.wrapping_sub(1))?;
+            let (part_header, part_body) = split_block(part);
+            return find(part_header, part_body, rest, subtype == "DIGEST", depth + 1);
+        }
+    } else if media_type == "MESSAGE" && subtype == "RFC822" {
+        let (message_header, message_body) = split_block(body);
+        return find(message_header, message_body, path, false, depth + 1);
+    }
+    if number == 1 && rest.is_empty() {
+        Some(Part { header: header, body: body, in_digest: in_digest })
+    } else {
+        None
+    }
+}
+
+/// The fields of a header block which are named in `fields`, or those
+/// which aren't if `not` is set, followed by the blank line ending the
+/// block. Field names are case-insensitive.
+pub fn header_fields(header: &str, fields: &[String], not: bool) -> String {
+    let mut res = String::new();
+    let mut end = "\r\n";
+    let mut keep = false;
+    for line in header.split_inclusive('\n') {
+        if line.trim_end_matches(|c| c == '\r' || c == '\n').is_empty() {
+            end = line;
+            break;
+        }
+        // Continuation lines go with the field they continue.
+        if !line.starts_with(' ') && !line.starts_with('\t') {
+            let name = line.splitn(2, ':').next().unwrap_or("").trim_end();
+            keep = fields.iter().any(|field| field.eq_ignore_ascii_case(name)) != not;
+        }
+        if keep {
+            res.push_str(line);
+        }
+    }
+    res.push_str(end);
+    res
+}
+
+// The parts of a multipart/digest default to message/rfc822 rather than
+// text/plain, as set out in RFC 2046 - 5.1.5.
+fn describe(header: &str, body: &str, extensible: bool, in_digest: bool, depth: usize) -> String {
+    let (media_type, subtype, params) = content_type(header, in_digest);
+
+    if media_type == "MULTIPART" && depth < MAX_DEPTH {
+        let parts = match params.iter().find(|&&(ref name, _)| name == "BOUNDARY") {
+            Some(&(_, ref boundary)) => split_multipart(body, &boundary[..]),
+            None => Vec::new()
+        };
+        if !parts.is_empty() {
+            let mut res = "(".to_string();
+            for part in parts {
+                let (part_header, part_body) = split_part(part);
+                res.push_str(&describe(part_header, part_body, extensible,
+                                       subtype == "DIGEST", depth + 1)[..]);
+            }
+            res.push(' ');
+            res.push_str(&quote_imap_string(&subtype[..])[..]);
+            if extensible {
+                res.push(' ');
+                res.push_str(&param_list(&params[..])[..]);
+                res.push_str(&extension_fields(header)[..]);
+            }
+            res.push(')');
+            return res;
+        }
+    }
+
+    // A multipart body which can't be split up is passed off as text.
+    let (media_type, subtype, params) = if media_type == "MULTIPART" {
+        default_content_type()
+    } else {
+        (media_type, subtype, params)
+    };
+    let encoding = header_value(header, "Content-Transfer-Encoding")
+        .map(|encoding| encoding.to_ascii_uppercase())
+        .unwrap_or_else(|| "7BIT".to_string());
+    let mut res = format!("({} {} {} {} {} {} {}",
+                          quote_imap_string(&media_type[..]),
+                          quote_imap_string(&subtype[..]),
+                          param_list(&params[..]),
+                          nstring(header_value(header, "Content-ID")),
+                          nstring(header_value(header, "Content-Description")),
+                          quote_imap_string(&encoding[..]),
+                          body.len());
+    if media_type == "MESSAGE" && subtype == "RFC822" && depth < MAX_DEPTH {
+        // The encapsulated message is described along with the part.
+        let (message_header, message_body) = split_part(body);
+        res.push_str(&format!(" {} {} {}", envelope(message_header),
+                              describe(message_header, message_body, extensible, false, depth + 1),
+                              body.lines().count())[..]);
+    } else if media_type == "TEXT" {
+        res.push_str(&format!(" {}", body.lines().count())[..]);
+    }
+    if extensible {
+        res.push(' ');
+        res.push_str(&nstring(header_value(header, "Content-MD5"))[..]);
+        res.push_str(&extension_fields(header)[..]);
+    }
+    res.push(')');
+    res
+}
+
+// The ENVELOPE of an encapsulated message, in the same form as
+// `Message::get_envelope` gives it for a message which isn't decoded.
+fn envelope(header: &str) -> String {
+    let quoted = |name| nstring(header_value(header, name));
+    let addresses = |name| header_value(header, name)
+        .map_or_else(|| "NIL".to_string(), |value| address_list(&value[..], false));
+    format!("({} {} {} {} {} {} {} {} {} {})",
+            quoted("Date"), quoted("Subject"), addresses("From"), addresses("Sender"),
+            addresses("Reply-To"), addresses("To"), addresses("Cc"), addresses("Bcc"),
+            quoted("In-Reply-To"), quoted("Message-ID"))
+}
+
+/// The body of a message as SEARCH BODY looks through it. Text parts sent
+/// as base64 or quoted-printable are decoded into their charset, so that
+/// what the reader sees can be found. Everything else, including the header
+/// blocks of the parts, is kept as it is.
+pub fn decoded_body(header: &str, body: &str) -> String {
+    let mut res = String::new();
+    decode_part(header, body, false, 0, &mut res);
+    res
+}
+
+fn decode_part(header: &str, body: &str, in_digest: bool, depth: usize, res: &mut String) {
+    let (media_type, subtype, params) = content_type(header, in_digest);
+    if media_type == "MULTIPART" && depth < MAX_DEPTH {
+        let parts = match params.iter().find(|&&(ref name, _)| name == "BOUNDARY") {
+            Some(&(_, ref boundary)) => split_multipart(body, &boundary[..]),
+            None => Vec::new()
+        };
+        if !parts.is_empty() {
+            for part in parts {
+                let (part_header, part_body) = split_block(part);
+                res.push_str(part_header);
+                decode_part(part_header, part_body, subtype == "DIGEST", depth + 1, res);
+                res.push('\n');
+            }
+            return;
+        }
+    } else if media_type == "MESSAGE" && subtype == "RFC822" && depth < MAX_DEPTH {
+        let (message_header, message_body) = split_block(body);
+        res.push_str(message_header);
+        decode_part(message_header, message_body, false, depth + 1, res);
+        return;
+    }
+
+    // A multipart body which can't be split up is text, as in its
+    // description.
+    let encoding = match header_value(header, "Content-Transfer-Encoding") {
+        Some(ref encoding) if media_type == "TEXT" || media_type == "MULTIPART" =>
+            encoding.to_ascii_lowercase(),
+        _ => String::new()
+    };
+    let decoded = match &encoding[..] {
+        "base64" => decode_base64(&body.split_whitespace().collect::<String>()[..]),
+        "quoted-printable" => Some(decode_quoted_printable(body)),
+        _ => None
+    };
+    match decoded {
+        Some(bytes) => {
+            let charset = params.iter().find(|&&(ref name, _)| name == "CHARSET")
+                .map_or("us-ascii", |&(_, ref charset)| &charset[..]);
+            match decode_charset(charset, &bytes[..]) {
+                Some(text) => res.push_str(&text[..]),
+                None => res.push_str(&String::from_utf8_lossy(&bytes[..])[..])
+            }
+        }
+        None => res.push_str(body)
+    }
+}
+
+// Undo the quoted-printable encoding (RFC 2045 - 6.7). Soft line breaks are
+// removed and an `=` which doesn't start an escape is kept as it is.
+fn decode_quoted_printable(body: &str) -> Vec<u8> {
+    let bytes = body.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            res.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &bytes[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let Some(byte) = rest.get(..2)
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            res.push(byte);
+            i += 3;
+        } else {
+            res.push(b'=');
+            i += 1;
+        }
+    }
+    res
+}
+
+// The disposition, language and location which end the extension data of
+// every kind of part, each preceded by a space.
+fn extension_fields(header: &str) -> String {
+    let disposition = match header_value(header, "Content-Disposition") {
+        Some(value) => {
+            let (disposition, params) = parse_params(&value[..]);
+            if disposition.is_empty() {
+                "NIL".to_string()
+            } else {
+                format!("({} {})", quote_imap_string(&disposition.to_ascii_uppercase()[..]),
+                        param_list(&params[..]))
+            }
+        }
+        None => "NIL".to_string()
+    };
+
+    // A single language is sent as a string, and several as a list.
+    let languages: Vec<String> = header_value(header, "Content-Language")
+        .map(|value| value.split(',')
+             .map(str::trim)
+             .filter(|language| !language.is_empty())
+             .map(quote_imap_string)
+             .collect())
+        .unwrap_or_else(Vec::new);
+    let language = match languages.len() {
+        0 => "NIL".to_string(),
+        1 => languages[0].clone(),
+        _ => format!("({})", languages.join(" "))
+    };
+
+    format!(" {} {} {}", disposition, language, nstring(header_value(header, "Content-Location")))
+}
+
+// The media type and subtype, in uppercase, and the parameters of the
+// Content-Type header field, falling back on the default when it is missing
+// or malformed.
+fn content_type(header: &str, in_digest: bool) -> (String, String, Vec<(String, String)>) {
+    let value = match header_value(header, "Content-Type") {
+        Some(value) => value,
+        None if in_digest => return ("MESSAGE".to_string(), "RFC822".to_string(), Vec::new()),
+        None => return default_content_type()
+    };
+    let (media, params) = parse_params(&value[..]);
+    let mut media = media.splitn(2, '/');
+    match (media.next().map(str::trim), media.next().map(str::trim)) {
+        (Some(media_type), Some(subtype)) if !media_type.is_empty() && !subtype.is_empty() =>
+            (media_type.to_ascii_uppercase(), subtype.to_ascii_uppercase(), params),
+        _ => default_content_type()
+    }
+}
+
+fn default_content_type() -> (String, String, Vec<(String, String)>) {
+    ("TEXT".to_string(), "PLAIN".to_string(),
+     vec![("CHARSET".to_string(), "us-ascii".to_string())])
+}
+
+/// The first value of a header field in a raw header, unfolded. The field
+/// name is case-insensitive.
+fn header_value(header: &str, name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in header.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(ref mut value) = value {
+                value.push(' ');
+                value.push_str(line.trim_start());
+            }
+            continue;
+        }
+        if value.is_some() {
+            break;
+        }
+        let mut split = line.splitn(2, ':');
+        if split.next().map_or(false, |field| field.trim_end().eq_ignore_ascii_case(name)) {
+            value = split.next().map(|value| value.trim().to_string());
+        }
+    }
+    value.map(|value| value.trim().to_string())
+}
+
+/// Splits a structured header field value, such as
+/// `text/plain; charset="utf-8"`, into the value itself and its parameters.
+/// Parameter names are given in uppercase and quoted values are unquoted.
+fn parse_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                pieces.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&value[start..]);
+
+    let mut params = Vec::new();
+    for piece in &pieces[1..] {
+        let mut split = piece.splitn(2, '=');
+        let name = split.next().unwrap_or("").trim();
+        match split.next() {
+            Some(value) if !name.is_empty() =>
+                params.push((name.to_ascii_uppercase(), unquote(value.trim()))),
+            _ => {}
+        }
+    }
+    (pieces[0].trim().to_string(), params)
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return value.to_string();
+    }
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                res.push(escaped);
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+/// The body of each part of a multipart body. The line break before each
+/// delimiter line belongs to the delimiter, so it isn't part of the body
+/// before it. A body without a closing delimiter ends at the end of the
+/// message.
+fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    for line in body.split_inclusive('\n') {
+        let content = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        if content.starts_with(&delimiter[..]) {
+            let rest = content[delimiter.len()..].trim_end();
+            if rest.is_empty() || rest == "--" {
+                if let Some(start) = start {
+                    let part = &body[start..pos];
+                    let part = if part.ends_with("\r\n") {
+                        &part[..part.len() - 2]
+                    } else if part.ends_with('\n') {
+                        &part[..part.len() - 1]
+                    } else {
+                        part
+                    };
+                    parts.push(part);
+                }
+                if rest == "--" {
+                    return parts;
+                }
+                start = Some(pos + line.len());
+            }
+        }
+        pos += line.len();
+    }
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Splits a body part at the blank line between its header and its body.
+fn split_part(part: &str) -> (&str, &str) {
+    if part.starts_with('\n') {
+        return ("", &part[1..]);
+    }
+    if part.starts_with("\r\n") {
+        return ("", &part[2..]);
+    }
+    match (part.find("\n\n"), part.find("\r\n\r\n")) {
+        (Some(lf), Some(crlf)) if crlf < lf => (&part[..crlf + 2], &part[crlf + 4..]),
+        (Some(lf), _) => (&part[..lf + 1], &part[lf + 2..]),
+        (None, Some(crlf)) => (&part[..crlf + 2], &part[crlf + 4..]),
+        (None, None) => (part, "")
+    }
+}
+
+// Like `split_part`, but with the blank line kept at the end of the header.
+fn split_block(part: &str) -> (&str, &str) {
+    let (_, body) = split_part(part);
+    (&part[..part.len() - body.len()], body)
+}
+
+// A parenthesized list of parameter names and values, or NIL.
+fn param_list(params: &[(String, String)]) -> String {
+    if params.is_empty() {
+        return "NIL".to_string();
+    }
+    let pairs: Vec<String> = params.iter().map(|&(ref name, ref value)| {
+        format!("{} {}", quote_imap_string(&name[..]), quote_imap_string(&value[..]))
+    }).collect();
+    format!("({})", pairs.join(" "))
+}
+
+fn nstring(value: Option<String>) -> String {
+    match value {
+        Some(value) => quote_imap_string(&value[..]),
+        None => "NIL".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body_structure, decoded_body, find_part, header_fields, header_value, parse_params,
+                split_multipart};
+
+    #[test]
+    fn test_text_part() {
+        let header = "Subject: hi\nContent-Type: text/plain;\n charset=\"utf-8\"; format=flowed\n";
+        assert_eq!(body_structure(header, "one\ntwo\n", false),
+                   "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\" \"FORMAT\" \"flowed\") NIL NIL \"7BIT\" 8 2)");
+        assert_eq!(body_structure(header, "one\ntwo\n", true),
+                   "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\" \"FORMAT\" \"flowed\") NIL NIL \"7BIT\" 8 2 NIL NIL NIL NIL)");
+
+        // A message without a Content-Type is plain US-ASCII text.
+        assert_eq!(body_structure("Subject: hi\n", "hello\n", false),
+                   "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 6 1)");
+    }
+
+    #[test]
+    fn test_multipart() {
+        let header = "Content-Type: multipart/mixed; boundary=\"b1\"\n";
+        let body = "Preamble\n\
+                    --b1\n\
+                    \n\
+                    Hello\n\
+                    --b1\n\
+                    Content-Type: image/png; name=dot.png\n\
+                    Content-ID: <dot@example.com>\n\
+                    Content-Transfer-Encoding: base64\n\
+                    \n\
+                    iVBORw0K\n\
+                    --b1--\n\
+                    Epilogue\n";
+        let parts = "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 5 1)\
+                     (\"IMAGE\" \"PNG\" (\"NAME\" \"dot.png\") \"<dot@example.com>\" NIL \"BASE64\" 8";
+        assert_eq!(body_structure(header, body, false), format!("({}) \"MIXED\")", parts));
+        // BODYSTRUCTURE adds the MD5 of each part and the parameters of the
+        // multipart.
+        assert_eq!(body_structure(header, body, true),
+                   "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 5 1 NIL NIL NIL NIL)\
+                    (\"IMAGE\" \"PNG\" (\"NAME\" \"dot.png\") \"<dot@example.com>\" NIL \"BASE64\" 8 \
+                    NIL NIL NIL NIL) \"MIXED\" (\"BOUNDARY\" \"b1\") NIL NIL NIL)");
+    }
+
+    #[test]
+    fn test_extension_fields() {
+        let header = "Content-Type: application/pdf; name=\"report.pdf\"\n\
+                      Content-Disposition: attachment;\n filename=\"Q3 report.pdf\"; size=4\n\
+                      Content-Language: en, fr\n\
+                      Content-Location: http://example.com/report.pdf\n\
+                      Content-MD5: Q2hlY2sgSW50ZWdyaXR5IQ==\n";
+        let extension = "\"Q2hlY2sgSW50ZWdyaXR5IQ==\" \
+                         (\"ATTACHMENT\" (\"FILENAME\" \"Q3 report.pdf\" \"SIZE\" \"4\")) \
+                         (\"en\" \"fr\") \"http://example.com/report.pdf\"";
+        let basic = "\"APPLICATION\" \"PDF\" (\"NAME\" \"report.pdf\") NIL NIL \"7BIT\" 4";
+        assert_eq!(body_structure(header, "%PDF", true), format!("({} {})", basic, extension));
+        // BODY leaves the extension data out.
+        assert_eq!(body_structure(header, "%PDF", false), format!("({})", basic));
+
+        let header = "Content-Type: multipart/mixed; boundary=b\n\
+                      Content-Disposition: inline\n\
+                      Content-Language: de\n";
+        assert_eq!(body_structure(header, "--b\n\nHi\n--b--\n", true),
+                   "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 2 1 NIL NIL NIL NIL) \
+                    \"MIXED\" (\"BOUNDARY\" \"b\") (\"INLINE\" NIL) \"de\" NIL)");
+    }
+
+    #[test]
+    fn test_nested_multipart() {
+        let header = "Content-Type: multipart/mixed; boundary=outer\n";
+        let body = "--outer\n\
+                    Content-Type: multipart/alternative; boundary=inner\n\
+                    \n\
+                    --inner\n\
+                    \n\
+                    Hi\n\
+                    --inner\n\
+                    Content-Type: text/html\n\
+                    \n\
+                    <p>Hi</p>\n\
+                    --inner--\n\
+                    --outer\n\
+                    Content-Type: multipart/digest; boundary=d\n\
+                    \n\
+                    --d\n\
+                    \n\
+                    Subject: inside\n\
+                    \n\
+                    Hi\n\
+                    --d--\n\
+                    --outer--\n";
+        assert_eq!(body_structure(header, body, false),
+                   "(((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 2 1)\
+                    (\"TEXT\" \"HTML\" NIL NIL NIL \"7BIT\" 9 1) \"ALTERNATIVE\")\
+                    ((\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 19 \
+                    (NIL \"inside\" NIL NIL NIL NIL NIL NIL NIL NIL) \
+                    (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 2 1) 3) \
+                    \"DIGEST\") \"MIXED\")");
+
+        // Without a boundary the parts can't be found.
+        assert_eq!(body_structure("Content-Type: multipart/mixed\n", "--b\n\nHi\n--b--\n", false),
+                   "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 14 4)");
+    }
+
+    #[test]
+    fn test_forwarded_message() {
+        let header = "Content-Type: multipart/mixed; boundary=b\n";
+        let body = "--b\n\
+                    \n\
+                    See below\n\
+                    --b\n\
+                    Content-Type: message/rfc822\n\
+                    Content-Disposition: attachment\n\
+                    \n\
+                    Date: Mon, 7 Feb 1994 21:52:25 -0800\n\
+                    From: a@example.com\n\
+                    To: Bob <b@example.com>, c@example.com\n\
+                    Subject: Lunch\n\
+                    Message-ID: <1@example.com>\n\
+                    Content-Type: text/plain; charset=utf-8\n\
+                    \n\
+                    Noon?\n\
+                    --b--\n";
+        let envelope = "(\"Mon, 7 Feb 1994 21:52:25 -0800\" \"Lunch\" \
+                        ((NIL NIL \"a\" \"example.com\")) NIL NIL \
+                        ((\"Bob\" NIL \"b\" \"example.com\")(NIL NIL \"c\" \"example.com\")) \
+                        NIL NIL NIL \"<1@example.com>\")";
+        let text = "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 9 1";
+        let message = "\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 185";
+        let inner = "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 5 1";
+        assert_eq!(body_structure(header, body, false),
+                   format!("({})({} {} {}) 8) \"MIXED\")", text, message, envelope, inner));
+        assert_eq!(body_structure(header, body, true),
+                   format!("({} NIL NIL NIL NIL)({} {} {} NIL NIL NIL NIL) 8 NIL (\"ATTACHMENT\" NIL) \
+                            NIL NIL) \"MIXED\" (\"BOUNDARY\" \"b\") NIL NIL NIL)",
+                           text, message, envelope, inner));
+    }
+
+    #[test]
+    fn test_find_part() {
+        let message = "Content-Type: multipart/mixed; boundary=outer\n\
+                       \n\
+                       --outer\n\
+                       \n\
+                       One\n\
+                       --outer\n\
+                       Content-Type: multipart/alternative; boundary=inner\n\
+                       \n\
+                       --inner\n\
+                       Content-Type: text/plain\n\
+                       \n\
+                       Two\n\
+                       --inner--\n\
+                       --outer\n\
+                       Content-Type: message/rfc822\n\
+                       \n\
+                       Subject: three\n\
+                       \n\
+                       Three\n\
+                       --outer--\n";
+        let body = |path: &[usize]| find_part(message, path).map(|part| part.body);
+        assert_eq!(body(&[1]), Some("One"));
+        assert_eq!(body(&[2, 1]), Some("Two"));
+        assert_eq!(find_part(message, &[2, 1]).unwrap().header, "Content-Type: text/plain\n\n");
+        assert_eq!(body(&[3]), Some("Subject: three\n\nThree"));
+        assert_eq!(find_part(message, &[3]).unwrap().message(), Some(("Subject: three\n\n", "Three")));
+        assert_eq!(find_part(message, &[2]).unwrap().message(), None);
+        // The message held in part 3 isn't a multipart, so its part 1 is its
+        // text.
+        assert_eq!(body(&[3, 1]), Some("Three"));
+        assert_eq!(body(&[4]), None);
+        assert_eq!(body(&[0]), None);
+        assert_eq!(body(&[1, 2]), None);
+
+        // A message which isn't a multipart is its own part 1.
+        assert_eq!(find_part("Subject: hi\n\nHi", &[1]).map(|part| part.body), Some("Hi"));
+    }
+
+    #[test]
+    fn test_decoded_body() {
+        // Each text part is decoded into its charset.
+        let header = "Content-Type: multipart/alternative; boundary=b\n";
+        let body = "--b\n\
+                    Content-Type: text/plain; charset=utf-8\n\
+                    Content-Transfer-Encoding: quoted-printable\n\n\
+                    Caf=C3=A9 au lait, =\nplease=\n\
+                    --b\n\
+                    Content-Type: text/html; charset=iso-8859-1\n\
+                    Content-Transfer-Encoding: base64\n\n\
+                    PHA+Q2Fm6SBub2lyPC9wPg==\n\
+                    --b--\n";
+        let decoded = decoded_body(header, body);
+        assert!(decoded.contains("Café au lait, please"), "{}", decoded);
+        assert!(decoded.contains("<p>Café noir</p>"), "{}", decoded);
+        assert!(decoded.contains("Content-Type: text/html"), "{}", decoded);
+
+        // Other parts are left alone, as is a part without an encoding.
+        let header = "Content-Type: image/png\nContent-Transfer-Encoding: base64\n";
+        assert_eq!(decoded_body(header, "iVBORw0KGgo=\n"), "iVBORw0KGgo=\n");
+        assert_eq!(decoded_body("Subject: hi\n", "a=3Db\n"), "a=3Db\n");
+        // As is an escape which isn't one.
+        let header = "Content-Transfer-Encoding: quoted-printable\n";
+        assert_eq!(decoded_body(header, "1 =XY 2 =3D 3="), "1 =XY 2 = 3=");
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let header = "From: a@example.com\r\nSubject: a\r\n long one\r\nTo: b@example.com\r\n\r\n";
+        let fields = vec!["SUBJECT".to_string(), "to".to_string()];
+        assert_eq!(header_fields(header, &fields, false),
+                   "Subject: a\r\n long one\r\nTo: b@example.com\r\n\r\n");
+        assert_eq!(header_fields(header, &fields, true), "From: a@example.com\r\n\r\n");
+        assert_eq!(header_fields("", &fields, false), "\r\n");
+    }
+
+    #[test]
+    fn test_split_multipart() {
+        assert_eq!(split_multipart("--b\r\nA\r\n--b \r\nB\r\n--b--\r\n", "b"), vec!["A", "B"]);
+        // A line which only starts with the delimiter doesn't end the part.
+        assert_eq!(split_multipart("--b\nA\n--bc\n--b--\n", "b"), vec!["A\n--bc"]);
+        assert_eq!(split_multipart("--b\nA\n--b\nB\n", "b"), vec!["A", "B\n"]);
+        assert_eq!(split_multipart("No parts\n", "b"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_params() {
+        assert_eq!(parse_params("text/plain; name=\"a; \\\"b\\\"\"; charset=utf-8;"),
+                   ("text/plain".to_string(),
+                    vec![("NAME".to_string(), "a; \"b\"".to_string()),
+                         ("CHARSET".to_string(), "utf-8".to_string())]));
+        assert_eq!(header_value("X-A: 1\ncontent-id: <a>\n\t<b>\nX-B: 2\n", "Content-ID"),
+                   Some("<a> <b>".to_string()));
+        assert_eq!(header_value("X-A: 1\n", "Content-ID"), None);
+    }
+}